@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::cli::parse_filesystem;
+use crate::partition_layout::{self, RawPartition};
+use crate::boot_entries::BootPriority;
+use crate::{BootMode, DiskInfo, FileConfig, InstallationConfig, SecureBootConfig};
+
+// Fichero de respuestas para instalaciones desatendidas. Sigue el mismo
+// formato que los config/*.toml del sistema de compilación:
+//
+//   [disk]
+//   model = "Samsung SSD"
+//
+//   [general]
+//   efi_size = 512
+//   filesystem = "redoxfs"
+//   filesystem_config = "config/x86_64/server.toml"
+//   hostname = "lab-01"
+//   dual_boot = true   (instalar en el espacio libre junto a otro sistema)
+//   free_gap = 2       (hueco libre a usar; por defecto el mayor)
+//   restore_table_on_failure = true
+//   backup_dir = "/root/backups"
+//   upgrade = true     (actualizar la instalación de Redox existente)
+//   redoxfs_direct = true  (escribir el root RedoxFS sin montarlo con FUSE)
+//   boot_mode = "hybrid"  (uefi, hybrid o bios; por defecto uefi)
+//   boot_entry = "next"   (first, last o next; por defecto first)
+//
+//   [secure_boot]  (opcional: firma el bootloader para Secure Boot)
+//   key = "/root/mok/redox.key"
+//   certificate = "/root/mok/redox.crt"
+//   shim = "/usr/lib/shim/shimx64.efi.signed"    (opcional)
+//   mok_manager = "/usr/lib/shim/mmx64.efi.signed"  (opcional, con shim)
+//
+//   [packages]
+//   ion = {}
+//
+//   [[files]]
+//   path = "/etc/motd"
+//   data = "Bienvenido"
+//
+//   [[partitions]]  (opcional, ver partition_layout.rs)
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnswerFile {
+    #[serde(default)]
+    pub disk: DiskSelector,
+    #[serde(default)]
+    pub general: GeneralAnswers,
+    pub secure_boot: Option<SecureBootConfig>,
+    #[serde(default)]
+    pub packages: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    pub files: Vec<FileConfig>,
+    #[serde(default)]
+    pub partitions: Vec<RawPartition>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskSelector {
+    // Ruta exacta del dispositivo, p. ej. /dev/sdb
+    pub path: Option<String>,
+    // Subcadena del modelo (sin distinguir mayúsculas)
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeneralAnswers {
+    pub efi_size: Option<u64>,
+    pub filesystem: Option<String>,
+    pub filesystem_config: Option<String>,
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub dual_boot: bool,
+    pub free_gap: Option<usize>,
+    #[serde(default)]
+    pub restore_table_on_failure: bool,
+    pub backup_dir: Option<String>,
+    #[serde(default)]
+    pub upgrade: bool,
+    #[serde(default)]
+    pub redoxfs_direct: bool,
+    pub boot_mode: Option<String>,
+    pub boot_entry: Option<String>,
+}
+
+impl AnswerFile {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Error leyendo fichero de respuestas {}: {}", path, e))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Error en fichero de respuestas {}: {}", path, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    pub fn installation_config(&self) -> Result<InstallationConfig, String> {
+        let mut config = InstallationConfig::default();
+
+        if let Some(efi_size) = self.general.efi_size {
+            if efi_size < 100 {
+                return Err(format!("efi_size inválido: {} (mínimo 100 MB)", efi_size));
+            }
+            config.efi_size_mb = efi_size;
+        }
+
+        if let Some(filesystem) = &self.general.filesystem {
+            config.filesystem_type = parse_filesystem(filesystem)
+                .ok_or_else(|| format!("filesystem inválido: {} (redoxfs/ext4)", filesystem))?;
+        }
+
+        if let Some(filesystem_config) = &self.general.filesystem_config {
+            config.filesystem_config = filesystem_config.clone();
+        }
+
+        if let Some(hostname) = &self.general.hostname {
+            if hostname.trim().is_empty() || hostname.contains(char::is_whitespace) {
+                return Err(format!("hostname inválido: '{}'", hostname));
+            }
+            config.hostname = hostname.clone();
+        }
+
+        if self.general.free_gap.is_some() && !self.general.dual_boot {
+            return Err("free_gap solo es válido con dual_boot = true".to_string());
+        }
+        if self.general.free_gap == Some(0) {
+            return Err("free_gap empieza en 1".to_string());
+        }
+        config.dual_boot = self.general.dual_boot;
+        config.free_gap = self.general.free_gap;
+        config.restore_table_on_failure = self.general.restore_table_on_failure;
+        if let Some(backup_dir) = &self.general.backup_dir {
+            config.backup_dir = backup_dir.clone();
+        }
+        if self.general.upgrade && self.general.dual_boot {
+            return Err("upgrade no se puede combinar con dual_boot".to_string());
+        }
+        config.upgrade = self.general.upgrade;
+        if self.general.redoxfs_direct && self.general.upgrade {
+            return Err("redoxfs_direct no se puede combinar con upgrade".to_string());
+        }
+        config.redoxfs_direct = self.general.redoxfs_direct;
+
+        if let Some(boot_mode) = &self.general.boot_mode {
+            config.boot_mode = BootMode::parse(boot_mode)?;
+            if config.boot_mode.bios() && config.dual_boot {
+                return Err(format!("boot_mode = \"{}\" no se puede combinar con dual_boot", boot_mode));
+            }
+        }
+
+        if let Some(boot_entry) = &self.general.boot_entry {
+            config.boot_priority = BootPriority::parse(boot_entry)?;
+        }
+
+        if let Some(secure_boot) = &self.secure_boot {
+            secure_boot.validate()?;
+            if config.boot_mode == BootMode::Bios {
+                return Err("[secure_boot] no se puede combinar con boot_mode = \"bios\"".to_string());
+            }
+            config.secure_boot = Some(secure_boot.clone());
+        }
+
+        config.packages = self.packages.keys().cloned().collect();
+
+        for file in &self.files {
+            if !file.path.starts_with('/') {
+                return Err(format!("La ruta de [[files]] debe ser absoluta: {}", file.path));
+            }
+        }
+        config.files = self.files.clone();
+
+        if !self.partitions.is_empty() {
+            // Con una distribución explícita el tamaño EFI y el sistema de
+            // archivos de root se indican en cada partición
+            if self.general.efi_size.is_some() || self.general.filesystem.is_some() {
+                return Err("efi_size y filesystem no se pueden combinar con [[partitions]]".to_string());
+            }
+
+            config.partitions = self.partitions
+                .iter()
+                .enumerate()
+                .map(|(i, partition)| partition.to_spec(i))
+                .collect::<Result<_, _>>()?;
+            partition_layout::validate_layout(&config.partitions, config.boot_mode)?;
+            if config.dual_boot {
+                return Err("dual_boot no se puede combinar con [[partitions]]".to_string());
+            }
+            if config.upgrade {
+                return Err("upgrade no se puede combinar con [[partitions]]".to_string());
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl DiskSelector {
+    pub fn is_empty(&self) -> bool {
+        self.path.is_none() && self.model.is_none()
+    }
+
+    pub fn matches(&self, disk: &DiskInfo) -> bool {
+        if let Some(path) = &self.path {
+            if &disk.name != path {
+                return false;
+            }
+        }
+        if let Some(model) = &self.model {
+            if !disk.model.to_lowercase().contains(&model.to_lowercase()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Devuelve el único disco que cumple el selector
+    pub fn select(&self, disks: Vec<DiskInfo>) -> Result<DiskInfo, String> {
+        if self.is_empty() {
+            return Err("El fichero de respuestas no indica el disco ([disk] path o model)".to_string());
+        }
+
+        let mut matching: Vec<DiskInfo> = disks.into_iter().filter(|disk| self.matches(disk)).collect();
+        match matching.len() {
+            0 => Err(format!("Ningún disco disponible coincide con el selector {:?}", self)),
+            1 => Ok(matching.remove(0)),
+            _ => Err(format!(
+                "Varios discos coinciden con el selector: {}",
+                matching.iter().map(|disk| disk.name.as_str()).collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FilesystemType, PartitionFilesystem, PartitionSize};
+
+    fn config(contents: &str) -> Result<InstallationConfig, String> {
+        AnswerFile::parse(contents)?.installation_config()
+    }
+
+    fn disk(name: &str, model: &str) -> DiskInfo {
+        DiskInfo {
+            name: name.to_string(),
+            model: model.to_string(),
+            ..DiskInfo::default()
+        }
+    }
+
+    #[test]
+    fn parses_a_complete_answer_file() {
+        let answers = AnswerFile::parse(
+            r#"
+            [disk]
+            model = "samsung"
+
+            [general]
+            efi_size = 256
+            filesystem = "ext4"
+            hostname = "lab-01"
+            boot_mode = "hybrid"
+            boot_entry = "last"
+            restore_table_on_failure = true
+
+            [packages]
+            ion = {}
+            coreutils = {}
+
+            [[files]]
+            path = "/etc/motd"
+            data = "Bienvenido"
+            mode = 0o644
+            "#,
+        )
+        .unwrap();
+        let config = answers.installation_config().unwrap();
+
+        assert_eq!(answers.disk.model.as_deref(), Some("samsung"));
+        assert_eq!(config.efi_size_mb, 256);
+        assert!(matches!(config.filesystem_type, FilesystemType::Ext4));
+        assert_eq!(config.hostname, "lab-01");
+        assert_eq!(config.boot_mode, BootMode::Hybrid);
+        assert_eq!(config.boot_priority, BootPriority::Last);
+        assert!(config.restore_table_on_failure);
+        assert_eq!(config.packages, ["coreutils", "ion"]);
+        assert_eq!(config.files.len(), 1);
+        assert_eq!(config.files[0].mode, Some(0o644));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(AnswerFile::parse("[general]\nefi_sise = 512\n").unwrap_err().contains("efi_sise"));
+        assert!(AnswerFile::parse("[disk]\nserial = \"X\"\n").unwrap_err().contains("serial"));
+        assert!(AnswerFile::parse("[extra]\n").unwrap_err().contains("extra"));
+        assert!(AnswerFile::parse("[[files]]\npath = \"/a\"\nowner = 0\n").unwrap_err().contains("owner"));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let cases = [
+            ("[general]\nefi_size = 50", "efi_size inválido"),
+            ("[general]\nfilesystem = \"btrfs\"", "filesystem inválido"),
+            ("[general]\nhostname = \"two words\"", "hostname inválido"),
+            ("[general]\nfree_gap = 1", "free_gap solo es válido"),
+            ("[general]\nupgrade = true\ndual_boot = true", "upgrade no se puede combinar"),
+            ("[general]\nupgrade = true\nredoxfs_direct = true", "redoxfs_direct no se puede combinar"),
+            ("[[files]]\npath = \"etc/motd\"", "debe ser absoluta"),
+        ];
+        for (contents, expected) in cases {
+            let err = config(contents).unwrap_err();
+            assert!(err.contains(expected), "{}: {}", contents, err);
+        }
+    }
+
+    #[test]
+    fn partitions_replace_the_default_layout() {
+        let config = config(
+            r#"
+            [[partitions]]
+            size = "512M"
+            filesystem = "efi"
+
+            [[partitions]]
+            size = "rest"
+            filesystem = "redoxfs"
+            mount = "/"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.partitions.len(), 2);
+        assert_eq!(config.partitions[0].size, PartitionSize::Mebibytes(512));
+        assert_eq!(config.partitions[1].filesystem, PartitionFilesystem::RedoxFS);
+        assert_eq!(config.partitions[1].mount_point.as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn partitions_are_validated() {
+        let without_root = "[[partitions]]\nsize = \"512M\"\nfilesystem = \"efi\"\n";
+        assert!(config(without_root).unwrap_err().contains("montada en /"));
+
+        let with_efi_size = format!("[general]\nefi_size = 512\n{}", without_root);
+        assert!(config(&with_efi_size).unwrap_err().contains("no se pueden combinar con [[partitions]]"));
+    }
+
+    #[test]
+    fn disk_selector_picks_exactly_one_disk() {
+        let disks = || vec![disk("/dev/sda", "Samsung SSD 870"), disk("/dev/sdb", "Kingston A400"), disk("/dev/sdc", "Samsung T7")];
+        let by_path = DiskSelector { path: Some("/dev/sdb".to_string()), model: None };
+        let by_model = DiskSelector { path: None, model: Some("ssd".to_string()) };
+        let both = DiskSelector { path: Some("/dev/sdc".to_string()), model: Some("ssd".to_string()) };
+        let ambiguous = DiskSelector { path: None, model: Some("samsung".to_string()) };
+
+        assert_eq!(by_path.select(disks()).unwrap().name, "/dev/sdb");
+        assert_eq!(by_model.select(disks()).unwrap().name, "/dev/sda");
+        assert!(both.select(disks()).unwrap_err().starts_with("Ningún disco"));
+        assert!(ambiguous.select(disks()).unwrap_err().contains("/dev/sda, /dev/sdc"));
+        assert!(DiskSelector::default().select(disks()).unwrap_err().contains("no indica el disco"));
+    }
+}
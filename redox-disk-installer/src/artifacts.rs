@@ -0,0 +1,342 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::target_arch::TargetArch;
+
+// Valores por defecto del sistema de compilación de Redox (mk/config.mk)
+const DEFAULT_ARCH: &str = "x86_64";
+const DEFAULT_CONFIG_NAME: &str = "desktop";
+
+// Árbol de Redox compilado del que se toman los artefactos:
+//   <source_root>/build/<arch>/<config_name>/...
+//   <source_root>/cookbook/recipes/<categoría>/<receta>/target/<arch>-unknown-redox/...
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildLocation {
+    pub source_root: PathBuf,
+    pub arch: String,
+    pub config_name: String,
+}
+
+impl Default for BuildLocation {
+    fn default() -> Self {
+        Self {
+            source_root: PathBuf::from("."),
+            arch: DEFAULT_ARCH.to_string(),
+            config_name: DEFAULT_CONFIG_NAME.to_string(),
+        }
+    }
+}
+
+impl BuildLocation {
+    // Los valores por defecto se pueden cambiar con REDOX_SOURCE_ROOT y con
+    // ARCH y CONFIG_NAME, las mismas variables que usa make
+    pub fn from_env() -> Self {
+        let mut location = Self::default();
+        if let Some(source_root) = env::var_os("REDOX_SOURCE_ROOT").filter(|value| !value.is_empty()) {
+            location.source_root = PathBuf::from(source_root);
+        }
+        if let Some(arch) = env::var("ARCH").ok().filter(|value| !value.is_empty()) {
+            location.arch = arch;
+        }
+        if let Some(config_name) = env::var("CONFIG_NAME").ok().filter(|value| !value.is_empty()) {
+            location.config_name = config_name;
+        }
+        location
+    }
+
+    pub fn target_arch(&self) -> Result<TargetArch, String> {
+        TargetArch::parse(&self.arch)
+    }
+
+    // Target de las recetas, p. ej. x86_64-unknown-redox
+    pub fn target(&self) -> String {
+        format!("{}-unknown-redox", self.arch)
+    }
+
+    // Configuración de la imagen, relativa a la raíz del árbol
+    pub fn filesystem_config(&self) -> String {
+        format!("config/{}/{}.toml", self.arch, self.config_name)
+    }
+
+    // Las rutas relativas se interpretan desde la raíz del árbol
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            self.source_root.join(path)
+        }
+    }
+
+    fn build_dir(&self) -> String {
+        format!("build/{}/{}", self.arch, self.config_name)
+    }
+}
+
+// Artefacto encontrado y el motivo por el que se eligió
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.path.display(), self.reason)
+    }
+}
+
+// Busca los artefactos de la compilación y las herramientas de RedoxFS
+#[derive(Debug, Clone)]
+pub struct ArtifactResolver {
+    location: BuildLocation,
+    // Rutas explícitas de REDOXFS_MKFS y REDOXFS_MOUNT
+    redoxfs_mkfs: Option<PathBuf>,
+    redoxfs_mount: Option<PathBuf>,
+    // Directorios de PATH
+    path: Vec<PathBuf>,
+}
+
+impl ArtifactResolver {
+    pub fn new(location: BuildLocation) -> Self {
+        let var = |name: &str| env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+        let path = env::var_os("PATH")
+            .map(|path| env::split_paths(&path).collect())
+            .unwrap_or_default();
+        Self::with_tools(location, var("REDOXFS_MKFS"), var("REDOXFS_MOUNT"), path)
+    }
+
+    pub fn with_tools(
+        location: BuildLocation,
+        redoxfs_mkfs: Option<PathBuf>,
+        redoxfs_mount: Option<PathBuf>,
+        path: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            location,
+            redoxfs_mkfs,
+            redoxfs_mount,
+            path,
+        }
+    }
+
+    pub fn location(&self) -> &BuildLocation {
+        &self.location
+    }
+
+    pub fn bootloader(&self) -> Result<Artifact, String> {
+        let target = self.location.target();
+        let build = self.location.build_dir();
+        let arch = &self.location.arch;
+        self.first_existing("Bootloader", &[
+            (format!("cookbook/recipes/core/bootloader/target/{}/build/bootloader.efi", target), "receta bootloader compilada".to_string()),
+            (format!("cookbook/recipes/core/bootloader/target/{}/stage/boot/bootloader.efi", target), "stage de la receta bootloader".to_string()),
+            (format!("{}/bootloader-live.efi", build), format!("imagen live de {}", build)),
+            (format!("{}/bootloader.efi", build), format!("salida de make en {}", build)),
+            (
+                format!("cookbook/recipes/core/bootloader/source/build/bootloader_{}-unknown-uefi.efi", arch),
+                "fuente de la receta bootloader".to_string(),
+            ),
+            ("build/bootloader.efi".to_string(), "bootloader genérico en build/".to_string()),
+        ])
+    }
+
+    // Imagen con el MBR (stage 1) y el stage 2 del bootloader BIOS
+    pub fn bios_bootloader(&self) -> Result<Artifact, String> {
+        let target = self.location.target();
+        let build = self.location.build_dir();
+        self.first_existing("Bootloader BIOS", &[
+            (format!("cookbook/recipes/core/bootloader/target/{}/build/bootloader.bios", target), "receta bootloader compilada".to_string()),
+            (format!("cookbook/recipes/core/bootloader/target/{}/stage/boot/bootloader.bios", target), "stage de la receta bootloader".to_string()),
+            (format!("{}/bootloader.bios", build), format!("salida de make en {}", build)),
+        ])
+    }
+
+    pub fn kernel(&self) -> Result<Artifact, String> {
+        let target = self.location.target();
+        let build = self.location.build_dir();
+        self.first_existing("Kernel", &[
+            (format!("cookbook/recipes/core/kernel/target/{}/build/kernel", target), "receta kernel compilada".to_string()),
+            (format!("cookbook/recipes/core/kernel/target/{}/stage/boot/kernel", target), "stage de la receta kernel".to_string()),
+            (format!("{}/kernel", build), format!("salida de make en {}", build)),
+            (format!("{}/harddrive/kernel", build), format!("imagen harddrive de {}", build)),
+            (
+                format!("cookbook/recipes/core/kernel/source/target/{}/release/kernel", target),
+                "fuente de la receta kernel".to_string(),
+            ),
+        ])
+    }
+
+    // El initfs es opcional: algunas configuraciones no lo generan
+    pub fn initfs(&self) -> Option<Artifact> {
+        let target = self.location.target();
+        let build = self.location.build_dir();
+        self.first_existing("Initfs", &[
+            (format!("cookbook/recipes/core/base-initfs/target/{}/build/initfs.img", target), "receta base-initfs compilada".to_string()),
+            (format!("{}/initfs.img", build), format!("salida de make en {}", build)),
+            (format!("{}/harddrive/initfs.img", build), format!("imagen harddrive de {}", build)),
+        ])
+        .ok()
+    }
+
+    // Stage de una receta: cookbook/recipes/<categoría>/<receta>/target/<target>/stage
+    pub fn recipe_stage(&self, recipe: &str) -> Option<Artifact> {
+        let recipes = self.location.source_root.join("cookbook/recipes");
+        let mut categories: Vec<PathBuf> = fs::read_dir(&recipes)
+            .ok()?
+            .flatten()
+            .map(|category| category.path())
+            .collect();
+        categories.sort();
+
+        categories.into_iter().find_map(|category| {
+            let stage = category.join(recipe).join("target").join(self.location.target()).join("stage");
+            let name = category.file_name()?.to_string_lossy().into_owned();
+            stage.is_dir().then(|| Artifact {
+                path: stage,
+                reason: format!("stage de la receta {}/{}", name, recipe),
+            })
+        })
+    }
+
+    pub fn redoxfs_mkfs(&self) -> Result<Artifact, String> {
+        self.redoxfs_tool("redoxfs-mkfs", "REDOXFS_MKFS", self.redoxfs_mkfs.as_deref())
+    }
+
+    pub fn redoxfs_mount(&self) -> Result<Artifact, String> {
+        self.redoxfs_tool("redoxfs", "REDOXFS_MOUNT", self.redoxfs_mount.as_deref())
+    }
+
+    // Una ruta explícita tiene prioridad y no se sustituye por otra si no
+    // existe; si no, se usan las fstools de la compilación, el repositorio
+    // redoxfs del árbol y por último PATH
+    fn redoxfs_tool(&self, name: &str, variable: &str, explicit: Option<&Path>) -> Result<Artifact, String> {
+        if let Some(path) = explicit {
+            if path.is_file() {
+                return Ok(Artifact {
+                    path: path.to_path_buf(),
+                    reason: format!("indicado en {}", variable),
+                });
+            }
+            return Err(format!("{} apunta a {}, que no existe", variable, path.display()));
+        }
+
+        let in_tree = [
+            (format!("build/fstools/bin/{}", name), "fstools de la compilación de Redox"),
+            (format!("redoxfs/target/release/{}", name), "redoxfs compilado en el árbol de Redox"),
+        ];
+        for (relative, reason) in in_tree {
+            let path = self.location.source_root.join(&relative);
+            if path.is_file() {
+                return Ok(Artifact { path, reason: reason.to_string() });
+            }
+        }
+
+        if let Some(path) = self.path.iter().map(|dir| dir.join(name)).find(|path| path.is_file()) {
+            return Ok(Artifact { path, reason: "encontrado en PATH".to_string() });
+        }
+
+        Err(format!(
+            "{} no encontrado en {}/build/fstools/bin, {}/redoxfs/target/release ni en PATH\n   \
+             Compila las herramientas con 'make fstools' o indica la ruta con {}",
+            name,
+            self.location.source_root.display(),
+            self.location.source_root.display(),
+            variable
+        ))
+    }
+
+    fn first_existing(&self, what: &str, candidates: &[(String, String)]) -> Result<Artifact, String> {
+        candidates
+            .iter()
+            .map(|(relative, reason)| (self.location.source_root.join(relative), reason))
+            .find(|(path, _)| path.exists())
+            .map(|(path, reason)| Artifact { path, reason: reason.clone() })
+            .ok_or_else(|| {
+                format!(
+                    "{} no encontrado en ninguna ubicación esperada de {} ({}, {}).\n   Rutas buscadas:\n{}",
+                    what,
+                    self.location.source_root.display(),
+                    self.location.arch,
+                    self.location.config_name,
+                    candidates
+                        .iter()
+                        .map(|(relative, _)| format!("     - {}", relative))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(root: &Path, relative: &str) -> PathBuf {
+        let path = root.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "x").unwrap();
+        path
+    }
+
+    fn location(root: &Path, arch: &str) -> BuildLocation {
+        BuildLocation {
+            source_root: root.to_path_buf(),
+            arch: arch.to_string(),
+            config_name: "server".to_string(),
+        }
+    }
+
+    #[test]
+    fn build_artifacts_follow_arch_and_config_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        touch(root, "build/x86_64/server/bootloader.efi");
+        let kernel = touch(root, "cookbook/recipes/core/kernel/target/aarch64-unknown-redox/stage/boot/kernel");
+        touch(root, "cookbook/recipes/core/kernel/target/x86_64-unknown-redox/stage/boot/kernel");
+        touch(root, "cookbook/recipes/shells/ion/target/aarch64-unknown-redox/stage/usr/bin/ion");
+
+        let x86 = ArtifactResolver::with_tools(location(root, "x86_64"), None, None, Vec::new());
+        let bootloader = x86.bootloader().unwrap();
+        assert_eq!(bootloader.path, root.join("build/x86_64/server/bootloader.efi"));
+        assert_eq!(bootloader.reason, "salida de make en build/x86_64/server");
+        assert!(x86.recipe_stage("ion").is_none());
+
+        let arm = ArtifactResolver::with_tools(location(root, "aarch64"), None, None, Vec::new());
+        assert_eq!(arm.kernel().unwrap().path, kernel);
+        let err = arm.bootloader().unwrap_err();
+        assert!(err.contains("build/aarch64/server/bootloader.efi"), "{}", err);
+        let stage = arm.recipe_stage("ion").unwrap();
+        assert_eq!(stage.reason, "stage de la receta shells/ion");
+        assert_eq!(location(root, "aarch64").filesystem_config(), "config/aarch64/server.toml");
+    }
+
+    #[test]
+    fn redoxfs_tools_prefer_explicit_then_tree_then_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("redox");
+        let bin = dir.path().join("bin");
+        let in_path = touch(&bin, "redoxfs-mkfs");
+        touch(&bin, "redoxfs");
+        let resolver = |mkfs: Option<PathBuf>| {
+            ArtifactResolver::with_tools(location(&root, "x86_64"), mkfs, None, vec![dir.path().join("missing"), bin.clone()])
+        };
+
+        let found = resolver(None).redoxfs_mkfs().unwrap();
+        assert_eq!((found.path, found.reason.as_str()), (in_path.clone(), "encontrado en PATH"));
+
+        let fstools = touch(&root, "build/fstools/bin/redoxfs-mkfs");
+        assert_eq!(resolver(None).redoxfs_mkfs().unwrap().path, fstools);
+
+        let explicit = resolver(Some(in_path.clone())).redoxfs_mkfs().unwrap();
+        assert_eq!((explicit.path, explicit.reason.as_str()), (in_path, "indicado en REDOXFS_MKFS"));
+        let err = resolver(Some(dir.path().join("nope"))).redoxfs_mkfs().unwrap_err();
+        assert!(err.starts_with("REDOXFS_MKFS apunta a"), "{}", err);
+
+        let empty = ArtifactResolver::with_tools(location(&root, "x86_64"), None, None, Vec::new());
+        assert!(empty.redoxfs_mount().unwrap_err().contains("make fstools"));
+    }
+}
@@ -0,0 +1,662 @@
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+// Firma Authenticode de ejecutables PE/COFF para Secure Boot. El firmware (o
+// shim) calcula el hash de las cabeceras y las secciones del ejecutable sin
+// el campo CheckSum, sin la entrada de la tabla de certificados y sin la
+// propia tabla (ver image_digest), y lo compara con el que
+// va dentro de una firma PKCS#7 SignedData añadida al final del fichero como
+// WIN_CERTIFICATE. El DER se construye y se lee aquí mismo: solo hacen falta
+// unas pocas estructuras fijas.
+
+// OIDs ya codificados (contenido del TLV 0x06)
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x01];
+const OID_SIGNED_DATA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+const OID_CONTENT_TYPE: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x03];
+const OID_MESSAGE_DIGEST: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x04];
+const OID_SPC_INDIRECT_DATA: &[u8] = &[0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+const OID_SPC_PE_IMAGE_DATA: &[u8] = &[0x2B, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x0F];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+// Cabecera WIN_CERTIFICATE: revisión 2.0 con una firma PKCS#7
+const WIN_CERT_REVISION: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+// Índice de la tabla de certificados en los data directories
+const CERTIFICATE_TABLE: usize = 4;
+
+// Certificado X.509 con clave RSA, con lo necesario para identificar al
+// firmante dentro de la firma
+pub struct Certificate {
+    der: Vec<u8>,
+    // Name del emisor y número de serie, tal cual aparecen en el DER
+    issuer: Vec<u8>,
+    serial: Vec<u8>,
+    common_name: Option<String>,
+    public_key: RsaPublicKey,
+}
+
+impl Certificate {
+    // Acepta el certificado en PEM o en DER (como los .cer de MOK)
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Error leyendo {}: {}", path.display(), e))?;
+        let der = match std::str::from_utf8(&data) {
+            Ok(text) if text.contains("-----BEGIN CERTIFICATE-----") => pem_contents(text, "CERTIFICATE")
+                .ok_or_else(|| format!("{}: certificado PEM mal formado", path.display()))?,
+            _ => data,
+        };
+        Self::parse(der).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(der: Vec<u8>) -> Result<Self, String> {
+        let invalid = |what: &str| format!("certificado X.509 inválido ({})", what);
+
+        let mut tbs = Der::new(&der).contents(0x30)
+            .and_then(|certificate| Der::new(certificate).contents(0x30))
+            .map(Der::new)
+            .ok_or_else(|| invalid("estructura"))?;
+        if tbs.peek() == Some(0xA0) {
+            tbs.element(0xA0);
+        }
+        let serial = tbs.element(0x02).ok_or_else(|| invalid("número de serie"))?.to_vec();
+        tbs.contents(0x30).ok_or_else(|| invalid("algoritmo"))?;
+        let issuer = tbs.element(0x30).ok_or_else(|| invalid("emisor"))?.to_vec();
+        tbs.contents(0x30).ok_or_else(|| invalid("validez"))?;
+        let subject = tbs.contents(0x30).ok_or_else(|| invalid("sujeto"))?;
+        let mut key_info = tbs.contents(0x30).map(Der::new).ok_or_else(|| invalid("clave pública"))?;
+
+        let algorithm = key_info.contents(0x30).and_then(|algorithm| Der::new(algorithm).contents(0x06));
+        if algorithm != Some(OID_RSA_ENCRYPTION) {
+            return Err("el certificado no tiene clave RSA (Secure Boot solo admite RSA)".to_string());
+        }
+        let mut rsa_key = key_info.contents(0x03)
+            .and_then(|bits| bits.get(1..))
+            .and_then(|bits| Der::new(bits).contents(0x30))
+            .map(Der::new)
+            .ok_or_else(|| invalid("clave RSA"))?;
+        let (modulus, exponent) = rsa_key.contents(0x02).zip(rsa_key.contents(0x02))
+            .ok_or_else(|| invalid("clave RSA"))?;
+        let public_key = RsaPublicKey::new(BigUint::from_bytes_be(modulus), BigUint::from_bytes_be(exponent))
+            .map_err(|e| format!("clave RSA del certificado no válida: {}", e))?;
+
+        let common_name = common_name(subject);
+        Ok(Self { der, issuer, serial, common_name, public_key })
+    }
+
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    // CN del sujeto o, si no tiene, la huella del certificado
+    pub fn name(&self) -> String {
+        self.common_name.clone().unwrap_or_else(|| format!("SHA-256 {}", self.fingerprint()))
+    }
+
+    // Huella SHA-256 como la muestran mokutil y openssl
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(&self.der).iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+    }
+}
+
+pub struct SigningKey {
+    key: RsaPrivateKey,
+    certificate: Certificate,
+}
+
+impl SigningKey {
+    // Clave RSA en PEM (PKCS#8 o PKCS#1) y el certificado que le corresponde
+    pub fn load(key_path: &Path, certificate_path: &Path) -> Result<Self, String> {
+        let pem = fs::read_to_string(key_path)
+            .map_err(|e| format!("Error leyendo {}: {}", key_path.display(), e))?;
+        let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|_| format!("{} no es una clave RSA en PEM (PKCS#8 o PKCS#1)", key_path.display()))?;
+        let certificate = Certificate::load(certificate_path)?;
+        if key.to_public_key() != certificate.public_key {
+            return Err(format!(
+                "La clave {} no corresponde al certificado {} ({})",
+                key_path.display(),
+                certificate_path.display(),
+                certificate.name()
+            ));
+        }
+        Ok(Self { key, certificate })
+    }
+
+    pub fn certificate(&self) -> &Certificate {
+        &self.certificate
+    }
+}
+
+// Posiciones de la cabecera PE que intervienen en el hash y en la firma
+struct PeLayout {
+    checksum: usize,
+    certificate_entry: usize,
+    size_of_headers: usize,
+    // Offset y tamaño de la tabla de certificados, si el fichero ya la tiene
+    certificate_table: Option<(usize, usize)>,
+    // PointerToRawData y SizeOfRawData de cada sección con datos, en orden
+    // de posición en el fichero
+    sections: Vec<(usize, usize)>,
+}
+
+fn pe_layout(image: &[u8]) -> Result<PeLayout, String> {
+    let not_pe = || "no es un ejecutable PE/EFI".to_string();
+    let u16_at = |offset: usize| image.get(offset..offset + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    let u32_at = |offset: usize| image.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+
+    if image.get(..2) != Some(b"MZ") {
+        return Err(not_pe());
+    }
+    let pe = u32_at(0x3C).ok_or_else(not_pe)? as usize;
+    if image.get(pe..pe + 4) != Some(b"PE\0\0") {
+        return Err(not_pe());
+    }
+    let section_count = u16_at(pe + 6).ok_or_else(not_pe)? as usize;
+    let optional_size = u16_at(pe + 20).ok_or_else(not_pe)? as usize;
+    let optional = pe + 24;
+    // PE32 o PE32+: cambia dónde empiezan los data directories
+    let directories = match u16_at(optional) {
+        Some(0x10B) => 96,
+        Some(0x20B) => 112,
+        _ => return Err("cabecera opcional PE desconocida".to_string()),
+    };
+    let count = u32_at(optional + directories - 4).ok_or_else(not_pe)? as usize;
+    let certificate_entry = optional + directories + CERTIFICATE_TABLE * 8;
+    if count <= CERTIFICATE_TABLE || certificate_entry + 8 > optional + optional_size {
+        return Err("la cabecera PE no tiene entrada para la tabla de certificados".to_string());
+    }
+    let size_of_headers = u32_at(optional + 60).ok_or_else(not_pe)? as usize;
+    if size_of_headers < certificate_entry + 8 || size_of_headers > image.len() {
+        return Err("SizeOfHeaders fuera del fichero".to_string());
+    }
+
+    let offset = u32_at(certificate_entry).ok_or_else(not_pe)? as usize;
+    let size = u32_at(certificate_entry + 4).ok_or_else(not_pe)? as usize;
+    let certificate_table = if size == 0 {
+        None
+    } else if offset >= size_of_headers && offset + size == image.len() {
+        Some((offset, size))
+    } else {
+        return Err("la tabla de certificados no está al final del fichero".to_string());
+    };
+
+    let data_end = certificate_table.map_or(image.len(), |(offset, _)| offset);
+    let mut sections = Vec::new();
+    for header in (0..section_count).map(|i| optional + optional_size + i * 40) {
+        let size = u32_at(header + 16).ok_or_else(not_pe)? as usize;
+        let offset = u32_at(header + 20).ok_or_else(not_pe)? as usize;
+        if size == 0 {
+            continue;
+        }
+        if offset < size_of_headers || offset + size > data_end {
+            return Err("sección PE fuera del fichero".to_string());
+        }
+        sections.push((offset, size));
+    }
+    sections.sort();
+
+    Ok(PeLayout {
+        checksum: optional + 64,
+        certificate_entry,
+        size_of_headers,
+        certificate_table,
+        sections,
+    })
+}
+
+// Hash Authenticode como lo calculan EDK2 y shim: las cabeceras menos
+// CheckSum y la entrada de la tabla de certificados, los datos de cada
+// sección por orden de posición y, si el fichero es más largo que lo ya
+// contado, lo que sigue a esa cantidad de bytes hasta la tabla. Lo que
+// queda entre secciones no se firma.
+fn image_digest(image: &[u8], layout: &PeLayout) -> Vec<u8> {
+    let end = layout.certificate_table.map_or(image.len(), |(offset, _)| offset);
+    let mut hasher = Sha256::new();
+    hasher.update(&image[..layout.checksum]);
+    hasher.update(&image[layout.checksum + 4..layout.certificate_entry]);
+    hasher.update(&image[layout.certificate_entry + 8..layout.size_of_headers]);
+    let mut hashed = layout.size_of_headers;
+    for &(offset, size) in &layout.sections {
+        hasher.update(&image[offset..offset + size]);
+        hashed += size;
+    }
+    if end > hashed {
+        hasher.update(&image[hashed..end]);
+    }
+    hasher.finalize().to_vec()
+}
+
+// Devuelve `image` firmado; una firma anterior se sustituye
+pub fn sign(image: &[u8], key: &SigningKey) -> Result<Vec<u8>, String> {
+    let layout = pe_layout(image)?;
+    let mut signed = image[..layout.certificate_table.map_or(image.len(), |(offset, _)| offset)].to_vec();
+    // La tabla de certificados va alineada a 8 bytes
+    signed.resize(signed.len().next_multiple_of(8), 0);
+    let layout = PeLayout { certificate_table: None, ..layout };
+
+    let signature = signed_data(&image_digest(&signed, &layout), key)?;
+    let length = (8 + signature.len()).next_multiple_of(8);
+    let offset = signed.len();
+    signed.extend_from_slice(&(length as u32).to_le_bytes());
+    signed.extend_from_slice(&WIN_CERT_REVISION.to_le_bytes());
+    signed.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+    signed.extend_from_slice(&signature);
+    signed.resize(offset + length, 0);
+
+    let entry = layout.certificate_entry;
+    signed[entry..entry + 4].copy_from_slice(&(offset as u32).to_le_bytes());
+    signed[entry + 4..entry + 8].copy_from_slice(&(length as u32).to_le_bytes());
+    let checksum = pe_checksum(&signed, layout.checksum);
+    signed[layout.checksum..layout.checksum + 4].copy_from_slice(&checksum.to_le_bytes());
+    Ok(signed)
+}
+
+// Comprueba sin conexión que `image` lleva una firma de `certificate` que
+// cubre su contenido actual
+pub fn verify(image: &[u8], certificate: &Certificate) -> Result<(), String> {
+    let layout = pe_layout(image)?;
+    let (offset, size) = layout.certificate_table.ok_or_else(|| "el ejecutable no está firmado".to_string())?;
+    let table = &image[offset..offset + size];
+    let length = u32::from_le_bytes(table.get(..4).ok_or("tabla de certificados vacía")?.try_into().unwrap()) as usize;
+    if length < 8 || length > table.len() || table[4..8] != [0x00, 0x02, 0x02, 0x00] {
+        return Err("la tabla de certificados no contiene una firma PKCS#7".to_string());
+    }
+
+    let invalid = |what: &str| format!("firma PKCS#7 mal formada ({})", what);
+    let mut content_info = Der::new(&table[8..length]).contents(0x30).map(Der::new).ok_or_else(|| invalid("ContentInfo"))?;
+    if content_info.contents(0x06) != Some(OID_SIGNED_DATA) {
+        return Err(invalid("no es SignedData"));
+    }
+    let mut signed_data = content_info.contents(0xA0)
+        .and_then(|explicit| Der::new(explicit).contents(0x30))
+        .map(Der::new)
+        .ok_or_else(|| invalid("SignedData"))?;
+    signed_data.element(0x02).ok_or_else(|| invalid("versión"))?;
+    signed_data.element(0x31).ok_or_else(|| invalid("algoritmos"))?;
+
+    let mut encapsulated = signed_data.contents(0x30).map(Der::new).ok_or_else(|| invalid("contenido"))?;
+    if encapsulated.contents(0x06) != Some(OID_SPC_INDIRECT_DATA) {
+        return Err(invalid("el contenido no es SpcIndirectDataContent"));
+    }
+    let indirect = encapsulated.contents(0xA0)
+        .and_then(|explicit| Der::new(explicit).contents(0x30))
+        .ok_or_else(|| invalid("SpcIndirectDataContent"))?;
+    let mut indirect_fields = Der::new(indirect);
+    indirect_fields.element(0x30).ok_or_else(|| invalid("SpcPeImageData"))?;
+    let mut digest_info = indirect_fields.contents(0x30).map(Der::new).ok_or_else(|| invalid("DigestInfo"))?;
+    if digest_info.contents(0x30).and_then(|algorithm| Der::new(algorithm).contents(0x06)) != Some(OID_SHA256) {
+        return Err("la firma no usa SHA-256".to_string());
+    }
+    if digest_info.contents(0x04) != Some(image_digest(image, &layout).as_slice()) {
+        return Err("el hash Authenticode no coincide: el fichero cambió después de firmarlo".to_string());
+    }
+
+    if signed_data.peek() == Some(0xA0) {
+        signed_data.element(0xA0);
+    }
+    let mut signer = signed_data.contents(0x31)
+        .and_then(|signers| Der::new(signers).contents(0x30))
+        .map(Der::new)
+        .ok_or_else(|| invalid("SignerInfo"))?;
+    signer.element(0x02).ok_or_else(|| invalid("versión del firmante"))?;
+    let mut issuer_and_serial = signer.contents(0x30).map(Der::new).ok_or_else(|| invalid("emisor"))?;
+    if issuer_and_serial.element(0x30) != Some(certificate.issuer.as_slice())
+        || issuer_and_serial.element(0x02) != Some(certificate.serial.as_slice())
+    {
+        return Err(format!("el ejecutable no está firmado con el certificado {}", certificate.name()));
+    }
+    signer.element(0x30).ok_or_else(|| invalid("algoritmo del firmante"))?;
+    let attributes = signer.contents(0xA0).ok_or_else(|| invalid("atributos firmados"))?;
+    signer.element(0x30).ok_or_else(|| invalid("algoritmo de la firma"))?;
+    let signature = signer.contents(0x04).ok_or_else(|| invalid("firma"))?;
+
+    let expected = Sha256::digest(indirect);
+    if message_digest(attributes) != Some(expected.as_slice()) {
+        return Err("el atributo messageDigest no corresponde al contenido firmado".to_string());
+    }
+    // Los atributos se firman codificados como SET, no con la etiqueta [0]
+    let signed_attributes = tlv(0x31, attributes);
+    certificate
+        .public_key
+        .verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&signed_attributes), signature)
+        .map_err(|_| format!("la firma RSA no es válida para el certificado {}", certificate.name()))
+}
+
+// ContentInfo con un SignedData de Authenticode para `digest`
+fn signed_data(digest: &[u8], key: &SigningKey) -> Result<Vec<u8>, String> {
+    let sha256 = || tlv(0x30, &[tlv(0x06, OID_SHA256), tlv(0x05, &[])].concat());
+
+    // SpcPeImageData sin flags y con el enlace "<<<Obsolete>>>" que ponen
+    // todas las herramientas de firma
+    let obsolete: Vec<u8> = "<<<Obsolete>>>".encode_utf16().flat_map(u16::to_be_bytes).collect();
+    let pe_image_data = tlv(0x30, &[tlv(0x03, &[0x00]), tlv(0xA0, &tlv(0xA2, &tlv(0x80, &obsolete)))].concat());
+    let indirect = [
+        tlv(0x30, &[tlv(0x06, OID_SPC_PE_IMAGE_DATA), pe_image_data].concat()),
+        tlv(0x30, &[sha256(), tlv(0x04, digest)].concat()),
+    ]
+    .concat();
+
+    // messageDigest cubre el contenido de SpcIndirectDataContent sin su
+    // cabecera DER
+    let mut attributes = [
+        tlv(0x30, &[tlv(0x06, OID_CONTENT_TYPE), tlv(0x31, &tlv(0x06, OID_SPC_INDIRECT_DATA))].concat()),
+        tlv(0x30, &[tlv(0x06, OID_MESSAGE_DIGEST), tlv(0x31, &tlv(0x04, &Sha256::digest(&indirect)))].concat()),
+    ];
+    // En DER los elementos de un SET OF van ordenados
+    attributes.sort();
+    let attributes = attributes.concat();
+    let signature = key
+        .key
+        .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(tlv(0x31, &attributes)))
+        .map_err(|e| format!("Error firmando con la clave RSA: {}", e))?;
+
+    let certificate = &key.certificate;
+    let signer_info = tlv(0x30, &[
+        tlv(0x02, &[1]),
+        tlv(0x30, &[certificate.issuer.as_slice(), certificate.serial.as_slice()].concat()),
+        sha256(),
+        tlv(0xA0, &attributes),
+        tlv(0x30, &[tlv(0x06, OID_RSA_ENCRYPTION), tlv(0x05, &[])].concat()),
+        tlv(0x04, &signature),
+    ].concat());
+    let signed_data = tlv(0x30, &[
+        tlv(0x02, &[1]),
+        tlv(0x31, &sha256()),
+        tlv(0x30, &[tlv(0x06, OID_SPC_INDIRECT_DATA), tlv(0xA0, &tlv(0x30, &indirect))].concat()),
+        tlv(0xA0, &certificate.der),
+        tlv(0x31, &signer_info),
+    ].concat());
+    Ok(tlv(0x30, &[tlv(0x06, OID_SIGNED_DATA), tlv(0xA0, &signed_data)].concat()))
+}
+
+// Valor del atributo messageDigest entre los atributos firmados
+fn message_digest(attributes: &[u8]) -> Option<&[u8]> {
+    let mut attributes = Der::new(attributes);
+    while let Some(attribute) = attributes.contents(0x30) {
+        let mut attribute = Der::new(attribute);
+        if attribute.contents(0x06) == Some(OID_MESSAGE_DIGEST) {
+            return attribute.contents(0x31).and_then(|values| Der::new(values).contents(0x04));
+        }
+    }
+    None
+}
+
+// CN de un Name X.509
+fn common_name(name: &[u8]) -> Option<String> {
+    let mut relative_names = Der::new(name);
+    while let Some(relative_name) = relative_names.contents(0x31) {
+        let mut attribute = Der::new(relative_name).contents(0x30).map(Der::new)?;
+        if attribute.contents(0x06) == Some(OID_COMMON_NAME) {
+            let (_, value) = attribute.read()?;
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    None
+}
+
+// Suma de comprobación de la cabecera opcional PE: suma de palabras de 16
+// bits con acarreo, sin el propio campo, más el tamaño del fichero
+fn pe_checksum(image: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (index, word) in image.chunks(2).enumerate() {
+        let offset = index * 2;
+        if offset == checksum_offset || offset == checksum_offset + 2 {
+            continue;
+        }
+        sum += u16::from_le_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u64;
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    (sum as u32 & 0xFFFF) + image.len() as u32
+}
+
+fn pem_contents(text: &str, label: &str) -> Option<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin)? + begin.len();
+    let body: String = text[start..start + text[start..].find(&end)?].split_whitespace().collect();
+    BASE64.decode(body).ok()
+}
+
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|&byte| byte == 0).collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend_from_slice(&bytes);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+// Lector de una secuencia de elementos DER (etiquetas de un byte)
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    // Etiqueta y contenido del siguiente elemento
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (header, len) = self.header()?;
+        let tag = self.data[0];
+        let contents = &self.data[header..header + len];
+        self.data = &self.data[header + len..];
+        Some((tag, contents))
+    }
+
+    // Contenido del siguiente elemento si tiene la etiqueta `tag`
+    fn contents(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.peek()? != tag {
+            return None;
+        }
+        self.read().map(|(_, contents)| contents)
+    }
+
+    // El siguiente elemento completo, con su cabecera
+    fn element(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let whole = self.data;
+        let (header, len) = self.header()?;
+        self.contents(tag)?;
+        Some(&whole[..header + len])
+    }
+
+    // Tamaño de la cabecera (etiqueta y longitud) y del contenido
+    fn header(&self) -> Option<(usize, usize)> {
+        let first = *self.data.get(1)?;
+        let (header, len) = if first < 0x80 {
+            (2, first as usize)
+        } else {
+            let count = (first & 0x7F) as usize;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let bytes = self.data.get(2..2 + count)?;
+            (2 + count, bytes.iter().fold(0usize, |len, &byte| len << 8 | byte as usize))
+        };
+        (header + len <= self.data.len()).then_some((header, len))
+    }
+}
+
+// Clave y certificado de prueba, generados con
+// `openssl req -x509 -newkey rsa:2048 -nodes -subj "/CN=Redox Test MOK"`
+#[cfg(test)]
+pub const TEST_KEY: &str = include_str!("../testdata/secure-boot.key");
+#[cfg(test)]
+pub const TEST_CERTIFICATE: &str = include_str!("../testdata/secure-boot.crt");
+
+// Ejecutable PE32+ mínimo con una sección .text que contiene `payload`
+#[cfg(test)]
+pub fn pe_image(machine: u16, payload: &[u8]) -> Vec<u8> {
+    const OPTIONAL_SIZE: usize = 112 + 16 * 8;
+    let section_size = payload.len().next_multiple_of(0x200);
+    let mut image = vec![0u8; 0x200];
+    image[..2].copy_from_slice(b"MZ");
+    image[0x3C..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    let mut put = |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+    put(0x40, b"PE\0\0");
+    put(0x44, &machine.to_le_bytes());
+    put(0x46, &1u16.to_le_bytes());
+    put(0x54, &(OPTIONAL_SIZE as u16).to_le_bytes());
+    put(0x56, &0x0022u16.to_le_bytes());
+
+    let optional = 0x58;
+    put(optional, &0x20Bu16.to_le_bytes());
+    put(optional + 16, &0x1000u32.to_le_bytes());
+    put(optional + 32, &0x1000u32.to_le_bytes());
+    put(optional + 36, &0x200u32.to_le_bytes());
+    put(optional + 56, &(0x1000 + section_size.next_multiple_of(0x1000) as u32).to_le_bytes());
+    put(optional + 60, &0x200u32.to_le_bytes());
+    put(optional + 68, &10u16.to_le_bytes());
+    put(optional + 108, &16u32.to_le_bytes());
+
+    let section = optional + OPTIONAL_SIZE;
+    put(section, b".text\0\0\0");
+    put(section + 8, &(payload.len() as u32).to_le_bytes());
+    put(section + 12, &0x1000u32.to_le_bytes());
+    put(section + 16, &(section_size as u32).to_le_bytes());
+    put(section + 20, &0x200u32.to_le_bytes());
+    put(section + 36, &0x6000_0020u32.to_le_bytes());
+
+    image.extend_from_slice(payload);
+    image.resize(0x200 + section_size, 0);
+    image
+}
+
+// Escribe la clave y el certificado de prueba en `dir`
+#[cfg(test)]
+pub fn write_test_key(dir: &Path) -> (std::path::PathBuf, std::path::PathBuf) {
+    let (key, certificate) = (dir.join("secure-boot.key"), dir.join("secure-boot.crt"));
+    fs::write(&key, TEST_KEY).unwrap();
+    fs::write(&certificate, TEST_CERTIFICATE).unwrap();
+    (key, certificate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> (tempfile::TempDir, SigningKey) {
+        let dir = tempfile::tempdir().unwrap();
+        let (key, certificate) = write_test_key(dir.path());
+        let key = SigningKey::load(&key, &certificate).unwrap();
+        (dir, key)
+    }
+
+    #[test]
+    fn signed_image_verifies_and_detects_changes() {
+        let (_dir, key) = test_key();
+        let image = pe_image(0x8664, b"redox bootloader");
+
+        let signed = sign(&image, &key).unwrap();
+
+        assert_eq!(signed.len() % 8, 0);
+        // Del fichero original solo cambian CheckSum y la entrada de la tabla
+        // de certificados
+        let layout = pe_layout(&signed).unwrap();
+        let changed: Vec<usize> = (0..image.len()).filter(|&i| image[i] != signed[i]).collect();
+        assert!(changed.iter().all(|&i| (layout.checksum..layout.checksum + 4).contains(&i)
+            || (layout.certificate_entry..layout.certificate_entry + 8).contains(&i)), "{:?}", changed);
+        assert_eq!(layout.certificate_table.unwrap().0, image.len());
+        verify(&signed, key.certificate()).unwrap();
+        assert_eq!(key.certificate().name(), "Redox Test MOK");
+
+        // Volver a firmar sustituye la firma en lugar de añadir otra
+        let resigned = sign(&signed, &key).unwrap();
+        assert_eq!(resigned.len(), signed.len());
+        verify(&resigned, key.certificate()).unwrap();
+
+        let mut tampered = signed.clone();
+        tampered[0x200] ^= 1;
+        let err = verify(&tampered, key.certificate()).unwrap_err();
+        assert!(err.contains("el fichero cambió"), "{}", err);
+        assert!(verify(&image, key.certificate()).unwrap_err().contains("no está firmado"));
+    }
+
+    #[test]
+    fn digest_covers_sections_in_file_order_and_skips_gaps() {
+        let (_dir, key) = test_key();
+        // .text en 0x200 y .data en 0x600, con un hueco entre ambas y la
+        // cabecera de .data antes que la de .text
+        let mut image = pe_image(0x8664, b"text");
+        image[0x46..0x48].copy_from_slice(&2u16.to_le_bytes());
+        let text_header = image[0x148..0x170].to_vec();
+        let mut data_header = text_header.clone();
+        data_header[..8].copy_from_slice(b".data\0\0\0");
+        data_header[20..24].copy_from_slice(&0x600u32.to_le_bytes());
+        image[0x148..0x170].copy_from_slice(&data_header);
+        image[0x170..0x198].copy_from_slice(&text_header);
+        image.resize(0x600, 0xCC);
+        image.extend_from_slice(&[0xDD; 0x200]);
+
+        let layout = pe_layout(&image).unwrap();
+        assert_eq!(layout.sections, [(0x200, 0x200), (0x600, 0x200)]);
+        let mut expected = Sha256::new();
+        expected.update(&image[..layout.checksum]);
+        expected.update(&image[layout.checksum + 4..layout.certificate_entry]);
+        expected.update(&image[layout.certificate_entry + 8..0x200]);
+        expected.update(&image[0x200..0x400]);
+        expected.update(&image[0x600..0x800]);
+        // 0x600 bytes contados: lo que sigue hasta el final también entra
+        expected.update(&image[0x600..0x800]);
+        assert_eq!(image_digest(&image, &layout), expected.finalize().to_vec());
+
+        let signed = sign(&image, &key).unwrap();
+        verify(&signed, key.certificate()).unwrap();
+        // El hueco no está firmado; las secciones sí
+        let mut gap = signed.clone();
+        gap[0x500] ^= 1;
+        verify(&gap, key.certificate()).unwrap();
+        let mut data = signed.clone();
+        data[0x700] ^= 1;
+        assert!(verify(&data, key.certificate()).unwrap_err().contains("el fichero cambió"));
+    }
+
+    #[test]
+    fn verification_requires_the_signing_certificate() {
+        let (dir, key) = test_key();
+        let signed = sign(&pe_image(0xAA64, b"loader"), &key).unwrap();
+
+        let other = dir.path().join("other.crt");
+        fs::write(&other, include_str!("../testdata/other.crt")).unwrap();
+        let other = Certificate::load(&other).unwrap();
+        let err = verify(&signed, &other).unwrap_err();
+        assert!(err.contains("no está firmado con el certificado Other"), "{}", err);
+
+        // El certificado en DER (como se inscribe en MOK) es el mismo
+        let der = dir.path().join("mok.cer");
+        fs::write(&der, key.certificate().der()).unwrap();
+        verify(&signed, &Certificate::load(&der).unwrap()).unwrap();
+
+        let err = SigningKey::load(&dir.path().join("secure-boot.key"), &dir.path().join("other.crt")).err().unwrap();
+        assert!(err.contains("no corresponde al certificado"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_files_that_cannot_be_signed() {
+        let (_dir, key) = test_key();
+        assert!(sign(b"not a PE", &key).unwrap_err().contains("no es un ejecutable PE"));
+        // Las cabeceras mínimas de target_arch no tienen cabecera opcional
+        let err = sign(&crate::target_arch::pe_stub(0x8664, &[0u8; 64]), &key).unwrap_err();
+        assert!(err.contains("cabecera opcional"), "{}", err);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use crate::gpt::{self, GptPartition, GptTable};
+
+// Arranque BIOS de Redox. bootloader.bios es una imagen del principio del
+// disco: su primer sector es el MBR con el stage 1, que carga el stage 2
+// desde los sectores que siguen a la tabla GPT. Cada parte se copia a la
+// misma posición del disco: el código de arranque sobre el MBR protector,
+// sin tocar su tabla de particiones, y el stage 2 en la partición de
+// arranque BIOS.
+
+// Bytes de código de arranque del MBR; después van la firma del disco y la
+// tabla de particiones
+const MBR_BOOT_CODE: usize = 440;
+const SECTOR_SIZE: u64 = 512;
+
+pub fn boot_partition(table: &GptTable) -> Option<&GptPartition> {
+    table.partitions.iter().find(|partition| partition.type_guid == gpt::BIOS_BOOT)
+}
+
+// Comprueba que la imagen es un bootloader BIOS antes de tocar el disco
+pub fn check_image(image: &[u8]) -> Result<(), String> {
+    if image.len() <= SECTOR_SIZE as usize || image[510..512] != [0x55, 0xAA] {
+        return Err("La imagen no es un bootloader BIOS (falta la firma 0x55AA del MBR o el stage 2)".to_string());
+    }
+    Ok(())
+}
+
+// Escribe el stage 1 en el MBR y el stage 2 en la partición de arranque BIOS
+// y comprueba lo escrito
+pub fn write_stages(file: &File, table: &GptTable, image: &[u8]) -> Result<(), String> {
+    check_image(image)?;
+    if table.sector_size != SECTOR_SIZE {
+        return Err(format!(
+            "El arranque BIOS necesita sectores de {} bytes (el disco usa {})",
+            SECTOR_SIZE, table.sector_size
+        ));
+    }
+    let partition = boot_partition(table)
+        .ok_or_else(|| "El disco no tiene partición de arranque BIOS".to_string())?;
+
+    let start = partition.first_lba * SECTOR_SIZE;
+    let end = (partition.last_lba + 1) * SECTOR_SIZE;
+    if image.len() as u64 <= start {
+        return Err(format!("El bootloader BIOS no contiene stage 2 a partir del byte {}", start));
+    }
+    if image.len() as u64 > end {
+        return Err(format!(
+            "El bootloader BIOS ocupa {} bytes y la partición de arranque BIOS acaba en el byte {}",
+            image.len(),
+            end
+        ));
+    }
+
+    let write = |offset: u64, data: &[u8]| {
+        file.write_all_at(data, offset)
+            .map_err(|e| format!("Error escribiendo el bootloader BIOS en el byte {}: {}", offset, e))
+    };
+    write(0, &image[..MBR_BOOT_CODE])?;
+    write(start, &image[start as usize..])?;
+    file.sync_all()
+        .map_err(|e| format!("Error sincronizando el bootloader BIOS: {}", e))?;
+
+    verify_stages(file, start, image)
+}
+
+// Relee el MBR y el stage 2: el código de arranque debe coincidir y el MBR
+// protector seguir intacto
+fn verify_stages(file: &File, start: u64, image: &[u8]) -> Result<(), String> {
+    let read = |offset: u64, len: usize| {
+        let mut data = vec![0u8; len];
+        file.read_exact_at(&mut data, offset)
+            .map(|_| data)
+            .map_err(|e| format!("Error releyendo el bootloader BIOS en el byte {}: {}", offset, e))
+    };
+
+    let mbr = read(0, SECTOR_SIZE as usize)?;
+    if mbr[..MBR_BOOT_CODE] != image[..MBR_BOOT_CODE] {
+        return Err("Verificación fallida: el código de arranque del MBR no coincide".to_string());
+    }
+    if mbr[446 + 4] != 0xEE || mbr[510..512] != [0x55, 0xAA] {
+        return Err("Verificación fallida: el MBR ya no es un MBR protector de GPT".to_string());
+    }
+    if read(start, image.len() - start as usize)? != image[start as usize..] {
+        return Err("Verificación fallida: el stage 2 no coincide".to_string());
+    }
+    Ok(())
+}
+
+// Imagen como bootloader.bios: MBR con firma, relleno hasta el sector 34 y
+// `stage2`
+#[cfg(test)]
+pub fn image_stub(stage2: &[u8]) -> Vec<u8> {
+    let mut image = vec![0x90u8; 34 * 512];
+    image[446..510].fill(0);
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+    image.extend_from_slice(stage2);
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+    fn disk_with_table(bios_boot: bool) -> (File, GptTable) {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(DISK_SIZE).unwrap();
+        let mut table = GptTable::new(DISK_SIZE, 512).unwrap();
+        if bios_boot {
+            table.add_bios_boot_partition().unwrap();
+        }
+        table.add_partition("REDOX_ROOT", gpt::REDOX_ROOT, None).unwrap();
+        table.write_to(&file).unwrap();
+        (file, table)
+    }
+
+    #[test]
+    fn writes_boot_code_and_stage2_keeping_the_gpt() {
+        let (file, table) = disk_with_table(true);
+        let image = image_stub(b"stage2");
+
+        write_stages(&file, &table, &image).unwrap();
+
+        let mut data = vec![0u8; 34 * 512 + 6];
+        file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data[..440], image[..440]);
+        assert_eq!(data[446 + 4], 0xEE);
+        assert_eq!(&data[34 * 512..], b"stage2");
+        let read = GptTable::read_from(&file, DISK_SIZE, 512).unwrap();
+        assert_eq!(read.partitions, table.partitions);
+    }
+
+    #[test]
+    fn rejects_images_and_disks_that_cannot_boot() {
+        let (file, table) = disk_with_table(true);
+        assert!(check_image(b"not a bootloader").is_err());
+        let oversized = image_stub(&vec![0u8; 1024 * 1024]);
+        assert!(write_stages(&file, &table, &oversized).unwrap_err().contains("acaba en el byte"));
+
+        let (file, table) = disk_with_table(false);
+        let err = write_stages(&file, &table, &image_stub(b"stage2")).unwrap_err();
+        assert!(err.contains("no tiene partición de arranque BIOS"), "{}", err);
+    }
+}
@@ -0,0 +1,427 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+#[cfg(test)]
+use std::path::Path;
+
+use crate::command_runner::CommandRunner;
+use crate::gpt::Guid;
+#[cfg(test)]
+use crate::gpt::{self, GptTable};
+
+// Entradas de arranque UEFI (variables Boot#### de la NVRAM). Se leen
+// directamente de efivarfs, que refleja la NVRAM en cada momento, y se
+// crean, ordenan y borran con efibootmgr. Después de cada cambio se vuelve a
+// leer efivarfs para comprobar que el firmware lo aceptó.
+
+// Espacio de nombres de las variables globales de la especificación UEFI
+const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+const LOAD_OPTION_ACTIVE: u32 = 0x0000_0001;
+// Nodo Hard Drive de una ruta de dispositivo (tipo Media, subtipo 1) y su
+// tipo de firma GUID, la de una partición GPT
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const HARD_DRIVE_SUBTYPE: u8 = 0x01;
+const HARD_DRIVE_LENGTH: usize = 42;
+const SIGNATURE_GUID: u8 = 0x02;
+const END_DEVICE_PATH: u8 = 0x7F;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootEntry {
+    pub number: u16,
+    pub label: String,
+    pub active: bool,
+    // GUID único de la partición GPT del cargador, si la ruta lo indica
+    pub partition_guid: Option<Guid>,
+}
+
+impl BootEntry {
+    // Forma que usa efibootmgr: cuatro dígitos hexadecimales
+    pub fn bootnum(&self) -> String {
+        format!("{:04X}", self.number)
+    }
+}
+
+// Posición de la nueva entrada en el orden de arranque
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootPriority {
+    // La primera de BootOrder, como hace efibootmgr --create
+    First,
+    // La última de BootOrder: el sistema actual sigue arrancando por defecto
+    Last,
+    // La última de BootOrder y en BootNext: Redox arranca solo la próxima vez
+    Next,
+}
+
+impl BootPriority {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "first" => Ok(BootPriority::First),
+            "last" => Ok(BootPriority::Last),
+            "next" => Ok(BootPriority::Next),
+            _ => Err(format!("Prioridad de arranque inválida: {} (first/last/next)", name)),
+        }
+    }
+}
+
+// Vista de /sys/firmware/efi del sistema en ejecución
+pub struct UefiFirmware {
+    root: PathBuf,
+}
+
+impl UefiFirmware {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    // El kernel solo crea /sys/firmware/efi si el sistema arrancó por UEFI
+    pub fn booted_uefi(&self) -> bool {
+        self.root.is_dir()
+    }
+
+    fn efivars(&self) -> PathBuf {
+        self.root.join("efivars")
+    }
+
+    // Contenido de la variable sin los 4 bytes de atributos que antepone
+    // efivarfs; None si no existe
+    fn variable(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let path = self.efivars().join(format!("{}-{}", name, EFI_GLOBAL_VARIABLE));
+        match fs::read(&path) {
+            Ok(data) if data.len() >= 4 => Ok(Some(data[4..].to_vec())),
+            Ok(_) => Err(format!("Variable UEFI {} truncada", name)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Error leyendo {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn entries(&self) -> Result<Vec<BootEntry>, String> {
+        let efivars = self.efivars();
+        let dir = fs::read_dir(&efivars)
+            .map_err(|e| format!("No se pueden leer las variables UEFI en {}: {}", efivars.display(), e))?;
+
+        let suffix = format!("-{}", EFI_GLOBAL_VARIABLE);
+        let mut entries = Vec::new();
+        for file in dir.flatten() {
+            let name = file.file_name().to_string_lossy().into_owned();
+            let Some(number) = name
+                .strip_suffix(&suffix)
+                .and_then(|name| name.strip_prefix("Boot"))
+                .filter(|hex| hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+            else {
+                continue;
+            };
+
+            let variable = format!("Boot{:04X}", number);
+            if let Some(entry) = self.variable(&variable)?.and_then(|data| parse_load_option(number, &data)) {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by_key(|entry| entry.number);
+        Ok(entries)
+    }
+
+    pub fn boot_order(&self) -> Result<Vec<u16>, String> {
+        Ok(self
+            .variable("BootOrder")?
+            .unwrap_or_default()
+            .chunks_exact(2)
+            .map(|number| u16::from_le_bytes([number[0], number[1]]))
+            .collect())
+    }
+
+    pub fn boot_next(&self) -> Result<Option<u16>, String> {
+        Ok(self
+            .variable("BootNext")?
+            .filter(|data| data.len() == 2)
+            .map(|data| u16::from_le_bytes([data[0], data[1]])))
+    }
+}
+
+// EFI_LOAD_OPTION: atributos (u32), longitud de las rutas (u16), la
+// descripción en UTF-16 terminada en cero y las rutas de dispositivo
+fn parse_load_option(number: u16, data: &[u8]) -> Option<BootEntry> {
+    let attributes = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let paths_len = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+    let description: Vec<u16> = data
+        .get(6..)?
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    let paths_start = 6 + (description.len() + 1) * 2;
+    Some(BootEntry {
+        number,
+        label: String::from_utf16_lossy(&description),
+        active: attributes & LOAD_OPTION_ACTIVE != 0,
+        partition_guid: data.get(paths_start..paths_start + paths_len).and_then(partition_guid),
+    })
+}
+
+// Firma GPT del nodo Hard Drive de la primera ruta de dispositivo
+fn partition_guid(mut path: &[u8]) -> Option<Guid> {
+    while path.len() >= 4 {
+        let length = u16::from_le_bytes([path[2], path[3]]) as usize;
+        if path[0] == END_DEVICE_PATH || length < 4 || length > path.len() {
+            return None;
+        }
+        if path[0] == MEDIA_DEVICE_PATH
+            && path[1] == HARD_DRIVE_SUBTYPE
+            && length >= HARD_DRIVE_LENGTH
+            && path[41] == SIGNATURE_GUID
+        {
+            return Some(Guid::from_bytes(path[24..40].try_into().ok()?));
+        }
+        path = &path[length..];
+    }
+    None
+}
+
+// Argumentos de efibootmgr para crear la entrada. `disk` es el disco
+// completo tal como lo nombra el kernel (/dev/sda, /dev/nvme0n1) y
+// `partition` el número de la ESP.
+pub fn create_args(disk: &str, partition: usize, label: &str, loader: &str, priority: BootPriority) -> Vec<String> {
+    let create = match priority {
+        BootPriority::First => "--create",
+        // Sin tocar BootOrder: la entrada se coloca después
+        BootPriority::Last | BootPriority::Next => "--create-only",
+    };
+    [create, "--disk", disk, "--part", &partition.to_string(), "--label", label, "--loader", loader]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect()
+}
+
+pub struct BootManager<'a> {
+    runner: &'a dyn CommandRunner,
+    firmware: &'a UefiFirmware,
+}
+
+impl<'a> BootManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, firmware: &'a UefiFirmware) -> Self {
+        Self { runner, firmware }
+    }
+
+    pub fn labelled(&self, label: &str) -> Result<Vec<BootEntry>, String> {
+        Ok(self.firmware.entries()?.into_iter().filter(|entry| entry.label == label).collect())
+    }
+
+    // Crea la entrada y devuelve la que apareció en la NVRAM
+    pub fn create(&self, disk: &str, partition: usize, label: &str, loader: &str, priority: BootPriority) -> Result<BootEntry, String> {
+        let before = self.firmware.entries()?;
+        self.efibootmgr(&create_args(disk, partition, label, loader, priority))?;
+
+        self.firmware
+            .entries()?
+            .into_iter()
+            .find(|entry| entry.label == label && before.iter().all(|old| old.number != entry.number))
+            .ok_or_else(|| format!("efibootmgr terminó sin crear la entrada \"{}\" en la NVRAM", label))
+    }
+
+    // Coloca `entry` en BootOrder (y BootNext) según `priority`
+    pub fn prioritize(&self, entry: &BootEntry, priority: BootPriority) -> Result<(), String> {
+        let mut order = self.firmware.boot_order()?;
+        if priority == BootPriority::First {
+            if order.first() != Some(&entry.number) {
+                return Err(format!("La entrada Boot{} no quedó la primera en BootOrder", entry.bootnum()));
+            }
+            return Ok(());
+        }
+
+        order.retain(|&number| number != entry.number);
+        order.push(entry.number);
+        let order_arg = order.iter().map(|number| format!("{:04X}", number)).collect::<Vec<_>>().join(",");
+        self.efibootmgr(&["--bootorder".to_string(), order_arg])?;
+        if self.firmware.boot_order()? != order {
+            return Err(format!("El firmware no aceptó el nuevo BootOrder con Boot{}", entry.bootnum()));
+        }
+
+        if priority == BootPriority::Next {
+            self.efibootmgr(&["--bootnext".to_string(), entry.bootnum()])?;
+            if self.firmware.boot_next()? != Some(entry.number) {
+                return Err(format!("El firmware no aceptó BootNext = Boot{}", entry.bootnum()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, entry: &BootEntry) -> Result<(), String> {
+        self.efibootmgr(&["--bootnum".to_string(), entry.bootnum(), "--delete-bootnum".to_string()])?;
+        if self.firmware.entries()?.iter().any(|found| found.number == entry.number) {
+            return Err(format!("La entrada Boot{} sigue en la NVRAM", entry.bootnum()));
+        }
+        Ok(())
+    }
+
+    fn efibootmgr(&self, args: &[String]) -> Result<(), String> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = self.runner.run("efibootmgr", &args)
+            .map_err(|e| format!("No se pudo ejecutar efibootmgr: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "efibootmgr {} falló: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Escribe una variable como la expone efivarfs (atributos + datos)
+#[cfg(test)]
+pub fn write_variable(efivars: &Path, name: &str, data: &[u8]) {
+    let mut contents = 7u32.to_le_bytes().to_vec();
+    contents.extend_from_slice(data);
+    fs::write(efivars.join(format!("{}-{}", name, EFI_GLOBAL_VARIABLE)), contents).unwrap();
+}
+
+#[cfg(test)]
+pub fn write_entry(efivars: &Path, number: u16, label: &str) {
+    write_entry_on(efivars, number, label, None);
+}
+
+// Entrada cuya ruta empieza por el nodo Hard Drive de la partición `partition`
+#[cfg(test)]
+pub fn write_entry_on(efivars: &Path, number: u16, label: &str, partition: Option<Guid>) {
+    let mut path = Vec::new();
+    if let Some(guid) = partition {
+        path.extend_from_slice(&[MEDIA_DEVICE_PATH, HARD_DRIVE_SUBTYPE]);
+        path.extend_from_slice(&(HARD_DRIVE_LENGTH as u16).to_le_bytes());
+        path.extend_from_slice(&[0u8; 20]);
+        path.extend_from_slice(guid.as_bytes());
+        path.extend_from_slice(&[0x02, SIGNATURE_GUID]);
+    }
+    path.extend_from_slice(&[END_DEVICE_PATH, 0xFF, 4, 0]);
+
+    let mut data = LOAD_OPTION_ACTIVE.to_le_bytes().to_vec();
+    data.extend_from_slice(&(path.len() as u16).to_le_bytes());
+    for unit in label.encode_utf16().chain([0]) {
+        data.extend_from_slice(&unit.to_le_bytes());
+    }
+    data.extend_from_slice(&path);
+    write_variable(efivars, &format!("Boot{:04X}", number), &data);
+}
+
+#[cfg(test)]
+pub fn write_boot_order(efivars: &Path, order: &[u16]) {
+    let data: Vec<u8> = order.iter().flat_map(|number| number.to_le_bytes()).collect();
+    write_variable(efivars, "BootOrder", &data);
+}
+
+// Simula efibootmgr sobre un directorio que hace de efivarfs, para usarlo
+// con FakeRunner::on
+#[cfg(test)]
+pub fn fake_efibootmgr(efivars: &Path) -> impl Fn(&[&str]) + 'static {
+    let efivars = efivars.to_path_buf();
+    move |args: &[&str]| {
+        let firmware = UefiFirmware::new(efivars.parent().unwrap());
+        let value = |flag: &str| args.iter().position(|arg| *arg == flag).map(|i| args[i + 1]);
+        let number = |hex: &str| u16::from_str_radix(hex, 16).unwrap();
+        let mut order = firmware.boot_order().unwrap();
+
+        if let Some(label) = value("--label") {
+            let used: Vec<u16> = firmware.entries().unwrap().iter().map(|entry| entry.number).collect();
+            let new = (0..).find(|number| !used.contains(number)).unwrap();
+            // Como efibootmgr, la ruta lleva el GUID de la partición del disco
+            let partition = gpt::open_disk_readonly(value("--disk").unwrap())
+                .and_then(|(file, size, sector_size)| GptTable::read_from(&file, size, sector_size))
+                .ok()
+                .and_then(|table| {
+                    let number = value("--part").unwrap().parse().unwrap();
+                    table.partition(number).map(|partition| partition.unique_guid)
+                });
+            write_entry_on(&efivars, new, label, partition);
+            if args.contains(&"--create") {
+                order.insert(0, new);
+            }
+        } else if args.contains(&"--delete-bootnum") {
+            let removed = number(value("--bootnum").unwrap());
+            fs::remove_file(efivars.join(format!("Boot{:04X}-{}", removed, EFI_GLOBAL_VARIABLE))).unwrap();
+            order.retain(|&number| number != removed);
+        } else if let Some(bootorder) = value("--bootorder") {
+            order = bootorder.split(',').map(number).collect();
+        } else if let Some(next) = value("--bootnext") {
+            write_variable(&efivars, "BootNext", &number(next).to_le_bytes());
+        }
+        write_boot_order(&efivars, &order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeRunner;
+
+    fn firmware_with(entries: &[(u16, &str)]) -> (tempfile::TempDir, UefiFirmware) {
+        let dir = tempfile::tempdir().unwrap();
+        let efivars = dir.path().join("efi/efivars");
+        fs::create_dir_all(&efivars).unwrap();
+        for (number, label) in entries {
+            write_entry(&efivars, *number, label);
+        }
+        write_boot_order(&efivars, &entries.iter().map(|(number, _)| *number).collect::<Vec<_>>());
+        let firmware = UefiFirmware::new(dir.path().join("efi"));
+        (dir, firmware)
+    }
+
+    #[test]
+    fn reads_entries_and_order_from_efivarfs() {
+        let (dir, firmware) = firmware_with(&[(0x1A, "debian"), (3, "Redox OS")]);
+        fs::write(dir.path().join("efi/efivars/Timeout-8be4df61-93ca-11d2-aa0d-00e098032b8c"), [7, 0, 0, 0, 5, 0]).unwrap();
+
+        let entries = firmware.entries().unwrap();
+        assert_eq!(entries.iter().map(BootEntry::bootnum).collect::<Vec<_>>(), ["0003", "001A"]);
+        assert_eq!(entries[1].label, "debian");
+        assert!(entries[0].active);
+        assert_eq!(firmware.boot_order().unwrap(), [0x1A, 3]);
+        assert_eq!(firmware.boot_next().unwrap(), None);
+        assert!(!UefiFirmware::new(dir.path().join("bios")).booted_uefi());
+    }
+
+    #[test]
+    fn creates_entry_on_the_whole_disk_and_sets_boot_next() {
+        let (dir, firmware) = firmware_with(&[(0, "debian")]);
+        let runner = FakeRunner::new().on("efibootmgr", fake_efibootmgr(&dir.path().join("efi/efivars")));
+        let manager = BootManager::new(&runner, &firmware);
+
+        let entry = manager.create("/dev/nvme0n1", 1, "Redox OS", "\\EFI\\redox\\redox-bootloader.efi", BootPriority::Next).unwrap();
+        manager.prioritize(&entry, BootPriority::Next).unwrap();
+
+        assert_eq!(entry.bootnum(), "0001");
+        assert_eq!(runner.calls()[0], "efibootmgr --create-only --disk /dev/nvme0n1 --part 1 --label Redox OS --loader \\EFI\\redox\\redox-bootloader.efi");
+        assert_eq!(firmware.boot_order().unwrap(), [0, 1]);
+        assert_eq!(firmware.boot_next().unwrap(), Some(1));
+
+        manager.delete(&entry).unwrap();
+        assert!(manager.labelled("Redox OS").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_the_partition_of_the_loader() {
+        let (dir, firmware) = firmware_with(&[(0, "debian")]);
+        let guid = Guid::parse("0b2c6d2e-5a5e-4c1f-9a43-2f1d3e4c5b6a");
+        write_entry_on(&dir.path().join("efi/efivars"), 1, "Redox OS", Some(guid));
+
+        let entries = firmware.entries().unwrap();
+        assert_eq!(entries[0].partition_guid, None);
+        assert_eq!(entries[1].label, "Redox OS");
+        assert_eq!(entries[1].partition_guid, Some(guid));
+    }
+
+    #[test]
+    fn reports_failures_instead_of_ignoring_them() {
+        let (_dir, firmware) = firmware_with(&[]);
+        let silent = FakeRunner::new();
+        let err = BootManager::new(&silent, &firmware)
+            .create("/dev/sda", 1, "Redox OS", "\\EFI\\redox\\redox-bootloader.efi", BootPriority::First)
+            .unwrap_err();
+        assert!(err.contains("sin crear la entrada"), "{}", err);
+
+        let failing = FakeRunner::new().respond("efibootmgr", 5, "", "Could not prepare Boot variable: No space left on device");
+        let err = BootManager::new(&failing, &firmware)
+            .create("/dev/sda", 1, "Redox OS", "\\EFI\\redox\\redox-bootloader.efi", BootPriority::First)
+            .unwrap_err();
+        assert!(err.ends_with("No space left on device"), "{}", err);
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::FileConfig;
+
+// Primer uid y gid que se asignan a usuarios y grupos sin uno explícito
+const FIRST_AUTO_ID: u32 = 1000;
+// Shell de los usuarios que no indican uno
+const DEFAULT_SHELL: &str = "/bin/ion";
+// Directorios que redox_installer crea en el directorio personal de los
+// usuarios normales (uid >= 1000)
+const XDG_DIRS: [&str; 13] = [
+    "Desktop",
+    "Documents",
+    "Downloads",
+    "Music",
+    "Pictures",
+    "Public",
+    "Templates",
+    "Videos",
+    ".config",
+    ".local",
+    ".local/share",
+    ".local/share/Trash",
+    ".local/share/Trash/info",
+];
+const USER_DIRS: &str = r#"# Produced by redox installer
+XDG_DESKTOP_DIR="$HOME/Desktop"
+XDG_DOCUMENTS_DIR="$HOME/Documents"
+XDG_DOWNLOAD_DIR="$HOME/Downloads"
+XDG_MUSIC_DIR="$HOME/Music"
+XDG_PICTURES_DIR="$HOME/Pictures"
+XDG_PUBLICSHARE_DIR="$HOME/Public"
+XDG_TEMPLATES_DIR="$HOME/Templates"
+XDG_VIDEOS_DIR="$HOME/Videos"
+"#;
+
+// Configuración del sistema de archivos usada por el sistema de compilación
+// (config/<arch>/<nombre>.toml). Solo se leen las secciones que afectan al
+// contenido de la partición root.
+#[derive(Debug, Default, Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    packages: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    files: Vec<FileConfig>,
+    #[serde(default)]
+    users: BTreeMap<String, UserConfig>,
+    #[serde(default)]
+    groups: BTreeMap<String, GroupConfig>,
+}
+
+// Entrada [users.<nombre>]; lo que falta toma el valor de redox_installer
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserConfig {
+    // En claro; sin ella la cuenta queda sin contraseña
+    pub password: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub name: Option<String>,
+    pub home: Option<String>,
+    pub shell: Option<String>,
+}
+
+// Entrada [groups.<nombre>]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GroupConfig {
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BuildConfig {
+    // Paquetes en orden alfabético, sin los marcados como "ignore"
+    pub packages: Vec<String>,
+    // Ficheros en el orden de aplicación: primero los de los includes
+    pub files: Vec<FileConfig>,
+    // Una entrada de un fichero sustituye a la del mismo nombre de sus includes
+    pub users: BTreeMap<String, UserConfig>,
+    pub groups: BTreeMap<String, GroupConfig>,
+}
+
+// /etc/passwd, /etc/shadow y /etc/group en el formato de redox_users
+// (campos separados por ';') y los directorios personales a crear
+#[derive(Debug, Default)]
+pub struct Accounts {
+    pub passwd: String,
+    pub shadow: String,
+    pub group: String,
+    pub homes: Vec<Home>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Home {
+    pub path: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Home {
+    // Subdirectorios XDG (y su user-dirs.dirs) que llevan los usuarios normales
+    pub fn xdg_dirs(&self) -> Option<(&'static [&'static str], &'static str)> {
+        (self.uid >= FIRST_AUTO_ID).then_some((&XDG_DIRS, USER_DIRS))
+    }
+}
+
+impl BuildConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut packages = BTreeMap::new();
+        let mut stack = Vec::new();
+        let mut config = Self::default();
+
+        Self::load_recursive(Path::new(path), &mut stack, &mut packages, &mut config)?;
+
+        let packages = packages
+            .into_iter()
+            // Un paquete puede declararse como "ignore" para excluirlo
+            .filter(|(_, package)| package.as_str() != Some("ignore"))
+            .map(|(name, _)| name)
+            .collect();
+
+        Ok(Self { packages, ..config })
+    }
+
+    // Los includes se aplican antes que el propio fichero, de modo que sus
+    // valores pueden ser sobrescritos, igual que hace redox_installer
+    fn load_recursive(
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        packages: &mut BTreeMap<String, toml::Value>,
+        config: &mut BuildConfig,
+    ) -> Result<(), String> {
+        let canonical = fs::canonicalize(path)
+            .map_err(|e| format!("Error abriendo configuración {}: {}", path.display(), e))?;
+
+        if stack.contains(&canonical) {
+            return Err(format!("Include circular en {}", path.display()));
+        }
+
+        let contents = fs::read_to_string(&canonical)
+            .map_err(|e| format!("Error leyendo configuración {}: {}", path.display(), e))?;
+        let raw: RawConfig = toml::from_str(&contents)
+            .map_err(|e| format!("Error en configuración {}: {}", path.display(), e))?;
+
+        stack.push(canonical.clone());
+
+        let base_dir = canonical.parent().unwrap_or(Path::new("."));
+        for include in &raw.include {
+            Self::load_recursive(&base_dir.join(include), stack, packages, config)?;
+        }
+
+        stack.pop();
+
+        packages.extend(raw.packages);
+        config.files.extend(raw.files);
+        config.users.extend(raw.users);
+        config.groups.extend(raw.groups);
+
+        Ok(())
+    }
+
+    pub fn preinstall_files(&self) -> impl Iterator<Item = &FileConfig> {
+        self.files.iter().filter(|file| !file.postinstall)
+    }
+
+    pub fn postinstall_files(&self) -> impl Iterator<Item = &FileConfig> {
+        self.files.iter().filter(|file| file.postinstall)
+    }
+
+    // Cuentas igual que redox_installer: usuarios en orden alfabético, uid y
+    // gid libres a partir de 1000, un grupo propio por usuario seguido de los
+    // de [groups] y contraseñas con hash argon2
+    pub fn accounts(&self) -> Result<Accounts, String> {
+        let mut accounts = Accounts::default();
+        let mut next_uid = FIRST_AUTO_ID;
+        let mut next_gid = FIRST_AUTO_ID;
+        let mut groups = Vec::new();
+
+        for (username, user) in &self.users {
+            let uid = user.uid.unwrap_or(next_uid);
+            next_uid = next_uid.max(uid + 1);
+            let gid = user.gid.unwrap_or(next_gid);
+            next_gid = next_gid.max(gid + 1);
+
+            let name = user.name.as_deref().unwrap_or(username);
+            let home = user.home.clone().unwrap_or_else(|| format!("/home/{}", username));
+            let shell = user.shell.as_deref().unwrap_or(DEFAULT_SHELL);
+            let password = hash_password(user.password.as_deref().unwrap_or_default())?;
+
+            accounts.passwd.push_str(&format!("{};{};{};{};{};{}\n", username, uid, gid, name, home, shell));
+            accounts.shadow.push_str(&format!("{};{}\n", username, password));
+            accounts.homes.push(Home { path: home, uid, gid });
+            groups.push((username.clone(), gid, vec![username.clone()]));
+        }
+
+        for (group, config) in &self.groups {
+            let gid = config.gid.unwrap_or(next_gid);
+            next_gid = next_gid.max(gid + 1);
+            groups.push((group.clone(), gid, config.members.clone()));
+        }
+
+        for (name, gid, members) in groups {
+            accounts.group.push_str(&format!("{};x;{};{}\n", name, gid, members.join(",")));
+        }
+
+        Ok(accounts)
+    }
+}
+
+// Hash argon2 que entiende redox_users; una contraseña vacía queda vacía
+fn hash_password(password: &str) -> Result<String, String> {
+    if password.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut random = [0u8; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .map_err(|e| format!("Error generando la sal de la contraseña: {}", e))?;
+    let salt = format!("{:X}", u64::from_ne_bytes(random));
+
+    argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &argon2::Config::default())
+        .map_err(|e| format!("Error calculando el hash de la contraseña: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn includes_are_applied_first_and_can_be_overridden() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "base.toml",
+            r#"
+            [packages]
+            kernel = {}
+            uutils = {}
+
+            [[files]]
+            path = "/etc/hostname"
+            data = "redox"
+
+            [users.user]
+            shell = "/usr/bin/ion"
+            "#,
+        );
+        let desktop = write(
+            dir.path(),
+            "x86_64/desktop.toml",
+            r#"
+            include = ["../base.toml"]
+
+            [packages]
+            orbital = {}
+            uutils = "ignore"
+
+            [[files]]
+            path = "/etc/motd"
+            data = "hola"
+
+            [users.user]
+            password = "secreto"
+            "#,
+        );
+
+        let config = BuildConfig::load(&desktop).unwrap();
+
+        assert_eq!(config.packages, ["kernel", "orbital"]);
+        let paths: Vec<&str> = config.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["/etc/hostname", "/etc/motd"]);
+        // La entrada del fichero sustituye entera a la del include
+        let user = &config.users["user"];
+        assert_eq!(user.password.as_deref(), Some("secreto"));
+        assert_eq!(user.shell, None);
+    }
+
+    #[test]
+    fn circular_and_missing_includes_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", "include = [\"b.toml\"]\n");
+        let b = write(dir.path(), "b.toml", "include = [\"a.toml\"]\n");
+        let missing = write(dir.path(), "c.toml", "include = [\"nope.toml\"]\n");
+
+        assert!(BuildConfig::load(&b).unwrap_err().starts_with("Include circular"));
+        assert!(BuildConfig::load(&missing).unwrap_err().contains("nope.toml"));
+    }
+
+    #[test]
+    fn accounts_match_redox_installer() {
+        let dir = tempfile::tempdir().unwrap();
+        // Los usuarios y grupos de config/base.toml
+        let path = write(
+            dir.path(),
+            "base.toml",
+            r#"
+            [users.root]
+            password = "password"
+            uid = 0
+            gid = 0
+            name = "root"
+            home = "/root"
+            shell = "/usr/bin/ion"
+
+            [users.user]
+            password = ""
+            shell = "/usr/bin/ion"
+
+            [groups.sudo]
+            gid = 1
+            members = ["user"]
+
+            [groups.staff]
+            members = ["root", "user"]
+            "#,
+        );
+
+        let accounts = BuildConfig::load(&path).unwrap().accounts().unwrap();
+
+        assert_eq!(accounts.passwd, "root;0;0;root;/root;/usr/bin/ion\nuser;1000;1000;user;/home/user;/usr/bin/ion\n");
+        assert_eq!(accounts.group, "root;x;0;root\nuser;x;1000;user\nstaff;x;1001;root,user\nsudo;x;1;user\n");
+        let shadow: Vec<&str> = accounts.shadow.lines().collect();
+        assert_eq!(shadow[1], "user;");
+        let hash = shadow[0].strip_prefix("root;").unwrap();
+        assert!(hash.starts_with("$argon2id$"), "{}", hash);
+        assert!(argon2::verify_encoded(hash, b"password").unwrap());
+        assert_eq!(accounts.homes, [
+            Home { path: "/root".to_string(), uid: 0, gid: 0 },
+            Home { path: "/home/user".to_string(), uid: 1000, gid: 1000 },
+        ]);
+        assert!(accounts.homes[0].xdg_dirs().is_none());
+        assert!(accounts.homes[1].xdg_dirs().is_some());
+    }
+}
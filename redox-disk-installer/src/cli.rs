@@ -0,0 +1,1112 @@
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::answer_file::AnswerFile;
+use crate::boot_entries::BootPriority;
+use crate::artifacts::{ArtifactResolver, BuildLocation};
+use crate::command_runner::SystemRunner;
+use crate::disk_image::{self, LoopDevice};
+use crate::disk_manager::DiskManager;
+use crate::gpt::{self, GptTable};
+use crate::direct_installer::DirectInstaller;
+use crate::table_backup::TableBackup;
+use crate::validation::SystemValidator;
+use crate::{BootMode, DiskInfo, FilesystemType, InstallationConfig, SecureBootConfig};
+
+// Códigos de salida del modo no interactivo, uno por cada clase de fallo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    Usage = 2,
+    NotRoot = 3,
+    SystemValidation = 4,
+    DiskValidation = 5,
+    InstallFailed = 6,
+    NotConfirmed = 7,
+}
+
+impl ExitCode {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CliCommand {
+    Menu,
+    Install(InstallArgs),
+    ListDisks,
+    // Muestra la tabla GPT de un disco y sus huecos libres
+    FreeSpace(String),
+    // Escribe de nuevo en el disco una tabla guardada antes de instalar
+    RestoreTable(RestoreArgs),
+    // Reinstala el bootloader, el kernel y la configuración de arranque
+    Repair(RepairArgs),
+    Help,
+}
+
+// Las opciones explícitas tienen prioridad sobre el fichero de respuestas
+#[derive(Debug, Clone, Default)]
+pub struct InstallArgs {
+    pub disk: Option<String>,
+    // Fichero de imagen que se instala a través de un dispositivo loop
+    pub image: Option<String>,
+    pub image_size: Option<u64>,
+    pub efi_size_mb: Option<u64>,
+    pub filesystem_type: Option<FilesystemType>,
+    pub config: Option<String>,
+    pub filesystem_config: Option<String>,
+    pub build: BuildArgs,
+    pub dual_boot: bool,
+    pub free_gap: Option<usize>,
+    // Actualiza la instalación de Redox existente en lugar de reinstalar
+    pub upgrade: bool,
+    // Escribe el root RedoxFS con la librería redoxfs, sin montarlo con FUSE
+    pub redoxfs_direct: bool,
+    pub boot_mode: Option<BootMode>,
+    pub boot_priority: Option<BootPriority>,
+    pub signing: SigningArgs,
+    // Solo muestra el plan de instalación, sin modificar nada
+    pub dry_run: bool,
+    pub json: bool,
+    pub restore_table_on_failure: bool,
+    pub backup_dir: Option<String>,
+    // Permite elegir un disco que sostiene el sistema en ejecución
+    pub allow_system_disk: bool,
+    pub yes: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreArgs {
+    pub backup: String,
+    // Por defecto, el disco del que se hizo la copia
+    pub disk: Option<String>,
+    pub yes: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepairArgs {
+    pub disk: String,
+    pub build: BuildArgs,
+    pub signing: SigningArgs,
+    pub allow_system_disk: bool,
+    pub yes: bool,
+}
+
+// Árbol de Redox del que se toman los artefactos. Lo que no se indica sale
+// de REDOX_SOURCE_ROOT, ARCH y CONFIG_NAME o de los valores por defecto.
+#[derive(Debug, Clone, Default)]
+pub struct BuildArgs {
+    pub source_root: Option<String>,
+    pub arch: Option<String>,
+    pub config_name: Option<String>,
+}
+
+impl BuildArgs {
+    pub fn location(&self) -> BuildLocation {
+        let mut location = BuildLocation::from_env();
+        if let Some(source_root) = &self.source_root {
+            location.source_root = source_root.into();
+        }
+        if let Some(arch) = &self.arch {
+            location.arch = arch.clone();
+        }
+        if let Some(config_name) = &self.config_name {
+            location.config_name = config_name.clone();
+        }
+        location
+    }
+
+    // Devuelve false si `name` no es una opción del árbol de Redox
+    fn parse(&mut self, name: &str, value: impl FnOnce(&str) -> Result<String, String>) -> Result<bool, String> {
+        match name {
+            "--source-root" => self.source_root = Some(value(name)?),
+            "--arch" => self.arch = Some(value(name)?),
+            "--config-name" => self.config_name = Some(value(name)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+// Firma del bootloader para Secure Boot, común a install y repair
+#[derive(Debug, Clone, Default)]
+pub struct SigningArgs {
+    pub key: Option<String>,
+    pub certificate: Option<String>,
+    pub shim: Option<String>,
+    pub mok_manager: Option<String>,
+}
+
+impl SigningArgs {
+    pub fn secure_boot(&self) -> Result<Option<SecureBootConfig>, String> {
+        let (key, certificate) = match (&self.key, &self.certificate) {
+            (Some(key), Some(certificate)) => (key.clone(), certificate.clone()),
+            (None, None) => {
+                if self.shim.is_some() || self.mok_manager.is_some() {
+                    return Err("--shim y --mok-manager necesitan --sign-key y --sign-cert".to_string());
+                }
+                return Ok(None);
+            }
+            _ => return Err("--sign-key y --sign-cert se indican juntos".to_string()),
+        };
+        let secure_boot = SecureBootConfig {
+            key,
+            certificate,
+            shim: self.shim.clone(),
+            mok_manager: self.mok_manager.clone(),
+        };
+        secure_boot.validate().map_err(|_| "--mok-manager solo es válido junto con --shim".to_string())?;
+        Ok(Some(secure_boot))
+    }
+
+    // Devuelve false si `name` no es una opción de firma
+    fn parse(&mut self, name: &str, value: impl FnOnce(&str) -> Result<String, String>) -> Result<bool, String> {
+        match name {
+            "--sign-key" => self.key = Some(value(name)?),
+            "--sign-cert" => self.certificate = Some(value(name)?),
+            "--shim" => self.shim = Some(value(name)?),
+            "--mok-manager" => self.mok_manager = Some(value(name)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn is_help(arg: &str) -> bool {
+    arg == "-h" || arg == "--help"
+}
+
+// -h y --help solo piden la ayuda en posición de opción, no como valor de
+// otra (--disk -h). Los analizadores de cada subcomando devuelven None si la
+// encuentran.
+pub fn parse_args(args: &[String]) -> Result<CliCommand, String> {
+    let Some(subcommand) = args.first() else {
+        return Ok(CliCommand::Menu);
+    };
+
+    let help_or = |parsed: Option<CliCommand>| parsed.unwrap_or(CliCommand::Help);
+    match subcommand.as_str() {
+        arg if is_help(arg) => Ok(CliCommand::Help),
+        "install" => parse_install_args(&args[1..]).map(|parsed| help_or(parsed.map(CliCommand::Install))),
+        "list-disks" => {
+            if args[1..].iter().any(|arg| is_help(arg)) {
+                return Ok(CliCommand::Help);
+            }
+            if let Some(extra) = args.get(1) {
+                return Err(format!("Argumento inesperado para list-disks: {}", extra));
+            }
+            Ok(CliCommand::ListDisks)
+        }
+        "free-space" => match &args[1..] {
+            [option, ..] if is_help(option) => Ok(CliCommand::Help),
+            [option, disk] if option == "--disk" => Ok(CliCommand::FreeSpace(disk.clone())),
+            [option] if option.starts_with("--disk=") => {
+                Ok(CliCommand::FreeSpace(option["--disk=".len()..].to_string()))
+            }
+            _ => Err("Uso: redox-disk-installer free-space --disk <DISPOSITIVO>".to_string()),
+        },
+        "restore-table" => parse_restore_args(&args[1..]).map(|parsed| help_or(parsed.map(CliCommand::RestoreTable))),
+        "repair" => parse_repair_args(&args[1..]).map(|parsed| help_or(parsed.map(CliCommand::Repair))),
+        "help" => Ok(CliCommand::Help),
+        "menu" => Ok(CliCommand::Menu),
+        other => Err(format!("Subcomando desconocido: {}", other)),
+    }
+}
+
+// Recorre las opciones de un subcomando aceptando tanto "--opcion valor"
+// como "--opcion=valor": `handle` recibe el nombre de cada una y una función
+// que devuelve su valor. Devuelve true si se pidió la ayuda.
+fn parse_options(
+    args: &[String],
+    mut handle: impl FnMut(&str, &mut dyn FnMut(&str) -> Result<String, String>) -> Result<(), String>,
+) -> Result<bool, String> {
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        if is_help(name) {
+            return Ok(true);
+        }
+
+        let mut value = |option: &str| -> Result<String, String> {
+            match inline_value.clone() {
+                Some(value) => Ok(value),
+                None => iter
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("Falta el valor para {}", option)),
+            }
+        };
+        handle(name, &mut value)?;
+    }
+
+    Ok(false)
+}
+
+fn parse_install_args(args: &[String]) -> Result<Option<InstallArgs>, String> {
+    let mut install_args = InstallArgs::default();
+
+    let help = parse_options(args, |name, value| {
+        match name {
+            "--disk" => {
+                install_args.disk = Some(value("--disk")?);
+            }
+            "--image" => {
+                install_args.image = Some(value("--image")?);
+            }
+            "--size" => {
+                install_args.image_size = Some(disk_image::parse_size(&value("--size")?)?);
+            }
+            "--efi-size" => {
+                let raw = value("--efi-size")?;
+                install_args.efi_size_mb = match raw.parse::<u64>() {
+                    Ok(n) if n >= 100 => Some(n),
+                    _ => {
+                        return Err(format!(
+                            "Tamaño EFI inválido: {} (debe ser un número >= 100 MB)",
+                            raw
+                        ))
+                    }
+                };
+            }
+            "--fs" => {
+                let raw = value("--fs")?;
+                install_args.filesystem_type = Some(parse_filesystem(&raw)
+                    .ok_or_else(|| format!("Sistema de archivos inválido: {} (redoxfs/ext4)", raw))?);
+            }
+            "--config" => {
+                install_args.config = Some(value("--config")?);
+            }
+            "--filesystem-config" => {
+                install_args.filesystem_config = Some(value("--filesystem-config")?);
+            }
+            "--dual-boot" => {
+                install_args.dual_boot = true;
+            }
+            "--gap" => {
+                let raw = value("--gap")?;
+                install_args.free_gap = match raw.parse::<usize>() {
+                    Ok(n) if n >= 1 => Some(n),
+                    _ => return Err(format!("Hueco inválido: {} (usa el número que muestra free-space)", raw)),
+                };
+            }
+            "--upgrade" => {
+                install_args.upgrade = true;
+            }
+            "--redoxfs-direct" => {
+                install_args.redoxfs_direct = true;
+            }
+            "--boot-mode" => {
+                install_args.boot_mode = Some(BootMode::parse(&value("--boot-mode")?)?);
+            }
+            "--boot-entry" => {
+                install_args.boot_priority = Some(BootPriority::parse(&value("--boot-entry")?)?);
+            }
+            "--restore-table-on-failure" => {
+                install_args.restore_table_on_failure = true;
+            }
+            "--backup-dir" => {
+                install_args.backup_dir = Some(value("--backup-dir")?);
+            }
+            "--allow-system-disk" => {
+                install_args.allow_system_disk = true;
+            }
+            "--dry-run" => {
+                install_args.dry_run = true;
+            }
+            "--json" => {
+                install_args.json = true;
+            }
+            "--yes" | "-y" => {
+                install_args.yes = true;
+            }
+            other if install_args.signing.parse(other, &mut *value)? => {}
+            other => {
+                if !install_args.build.parse(other, value)? {
+                    return Err(format!("Opción desconocida para install: {}", other));
+                }
+            }
+        }
+        Ok(())
+    })?;
+    if help {
+        return Ok(None);
+    }
+
+    if install_args.disk.is_some() && install_args.image.is_some() {
+        return Err("--disk y --image son incompatibles".to_string());
+    }
+    if install_args.image_size.is_some() && install_args.image.is_none() {
+        return Err("--size solo es válido junto con --image".to_string());
+    }
+    if install_args.dual_boot && install_args.image.is_some() {
+        return Err("--dual-boot no es compatible con --image".to_string());
+    }
+    if install_args.upgrade && install_args.image.is_some() {
+        return Err("--upgrade no es compatible con --image".to_string());
+    }
+    if install_args.upgrade && install_args.dual_boot {
+        return Err("--upgrade no es compatible con --dual-boot".to_string());
+    }
+    if install_args.redoxfs_direct && install_args.upgrade {
+        return Err("--redoxfs-direct no es compatible con --upgrade".to_string());
+    }
+    if install_args.boot_mode.is_some_and(BootMode::bios) && install_args.dual_boot {
+        return Err("--boot-mode hybrid/bios no es compatible con --dual-boot".to_string());
+    }
+    let secure_boot = install_args.signing.secure_boot()?;
+    if secure_boot.is_some() && install_args.boot_mode == Some(BootMode::Bios) {
+        return Err("--sign-key no es compatible con --boot-mode bios (Secure Boot es solo UEFI)".to_string());
+    }
+    if install_args.free_gap.is_some() && !install_args.dual_boot {
+        return Err("--gap solo es válido junto con --dual-boot".to_string());
+    }
+    if install_args.json && !install_args.dry_run {
+        return Err("--json solo es válido junto con --dry-run".to_string());
+    }
+
+    Ok(Some(install_args))
+}
+
+fn parse_restore_args(args: &[String]) -> Result<Option<RestoreArgs>, String> {
+    let mut restore_args = RestoreArgs::default();
+    let mut backup = None;
+
+    let help = parse_options(args, |name, value| {
+        match name {
+            "--backup" => backup = Some(value("--backup")?),
+            "--disk" => restore_args.disk = Some(value("--disk")?),
+            "--yes" | "-y" => restore_args.yes = true,
+            other => return Err(format!("Opción desconocida para restore-table: {}", other)),
+        }
+        Ok(())
+    })?;
+    if help {
+        return Ok(None);
+    }
+
+    restore_args.backup = backup.ok_or_else(|| {
+        "Uso: redox-disk-installer restore-table --backup <FICHERO> [--disk <DISPOSITIVO>] [--yes]".to_string()
+    })?;
+    Ok(Some(restore_args))
+}
+
+fn parse_repair_args(args: &[String]) -> Result<Option<RepairArgs>, String> {
+    let mut repair_args = RepairArgs::default();
+    let mut disk = None;
+
+    let help = parse_options(args, |name, value| {
+        match name {
+            "--disk" => disk = Some(value("--disk")?),
+            "--yes" | "-y" => repair_args.yes = true,
+            "--allow-system-disk" => repair_args.allow_system_disk = true,
+            other if repair_args.signing.parse(other, &mut *value)? => {}
+            other => {
+                if !repair_args.build.parse(other, value)? {
+                    return Err(format!("Opción desconocida para repair: {}", other));
+                }
+            }
+        }
+        Ok(())
+    })?;
+    if help {
+        return Ok(None);
+    }
+
+    repair_args.disk = disk.ok_or_else(|| "Uso: redox-disk-installer repair --disk <DISPOSITIVO> [--yes]".to_string())?;
+    repair_args.signing.secure_boot()?;
+    Ok(Some(repair_args))
+}
+
+pub fn parse_filesystem(name: &str) -> Option<FilesystemType> {
+    match name.trim().to_lowercase().as_str() {
+        "redoxfs" => Some(FilesystemType::RedoxFS),
+        "ext4" => Some(FilesystemType::Ext4),
+        _ => None,
+    }
+}
+
+pub fn run(command: CliCommand) -> ExitCode {
+    match command {
+        CliCommand::Install(args) => run_install(&args),
+        CliCommand::ListDisks => run_list_disks(),
+        CliCommand::FreeSpace(disk) => run_free_space(&disk),
+        CliCommand::RestoreTable(args) => run_restore_table(&args),
+        CliCommand::Repair(args) => run_repair(&args),
+        CliCommand::Help => {
+            print_usage();
+            ExitCode::Success
+        }
+        // El menú lo gestiona main directamente
+        CliCommand::Menu => ExitCode::Success,
+    }
+}
+
+// --filesystem-config tiene prioridad sobre filesystem_config de --config;
+// sin ninguna de las dos se usa la de --arch y --config-name
+fn filesystem_config(args: &InstallArgs, answer_file: Option<&AnswerFile>, location: &BuildLocation) -> String {
+    args.filesystem_config
+        .clone()
+        .or_else(|| answer_file.and_then(|answers| answers.general.filesystem_config.clone()))
+        .unwrap_or_else(|| location.filesystem_config())
+}
+
+fn run_install(args: &InstallArgs) -> ExitCode {
+    // El plan de --dry-run no modifica nada y se puede ver sin privilegios
+    if !args.dry_run && !crate::is_root() {
+        eprintln!("❌ Error: Este instalador debe ejecutarse como root");
+        return ExitCode::NotRoot;
+    }
+
+    let answer_file = match &args.config {
+        Some(path) => match AnswerFile::load(path) {
+            Ok(answer_file) => Some(answer_file),
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                return ExitCode::Usage;
+            }
+        },
+        None => None,
+    };
+
+    let mut config = match answer_file.as_ref().map(AnswerFile::installation_config) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("❌ Error en el fichero de respuestas: {}", e);
+            return ExitCode::Usage;
+        }
+        None => InstallationConfig::default(),
+    };
+    if !config.partitions.is_empty() && (args.efi_size_mb.is_some() || args.filesystem_type.is_some()) {
+        eprintln!("❌ Error: --efi-size y --fs no se pueden combinar con [[partitions]] en --config");
+        return ExitCode::Usage;
+    }
+    if let Some(efi_size_mb) = args.efi_size_mb {
+        config.efi_size_mb = efi_size_mb;
+    }
+    if let Some(filesystem_type) = &args.filesystem_type {
+        config.filesystem_type = filesystem_type.clone();
+    }
+    let location = args.build.location();
+    config.filesystem_config = filesystem_config(args, answer_file.as_ref(), &location);
+    if args.dual_boot {
+        if !config.partitions.is_empty() {
+            eprintln!("❌ Error: --dual-boot no se puede combinar con [[partitions]] en --config");
+            return ExitCode::Usage;
+        }
+        config.dual_boot = true;
+    }
+    if args.free_gap.is_some() {
+        config.free_gap = args.free_gap;
+    }
+    if args.upgrade {
+        if !config.partitions.is_empty() || config.dual_boot {
+            eprintln!("❌ Error: --upgrade no se puede combinar con [[partitions]] ni dual_boot en --config");
+            return ExitCode::Usage;
+        }
+        config.upgrade = true;
+    }
+    if args.redoxfs_direct {
+        if config.upgrade {
+            eprintln!("❌ Error: --redoxfs-direct no se puede combinar con upgrade en --config");
+            return ExitCode::Usage;
+        }
+        config.redoxfs_direct = true;
+    }
+    if let Some(boot_mode) = args.boot_mode {
+        config.boot_mode = boot_mode;
+    }
+    if let Some(boot_priority) = args.boot_priority {
+        config.boot_priority = boot_priority;
+    }
+    if let Some(secure_boot) = args.signing.secure_boot().expect("validado al leer los argumentos") {
+        config.secure_boot = Some(secure_boot);
+    }
+    if args.restore_table_on_failure {
+        config.restore_table_on_failure = true;
+    }
+    if let Some(backup_dir) = &args.backup_dir {
+        config.backup_dir = backup_dir.clone();
+    }
+    if config.dual_boot && args.image.is_some() {
+        eprintln!("❌ Error: el modo dual-boot no es compatible con --image");
+        return ExitCode::Usage;
+    }
+    if config.upgrade && args.image.is_some() {
+        eprintln!("❌ Error: la actualización no es compatible con --image");
+        return ExitCode::Usage;
+    }
+
+    if args.disk.is_none()
+        && args.image.is_none()
+        && answer_file.as_ref().is_none_or(|answers| answers.disk.is_empty())
+    {
+        eprintln!("❌ Error: Falta el disco de destino (--disk /dev/sdX, --image o [disk] en --config)");
+        return ExitCode::Usage;
+    }
+
+    let validator = SystemValidator::new();
+    if let Err(e) = validator.validate_system() {
+        eprintln!("❌ Error de validación: {}", e);
+        return ExitCode::SystemValidation;
+    }
+
+    if let Err(e) = validator.validate_redox_build(&ArtifactResolver::new(location.clone())) {
+        eprintln!("❌ Error de validación: {}", e);
+        eprintln!("   Ejecuta 'make all' para compilar Redox OS antes de continuar");
+        return ExitCode::SystemValidation;
+    }
+
+    if let Some(image) = &args.image {
+        return run_install_image(args, image, &validator, &config);
+    }
+
+    let mut disk_manager = DiskManager::new();
+    let disks = disk_manager.list_disks();
+    let selected = match (&args.disk, &answer_file) {
+        (Some(disk_path), _) => disks
+            .into_iter()
+            .find(|disk| &disk.name == disk_path)
+            .ok_or_else(|| format!("{} no es un disco disponible", disk_path)),
+        (None, Some(answer_file)) => answer_file.disk.select(disks),
+        (None, None) => unreachable!(),
+    };
+    let disk = match selected {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("❌ Error: {}", e);
+            eprintln!("   Usa 'redox-disk-installer list-disks' para ver los discos disponibles");
+            return ExitCode::DiskValidation;
+        }
+    };
+
+    if let Err(e) = validator.validate_disk(&disk, args.allow_system_disk) {
+        eprintln!("❌ Error validando disco: {}", e);
+        return ExitCode::DiskValidation;
+    }
+
+    // blockdev necesita abrir el disco; en --dry-run basta el tamaño de sysfs
+    let space = if args.dry_run {
+        validator.check_size(disk.size_bytes)
+    } else {
+        validator.check_disk_space(&disk.name)
+    };
+    if let Err(e) = space {
+        eprintln!("❌ Error de espacio en disco: {}", e);
+        return ExitCode::DiskValidation;
+    }
+
+    if args.dry_run {
+        return run_dry_run(args, &disk, &config);
+    }
+
+    if !args.yes && config.upgrade {
+        eprintln!("❌ Esta operación sustituirá el bootloader, el kernel, el initfs y los paquetes de Redox en {}", disk.name);
+        eprintln!("   (/home, /root y /etc se conservan)");
+        eprintln!("   Añade --yes para confirmar la actualización sin preguntas");
+        return ExitCode::NotConfirmed;
+    }
+    if !args.yes && config.dual_boot {
+        eprintln!("❌ Esta operación modificará la tabla de particiones de {}", disk.name);
+        eprintln!("   (las particiones existentes se conservan y la tabla actual se guarda en {})", config.backup_dir);
+        eprintln!("   Añade --yes para confirmar la instalación sin preguntas");
+        return ExitCode::NotConfirmed;
+    }
+    if !args.yes {
+        eprintln!("❌ Esta operación BORRARÁ TODOS los datos en {}", disk.name);
+        eprintln!("   Añade --yes para confirmar la instalación sin preguntas");
+        return ExitCode::NotConfirmed;
+    }
+
+    let direct_installer = DirectInstaller::new().with_build(args.build.location());
+    match direct_installer.install_redox_os(&disk, &config) {
+        Ok(_) => {
+            println!("✅ Instalación completada en {}", disk.name);
+            ExitCode::Success
+        }
+        Err(e) => {
+            eprintln!("❌ Error durante la instalación: {}", e);
+            ExitCode::InstallFailed
+        }
+    }
+}
+
+fn run_dry_run(args: &InstallArgs, disk: &DiskInfo, config: &InstallationConfig) -> ExitCode {
+    let direct_installer = DirectInstaller::new().with_build(args.build.location());
+
+    // Con --json la salida estándar queda solo para el plan: el progreso de
+    // la instalación simulada se envía a stderr
+    let plan = if args.json {
+        let _redirect = match StdoutToStderr::new() {
+            Ok(redirect) => redirect,
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                return ExitCode::InstallFailed;
+            }
+        };
+        direct_installer.plan_redox_os(disk, config)
+    } else {
+        direct_installer.plan_redox_os(disk, config)
+    };
+
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("❌ Error preparando el plan de instalación: {}", e);
+            return ExitCode::InstallFailed;
+        }
+    };
+
+    if args.json {
+        match plan.to_json() {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                return ExitCode::InstallFailed;
+            }
+        }
+    } else {
+        println!();
+        print!("{}", plan);
+    }
+    ExitCode::Success
+}
+
+// Redirige la salida estándar del proceso a stderr mientras existe
+struct StdoutToStderr {
+    saved: libc::c_int,
+}
+
+impl StdoutToStderr {
+    fn new() -> Result<Self, String> {
+        io::stdout().flush().ok();
+        // SAFETY: dup/dup2 solo operan sobre descriptores del propio proceso
+        let saved = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if saved < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            return Err(format!("Error redirigiendo la salida: {}", io::Error::last_os_error()));
+        }
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for StdoutToStderr {
+    fn drop(&mut self) {
+        io::stdout().flush().ok();
+        // SAFETY: `saved` es un duplicado válido de la salida estándar original
+        unsafe {
+            libc::dup2(self.saved, libc::STDOUT_FILENO);
+            libc::close(self.saved);
+        }
+    }
+}
+
+// Instala en un fichero de imagen asociado a un dispositivo loop, que se
+// libera al terminar aunque la instalación falle
+fn run_install_image(
+    args: &InstallArgs,
+    image: &str,
+    validator: &SystemValidator,
+    config: &InstallationConfig,
+) -> ExitCode {
+    if let Err(e) = validator.validate_image_tools() {
+        eprintln!("❌ Error de validación: {}", e);
+        return ExitCode::SystemValidation;
+    }
+
+    // El plan se calcula sin crear la imagen ni asociar un dispositivo loop
+    if args.dry_run {
+        return match disk_image::image_size(image, args.image_size) {
+            Ok(size) => run_dry_run(args, &disk_image::planned_disk_info(image, size), config),
+            Err(e) => {
+                eprintln!("❌ Error preparando la imagen: {}", e);
+                ExitCode::DiskValidation
+            }
+        };
+    }
+
+    if !args.yes {
+        eprintln!("❌ Esta operación SOBRESCRIBIRÁ {}", image);
+        eprintln!("   Añade --yes para confirmar la instalación sin preguntas");
+        return ExitCode::NotConfirmed;
+    }
+
+    let size = match disk_image::prepare_image(image, args.image_size) {
+        Ok(size) => size,
+        Err(e) => {
+            eprintln!("❌ Error preparando la imagen: {}", e);
+            return ExitCode::DiskValidation;
+        }
+    };
+
+    let loop_device = match LoopDevice::attach(Rc::new(SystemRunner), image) {
+        Ok(loop_device) => loop_device,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return ExitCode::InstallFailed;
+        }
+    };
+    let disk = loop_device.disk_info(image, size);
+
+    let direct_installer = DirectInstaller::new().with_build(args.build.location());
+    match direct_installer.install_redox_os(&disk, config) {
+        Ok(_) => {
+            println!("✅ Imagen {} creada; arráncala con QEMU u otra máquina virtual UEFI", image);
+            ExitCode::Success
+        }
+        Err(e) => {
+            eprintln!("❌ Error durante la instalación: {}", e);
+            ExitCode::InstallFailed
+        }
+    }
+}
+
+fn run_list_disks() -> ExitCode {
+    let mut disk_manager = DiskManager::new();
+    for disk in disk_manager.list_disks() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            disk.name, disk.size_human(), disk.model, disk.disk_type,
+            disk.size_bytes, disk.logical_sector_size, disk.transport, disk.serial
+        );
+    }
+    ExitCode::Success
+}
+
+fn run_free_space(disk: &str) -> ExitCode {
+    let table = match gpt::open_disk_readonly(disk)
+        .and_then(|(file, size, sector_size)| GptTable::read_from(&file, size, sector_size))
+    {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("❌ Error leyendo la tabla de particiones de {}: {}", disk, e);
+            return ExitCode::DiskValidation;
+        }
+    };
+
+    let mb = |first: u64, last: u64| (last - first + 1) * table.sector_size / 1024 / 1024;
+    println!("Particiones de {}:", disk);
+    for partition in &table.partitions {
+        let esp = if partition.type_guid == gpt::EFI_SYSTEM_PARTITION { "  [EFI]" } else { "" };
+        println!(
+            "  {}	{}-{}	{} MB	{}{}",
+            partition.number, partition.first_lba, partition.last_lba,
+            mb(partition.first_lba, partition.last_lba), partition.name, esp
+        );
+    }
+
+    let gaps = table.free_gaps();
+    if gaps.is_empty() {
+        println!("Sin espacio libre");
+    } else {
+        println!("Huecos libres (usa --dual-boot --gap N):");
+        for (i, (first, last)) in gaps.iter().enumerate() {
+            println!("  {}	{}-{}	{} MB", i + 1, first, last, mb(*first, *last));
+        }
+    }
+    ExitCode::Success
+}
+
+fn run_restore_table(args: &RestoreArgs) -> ExitCode {
+    if !crate::is_root() {
+        eprintln!("❌ Error: Este comando debe ejecutarse como root");
+        return ExitCode::NotRoot;
+    }
+
+    let backup = match TableBackup::load(&args.backup) {
+        Ok(backup) => backup,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return ExitCode::Usage;
+        }
+    };
+    let disk = args.disk.clone().unwrap_or_else(|| backup.disk.clone());
+
+    println!("Copia de {} del {}:", backup.disk, backup.created);
+    for line in backup.layout.describe() {
+        println!("  {}", line);
+    }
+
+    if !args.yes {
+        eprintln!("❌ Esta operación SOBRESCRIBIRÁ la tabla de particiones actual de {}", disk);
+        eprintln!("   Añade --yes para confirmar la restauración");
+        return ExitCode::NotConfirmed;
+    }
+
+    match backup.restore_to(&disk) {
+        Ok(()) => {
+            println!("✅ Tabla de particiones restaurada en {}", disk);
+            ExitCode::Success
+        }
+        Err(e) => {
+            eprintln!("❌ Error restaurando la tabla de particiones: {}", e);
+            ExitCode::DiskValidation
+        }
+    }
+}
+
+fn run_repair(args: &RepairArgs) -> ExitCode {
+    if !crate::is_root() {
+        eprintln!("❌ Error: Este comando debe ejecutarse como root");
+        return ExitCode::NotRoot;
+    }
+
+    let validator = SystemValidator::new();
+    if let Err(e) = validator.validate_system() {
+        eprintln!("❌ Error de validación: {}", e);
+        return ExitCode::SystemValidation;
+    }
+    if let Err(e) = validator.validate_redox_build(&ArtifactResolver::new(args.build.location())) {
+        eprintln!("❌ Error de validación: {}", e);
+        eprintln!("   Ejecuta 'make all' para compilar Redox OS antes de continuar");
+        return ExitCode::SystemValidation;
+    }
+
+    let mut disk_manager = DiskManager::new();
+    let Some(disk) = disk_manager.list_disks().into_iter().find(|disk| disk.name == args.disk) else {
+        eprintln!("❌ Error: {} no es un disco disponible", args.disk);
+        eprintln!("   Usa 'redox-disk-installer list-disks' para ver los discos disponibles");
+        return ExitCode::DiskValidation;
+    };
+    if let Err(e) = validator.validate_disk(&disk, args.allow_system_disk) {
+        eprintln!("❌ Error validando disco: {}", e);
+        return ExitCode::DiskValidation;
+    }
+
+    if !args.yes {
+        eprintln!("❌ Esta operación sustituirá el bootloader, el kernel y la configuración de arranque de {}", disk.name);
+        eprintln!("   (no se particiona ni se formatea nada)");
+        eprintln!("   Añade --yes para confirmar la reparación sin preguntas");
+        return ExitCode::NotConfirmed;
+    }
+
+    let config = InstallationConfig {
+        secure_boot: args.signing.secure_boot().expect("validado al leer los argumentos"),
+        ..InstallationConfig::default()
+    };
+    match DirectInstaller::new().with_build(args.build.location()).repair_redox_os(&disk, &config) {
+        Ok(()) => ExitCode::Success,
+        Err(e) => {
+            eprintln!("❌ Error durante la reparación: {}", e);
+            ExitCode::InstallFailed
+        }
+    }
+}
+
+pub fn print_usage() {
+    println!("Uso: redox-disk-installer [SUBCOMANDO] [OPCIONES]");
+    println!();
+    println!("Sin subcomando se muestra el menú interactivo.");
+    println!();
+    println!("Subcomandos:");
+    println!("  install       Instala Redox OS sin preguntas");
+    println!("  list-disks    Lista los discos disponibles (nombre, tamaño, modelo, tipo, bytes,");
+    println!("                tamaño de sector, bus y número de serie)");
+    println!("  free-space --disk <DISPOSITIVO>");
+    println!("                Muestra las particiones GPT y los huecos libres del disco");
+    println!("  restore-table --backup <FICHERO> [--disk <DISPOSITIVO>] [--yes]");
+    println!("                Restaura la tabla de particiones guardada antes de instalar");
+    println!("  repair --disk <DISPOSITIVO> [--source-root DIR] [--arch ARCH] [--config-name NOMBRE] [--yes]");
+    println!("                Reinstala el bootloader, el kernel, la configuración de arranque y");
+    println!("                la entrada UEFI de una instalación existente sin formatear");
+    println!("                (admite también --allow-system-disk y las opciones de Secure Boot");
+    println!("                de install)");
+    println!("  help          Muestra esta ayuda");
+    println!();
+    println!("Opciones de install:");
+    println!("  --disk <DISPOSITIVO>   Disco de destino, p. ej. /dev/sdb");
+    println!("  --image <FICHERO>      Instala en un fichero de imagen en lugar de un disco");
+    println!("  --size <TAMAÑO>        Tamaño de la imagen a crear, p. ej. 4G (mínimo: 2G)");
+    println!("  --efi-size <MB>        Tamaño de la partición EFI en MB (por defecto: 512, mínimo: 100)");
+    println!("  --fs <redoxfs|ext4>    Sistema de archivos para root (por defecto: redoxfs)");
+    println!("  --filesystem-config <FICHERO>");
+    println!("                         Configuración de Redox a instalar en root, relativa a la raíz");
+    println!("                         del árbol (por defecto: config/<arch>/<config-name>.toml)");
+    println!("  --source-root <DIR>    Raíz del árbol de Redox compilado (por defecto: el directorio");
+    println!("                         actual o REDOX_SOURCE_ROOT)");
+    println!("  --arch <ARCH>          Arquitectura de la compilación: x86_64, aarch64, riscv64gc o");
+    println!("                         i686 (por defecto: x86_64 o ARCH)");
+    println!("  --config-name <NOMBRE> Configuración compilada: build/<arch>/<nombre> y");
+    println!("                         config/<arch>/<nombre>.toml (por defecto: desktop o CONFIG_NAME)");
+    println!("  --config <FICHERO>     Fichero de respuestas TOML (disco, EFI, sistema de archivos,");
+    println!("                         hostname, paquetes y ficheros extra)");
+    println!("  --dual-boot            Instala en el espacio libre junto al sistema existente,");
+    println!("                         reutilizando su partición EFI sin formatearla");
+    println!("  --gap <N>              Hueco libre a usar con --dual-boot (por defecto: el mayor)");
+    println!("  --upgrade              Actualiza la instalación de Redox del disco: sustituye el");
+    println!("                         bootloader, el kernel, el initfs y los paquetes sin");
+    println!("                         particionar y conservando /home, /root y /etc");
+    println!("  --redoxfs-direct       Crea y escribe el root RedoxFS con la librería redoxfs, sin");
+    println!("                         montarlo con FUSE (solo instalaciones nuevas)");
+    println!("  --boot-mode <MODO>     uefi, hybrid (UEFI y BIOS, como harddrive.img) o bios (sin");
+    println!("                         partición EFI); hybrid y bios escriben el bootloader BIOS en");
+    println!("                         el MBR y en una partición de arranque BIOS (por defecto: uefi)");
+    println!("  --boot-entry <POS>     Posición de la entrada UEFI \"Redox OS\": first (primera en");
+    println!("                         BootOrder), last (al final) o next (al final y solo en el");
+    println!("                         próximo arranque, con BootNext) (por defecto: first)");
+    println!("  --sign-key <FICHERO>   Clave RSA (PEM) con la que se firman los cargadores UEFI para");
+    println!("                         Secure Boot; el certificado se deja en EFI/redox/redox-mok.cer");
+    println!("  --sign-cert <FICHERO>  Certificado X.509 (PEM o DER) de esa clave");
+    println!("  --shim <FICHERO>       shim firmado que arranca el bootloader firmado (grubx64.efi)");
+    println!("  --mok-manager <FICHERO>");
+    println!("                         MokManager firmado para inscribir el certificado (con --shim)");
+    println!("  --restore-table-on-failure");
+    println!("                         Si la instalación falla, restaura la tabla de particiones");
+    println!("                         original del disco (el resto de cambios siempre se deshace)");
+    println!("  --backup-dir <DIR>     Dónde guardar la copia de la tabla de particiones original");
+    println!("                         (por defecto: /var/backups/redox-disk-installer)");
+    println!("  --allow-system-disk    Permite instalar en un disco que usa el sistema en ejecución");
+    println!("                         (/, /boot, swap, LVM, dm-crypt o RAID md)");
+    println!("  --dry-run              Muestra lo que se haría (tabla GPT, comandos, ficheros y");
+    println!("                         configuración) sin modificar nada; no requiere root");
+    println!("  --json                 Con --dry-run, escribe el plan en JSON en la salida estándar");
+    println!("  -y, --yes              Confirma el borrado del disco o de la imagen sin preguntar");
+    println!();
+    println!("Herramientas de RedoxFS: REDOXFS_MKFS y REDOXFS_MOUNT indican su ruta; si no, se");
+    println!("buscan en build/fstools/bin y redoxfs/target/release del árbol y después en PATH.");
+    println!();
+    println!("Códigos de salida:");
+    println!("  0  Éxito");
+    println!("  2  Argumentos inválidos");
+    println!("  3  No se ejecuta como root");
+    println!("  4  Validación del sistema o de la compilación de Redox fallida");
+    println!("  5  Disco inválido, inexistente o demasiado pequeño");
+    println!("  6  Error durante la instalación o la reparación");
+    println!("  7  Instalación no confirmada (falta --yes)");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<CliCommand, String> {
+        let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+        parse_args(&args)
+    }
+
+    fn install(args: &str) -> InstallArgs {
+        match parse(&format!("install {}", args)) {
+            Ok(CliCommand::Install(install_args)) => install_args,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).unwrap_err()
+    }
+
+    #[test]
+    fn help_is_only_requested_in_flag_position() {
+        for args in ["-h", "--help", "install --disk /dev/sdb -h", "list-disks -h", "repair --help", "free-space --help", "restore-table -h"] {
+            assert!(matches!(parse(args), Ok(CliCommand::Help)), "{}", args);
+        }
+        assert!(matches!(parse(""), Ok(CliCommand::Menu)));
+
+        // Como valor de otra opción es solo un valor
+        assert_eq!(install("--disk -h --yes").disk.as_deref(), Some("-h"));
+        assert_eq!(install("--disk=--help").disk.as_deref(), Some("--help"));
+        assert_eq!(install("--backup-dir=--help --disk /dev/sdb").backup_dir.as_deref(), Some("--help"));
+    }
+
+    #[test]
+    fn install_accepts_separate_and_inline_values() {
+        let args = install("--disk=/dev/sdb --efi-size 512 --fs ext4 --config=answers.toml --boot-mode=hybrid --boot-entry next -y");
+
+        assert_eq!(args.disk.as_deref(), Some("/dev/sdb"));
+        assert_eq!(args.efi_size_mb, Some(512));
+        assert!(matches!(args.filesystem_type, Some(FilesystemType::Ext4)));
+        assert_eq!(args.config.as_deref(), Some("answers.toml"));
+        assert_eq!(args.boot_mode, Some(BootMode::Hybrid));
+        assert_eq!(args.boot_priority, Some(BootPriority::Next));
+        assert!(args.yes);
+    }
+
+    #[test]
+    fn image_plan_can_be_requested() {
+        let args = install("--image redox.img --size 4G --dry-run --json");
+
+        assert_eq!(args.image.as_deref(), Some("redox.img"));
+        assert_eq!(args.image_size, Some(4 * 1024 * 1024 * 1024));
+        assert!(args.dry_run && args.json);
+    }
+
+    #[test]
+    fn install_rejects_invalid_values_and_unknown_options() {
+        assert!(error("install --efi-size 50").starts_with("Tamaño EFI inválido"));
+        assert!(error("install --fs btrfs").starts_with("Sistema de archivos inválido"));
+        assert_eq!(error("install --disk"), "Falta el valor para --disk");
+        assert_eq!(error("install --label x"), "Opción desconocida para install: --label");
+        assert_eq!(error("frobnicate"), "Subcomando desconocido: frobnicate");
+        assert_eq!(error("list-disks --all"), "Argumento inesperado para list-disks: --all");
+    }
+
+    #[test]
+    fn install_rejects_incompatible_options() {
+        let cases = [
+            ("--disk /dev/sdb --image redox.img", "--disk y --image son incompatibles"),
+            ("--disk /dev/sdb --size 2G", "--size solo es válido junto con --image"),
+            ("--image redox.img --dual-boot", "--dual-boot no es compatible con --image"),
+            ("--disk /dev/sdb --upgrade --dual-boot", "--upgrade no es compatible con --dual-boot"),
+            ("--disk /dev/sdb --redoxfs-direct --upgrade", "--redoxfs-direct no es compatible con --upgrade"),
+            ("--disk /dev/sdb --gap 2", "--gap solo es válido junto con --dual-boot"),
+            ("--disk /dev/sdb --json", "--json solo es válido junto con --dry-run"),
+            ("--disk /dev/sdb --sign-key a.key", "--sign-key y --sign-cert se indican juntos"),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(error(&format!("install {}", args)), expected, "{}", args);
+        }
+        assert!(error("install --disk /dev/sdb --dual-boot --boot-mode bios").starts_with("--boot-mode hybrid/bios"));
+        assert!(error("install --disk /dev/sdb --sign-key a.key --sign-cert a.crt --boot-mode bios")
+            .starts_with("--sign-key no es compatible con --boot-mode bios"));
+    }
+
+    #[test]
+    fn repair_and_restore_table_need_their_target() {
+        assert!(error("repair --yes").starts_with("Uso: redox-disk-installer repair"));
+        assert!(error("restore-table --disk /dev/sdb").starts_with("Uso: redox-disk-installer restore-table"));
+
+        match parse("repair --disk /dev/sdb --arch aarch64 --allow-system-disk -y") {
+            Ok(CliCommand::Repair(args)) => {
+                assert_eq!(args.disk, "/dev/sdb");
+                assert_eq!(args.build.arch.as_deref(), Some("aarch64"));
+                assert!(args.allow_system_disk && args.yes);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        match parse("restore-table --backup=sdb.gpt -y") {
+            Ok(CliCommand::RestoreTable(args)) => {
+                assert_eq!(args.backup, "sdb.gpt");
+                assert!(args.yes);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn filesystem_config_from_the_answer_file_is_kept() {
+        let args = install("--disk /dev/sdb --arch aarch64 --config-name server");
+        let location = args.build.location();
+        // Aunque sea la ruta por defecto, la indicó el fichero de respuestas
+        let answers = AnswerFile::parse("[general]\nfilesystem_config = \"config/x86_64/desktop.toml\"\n").unwrap();
+
+        assert_eq!(filesystem_config(&args, Some(&answers), &location), "config/x86_64/desktop.toml");
+        assert_eq!(filesystem_config(&args, Some(&AnswerFile::parse("").unwrap()), &location), "config/aarch64/server.toml");
+        assert_eq!(filesystem_config(&args, None, &location), "config/aarch64/server.toml");
+
+        let args = install("--disk /dev/sdb --filesystem-config mine.toml");
+        assert_eq!(filesystem_config(&args, Some(&answers), &location), "mine.toml");
+    }
+
+    #[test]
+    fn free_space_needs_the_disk() {
+        match parse("free-space --disk=/dev/sdb") {
+            Ok(CliCommand::FreeSpace(disk)) => assert_eq!(disk, "/dev/sdb"),
+            other => panic!("{:?}", other),
+        }
+        assert!(error("free-space").starts_with("Uso: redox-disk-installer free-space"));
+    }
+}
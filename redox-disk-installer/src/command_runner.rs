@@ -0,0 +1,162 @@
+use std::io;
+use std::process::{Child, Command, Output, Stdio};
+use std::time::Duration;
+
+// Capa de ejecución de comandos externos (mkfs.vfat, mount, losetup...).
+// DirectInstaller, SystemValidator y LoopDevice la reciben inyectada para
+// poder probarlos sin root ni discos reales.
+pub trait CommandRunner {
+    // Ejecuta el programa y espera a que termine
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+
+    // Lanza el programa en segundo plano (p. ej. el daemon FUSE de redoxfs)
+    // con su stderr en una tubería para poder mostrarlo si falla
+    fn spawn(&self, program: &str, args: &[&str]) -> io::Result<Child>;
+
+    // Pausa para dar tiempo al kernel a procesar cambios en el disco
+    fn wait(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+// Implementación real basada en std::process::Command
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+
+    fn spawn(&self, program: &str, args: &[&str]) -> io::Result<Child> {
+        Command::new(program).args(args).stderr(Stdio::piped()).spawn()
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeRunner;
+
+#[cfg(test)]
+mod fake {
+    use std::cell::RefCell;
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Child, Command, ExitStatus, Output, Stdio};
+    use std::time::Duration;
+
+    use super::CommandRunner;
+
+    enum Response {
+        Exit { code: i32, stdout: String, stderr: String },
+        Error(io::ErrorKind),
+    }
+
+    // Simula lo que el programa cambia fuera del proceso (p. ej. la NVRAM);
+    // recibe los argumentos
+    type Effect = Box<dyn Fn(&[&str])>;
+
+    // Doble de pruebas: registra cada línea de comando ejecutada y responde
+    // según un guion de prefijos. Sin guion, todo comando termina con éxito
+    // y sin salida.
+    #[derive(Default)]
+    pub struct FakeRunner {
+        calls: RefCell<Vec<String>>,
+        responses: Vec<(String, Response)>,
+        effects: Vec<(String, Effect)>,
+    }
+
+    impl FakeRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        // Responde a los comandos que empiezan por `prefix`. El primer prefijo
+        // registrado que coincida es el que se usa.
+        pub fn respond(mut self, prefix: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+            self.responses.push((
+                prefix.to_string(),
+                Response::Exit {
+                    code,
+                    stdout: stdout.to_string(),
+                    stderr: stderr.to_string(),
+                },
+            ));
+            self
+        }
+
+        // Simula que el programa no se pudo ejecutar (p. ej. no instalado)
+        pub fn fail_to_start(mut self, prefix: &str) -> Self {
+            self.responses
+                .push((prefix.to_string(), Response::Error(io::ErrorKind::NotFound)));
+            self
+        }
+
+        // Ejecuta `effect` con los argumentos de cada comando que empiece por
+        // `prefix` y termine con éxito (también los lanzados con spawn)
+        pub fn on(mut self, prefix: &str, effect: impl Fn(&[&str]) + 'static) -> Self {
+            self.effects.push((prefix.to_string(), Box::new(effect)));
+            self
+        }
+
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+
+        fn record(&self, program: &str, args: &[&str]) -> Option<&Response> {
+            let line = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let response = self
+                .responses
+                .iter()
+                .find(|(prefix, _)| line.starts_with(prefix.as_str()))
+                .map(|(_, response)| response);
+            // Sin respuesta en el guion el comando termina con éxito
+            if response.is_none() {
+                if let Some((_, effect)) = self.effects.iter().find(|(prefix, _)| line.starts_with(prefix.as_str())) {
+                    effect(args);
+                }
+            }
+            self.calls.borrow_mut().push(line);
+            response
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+            match self.record(program, args) {
+                Some(Response::Exit { code, stdout, stderr }) => Ok(Output {
+                    status: ExitStatus::from_raw(code << 8),
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: stderr.as_bytes().to_vec(),
+                }),
+                Some(Response::Error(kind)) => Err(io::Error::new(*kind, format!("{}: simulado", program))),
+                None => Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }),
+            }
+        }
+
+        fn spawn(&self, program: &str, args: &[&str]) -> io::Result<Child> {
+            // Un proceso real e inofensivo para que el llamante tenga un Child
+            // que termina con el código y el stderr del guion
+            let (code, stderr) = match self.record(program, args) {
+                Some(Response::Error(kind)) => return Err(io::Error::new(*kind, format!("{}: simulado", program))),
+                Some(Response::Exit { code, stderr, .. }) => (*code, stderr.as_str()),
+                None => (0, ""),
+            };
+            Command::new("sh")
+                .args(["-c", "printf '%s' \"$1\" >&2; exit \"$2\"", "sh", stderr, &code.to_string()])
+                .stderr(Stdio::piped())
+                .spawn()
+        }
+
+        // Sin las pausas reales, pero dejando avanzar a los procesos lanzados
+        // con spawn
+        fn wait(&self, _duration: Duration) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
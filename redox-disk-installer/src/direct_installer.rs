@@ -1,27 +1,130 @@
+use std::cell::RefCell;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
-use crate::{DiskInfo, InstallationConfig, FilesystemType};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::rc::Rc;
+use std::time::Duration;
+use crate::artifacts::{Artifact, ArtifactResolver, BuildLocation};
+use crate::authenticode::{self, Certificate, SigningKey};
+use crate::bios_boot;
+use crate::boot_entries::{self, BootManager, UefiFirmware};
+use crate::build_config::BuildConfig;
+use crate::command_runner::{CommandRunner, SystemRunner};
+use crate::disk_inspect::{self, InspectedPartition};
+use crate::fs_writer::{FilesystemWriter, MountedWriter, RedoxFsWriter};
+use crate::gpt::{self, GptTable};
+use crate::install_plan::{InstallPlan, PlannedAction};
+use crate::mounts;
+use crate::partition_layout;
+use crate::redoxfs_mount::RedoxFsMount;
+use crate::rollback::{self, InterruptGuard, UndoAction, UndoStack};
+use crate::sysfs;
+use crate::table_backup::TableBackup;
+use crate::target_arch;
+use crate::{
+    BootMode, DiskInfo, FileConfig, FilesystemType, InstallationConfig, PartitionFilesystem, PartitionSize, PartitionSpec,
+    SecureBootConfig,
+};
+
+// Fichero que el instalador deja en root para marcar una instalación suya
+const REDOX_BOOT_MARKER: &str = "/boot/.redox_boot";
+
+// Etiqueta de la entrada de arranque UEFI que crea el instalador
+const BOOT_ENTRY_LABEL: &str = "Redox OS";
+// Cargador al que apunta esa entrada, como ruta UEFI dentro de la ESP
+const BOOT_ENTRY_LOADER: &str = "\\EFI\\redox\\redox-bootloader.efi";
+// Bootloader de Redox dentro de la ESP: identifica una ESP compartida
+const REDOX_BOOTLOADER: &str = "EFI/redox/redox-bootloader.efi";
+// Certificado de firma que se deja en la ESP para inscribirlo como MOK
+const MOK_CERTIFICATE: &str = "EFI/redox/redox-mok.cer";
+
+// Tiempo máximo que se espera a que el montaje de redoxfs aparezca
+const REDOXFS_MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Directorios de root cuyos ficheros existentes no se sobrescriben al
+// actualizar: la configuración y los datos de los usuarios
+const PRESERVED_ON_UPGRADE: [&str; 3] = ["/etc", "/home", "/root"];
+
+// Sistema de archivos de destino de las escrituras de la instalación
+#[derive(Clone, Copy)]
+enum Volume {
+    Esp,
+    Root,
+}
 
-// Rutas a las herramientas de RedoxFS
-const REDOXFS_MKFS: &str = "/home/moebius/redox/redoxfs/target/release/redoxfs-mkfs";
-const REDOXFS_MOUNT: &str = "/home/moebius/redox/redoxfs/target/release/redoxfs";
+// Partición de la distribución junto con su número en la tabla GPT
+struct PlacedPartition {
+    number: usize,
+    // Nodo en /dev de la partición, tal como lo nombra el kernel
+    device: String,
+    spec: PartitionSpec,
+    // Partición existente que se reutiliza sin formatear (ESP en dual-boot)
+    reused: bool,
+}
 
 pub struct DirectInstaller {
+    runner: Rc<dyn CommandRunner>,
     efi_mount_point: String,
     root_mount_point: String,
-    redoxfs_uuid: Option<String>,
+    // Dónde se buscan el bootloader, el kernel, los paquetes y las
+    // herramientas de RedoxFS
+    artifacts: ArtifactResolver,
+    // Dónde se buscan las particiones del disco y los montajes activos
+    sys_block: PathBuf,
+    mountinfo: PathBuf,
+    // /sys/firmware/efi: modo de arranque del host y entradas de la NVRAM
+    firmware: UefiFirmware,
+    // UUID que devuelve redoxfs-mkfs al formatear la partición root
+    redoxfs_uuid: RefCell<Option<String>>,
+    // Con --redoxfs-direct, partición root que se escribe con la librería
+    // redoxfs sin montarla, y su sistema de archivos una vez creado
+    direct_root: RefCell<Option<String>>,
+    root_writer: RefCell<Option<Rc<dyn FilesystemWriter>>>,
+    // Solo en --dry-run: acciones registradas en lugar de ejecutadas
+    plan: RefCell<Option<InstallPlan>>,
+    // Cómo deshacer los pasos ya completados si la instalación falla
+    undo: UndoStack,
 }
 
 impl DirectInstaller {
     pub fn new() -> Self {
+        Self::with_runner(Rc::new(SystemRunner))
+    }
+
+    pub fn with_runner(runner: Rc<dyn CommandRunner>) -> Self {
         Self {
+            runner: runner.clone(),
             efi_mount_point: "/tmp/redox_install_efi".to_string(),
             root_mount_point: "/tmp/redox_install_root".to_string(),
-            redoxfs_uuid: None,
+            artifacts: ArtifactResolver::new(BuildLocation::from_env()),
+            sys_block: PathBuf::from("/sys/block"),
+            mountinfo: PathBuf::from("/proc/self/mountinfo"),
+            firmware: UefiFirmware::new("/sys/firmware/efi"),
+            redoxfs_uuid: RefCell::new(None),
+            direct_root: RefCell::new(None),
+            root_writer: RefCell::new(None),
+            plan: RefCell::new(None),
+            undo: UndoStack::new(runner.clone()),
         }
     }
 
+    // Árbol de Redox, arquitectura y configuración de los que instalar
+    pub fn with_build(mut self, location: BuildLocation) -> Self {
+        self.artifacts = ArtifactResolver::new(location);
+        self
+    }
+
+    // Recorre la instalación completa sin modificar el disco ni el sistema y
+    // devuelve las acciones que se ejecutarían
+    pub fn plan_redox_os(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<InstallPlan, String> {
+        *self.plan.borrow_mut() = Some(InstallPlan::new(&disk.name));
+        let result = self.install_redox_os(disk, config);
+        let plan = self.plan.borrow_mut().take().expect("el plan se creó al empezar");
+        result.map(|_| plan)
+    }
+
     pub fn install_redox_os(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
         println!();
         println!("╔═══════════════════════════════════════════════════╗");
@@ -29,71 +132,515 @@ impl DirectInstaller {
         println!("╚═══════════════════════════════════════════════════╝");
         println!();
 
+        // Se actualiza el arranque con el que se instaló, no el de la configuración
+        let detected;
+        let config = if config.upgrade {
+            let boot_mode = self.detect_boot_mode(disk)
+                .map_err(|e| format!("{} no tiene una instalación de Redox OS: {}", disk.name, e))?;
+            println!("🔍 Arranque detectado: {}", boot_mode.describe());
+            detected = InstallationConfig { boot_mode, ..config.clone() };
+            &detected
+        } else {
+            config
+        };
+
         // Mostrar resumen de configuración
         println!("📋 Configuración de instalación:");
         println!("   Disco:            {}", disk.name);
-        println!("   Tamaño disco:     {}", disk.size);
-        println!("   Partición EFI:    {} MB", config.efi_size_mb);
-        println!("   Sistema archivos: {:?}", config.filesystem_type);
+        println!("   Tamaño disco:     {} ({} bytes)", disk.size_human(), disk.size_bytes);
+        println!("   Configuración:    {}", config.filesystem_config);
+        let layout = config.partition_layout();
+        if config.upgrade {
+            println!("   Modo actualización: se conservan las particiones, /home, /root y /etc");
+        } else {
+            println!("   Particiones:");
+            for (i, spec) in layout.iter().enumerate() {
+                println!("     {}. {}", i + 1, Self::describe_partition(spec));
+            }
+        }
+        if config.dual_boot {
+            println!("   Modo dual-boot: se usa el espacio libre y la partición EFI existente");
+        }
+        if !config.upgrade {
+            println!("   Arranque:         {}", config.boot_mode.describe());
+        }
         println!();
 
-        // Verificar disco
-        self.verify_disk(disk)?;
+        // Leer la configuración y validar la distribución antes de tocar el
+        // disco para fallar pronto
+        partition_layout::validate_layout(&layout, config.boot_mode)?;
+        if config.dual_boot && !config.partitions.is_empty() {
+            return Err("El modo dual-boot no admite una distribución de particiones personalizada".to_string());
+        }
+        if config.upgrade && (config.dual_boot || !config.partitions.is_empty()) {
+            return Err("La actualización no admite dual-boot ni una distribución de particiones".to_string());
+        }
+        if config.boot_mode.bios() {
+            if config.dual_boot {
+                return Err("El arranque BIOS no admite dual-boot: el MBR pertenece a la instalación existente".to_string());
+            }
+            if disk.logical_sector_size != 512 {
+                return Err(format!(
+                    "El arranque BIOS necesita sectores de 512 bytes ({} usa {})",
+                    disk.name, disk.logical_sector_size
+                ));
+            }
+        }
+        if let Some(secure_boot) = &config.secure_boot {
+            if !config.boot_mode.uefi() {
+                return Err("Secure Boot solo se aplica al arranque UEFI".to_string());
+            }
+            secure_boot.validate()?;
+        }
+        if config.redoxfs_direct {
+            // Sin montar root no se puede montar nada dentro de ella
+            let root_is_redoxfs = layout.iter().any(|spec| {
+                spec.mount_point.as_deref() == Some("/") && spec.filesystem == PartitionFilesystem::RedoxFS
+            });
+            if config.upgrade || !root_is_redoxfs || !partition_layout::extra_mounts(&layout).is_empty() {
+                return Err(
+                    "--redoxfs-direct solo se aplica a una instalación nueva con root en RedoxFS y sin otras particiones montadas dentro"
+                        .to_string(),
+                );
+            }
+        }
+        self.check_bootloaders(config.boot_mode, config.secure_boot.as_ref())?;
+        let filesystem_config = self.artifacts.location().resolve(&config.filesystem_config);
+        let build_config = BuildConfig::load(&filesystem_config.to_string_lossy())?;
+
+        // Ctrl-C deja de matar el proceso: se deshace lo hecho hasta ese punto
+        let _interrupts = InterruptGuard::install()?;
+        let steps = if config.upgrade {
+            self.run_upgrade_steps(disk, config, &build_config)
+        } else {
+            self.run_installation_steps(disk, config, &layout, &build_config)
+        };
+        let placed = match steps {
+            Ok(placed) => placed,
+            Err(e) => {
+                self.undo.rollback();
+                return Err(e);
+            }
+        };
+        self.undo.commit();
+
+        // Resumen final
+        self.print_installation_summary(disk, config, &placed)?;
 
-        // Desmontar particiones existentes
+        Ok(())
+    }
+
+    // Vuelve a instalar el bootloader, el kernel y la configuración de
+    // arranque de una instalación existente sin particionar ni formatear
+    pub fn repair_redox_os(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
+        println!();
+        println!("╔═══════════════════════════════════════════════════╗");
+        println!("║       🛠️  Reparación del arranque de Redox OS      ║");
+        println!("╚═══════════════════════════════════════════════════╝");
+        println!();
+
+        // Se repara el arranque con el que se instaló, no el de la configuración
+        let boot_mode = self.detect_boot_mode(disk)?;
+        println!("🔍 Arranque detectado: {}", boot_mode.describe());
+        if config.secure_boot.is_some() && !boot_mode.uefi() {
+            return Err("Secure Boot solo se aplica al arranque UEFI".to_string());
+        }
+        let config = &InstallationConfig { boot_mode, ..config.clone() };
+        self.check_bootloaders(boot_mode, config.secure_boot.as_ref())?;
+        let _interrupts = InterruptGuard::install()?;
+        if let Err(e) = self.run_repair_steps(disk, config) {
+            self.undo.rollback();
+            return Err(e);
+        }
+        self.undo.commit();
+
+        println!("✅ Arranque de Redox OS reparado en {}", disk.name);
+        Ok(())
+    }
+
+    fn run_repair_steps(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
+        println!("🔍 [1/6] Buscando la instalación existente...");
+        self.verify_disk(disk)?;
+        let (placed, shared_esp) = self.find_existing_installation(disk, config.boot_mode)?;
         self.unmount_existing_partitions(disk)?;
+        // Una ESP compartida se trata como en dual-boot: EFI/BOOT y la raíz
+        // de la ESP son del otro sistema
+        let config = &InstallationConfig { dual_boot: shared_esp, ..config.clone() };
+        println!("   ✅ Instalación encontrada");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("📁 [2/6] Montando particiones...");
+        self.mount_partitions(&placed)?;
+        self.check_installer_marker()?;
+        println!("   ✅ Particiones montadas");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("⚙️  [3/6] Reinstalando bootloader {}...", config.boot_mode.describe());
+        if config.boot_mode.uefi() {
+            self.install_bootloader(disk, config, &placed)?;
+        }
+        if config.boot_mode.bios() {
+            self.install_bios_bootloader(disk)?;
+        }
+        println!("   ✅ Bootloader reinstalado");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("🔧 [4/6] Reinstalando kernel de Redox...");
+        self.install_kernel(disk)?;
+        println!("   ✅ Kernel reinstalado");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("⚙️  [5/6] Regenerando configuración de arranque...");
+        self.create_config_files(config, &placed)?;
+        self.verify_boot_files(disk, config)?;
+        println!("   ✅ Configuración regenerada");
+        println!();
 
-        // Crear particiones
-        println!("📦 [1/8] Creando particiones...");
-        self.create_partitions(disk, config)?;
+        println!("🔓 [6/6] Desmontando particiones...");
+        self.unmount_partitions(&placed)?;
+        println!("   ✅ Particiones desmontadas");
+        println!();
+
+        Ok(())
+    }
+
+    // Comprueba que el firmware y el bootloader encontrarán lo que necesitan
+    fn verify_boot_files(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
+        let loader = self.removable_loader()?;
+        let removable_loader = format!("EFI/BOOT/{}", loader);
+        let mut required = vec![(Volume::Root, "boot/kernel"), (Volume::Root, "boot/redox.conf")];
+        if config.boot_mode.uefi() {
+            required.extend([
+                (Volume::Esp, removable_loader.as_str()),
+                (Volume::Esp, REDOX_BOOTLOADER),
+                (Volume::Esp, "boot/redox.conf"),
+            ]);
+        }
+        for (volume, path) in required {
+            let size = self.writer(volume).read_file(Path::new(path)).map(|contents| contents.len()).unwrap_or(0);
+            if size == 0 {
+                return Err(format!("Verificación fallida: falta {} o está vacío", self.volume_path(volume, path)));
+            }
+        }
+        // EFI/BOOT puede tener shim o el cargador de otro sistema: solo se
+        // comprueba la firma del bootloader de Redox
+        if let Some(secure_boot) = config.secure_boot.as_ref().filter(|_| config.boot_mode.uefi()) {
+            let certificate = Certificate::load(Path::new(&secure_boot.certificate))?;
+            let redox_loader = self.volume_path(Volume::Esp, REDOX_BOOTLOADER);
+            let image = self.writer(Volume::Esp).read_file(Path::new(REDOX_BOOTLOADER))
+                .map_err(|e| format!("Error leyendo {}: {}", redox_loader, e))?;
+            authenticode::verify(&image, &certificate)
+                .map_err(|e| format!("Verificación fallida: {}: {}", redox_loader, e))?;
+        }
+        println!("   ✅ Ficheros de arranque verificados");
+        if !config.boot_mode.uefi() {
+            return Ok(());
+        }
+
+        let manager = BootManager::new(self.runner.as_ref(), &self.firmware);
+        let has_entry = manager.labelled(BOOT_ENTRY_LABEL).is_ok_and(|entries| !entries.is_empty());
+        if !disk.name.starts_with("/dev/loop") && !has_entry {
+            println!("   ⚠️  No hay entrada de arranque \"{}\" en la NVRAM;", BOOT_ENTRY_LABEL);
+            println!("      el firmware usará EFI/BOOT/{}", loader);
+        }
+        Ok(())
+    }
+
+    fn run_installation_steps(
+        &self,
+        disk: &DiskInfo,
+        config: &InstallationConfig,
+        layout: &[PartitionSpec],
+        build_config: &BuildConfig,
+    ) -> Result<Vec<PlacedPartition>, String> {
+        let placed = if config.dual_boot {
+            // Las particiones del otro sistema pueden estar en uso: no se
+            // desmonta nada del disco
+            println!("📦 [1/8] Creando particiones en el espacio libre...");
+            self.plan_step("Crear particiones en el espacio libre");
+            self.create_partitions_in_free_space(disk, config, layout)?
+        } else {
+            // Verificar disco
+            self.plan_step("Preparar disco");
+            self.verify_disk(disk)?;
+
+            // Desmontar particiones existentes
+            self.unmount_existing_partitions(disk)?;
+
+            // Crear particiones
+            self.check_interrupted()?;
+            println!("📦 [1/8] Creando particiones...");
+            self.plan_step("Crear particiones");
+            self.create_partitions(disk, config, layout)?
+        };
         println!("   ✅ Particiones creadas");
         println!();
 
+        self.check_interrupted()?;
+
         // Formatear particiones
         println!("💾 [2/8] Formateando particiones...");
-        self.format_partitions(disk, config)?;
+        self.plan_step("Formatear particiones");
+        self.format_partitions(&placed, config.redoxfs_direct)?;
         println!("   ✅ Particiones formateadas");
         println!();
 
+        self.check_interrupted()?;
+
         // Montar particiones
         println!("📁 [3/8] Montando particiones...");
-        self.mount_partitions(disk)?;
+        self.plan_step("Montar particiones");
+        self.mount_partitions(&placed)?;
         println!("   ✅ Particiones montadas");
         println!();
 
+        self.check_interrupted()?;
+
         // Instalar bootloader
-        println!("⚙️  [4/8] Instalando bootloader UEFI...");
-        self.install_bootloader(disk)?;
+        println!("⚙️  [4/8] Instalando bootloader {}...", config.boot_mode.describe());
+        self.plan_step("Instalar bootloader");
+        if config.boot_mode.uefi() {
+            self.install_bootloader(disk, config, &placed)?;
+        }
+        if config.boot_mode.bios() {
+            self.install_bios_bootloader(disk)?;
+        }
         println!("   ✅ Bootloader instalado");
         println!();
 
+        self.check_interrupted()?;
+
         // Instalar sistema de archivos (crear directorios primero)
         println!("📂 [5/8] Instalando sistema de archivos...");
-        self.install_filesystem(disk)?;
+        self.plan_step("Instalar sistema de archivos");
+        self.install_filesystem(disk, config, build_config)?;
         println!("   ✅ Sistema de archivos instalado");
         println!();
 
+        self.check_interrupted()?;
+
         // Instalar kernel (después de crear directorios)
         println!("🔧 [6/8] Instalando kernel de Redox...");
+        self.plan_step("Instalar kernel");
         self.install_kernel(disk)?;
         println!("   ✅ Kernel instalado");
         println!();
 
+        self.check_interrupted()?;
+
         // Crear configuración
         println!("⚙️  [7/8] Creando configuración de arranque...");
-        self.create_config_files(disk)?;
+        self.plan_step("Crear configuración de arranque");
+        self.create_config_files(config, &placed)?;
         println!("   ✅ Configuración creada");
         println!();
 
+        self.check_interrupted()?;
+
         // Desmontar particiones
         println!("🔓 [8/8] Desmontando particiones...");
-        self.unmount_partitions(disk)?;
+        self.plan_step("Desmontar particiones");
+        self.unmount_partitions(&placed)?;
         println!("   ✅ Particiones desmontadas");
         println!();
 
-        // Resumen final
-        self.print_installation_summary(disk, config)?;
+        Ok(placed)
+    }
+
+    // Sustituye el bootloader, el kernel, el initfs y los paquetes de una
+    // instalación existente sin particionar ni formatear
+    fn run_upgrade_steps(
+        &self,
+        disk: &DiskInfo,
+        config: &InstallationConfig,
+        build_config: &BuildConfig,
+    ) -> Result<Vec<PlacedPartition>, String> {
+        println!("🔍 [1/6] Buscando la instalación existente...");
+        self.plan_step("Buscar instalación existente");
+        self.verify_disk(disk)?;
+        let (placed, shared_esp) = self.find_existing_installation(disk, config.boot_mode)?;
+        self.unmount_existing_partitions(disk)?;
+        // Una ESP compartida se trata como en dual-boot: EFI/BOOT y la raíz
+        // de la ESP son del otro sistema
+        let config = &InstallationConfig { dual_boot: shared_esp, ..config.clone() };
+        println!("   ✅ Instalación encontrada");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("📁 [2/6] Montando particiones...");
+        self.plan_step("Montar particiones");
+        self.mount_partitions(&placed)?;
+        self.check_installer_marker()?;
+        println!("   ✅ Particiones montadas");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("⚙️  [3/6] Actualizando bootloader {}...", config.boot_mode.describe());
+        self.plan_step("Actualizar bootloader");
+        if config.boot_mode.uefi() {
+            self.install_bootloader(disk, config, &placed)?;
+        }
+        if config.boot_mode.bios() {
+            self.install_bios_bootloader(disk)?;
+        }
+        println!("   ✅ Bootloader actualizado");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("🔧 [4/6] Actualizando kernel de Redox...");
+        self.plan_step("Actualizar kernel");
+        self.install_kernel(disk)?;
+        println!("   ✅ Kernel actualizado");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("📦 [5/6] Actualizando paquetes...");
+        self.plan_step("Actualizar paquetes");
+        println!("   Se conservan los ficheros existentes en {}", PRESERVED_ON_UPGRADE.join(", "));
+        self.install_redox_applications(&build_config.packages, &config.packages, &PRESERVED_ON_UPGRADE)?;
+        println!("   ✅ Paquetes actualizados");
+        println!();
+
+        self.check_interrupted()?;
+
+        println!("🔓 [6/6] Desmontando particiones...");
+        self.plan_step("Desmontar particiones");
+        self.unmount_partitions(&placed)?;
+        println!("   ✅ Particiones desmontadas");
+        println!();
+
+        Ok(placed)
+    }
+
+    // ESP y partición RedoxFS de una instalación anterior (sin ESP en el
+    // arranque solo BIOS). Devuelve también si la ESP es compartida con otro
+    // sistema (una instalación dual-boot)
+    fn find_existing_installation(&self, disk: &DiskInfo, boot_mode: BootMode) -> Result<(Vec<PlacedPartition>, bool), String> {
+        let mounts = mounts::read_mountinfo(&self.mountinfo)?;
+        let inspection = disk_inspect::inspect(disk, &self.sys_block, &mounts)?;
+        let missing = || format!("{} no tiene una instalación de Redox OS (partición RedoxFS y partición EFI con {})", disk.name, REDOX_BOOTLOADER);
+        let root = inspection.redox_root().ok_or_else(missing)?;
+        let esp = if boot_mode.uefi() {
+            Some(self.find_redox_esp(&inspection.efi_partitions())?.ok_or_else(missing)?)
+        } else {
+            None
+        };
+        let shared_esp = esp.is_some_and(|esp| !disk_inspect::is_redox_esp(esp));
+
+        let [esp_spec, root_spec]: [PartitionSpec; 2] = partition_layout::default_layout(0, &FilesystemType::RedoxFS)
+            .try_into()
+            .expect("la distribución por defecto tiene ESP y root");
+        let placed: Vec<PlacedPartition> = esp
+            .map(|esp| (esp.number, esp_spec))
+            .into_iter()
+            .chain([(root, root_spec)])
+            .map(|(number, spec)| {
+                let partition = inspection
+                    .partitions
+                    .iter()
+                    .find(|partition| partition.number == number)
+                    .expect("la partición viene de la inspección");
+                PlacedPartition {
+                    number,
+                    device: partition.device.clone(),
+                    spec: PartitionSpec {
+                        size: PartitionSize::Mebibytes(partition.bytes / 1024 / 1024),
+                        ..spec
+                    },
+                    reused: true,
+                }
+            })
+            .collect();
+
+        if let Some(esp) = Self::efi_partition(&placed) {
+            println!("   Partición EFI:  {}", esp.device);
+        }
+        if shared_esp {
+            println!("      (compartida con otro sistema: se trata como en dual-boot)");
+        }
+        println!("   Partición root: {}", Self::root_partition(&placed).device);
+        Ok((placed, shared_esp))
+    }
+
+    // Modo de arranque de una instalación existente: con partición de
+    // arranque BIOS es hybrid o bios según tenga o no partición EFI
+    fn detect_boot_mode(&self, disk: &DiskInfo) -> Result<BootMode, String> {
+        let (file, disk_size, sector_size) = gpt::open_disk_readonly(&disk.name)?;
+        let table = GptTable::read_from(&file, disk_size, sector_size)?;
+        let has_esp = table.partitions.iter().any(|partition| partition.type_guid == gpt::EFI_SYSTEM_PARTITION);
+        Ok(match (bios_boot::boot_partition(&table).is_some(), has_esp) {
+            (false, _) => BootMode::Uefi,
+            (true, true) => BootMode::Hybrid,
+            (true, false) => BootMode::Bios,
+        })
+    }
+
+    // La ESP REDOX_EFI la creó el instalador; cualquier otra solo es de Redox
+    // si contiene su bootloader, lo que se comprueba montándola en solo lectura
+    fn find_redox_esp<'a>(&self, esps: &[&'a InspectedPartition]) -> Result<Option<&'a InspectedPartition>, String> {
+        let Some(first) = esps.first() else {
+            return Ok(None);
+        };
+        // En --dry-run no se monta nada: se supone la más probable
+        if disk_inspect::is_redox_esp(first) || self.dry_run() {
+            return Ok(Some(first));
+        }
+
+        for esp in esps {
+            if let Some(mount_point) = esp.mount_points.first() {
+                if Path::new(mount_point).join(REDOX_BOOTLOADER).exists() {
+                    return Ok(Some(esp));
+                }
+                continue;
+            }
+
+            fs::create_dir_all(&self.efi_mount_point)
+                .map_err(|e| format!("Error creando {}: {}", self.efi_mount_point, e))?;
+            let output = self.runner.run("mount", &["-o", "ro", &esp.device, &self.efi_mount_point])
+                .map_err(|e| format!("Error ejecutando mount: {}", e))?;
+            if !output.status.success() {
+                continue;
+            }
+            let found = Path::new(&self.efi_mount_point).join(REDOX_BOOTLOADER).exists();
+            let output = self.runner.run("umount", &[&self.efi_mount_point])
+                .map_err(|e| format!("Error ejecutando umount: {}", e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "No se pudo desmontar {}: {}",
+                    self.efi_mount_point,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            if found {
+                return Ok(Some(esp));
+            }
+        }
+        Ok(None)
+    }
 
+    // Solo se actualiza o repara un root que haya creado este instalador
+    fn check_installer_marker(&self) -> Result<(), String> {
+        if self.dry_run() {
+            return Ok(());
+        }
+        if self.writer(Volume::Root).is_dir(Path::new(REDOX_BOOT_MARKER)).is_none() {
+            return Err(format!(
+                "La partición root no contiene {}: no parece una instalación hecha con este instalador",
+                REDOX_BOOT_MARKER
+            ));
+        }
         Ok(())
     }
 
@@ -103,309 +650,307 @@ impl DirectInstaller {
         }
 
         println!("🔍 Verificando disco {}...", disk.name);
-        
-        // Verificar que no esté montado leyendo /proc/mounts
-        let mounts = std::fs::read_to_string("/proc/mounts")
-            .map_err(|e| format!("Error leyendo /proc/mounts: {}", e))?;
-        
-        if mounts.contains(&disk.name) {
-            println!("   ⚠️  El disco está montado, desmontando...");
-            self.unmount_existing_partitions(disk)?;
-        }
-
         println!("   ✅ Disco verificado");
         Ok(())
     }
 
+    // Desmonta los montajes del disco y de sus particiones, los anidados
+    // primero. Antes se muestra qué está montado y dónde; si algo no se puede
+    // desmontar la instalación se detiene en lugar de borrar un disco en uso.
     fn unmount_existing_partitions(&self, disk: &DiskInfo) -> Result<(), String> {
-        let device_name = disk.name.trim_start_matches("/dev/");
-        
-        // Buscar particiones montadas
-        let output = Command::new("mount")
-            .output()
-            .map_err(|e| format!("Error ejecutando mount: {}", e))?;
-        
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        
-        for line in output_str.lines() {
-            if line.contains(device_name) {
-                if let Some(partition) = line.split_whitespace().next() {
-                    println!("   Desmontando {}...", partition);
-                    let _ = Command::new("umount")
-                        .arg("-f")
-                        .arg(partition)
-                        .output();
-                }
+        let mounts = mounts::read_mountinfo(&self.mountinfo)?;
+        let mounted = mounts::mounted_partitions(&self.sys_block, &mounts, &disk.name);
+        if mounted.is_empty() {
+            return Ok(());
+        }
+
+        println!("   ⚠️  Particiones de {} montadas:", disk.name);
+        for partition in &mounted {
+            println!("      {} en {} ({})", partition.device, partition.mount_point, partition.fs_type);
+        }
+
+        for partition in &mounted {
+            println!("   Desmontando {}...", partition.mount_point);
+            let output = self.execute("umount", &[&partition.mount_point])
+                .map_err(|e| format!("Error ejecutando umount: {}", e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "No se pudo desmontar {} ({}): {}",
+                    partition.mount_point,
+                    partition.device,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
             }
         }
-        
+
         // Esperar un poco para que se complete el desmontaje
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        
+        self.pause(Duration::from_secs(1));
+
         Ok(())
     }
 
-    fn create_partitions(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
-        // Limpiar tabla de particiones
-        println!("   Limpiando tabla de particiones...");
-        let _ = Command::new("wipefs")
-            .args(&["-a", &disk.name])
-            .output();
-
-        std::thread::sleep(std::time::Duration::from_secs(1));
+    fn create_partitions(&self, disk: &DiskInfo, config: &InstallationConfig, layout: &[PartitionSpec]) -> Result<Vec<PlacedPartition>, String> {
+        let (file, disk_size, sector_size) = self.open_disk(disk)?;
 
-        // Crear tabla GPT
         println!("   Creando tabla de particiones GPT...");
-        let output = Command::new("parted")
-            .args(&["-s", &disk.name, "mklabel", "gpt"])
-            .output()
-            .map_err(|e| format!("Error ejecutando parted: {}", e))?;
-
-        if !output.status.success() {
-            return Err(format!("Error creando tabla GPT: {}", String::from_utf8_lossy(&output.stderr)));
+        let mut table = GptTable::new(disk_size, sector_size)?;
+        if config.boot_mode.bios() {
+            let bios = table.add_bios_boot_partition()?;
+            println!("   Partición BIOS: arranque BIOS (sectores {}-{})", bios.first_lba, bios.last_lba);
         }
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        let mut placed = Vec::new();
+        for spec in layout {
+            let size = partition_layout::resolve_size(&spec.size, disk_size);
+            let partition = table.add_partition(&spec.label, partition_layout::type_guid(spec), size)?;
+            placed.push(PlacedPartition {
+                number: partition.number,
+                device: self.partition_device(disk, partition.number),
+                spec: spec.clone(),
+                reused: false,
+            });
+            println!(
+                "   Partición {}: {} MB (sectores {}-{})",
+                spec.label,
+                partition.size_bytes(sector_size) / 1024 / 1024,
+                partition.first_lba,
+                partition.last_lba
+            );
+        }
 
-        // Crear partición EFI
-        let efi_end = format!("{}MiB", config.efi_size_mb);
-        println!("   Creando partición EFI ({})...", efi_end);
-        
-        let output = Command::new("parted")
-            .args(&["-s", &disk.name, "mkpart", "primary", "fat32", "1MiB", &efi_end])
-            .output()
-            .map_err(|e| format!("Error creando partición EFI: {}", e))?;
+        if let Some(last) = table.partitions.last() {
+            let free = (table.last_usable_lba() - last.last_lba) * sector_size;
+            if free >= 1024 * 1024 {
+                println!("   Espacio sin asignar al final: {} MB", free / 1024 / 1024);
+            }
+        }
 
-        if !output.status.success() {
-            return Err(format!("Error creando partición EFI: {}", String::from_utf8_lossy(&output.stderr)));
+        self.save_original_table(disk, config, &file, disk_size, sector_size)?;
+        if let Some(plan) = self.plan.borrow_mut().as_mut() {
+            plan.record_table(&table, &[]);
+            return Ok(placed);
         }
 
-        // Marcar partición como ESP
-        let output = Command::new("parted")
-            .args(&["-s", &disk.name, "set", "1", "esp", "on"])
-            .output()
-            .map_err(|e| format!("Error marcando ESP: {}", e))?;
+        table.write_to(&file)?;
 
-        if !output.status.success() {
-            return Err(format!("Error marcando partición como ESP: {}", String::from_utf8_lossy(&output.stderr)));
-        }
+        // Pedir al kernel que cree los nodos de las nuevas particiones
+        gpt::reread_partition_table(&file)?;
+        drop(file);
+        self.wait_for_partitions(disk, &mut placed)?;
+
+        Ok(placed)
+    }
 
-        std::thread::sleep(std::time::Duration::from_secs(1));
+    // Instalación junto a otro sistema: conserva la tabla GPT existente, crea
+    // root (y la ESP si no hay ninguna) en un hueco libre y reutiliza la ESP
+    fn create_partitions_in_free_space(
+        &self,
+        disk: &DiskInfo,
+        config: &InstallationConfig,
+        layout: &[PartitionSpec],
+    ) -> Result<Vec<PlacedPartition>, String> {
+        let (file, disk_size, sector_size) = self.open_disk(disk)?;
+        let mut table = GptTable::read_from(&file, disk_size, sector_size)?;
+        let existing: Vec<usize> = table.partitions.iter().map(|partition| partition.number).collect();
+
+        println!("   Tabla GPT existente con {} particiones", table.partitions.len());
+        let gaps = table.free_gaps();
+        for (i, (first, last)) in gaps.iter().enumerate() {
+            println!("   Hueco {}: sectores {}-{} ({} MB)", i + 1, first, last, (last - first + 1) * sector_size / 1024 / 1024);
+        }
 
-        // Crear partición root (resto del disco)
-        println!("   Creando partición root (resto del disco)...");
-        let output = Command::new("parted")
-            .args(&["-s", &disk.name, "mkpart", "primary", &efi_end, "100%"])
-            .output()
-            .map_err(|e| format!("Error creando partición root: {}", e))?;
+        let gap = match config.free_gap {
+            Some(n) => *gaps.get(n.wrapping_sub(1))
+                .ok_or_else(|| format!("No existe el hueco libre {} (hay {})", n, gaps.len()))?,
+            None => gaps.iter()
+                .copied()
+                .max_by_key(|(first, last)| last - first)
+                .ok_or_else(|| format!("No hay espacio libre en {}", disk.name))?,
+        };
+        println!("   Usando sectores {}-{}", gap.0, gap.1);
+
+        let efi_spec = &layout[partition_layout::efi_index(layout)];
+        let root_spec = &layout[partition_layout::root_index(layout)];
+        let mut placed = Vec::new();
+        let mut new_numbers = Vec::new();
+        let mut root_start = gap.0;
+
+        match table.partitions.iter().find(|partition| partition.type_guid == gpt::EFI_SYSTEM_PARTITION) {
+            Some(esp) => {
+                println!("   Reutilizando la partición EFI existente {} ({})", esp.number, esp.name);
+                placed.push(PlacedPartition {
+                    number: esp.number,
+                    device: self.partition_device(disk, esp.number),
+                    spec: PartitionSpec { label: esp.name.clone(), ..efi_spec.clone() },
+                    reused: true,
+                });
+            }
+            None => {
+                println!("   El disco no tiene partición EFI; se crea una en el hueco");
+                let size = partition_layout::resolve_size(&efi_spec.size, disk_size);
+                let esp = table.add_partition_in(&efi_spec.label, gpt::EFI_SYSTEM_PARTITION, gap, size)?;
+                root_start = esp.last_lba + 1;
+                new_numbers.push(esp.number);
+                placed.push(PlacedPartition {
+                    number: esp.number,
+                    device: self.partition_device(disk, esp.number),
+                    spec: efi_spec.clone(),
+                    reused: false,
+                });
+            }
+        }
 
-        if !output.status.success() {
-            return Err(format!("Error creando partición root: {}", String::from_utf8_lossy(&output.stderr)));
+        let root = table.add_partition_in(&root_spec.label, partition_layout::type_guid(root_spec), (root_start, gap.1), None)?;
+        // Redox OS requiere al menos 2 GB, igual que check_disk_space
+        if root.size_bytes(sector_size) < 2 * 1024 * 1024 * 1024 {
+            return Err(format!(
+                "El hueco libre es demasiado pequeño para root ({} MB). Se requieren al menos 2 GB",
+                root.size_bytes(sector_size) / 1024 / 1024
+            ));
+        }
+        println!("   Partición {}: {} MB (sectores {}-{})", root_spec.label, root.size_bytes(sector_size) / 1024 / 1024, root.first_lba, root.last_lba);
+        new_numbers.push(root.number);
+        placed.push(PlacedPartition {
+            number: root.number,
+            device: self.partition_device(disk, root.number),
+            spec: root_spec.clone(),
+            reused: false,
+        });
+
+        self.save_original_table(disk, config, &file, disk_size, sector_size)?;
+        if let Some(plan) = self.plan.borrow_mut().as_mut() {
+            plan.record_table(&table, &existing);
+            return Ok(placed);
         }
 
-        // Sincronizar y esperar
-        Command::new("sync").output().ok();
-        std::thread::sleep(std::time::Duration::from_secs(2));
-        
-        let _ = Command::new("partprobe")
-            .arg(&disk.name)
-            .output();
-        
-        std::thread::sleep(std::time::Duration::from_secs(2));
+        table.update_on(&file)?;
 
-        // Verificar que las particiones existen
-        let (part1, part2) = self.get_partition_names(disk);
-        
-        if !Path::new(&part1).exists() || !Path::new(&part2).exists() {
-            return Err("Las particiones no se crearon correctamente".to_string());
+        // Con otras particiones del disco montadas BLKRRPART falla; en ese
+        // caso se registran solo las particiones nuevas
+        if let Err(e) = gpt::reread_partition_table(&file) {
+            println!("   ⚠️  {}; registrando solo las particiones nuevas", e);
+            gpt::add_partitions_to_kernel(&file, &table, &new_numbers)?;
         }
+        drop(file);
+        self.wait_for_partitions(disk, &mut placed)?;
 
-        Ok(())
+        Ok(placed)
     }
 
-    fn format_partitions(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
-        let (efi_partition, root_partition) = self.get_partition_names(disk);
+    // Guarda la tabla de particiones actual en un fichero antes de tocar el
+    // disco, para poder recuperarla con restore-table si era el disco
+    // equivocado. Con restore_table_on_failure, además, un fallo posterior de
+    // la instalación la restaura automáticamente.
+    fn save_original_table(
+        &self,
+        disk: &DiskInfo,
+        config: &InstallationConfig,
+        file: &fs::File,
+        disk_size: u64,
+        sector_size: u64,
+    ) -> Result<(), String> {
+        let recorded = self.record(|| PlannedAction::BackupPartitionTable {
+            directory: config.backup_dir.clone(),
+        });
+        if recorded {
+            return Ok(());
+        }
 
-        // Formatear partición EFI como FAT32
-        println!("   Formateando {} como FAT32...", efi_partition);
-        let output = Command::new("mkfs.vfat")
-            .args(&["-F", "32", "-n", "REDOX_EFI", &efi_partition])
-            .output()
-            .map_err(|e| format!("Error formateando EFI: {}", e))?;
+        let backup = TableBackup::capture(&disk.name, file, disk_size, sector_size)?;
+        if backup.snapshot.is_blank() {
+            println!("   El disco no tiene tabla de particiones previa: no se guarda copia");
+        } else {
+            let path = backup.save(&config.backup_dir)?;
+            println!("   💾 Copia de la tabla original ({}) guardada en {}", backup.layout.table, path.display());
+            println!("      Para recuperarla: redox-disk-installer restore-table --backup {} --disk {}", path.display(), disk.name);
+        }
 
-        if !output.status.success() {
-            return Err(format!("Error formateando partición EFI: {}", String::from_utf8_lossy(&output.stderr)));
+        if config.restore_table_on_failure {
+            self.push_undo(UndoAction::RestorePartitionTable {
+                disk: disk.name.clone(),
+                snapshot: backup.snapshot,
+            });
         }
+        Ok(())
+    }
 
-        // Formatear partición root según configuración
-        match config.filesystem_type {
-            FilesystemType::RedoxFS => {
-                println!("   Formateando {} como RedoxFS...", root_partition);
-                
-                // Verificar que redoxfs-mkfs existe
-                if !Path::new(REDOXFS_MKFS).exists() {
-                    return Err(format!(
-                        "redoxfs-mkfs no encontrado en {}\n   Compila RedoxFS primero: cd /home/moebius/redox/redoxfs && cargo build --release",
-                        REDOXFS_MKFS
-                    ));
-                }
-                
-                // Verificar que la partición existe y obtener su tamaño
-                println!("   Verificando partición {}...", root_partition);
-                
-                if !Path::new(&root_partition).exists() {
-                    return Err(format!("La partición {} no existe", root_partition));
-                }
-                
-                // Obtener tamaño del dispositivo de bloques usando blockdev
-                let size_output = Command::new("blockdev")
-                    .args(&["--getsize64", &root_partition])
-                    .output()
-                    .map_err(|e| format!("Error obteniendo tamaño de {}: {}", root_partition, e))?;
-                
-                if !size_output.status.success() {
-                    return Err(format!("No se pudo obtener el tamaño de {}", root_partition));
-                }
-                
-                let size_str = String::from_utf8_lossy(&size_output.stdout);
-                let size_bytes: u64 = size_str.trim()
-                    .parse()
-                    .map_err(|_| format!("Error parseando tamaño de partición: {}", size_str))?;
-                
-                if size_bytes == 0 {
-                    return Err(format!("La partición {} tiene tamaño 0 bytes", root_partition));
-                }
-                
-                let size_mb = size_bytes / 1024 / 1024;
-                let size_gb = size_bytes as f64 / 1024.0 / 1024.0 / 1024.0;
-                
-                println!("   ✅ Partición válida");
-                println!("   Tamaño: {} bytes ({} MB / {:.2} GB)", size_bytes, size_mb, size_gb);
-                
-                // IMPORTANTE: Limpiar metadata anterior de la partición
-                println!("   Limpiando metadata anterior de la partición...");
-                let wipefs_output = Command::new("wipefs")
-                    .args(&["-a", &root_partition])
-                    .output();
-                
-                match wipefs_output {
-                    Ok(output) if output.status.success() => {
-                        println!("   ✅ Metadata anterior limpiada");
-                    }
-                    Ok(output) => {
-                        let stderr = String::from_utf8_lossy(&output.stderr);
-                        if !stderr.is_empty() {
-                            println!("   ⚠️  wipefs: {}", stderr.trim());
-                        }
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  No se pudo ejecutar wipefs: {}", e);
+    // udev crea los nodos /dev de las particiones de forma asíncrona tras
+    // releer la tabla; se espera a que aparezcan en lugar de un tiempo fijo.
+    // El nombre de cada nodo se toma de /sys/block/<disco>/<partición>/partition;
+    // si el disco no está en sysfs se deduce del nombre del disco.
+    fn wait_for_partitions(&self, disk: &DiskInfo, placed: &mut [PlacedPartition]) -> Result<(), String> {
+        let dev_dir = Path::new(&disk.name).parent().unwrap_or(Path::new("/dev"));
+        let in_sysfs = sysfs::has_disk(&self.sys_block, &disk.name);
+
+        for _ in 0..50 {
+            if in_sysfs {
+                let partitions = sysfs::disk_partitions(&self.sys_block, &disk.name);
+                for partition in placed.iter_mut() {
+                    if let Some(found) = partitions.iter().find(|found| found.number == partition.number) {
+                        partition.device = dev_dir.join(&found.name).to_string_lossy().into_owned();
                     }
                 }
-                
-                // Escribir ceros al inicio de la partición para asegurar limpieza
-                println!("   Escribiendo ceros al inicio de la partición...");
-                let dd_output = Command::new("dd")
-                    .args(&[
-                        "if=/dev/zero",
-                        &format!("of={}", root_partition),
-                        "bs=1M",
-                        "count=10",
-                        "conv=notrunc"
-                    ])
-                    .output();
-                
-                match dd_output {
-                    Ok(output) if output.status.success() => {
-                        println!("   ✅ Partición limpiada");
-                    }
-                    Ok(_) => {
-                        println!("   ⚠️  Advertencia: No se pudo limpiar completamente la partición");
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Error ejecutando dd: {}", e);
+            }
+            if placed.iter().all(|partition| Path::new(&partition.device).exists()) {
+                return Ok(());
+            }
+            self.pause(Duration::from_millis(100));
+        }
+
+        Err(format!(
+            "Las particiones no aparecieron tras crear la tabla GPT ({})",
+            placed.iter().map(|partition| partition.device.as_str()).collect::<Vec<_>>().join(", ")
+        ))
+    }
+
+    fn format_partitions(&self, placed: &[PlacedPartition], redoxfs_direct: bool) -> Result<(), String> {
+        for PlacedPartition { device: partition, spec, reused, .. } in placed {
+            if *reused {
+                println!("   Se conserva {} ({}) sin formatear", partition, spec.label);
+                continue;
+            }
+
+            match spec.filesystem {
+                PartitionFilesystem::Efi => {
+                    println!("   Formateando {} como FAT32...", partition);
+                    let output = self.execute("mkfs.vfat", &["-F", "32", "-n", &spec.label, partition])
+                        .map_err(|e| format!("Error formateando EFI: {}", e))?;
+
+                    if !output.status.success() {
+                        return Err(format!("Error formateando partición EFI: {}", String::from_utf8_lossy(&output.stderr)));
                     }
                 }
-                
-                // Sincronizar antes de formatear
-                Command::new("sync").output().ok();
-                std::thread::sleep(std::time::Duration::from_secs(1));
-                
-                println!("   Usando: {}", REDOXFS_MKFS);
-                println!("   Ejecutando: {} {}", REDOXFS_MKFS, root_partition);
-                
-                // Usar redoxfs-mkfs de la carpeta redoxfs
-                // Nota: redoxfs-mkfs imprime mensajes a stderr incluso en éxito
-                let output = Command::new(REDOXFS_MKFS)
-                    .arg(&root_partition)
-                    .output()
-                    .map_err(|e| format!("Error ejecutando redoxfs-mkfs: {}", e))?;
-                
-                // Mostrar salida de redoxfs-mkfs para debugging
-                let stdout = String::from_utf8_lossy(&output.stdout);
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                
-                // redoxfs-mkfs imprime a stderr tanto éxitos como errores
-                if !stderr.is_empty() {
-                    for line in stderr.lines() {
-                        println!("   redoxfs-mkfs: {}", line);
+                PartitionFilesystem::RedoxFS => {
+                    println!("   Formateando {} como RedoxFS...", partition);
+                    let is_root = spec.mount_point.as_deref() == Some("/");
+                    let uuid = if is_root && redoxfs_direct {
+                        self.create_redoxfs(partition)?
+                    } else {
+                        self.format_redoxfs(partition)?
+                    };
+
+                    // El UUID de root se usa en la configuración de arranque
+                    if is_root && !uuid.is_empty() {
+                        *self.redoxfs_uuid.borrow_mut() = Some(uuid);
                     }
                 }
-                
-                if !stdout.is_empty() {
-                    println!("   Stdout: {}", stdout.trim());
-                }
-                
-                if !output.status.success() {
-                    return Err(format!(
-                        "redoxfs-mkfs falló (código: {:?})\n   Ver mensajes arriba para más detalles",
-                        output.status.code()
-                    ));
-                }
-                
-                // Verificar que el mensaje de éxito apareció
-                if !stderr.contains("created filesystem") {
-                    return Err(format!(
-                        "redoxfs-mkfs no reportó éxito. Salida:\n{}",
-                        stderr
-                    ));
+                PartitionFilesystem::Ext4 => {
+                    println!("   Formateando {} como ext4...", partition);
+                    let output = self.execute("mkfs.ext4", &["-F", "-L", &spec.label, partition])
+                        .map_err(|e| format!("Error formateando {}: {}", partition, e))?;
+
+                    if !output.status.success() {
+                        return Err(format!("Error formateando {}: {}", partition, String::from_utf8_lossy(&output.stderr)));
+                    }
                 }
-                
-                // Extraer UUID del mensaje de éxito
-                let redoxfs_uuid = if let Some(uuid_line) = stderr.lines().find(|line| line.contains("uuid")) {
-                    if let Some(uuid_part) = uuid_line.split_whitespace().last() {
-                        uuid_part.to_string()
-                    } else {
-                        return Err("No se pudo extraer UUID de redoxfs-mkfs".to_string());
+                PartitionFilesystem::Swap => {
+                    println!("   Creando área de intercambio en {}...", partition);
+                    let output = self.execute("mkswap", &["-L", &spec.label, partition])
+                        .map_err(|e| format!("Error ejecutando mkswap: {}", e))?;
+
+                    if !output.status.success() {
+                        return Err(format!("Error creando swap en {}: {}", partition, String::from_utf8_lossy(&output.stderr)));
                     }
-                } else {
-                    return Err("No se encontró UUID en la salida de redoxfs-mkfs".to_string());
-                };
-                
-                println!("   ✅ RedoxFS formateado exitosamente con UUID: {}", redoxfs_uuid);
-                
-                // Almacenar el UUID para usar en la configuración
-                // Nota: necesitamos una forma de pasar este UUID a create_config_files
-                // Por ahora, lo guardamos en un archivo temporal
-                let uuid_file = "/tmp/redox_install_uuid";
-                fs::write(uuid_file, &redoxfs_uuid)
-                    .map_err(|e| format!("Error guardando UUID: {}", e))?;
-                
-                // Sincronizar para asegurar que los cambios se escribieron al disco
-                println!("   Sincronizando datos al disco...");
-                Command::new("sync").output().ok();
-                std::thread::sleep(std::time::Duration::from_secs(2));
-                println!("   ✅ Sincronización completada");
-            }
-            FilesystemType::Ext4 => {
-                println!("   Formateando {} como ext4...", root_partition);
-                let output = Command::new("mkfs.ext4")
-                    .args(&["-F", "-L", "REDOX_ROOT", &root_partition])
-                    .output()
-                    .map_err(|e| format!("Error formateando root: {}", e))?;
-                
-                if !output.status.success() {
-                    return Err(format!("Error formateando partición root: {}", String::from_utf8_lossy(&output.stderr)));
                 }
             }
         }
@@ -413,433 +958,736 @@ impl DirectInstaller {
         Ok(())
     }
 
-    fn mount_partitions(&self, disk: &DiskInfo) -> Result<(), String> {
-        let (efi_partition, root_partition) = self.get_partition_names(disk);
+    // Crea root con la librería redoxfs y devuelve el UUID creado. A partir
+    // de aquí root se escribe directamente, sin montarla
+    fn create_redoxfs(&self, partition: &str) -> Result<String, String> {
+        *self.direct_root.borrow_mut() = Some(partition.to_string());
+        if self.record(|| PlannedAction::CreateRedoxFs { device: partition.to_string() }) {
+            return Ok(String::new());
+        }
+
+        self.verify_partition_size(partition)?;
+        let writer = RedoxFsWriter::create(Path::new(partition))
+            .map_err(|e| format!("Error creando RedoxFS en {}: {}", partition, e))?;
+        let uuid = writer.uuid();
+        println!("   ✅ RedoxFS creado sin FUSE con UUID: {}", uuid);
+        *self.root_writer.borrow_mut() = Some(Rc::new(writer));
 
-        // Crear directorios de montaje
-        fs::create_dir_all(&self.efi_mount_point)
-            .map_err(|e| format!("Error creando directorio EFI: {}", e))?;
-        fs::create_dir_all(&self.root_mount_point)
-            .map_err(|e| format!("Error creando directorio root: {}", e))?;
-
-        // Montar partición EFI
-        println!("   Montando {} en {}...", efi_partition, self.efi_mount_point);
-        let output = Command::new("mount")
-            .args(&[&efi_partition, &self.efi_mount_point])
-            .output()
-            .map_err(|e| format!("Error montando EFI: {}", e))?;
+        Ok(uuid)
+    }
 
-        if !output.status.success() {
-            return Err(format!("Error montando partición EFI: {}", String::from_utf8_lossy(&output.stderr)));
+    // Formatea una partición con redoxfs-mkfs y devuelve el UUID creado
+    fn format_redoxfs(&self, partition: &str) -> Result<String, String> {
+        let redoxfs_mkfs = self.artifacts.redoxfs_mkfs()?;
+        
+        // Verificar que la partición existe y obtener su tamaño (en --dry-run
+        // la partición aún no existe)
+        if !self.dry_run() {
+            self.verify_partition_size(partition)?;
         }
 
-        // Montar partición root
-        println!("   Montando {} en {}...", root_partition, self.root_mount_point);
+        // IMPORTANTE: Limpiar metadata anterior de la partición
+        println!("   Limpiando metadata anterior de la partición...");
+        let wipefs_output = self.execute("wipefs", &["-a", partition]);
         
-        // Para RedoxFS, necesitamos usar un enfoque diferente
-        // RedoxFS es un sistema de archivos FUSE que se ejecuta en foreground por defecto
-        // Por ahora, usaremos mount estándar que puede manejar RedoxFS si está registrado
+        match wipefs_output {
+            Ok(output) if output.status.success() => {
+                println!("   ✅ Metadata anterior limpiada");
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stderr.is_empty() {
+                    println!("   ⚠️  wipefs: {}", stderr.trim());
+                }
+            }
+            Err(e) => {
+                println!("   ⚠️  No se pudo ejecutar wipefs: {}", e);
+            }
+        }
         
-        println!("   ⚠️  Nota: RedoxFS requiere ejecución en segundo plano (FUSE)");
-        println!("   Usando mount estándar para compatibilidad...");
+        // Escribir ceros al inicio de la partición para asegurar limpieza
+        println!("   Escribiendo ceros al inicio de la partición...");
+        let dd_output = self.execute("dd", &[
+            "if=/dev/zero",
+            &format!("of={}", partition),
+            "bs=1M",
+            "count=10",
+            "conv=notrunc"
+        ]);
         
-        let output = Command::new("mount")
-            .args(&["-t", "auto", &root_partition, &self.root_mount_point])
-            .output()
-            .map_err(|e| format!("Error montando root: {}", e))?;
-
-        if !output.status.success() {
-            // Si mount falla, intentar con redoxfs en background usando spawn
-            println!("   ⚠️  Mount estándar falló, intentando RedoxFS en background...");
-            
-            if Path::new(REDOXFS_MOUNT).exists() {
-                println!("   Iniciando RedoxFS en modo background: {}", REDOXFS_MOUNT);
-                
-                // Iniciar redoxfs como proceso en background
-                let child = Command::new(REDOXFS_MOUNT)
-                    .args(&[&root_partition, &self.root_mount_point])
-                    .spawn()
-                    .map_err(|e| format!("Error iniciando redoxfs: {}", e))?;
-                
-                // Dar tiempo para que monte
-                println!("   Esperando que RedoxFS se monte...");
-                std::thread::sleep(std::time::Duration::from_secs(3));
-                
-                // Verificar que el directorio esté montado
-                let mount_check = Command::new("mountpoint")
-                    .arg(&self.root_mount_point)
-                    .output();
-                
-                match mount_check {
-                    Ok(output) if output.status.success() => {
-                        println!("   ✅ Partición montada con RedoxFS en background (PID: {})", child.id());
-                        
-                        // Verificar que podemos acceder al directorio
-                        if fs::metadata(&self.root_mount_point).is_ok() {
-                            println!("   ✅ Directorio de montaje accesible");
-                            
-                            // Intentar crear un directorio de prueba
-                            let test_dir = format!("{}/test_mount", self.root_mount_point);
-                            match fs::create_dir(&test_dir) {
-                                Ok(_) => {
-                                    let _ = fs::remove_dir(&test_dir);
-                                    println!("   ✅ RedoxFS funciona correctamente");
-                                    return Ok(());
-                                }
-                                Err(e) => {
-                                    println!("   ⚠️  Error escribiendo en RedoxFS: {}", e);
-                                    return Err("RedoxFS montado pero no accesible para escritura".to_string());
-                                }
-                            }
-                        } else {
-                            println!("   ⚠️  No se puede acceder al directorio de montaje");
-                            return Err("Directorio de montaje no accesible".to_string());
-                        }
-                    }
-                    Ok(output) => {
-                        println!("   ⚠️  mountpoint falló: {}", String::from_utf8_lossy(&output.stderr));
-                        return Err("RedoxFS no se montó correctamente".to_string());
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Error verificando mountpoint: {}", e);
-                        return Err("No se pudo verificar el montaje de RedoxFS".to_string());
-                    }
-                }
-            } else {
-                return Err(format!("Error montando partición root: {}", String::from_utf8_lossy(&output.stderr)));
+        match dd_output {
+            Ok(output) if output.status.success() => {
+                println!("   ✅ Partición limpiada");
+            }
+            Ok(_) => {
+                println!("   ⚠️  Advertencia: No se pudo limpiar completamente la partición");
+            }
+            Err(e) => {
+                println!("   ⚠️  Error ejecutando dd: {}", e);
             }
         }
         
-        println!("   ✅ Partición montada exitosamente");
+        // Sincronizar antes de formatear
+        self.execute("sync", &[]).ok();
+        self.pause(Duration::from_secs(1));
+        
+        println!("   Usando: {}", redoxfs_mkfs);
+        let redoxfs_mkfs = redoxfs_mkfs.path.to_string_lossy();
+        println!("   Ejecutando: {} {}", redoxfs_mkfs, partition);
+        
+        // Nota: redoxfs-mkfs imprime mensajes a stderr incluso en éxito
+        let output = self.execute(&redoxfs_mkfs, &[partition])
+            .map_err(|e| format!("Error ejecutando redoxfs-mkfs: {}", e))?;
 
-        Ok(())
+        // El UUID solo se conoce al formatear de verdad
+        if self.dry_run() {
+            return Ok(String::new());
+        }
+        
+        // Mostrar salida de redoxfs-mkfs para debugging
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        
+        // redoxfs-mkfs imprime a stderr tanto éxitos como errores
+        if !stderr.is_empty() {
+            for line in stderr.lines() {
+                println!("   redoxfs-mkfs: {}", line);
+            }
+        }
+        
+        if !stdout.is_empty() {
+            println!("   Stdout: {}", stdout.trim());
+        }
+        
+        if !output.status.success() {
+            return Err(format!(
+                "redoxfs-mkfs falló (código: {:?})\n   Ver mensajes arriba para más detalles",
+                output.status.code()
+            ));
+        }
+        
+        // Verificar que el mensaje de éxito apareció
+        if !stderr.contains("created filesystem") {
+            return Err(format!(
+                "redoxfs-mkfs no reportó éxito. Salida:\n{}",
+                stderr
+            ));
+        }
+        
+        // Extraer UUID del mensaje de éxito
+        let redoxfs_uuid = if let Some(uuid_line) = stderr.lines().find(|line| line.contains("uuid")) {
+            if let Some(uuid_part) = uuid_line.split_whitespace().last() {
+                uuid_part.to_string()
+            } else {
+                return Err("No se pudo extraer UUID de redoxfs-mkfs".to_string());
+            }
+        } else {
+            return Err("No se encontró UUID en la salida de redoxfs-mkfs".to_string());
+        };
+        
+        println!("   ✅ RedoxFS formateado exitosamente con UUID: {}", redoxfs_uuid);
+        
+        // Sincronizar para asegurar que los cambios se escribieron al disco
+        println!("   Sincronizando datos al disco...");
+        self.execute("sync", &[]).ok();
+        self.pause(Duration::from_secs(2));
+        println!("   ✅ Sincronización completada");
+
+        Ok(redoxfs_uuid)
+    }
+
+    fn verify_partition_size(&self, partition: &str) -> Result<(), String> {
+        println!("   Verificando partición {}...", partition);
+        
+        if !Path::new(partition).exists() {
+            return Err(format!("La partición {} no existe", partition));
+        }
+        
+        // Obtener tamaño del dispositivo de bloques usando blockdev
+        let size_output = self.runner.run("blockdev", &["--getsize64", partition])
+            .map_err(|e| format!("Error obteniendo tamaño de {}: {}", partition, e))?;
+        
+        if !size_output.status.success() {
+            return Err(format!("No se pudo obtener el tamaño de {}", partition));
+        }
+        
+        let size_str = String::from_utf8_lossy(&size_output.stdout);
+        let size_bytes: u64 = size_str.trim()
+            .parse()
+            .map_err(|_| format!("Error parseando tamaño de partición: {}", size_str))?;
+        
+        if size_bytes == 0 {
+            return Err(format!("La partición {} tiene tamaño 0 bytes", partition));
+        }
+        
+        let size_mb = size_bytes / 1024 / 1024;
+        let size_gb = size_bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+        
+        println!("   ✅ Partición válida");
+        println!("   Tamaño: {} bytes ({} MB / {:.2} GB)", size_bytes, size_mb, size_gb);
+
+        Ok(())
+    }
+
+    fn mount_partitions(&self, placed: &[PlacedPartition]) -> Result<(), String> {
+        let efi_partition = Self::efi_partition(placed).map(|partition| &partition.device);
+        let root_partition = &Self::root_partition(placed).device;
+        let root_direct = self.direct_root.borrow().is_some();
+
+        // Crear directorios de montaje
+        let mut mount_points = Vec::new();
+        if efi_partition.is_some() {
+            mount_points.push(&self.efi_mount_point);
+        }
+        if !root_direct {
+            mount_points.push(&self.root_mount_point);
+        }
+        for mount_point in mount_points {
+            if !Path::new(mount_point).exists() {
+                if !self.record(|| PlannedAction::CreateDirectory { path: mount_point.clone() }) {
+                    fs::create_dir_all(mount_point)
+                        .map_err(|e| format!("Error creando directorio {}: {}", mount_point, e))?;
+                }
+                self.push_undo(UndoAction::RemoveDir(mount_point.clone()));
+            }
+        }
+
+        // Montar partición EFI (no hay en el arranque solo BIOS)
+        if let Some(efi_partition) = efi_partition {
+            println!("   Montando {} en {}...", efi_partition, self.efi_mount_point);
+            let output = self.execute("mount", &[efi_partition, &self.efi_mount_point])
+                .map_err(|e| format!("Error montando EFI: {}", e))?;
+
+            if !output.status.success() {
+                return Err(format!("Error montando partición EFI: {}", String::from_utf8_lossy(&output.stderr)));
+            }
+            self.push_undo(UndoAction::Unmount(self.efi_mount_point.clone()));
+        }
+
+        if root_direct {
+            println!("   {} se escribe directamente con la librería redoxfs, sin montar", root_partition);
+            return Ok(());
+        }
+
+        // Montar partición root
+        println!("   Montando {} en {}...", root_partition, self.root_mount_point);
+        self.mount_filesystem(root_partition, &self.root_mount_point, &Self::root_partition(placed).spec.filesystem)?;
+
+        // Montar el resto de particiones (p. ej. /home) dentro de root
+        for (index, mount_point) in Self::extra_mounts(placed) {
+            let partition = &placed[index].device;
+            let target = format!("{}{}", self.root_mount_point, mount_point);
+            self.create_dir(Volume::Root, &mount_point)
+                .map_err(|e| format!("Error creando directorio {}: {}", target, e))?;
+
+            println!("   Montando {} en {}...", partition, target);
+            self.mount_filesystem(partition, &target, &placed[index].spec.filesystem)?;
+        }
+
+        Ok(())
+    }
+
+    fn mount_filesystem(&self, partition: &str, target: &str, filesystem: &PartitionFilesystem) -> Result<(), String> {
+        // El kernel monta los sistemas de archivos que conoce (FAT, ext4);
+        // RedoxFS necesita el daemon FUSE redoxfs
+        if *filesystem == PartitionFilesystem::RedoxFS {
+            return self.mount_redoxfs(partition, target);
+        }
+
+        let output = self.execute("mount", &["-t", "auto", partition, target])
+            .map_err(|e| format!("Error montando {}: {}", partition, e))?;
+        if !output.status.success() {
+            return Err(format!("Error montando {}: {}", partition, String::from_utf8_lossy(&output.stderr)));
+        }
+        println!("   ✅ Partición montada exitosamente");
+        self.push_undo(UndoAction::Unmount(target.to_string()));
+
+        Ok(())
+    }
+
+    fn mount_redoxfs(&self, partition: &str, target: &str) -> Result<(), String> {
+        let redoxfs = self.artifacts.redoxfs_mount()?;
+        println!("   Montando con RedoxFS (FUSE): {}", redoxfs);
+        let recorded = self.record(|| PlannedAction::Command {
+            program: redoxfs.path.display().to_string(),
+            args: vec![partition.to_string(), target.to_string()],
+        });
+        if recorded {
+            return Ok(());
+        }
+
+        let mount = RedoxFsMount::mount(
+            self.runner.clone(),
+            &redoxfs.path,
+            partition,
+            target,
+            &self.mountinfo,
+            REDOXFS_MOUNT_TIMEOUT,
+        )
+        .map_err(|e| format!("Error montando {} con RedoxFS: {}", partition, e))?;
+        println!("   ✅ Partición montada con RedoxFS (PID: {})", mount.pid().unwrap_or_default());
+        self.push_undo(UndoAction::StopFuse(mount));
+
+        Ok(())
     }
 
-    fn install_bootloader(&self, disk: &DiskInfo) -> Result<(), String> {
+    fn install_bootloader(&self, disk: &DiskInfo, config: &InstallationConfig, placed: &[PlacedPartition]) -> Result<(), String> {
         // Crear estructura EFI
-        let efi_boot_dir = format!("{}/EFI/BOOT", self.efi_mount_point);
-        let efi_redox_dir = format!("{}/EFI/redox", self.efi_mount_point);
+        let efi_boot_dir = "EFI/BOOT";
+        let efi_redox_dir = "EFI/redox";
         
-        fs::create_dir_all(&efi_boot_dir)
+        self.create_dir(Volume::Esp, efi_boot_dir)
             .map_err(|e| format!("Error creando directorio EFI/BOOT: {}", e))?;
-        fs::create_dir_all(&efi_redox_dir)
+        self.create_dir(Volume::Esp, efi_redox_dir)
             .map_err(|e| format!("Error creando directorio EFI/redox: {}", e))?;
 
-        // Buscar bootloader compilado
-        let bootloader_paths = vec![
-            "cookbook/recipes/core/bootloader/target/x86_64-unknown-redox/build/bootloader.efi",
-            "cookbook/recipes/core/bootloader/target/x86_64-unknown-redox/stage/boot/bootloader.efi",
-            "build/x86_64/desktop/bootloader-live.efi",
-            "build/x86_64/desktop/bootloader.efi",
-            "cookbook/recipes/core/bootloader/source/build/bootloader_x86_64-unknown-uefi.efi",
-            "build/bootloader.efi",
-        ];
-        
-        let bootloader_source = bootloader_paths.iter()
-            .find(|p| Path::new(p).exists())
-            .ok_or_else(|| {
-                format!(
-                    "Bootloader no encontrado en ninguna ubicación esperada.\n   Rutas buscadas:\n{}",
-                    bootloader_paths.iter()
-                        .map(|p| format!("     - {}", p))
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                )
-            })?;
-
-        println!("   Encontrado bootloader: {}", bootloader_source);
+        // Buscar bootloader compilado (su arquitectura ya se comprobó)
+        let bootloader = self.artifacts.bootloader()?;
+        println!("   Encontrado bootloader: {}", bootloader);
+        let bootloader_source = bootloader.path;
+        let loader = self.removable_loader()?;
+        let secure_boot = config.secure_boot.as_ref();
+        let signing = secure_boot.map(|secure_boot| self.signing_key(secure_boot)).transpose()?;
+        if let Some(key) = &signing {
+            println!("   Firmando para Secure Boot con el certificado {}", key.certificate().name());
+        }
         
         // Copiar bootloader
-        let bootx64_path = format!("{}/BOOTX64.EFI", efi_boot_dir);
+        let removable_path = format!("{}/{}", efi_boot_dir, loader);
         let redox_boot_path = format!("{}/redox-bootloader.efi", efi_redox_dir);
         
-        fs::copy(bootloader_source, &bootx64_path)
-            .map_err(|e| format!("Error copiando bootloader a BOOTX64.EFI: {}", e))?;
+        // Un cargador ajeno en la ruta de arranque por defecto (el del otro
+        // sistema en dual-boot) se conserva; solo se usa si está libre o si
+        // ya tiene el de Redox
+        let keep_removable = self.writer(Volume::Esp).is_dir(Path::new(&removable_path)).is_some()
+            && !self.is_redox_loader(&removable_path)?;
+        if keep_removable {
+            println!("   Se conserva EFI/BOOT/{} del sistema existente", loader);
+        } else {
+            self.install_boot_chain(efi_boot_dir, loader, &bootloader_source, secure_boot, signing.as_ref())?;
+        }
         
-        fs::copy(bootloader_source, &redox_boot_path)
-            .map_err(|e| format!("Error copiando bootloader a redox/: {}", e))?;
-
-        // Crear entrada de arranque con efibootmgr (opcional, puede fallar en VMs)
-        let disk_name = disk.name.trim_end_matches(char::is_numeric);
-        let _ = Command::new("efibootmgr")
-            .args(&[
-                "--create",
-                "--disk", disk_name,
-                "--part", "1",
-                "--label", "Redox OS",
-                "--loader", "\\EFI\\redox\\redox-bootloader.efi",
-            ])
-            .output();
+        self.install_loader(&bootloader_source, &redox_boot_path, signing.as_ref())?;
+        if secure_boot.is_some_and(|secure_boot| secure_boot.shim.is_some()) {
+            let shim = format!("shim{}.efi", self.efi_suffix()?);
+            self.install_boot_chain(efi_redox_dir, &shim, &bootloader_source, secure_boot, signing.as_ref())?;
+        }
+        if let Some(key) = &signing {
+            self.write_mok_certificate(key.certificate())?;
+        }
+
+        // La entrada creada al instalar sigue apuntando al mismo cargador
+        if config.upgrade {
+            println!("   Se conserva la entrada de arranque UEFI existente");
+            return Ok(());
+        }
+
+        // Una imagen se arrancará en otra máquina: no tocar la NVRAM del host
+        if disk.name.starts_with("/dev/loop") {
+            println!("   Imagen de disco: se omite la entrada de arranque UEFI");
+            return Ok(());
+        }
+
+        let efi_number = Self::efi_partition(placed)
+            .expect("la distribución UEFI validada tiene una partición EFI")
+            .number;
+        match self.create_boot_entry(disk, config, efi_number) {
+            Ok(()) => Ok(()),
+            // Sin entrada el firmware aún arranca Redox por la ruta extraíble
+            Err(e) if !keep_removable => {
+                println!("   ⚠️  {}", e);
+                println!("      El firmware arrancará Redox desde EFI/BOOT/{}", loader);
+                Ok(())
+            }
+            Err(e) => Err(format!("{} (EFI/BOOT/{} es del otro sistema: Redox no arrancaría)", e, loader)),
+        }
+    }
+
+    // El cargador es el de una instalación anterior de Redox si coincide con
+    // el bootloader (o el shim) que esta dejó en EFI/redox
+    fn is_redox_loader(&self, path: &str) -> Result<bool, String> {
+        let esp = self.writer(Volume::Esp);
+        let Ok(loader) = esp.read_file(Path::new(path)) else {
+            return Ok(false);
+        };
+        let redox_loaders = [REDOX_BOOTLOADER.to_string(), format!("EFI/redox/shim{}.efi", self.efi_suffix()?)];
+        Ok(redox_loaders.iter().any(|redox_loader| esp.read_file(Path::new(redox_loader)).is_ok_and(|redox| redox == loader)))
+    }
+
+    // Cargador que arranca el firmware desde `dir` con el nombre `first_stage`:
+    // el bootloader o, con shim, shim seguido del bootloader firmado con el
+    // nombre que shim carga (grubx64.efi) y de MokManager
+    fn install_boot_chain(
+        &self,
+        dir: &str,
+        first_stage: &str,
+        bootloader: &Path,
+        secure_boot: Option<&SecureBootConfig>,
+        signing: Option<&SigningKey>,
+    ) -> Result<(), String> {
+        let destination = format!("{}/{}", dir, first_stage);
+        let Some(shim) = secure_boot.and_then(|secure_boot| secure_boot.shim.as_deref()) else {
+            return self.install_loader(bootloader, &destination, signing);
+        };
+
+        let suffix = self.efi_suffix()?;
+        self.copy_file(shim, Volume::Esp, &destination)
+            .map_err(|e| format!("Error copiando shim a {}: {}", destination, e))?;
+        self.install_loader(bootloader, &format!("{}/grub{}.efi", dir, suffix), signing)?;
+        if let Some(mok_manager) = secure_boot.and_then(|secure_boot| secure_boot.mok_manager.as_deref()) {
+            let destination = format!("{}/mm{}.efi", dir, suffix);
+            self.copy_file(mok_manager, Volume::Esp, &destination)
+                .map_err(|e| format!("Error copiando MokManager a {}: {}", destination, e))?;
+        }
+        Ok(())
+    }
+
+    // Copia un cargador UEFI, firmado si se usa Secure Boot. La firma se
+    // comprueba releyendo el fichero escrito
+    fn install_loader(&self, source: &Path, destination: &str, signing: Option<&SigningKey>) -> Result<(), String> {
+        let Some(key) = signing else {
+            self.copy_file(source, Volume::Esp, destination)
+                .map_err(|e| format!("Error copiando bootloader a {}: {}", destination, e))?;
+            return Ok(());
+        };
+
+        let recorded = self.record(|| PlannedAction::SignFile {
+            source: source.display().to_string(),
+            destination: self.volume_path(Volume::Esp, destination),
+            certificate: key.certificate().name(),
+        });
+        if recorded {
+            return Ok(());
+        }
+
+        let image = fs::read(source).map_err(|e| format!("Error leyendo {}: {}", source.display(), e))?;
+        let signed = authenticode::sign(&image, key).map_err(|e| format!("Error firmando {}: {}", source.display(), e))?;
+        let esp = self.writer(Volume::Esp);
+        esp.write_file(Path::new(destination), &signed)
+            .map_err(|e| format!("Error escribiendo {}: {}", destination, e))?;
+        let written = esp.read_file(Path::new(destination)).map_err(|e| format!("Error releyendo {}: {}", destination, e))?;
+        authenticode::verify(&written, key.certificate())
+            .map_err(|e| format!("Verificación fallida: {}: {}", destination, e))
+    }
+
+    // El certificado queda en la ESP en DER, el formato que aceptan mokutil y
+    // el menú de Secure Boot del firmware
+    fn write_mok_certificate(&self, certificate: &Certificate) -> Result<(), String> {
+        let path = self.volume_path(Volume::Esp, MOK_CERTIFICATE);
+        let recorded = self.record(|| PlannedAction::WriteCertificate {
+            path: path.clone(),
+            subject: certificate.name(),
+            fingerprint: certificate.fingerprint(),
+        });
+        if !recorded {
+            self.writer(Volume::Esp).write_file(Path::new(MOK_CERTIFICATE), certificate.der())
+                .map_err(|e| format!("Error escribiendo {}: {}", path, e))?;
+        }
+        println!("   Certificado de firma en {} (SHA-256 {})", MOK_CERTIFICATE, certificate.fingerprint());
+        println!("      Inscríbelo antes de activar Secure Boot: mokutil --import redox-mok.cer");
+        println!("      o desde el menú de Secure Boot del firmware");
+        Ok(())
+    }
+
+    fn signing_key(&self, secure_boot: &SecureBootConfig) -> Result<SigningKey, String> {
+        SigningKey::load(Path::new(&secure_boot.key), Path::new(&secure_boot.certificate))
+    }
+
+    // Con shim la entrada arranca shim, que carga el bootloader firmado
+    fn boot_entry_loader(&self, config: &InstallationConfig) -> Result<String, String> {
+        match config.secure_boot.as_ref().and_then(|secure_boot| secure_boot.shim.as_ref()) {
+            Some(_) => Ok(format!("\\EFI\\redox\\shim{}.efi", self.efi_suffix()?)),
+            None => Ok(BOOT_ENTRY_LOADER.to_string()),
+        }
+    }
+
+    // Crea la entrada "Redox OS" en la NVRAM y borra las que dejaron
+    // instalaciones anteriores una vez que la nueva existe
+    fn create_boot_entry(&self, disk: &DiskInfo, config: &InstallationConfig, efi_number: usize) -> Result<(), String> {
+        if !self.firmware.booted_uefi() {
+            return Err("El sistema no arrancó por UEFI: no se puede crear la entrada de arranque".to_string());
+        }
+
+        let loader = self.boot_entry_loader(config)?;
+        let recorded = self.record(|| PlannedAction::Command {
+            program: "efibootmgr".to_string(),
+            args: boot_entries::create_args(&disk.name, efi_number, BOOT_ENTRY_LABEL, &loader, config.boot_priority),
+        });
+        if recorded {
+            return Ok(());
+        }
+
+        // Solo se sustituyen las entradas de Redox de esta misma ESP: las de
+        // otros discos pertenecen a otras instalaciones
+        let (file, disk_size, sector_size) = gpt::open_disk_readonly(&disk.name)?;
+        let esp_guid = GptTable::read_from(&file, disk_size, sector_size)?
+            .partition(efi_number)
+            .map(|partition| partition.unique_guid)
+            .ok_or_else(|| format!("{} no tiene la partición EFI {}", disk.name, efi_number))?;
+        let manager = BootManager::new(self.runner.as_ref(), &self.firmware);
+        let stale: Vec<_> = manager
+            .labelled(BOOT_ENTRY_LABEL)?
+            .into_iter()
+            .filter(|entry| entry.partition_guid == Some(esp_guid))
+            .collect();
+        let entry = manager.create(&disk.name, efi_number, BOOT_ENTRY_LABEL, &loader, config.boot_priority)?;
+        self.push_undo(UndoAction::RemoveBootEntry(entry.bootnum()));
+        manager.prioritize(&entry, config.boot_priority)?;
+        println!("   ✅ Entrada de arranque Boot{} \"{}\" creada", entry.bootnum(), BOOT_ENTRY_LABEL);
+
+        // La nueva entrada ya funciona: una anterior que no se puede borrar
+        // solo queda duplicada
+        for old in stale {
+            println!("   Eliminando la entrada de arranque anterior Boot{}", old.bootnum());
+            if let Err(e) = manager.delete(&old) {
+                println!("   ⚠️  No se pudo eliminar Boot{}: {}", old.bootnum(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bios_bootloader(&self) -> Result<(Artifact, Vec<u8>), String> {
+        let bootloader = self.artifacts.bios_bootloader()?;
+        let image = fs::read(&bootloader.path)
+            .map_err(|e| format!("Error leyendo {}: {}", bootloader.path.display(), e))?;
+        bios_boot::check_image(&image)
+            .map_err(|e| format!("{}: {}", bootloader.path.display(), e))?;
+        Ok((bootloader, image))
+    }
+
+    // Stage 1 en el MBR y stage 2 en la partición de arranque BIOS de la
+    // tabla ya escrita en el disco
+    fn install_bios_bootloader(&self, disk: &DiskInfo) -> Result<(), String> {
+        let (bootloader, image) = self.read_bios_bootloader()?;
+        println!("   Encontrado bootloader BIOS: {}", bootloader);
+
+        let recorded = self.record(|| PlannedAction::WriteBiosBootloader {
+            source: bootloader.path.display().to_string(),
+            bytes: image.len() as u64,
+        });
+        if recorded {
+            return Ok(());
+        }
+
+        let (file, disk_size, sector_size) = self.open_disk(disk)?;
+        let table = GptTable::read_from(&file, disk_size, sector_size)?;
+        bios_boot::write_stages(&file, &table, &image)?;
+        println!("   ✅ Stage 1 escrito en el MBR y stage 2 en la partición de arranque BIOS");
 
         Ok(())
     }
 
     fn install_kernel(&self, _disk: &DiskInfo) -> Result<(), String> {
         // Buscar kernel compilado
-        let kernel_paths = vec![
-            "cookbook/recipes/core/kernel/target/x86_64-unknown-redox/build/kernel",
-            "cookbook/recipes/core/kernel/target/x86_64-unknown-redox/stage/boot/kernel",
-            "build/x86_64/desktop/kernel",
-            "build/x86_64/desktop/harddrive/kernel",
-            "cookbook/recipes/core/kernel/source/target/x86_64-unknown-redox/release/kernel",
-        ];
-        
-        let kernel_source = kernel_paths.iter()
-            .find(|p| Path::new(p).exists())
-            .ok_or_else(|| {
-                format!(
-                    "Kernel no encontrado en ninguna ubicación esperada.\n   Rutas buscadas:\n{}",
-                    kernel_paths.iter()
-                        .map(|p| format!("     - {}", p))
-                        .collect::<Vec<_>>()
-                        .join("\n")
-                )
-            })?;
-
-        println!("   Encontrado kernel: {}", kernel_source);
+        let kernel = self.artifacts.kernel()?;
+        println!("   Encontrado kernel: {}", kernel);
+        let kernel_source = kernel.path;
         
         // El kernel debe estar en la partición RedoxFS (segunda partición)
         // El bootloader monta RedoxFS y busca el kernel ahí
-        let kernel_dest_root = format!("{}/boot/kernel", self.root_mount_point);
-        fs::copy(kernel_source, &kernel_dest_root)
+        self.copy_file(&kernel_source, Volume::Root, "boot/kernel")
             .map_err(|e| format!("Error copiando kernel a /boot/kernel en RedoxFS: {}", e))?;
         
         println!("   ✅ Kernel copiado a /boot/kernel en partición RedoxFS");
 
         // Buscar e instalar initfs si existe
-        let initfs_paths = vec![
-            "cookbook/recipes/core/base-initfs/target/x86_64-unknown-redox/build/initfs.img",
-            "build/x86_64/desktop/initfs.img",
-            "build/x86_64/desktop/harddrive/initfs.img",
-        ];
-        
-        for initfs_path in initfs_paths {
-            if Path::new(initfs_path).exists() {
-                println!("   Encontrado initfs: {}", initfs_path);
-                
-                // Verificar tamaño del initfs
-                let initfs_size = fs::metadata(initfs_path)
-                    .map_err(|e| format!("Error obteniendo tamaño del initfs: {}", e))?
-                    .len();
-                println!("   Tamaño del initfs: {} bytes", initfs_size);
-                
-                // El initfs debe estar en la partición RedoxFS (segunda partición)
-                // El bootloader monta RedoxFS y busca el initfs ahí como "initfs" (sin extensión)
-                let initfs_dest_root = format!("{}/boot/initfs", self.root_mount_point);
-                fs::copy(initfs_path, &initfs_dest_root)
-                    .map_err(|e| format!("Error copiando initfs a /boot/initfs en RedoxFS: {}", e))?;
-                
-                // Verificar que se copió correctamente
-                let copied_size = fs::metadata(&initfs_dest_root)
-                    .map_err(|e| format!("Error verificando initfs copiado: {}", e))?
-                    .len();
-                println!("   Initfs copiado: {} bytes", copied_size);
-                
-                if initfs_size == copied_size {
-                    println!("   ✅ Initfs copiado correctamente a /boot/initfs en partición RedoxFS");
-                } else {
-                    return Err(format!("Error: initfs no se copió correctamente ({} vs {} bytes)", initfs_size, copied_size));
-                }
-                break;
+        if let Some(initfs) = self.artifacts.initfs() {
+            println!("   Encontrado initfs: {}", initfs);
+            let initfs_path = initfs.path;
+            
+            // Verificar tamaño del initfs
+            let initfs_size = fs::metadata(&initfs_path)
+                .map_err(|e| format!("Error obteniendo tamaño del initfs: {}", e))?
+                .len();
+            println!("   Tamaño del initfs: {} bytes", initfs_size);
+            
+            // El initfs debe estar en la partición RedoxFS (segunda partición)
+            // El bootloader monta RedoxFS y busca el initfs ahí como "initfs" (sin extensión)
+            let copied_size = self.copy_file(&initfs_path, Volume::Root, "boot/initfs")
+                .map_err(|e| format!("Error copiando initfs a /boot/initfs en RedoxFS: {}", e))?;
+            
+            // Verificar que se copió correctamente
+            println!("   Initfs copiado: {} bytes", copied_size);
+            
+            if initfs_size == copied_size {
+                println!("   ✅ Initfs copiado correctamente a /boot/initfs en partición RedoxFS");
+            } else {
+                return Err(format!("Error: initfs no se copió correctamente ({} vs {} bytes)", initfs_size, copied_size));
             }
         }
 
         Ok(())
     }
 
-    fn install_filesystem(&self, _disk: &DiskInfo) -> Result<(), String> {
+    fn install_filesystem(&self, _disk: &DiskInfo, config: &InstallationConfig, build_config: &BuildConfig) -> Result<(), String> {
         println!("   Instalando sistema de archivos Redox (igual que harddrive.img)...");
         
         // Replicar exactamente la estructura del harddrive.img oficial
-        self.install_redox_filesystem_structure()?;
+        self.install_redox_filesystem_structure(config, build_config)?;
 
         Ok(())
     }
 
-    fn install_redox_filesystem_structure(&self) -> Result<(), String> {
-        // Crear estructura de directorios exacta como el instalador oficial
-        println!("   Creando estructura de directorios Redox...");
+    fn install_redox_filesystem_structure(&self, config: &InstallationConfig, build_config: &BuildConfig) -> Result<(), String> {
+        println!(
+            "   ✅ {} paquetes y {} ficheros en la configuración",
+            build_config.packages.len(),
+            build_config.files.len()
+        );
         
-        // Directorios principales (como en config/base.toml)
-        let redox_dirs = vec![
-            "/boot", "/usr", "/usr/bin", "/usr/lib", "/usr/libexec", "/usr/share", "/usr/include",
-            "/etc", "/var", "/var/log", "/var/lib", "/var/lib/pkg",
-            "/tmp", "/home", "/root",
-            "/proc", "/sys", "/dev", "/mnt", "/opt",
-        ];
+        // Directorios que redox_installer crea fuera de [[files]]: /boot para el
+        // kernel, /tmp para init y los directorios personales de [users]
+        let redox_dirs = ["/boot", "/tmp", "/home", "/root"];
         
         for dir in redox_dirs {
-            let full_path = format!("{}{}", self.root_mount_point, dir);
-            fs::create_dir_all(&full_path)
+            self.create_dir(Volume::Root, dir)
                 .map_err(|e| format!("Error creando directorio {}: {}", dir, e))?;
         }
-
-        // Crear enlaces simbólicos (usrmerge como en Redox oficial)
-        self.create_redox_symlinks()?;
         
-        // Crear archivos de configuración del sistema
-        self.create_redox_config_files()?;
+        // [[files]] previos a los paquetes (directorios, usrmerge, init.d, os-release...)
+        println!("   Creando ficheros de la configuración...");
+        self.install_files(build_config.preinstall_files())?;
         
         // Crear directorio /boot/ en la partición raíz (requerido por Redox)
         self.create_boot_directory()?;
         
         // Instalar aplicaciones compiladas
         println!("   Instalando aplicaciones de Redox...");
-        self.install_redox_applications()?;
-        
-        Ok(())
-    }
-
-    fn create_redox_symlinks(&self) -> Result<(), String> {
-        println!("   Creando enlaces simbólicos (usrmerge)...");
+        self.install_redox_applications(&build_config.packages, &config.packages, &[])?;
         
-        // Crear enlaces simbólicos como en Redox oficial
-        let symlinks = vec![
-            ("/bin", "/usr/bin"),
-            ("/lib", "/usr/lib"),
-            ("/include", "/usr/include"),
-            ("/sbin", "/usr/sbin"),
-        ];
-        
-        for (link, target) in symlinks {
-            let link_path = format!("{}{}", self.root_mount_point, link);
-            let target_path = format!("{}{}", self.root_mount_point, target);
-            
-            // Eliminar si existe
-            let _ = fs::remove_file(&link_path);
-            let _ = fs::remove_dir(&link_path);
-            
-            // Crear enlace simbólico
-            std::os::unix::fs::symlink(target, &link_path)
-                .map_err(|e| format!("Error creando enlace {} -> {}: {}", link, target, e))?;
-        }
+        // [[files]] marcados con postinstall = true
+        self.install_files(build_config.postinstall_files())?;
         
-        println!("   ✅ Enlaces simbólicos creados");
-        Ok(())
-    }
-
-    fn create_redox_config_files(&self) -> Result<(), String> {
-        println!("   Creando archivos de configuración Redox...");
+        self.install_accounts(build_config)?;
         
-        // /etc/hostname
-        let hostname_path = format!("{}/etc/hostname", self.root_mount_point);
-        fs::write(&hostname_path, "redox")
+        // Hostname y ficheros extra del fichero de respuestas (al final para
+        // que puedan sobrescribir la configuración de los paquetes)
+        self.write_file(Volume::Root, "/etc/hostname", &config.hostname)
             .map_err(|e| format!("Error creando /etc/hostname: {}", e))?;
-
-        // /usr/lib/os-release
-        let os_release = r#"PRETTY_NAME="Redox OS 0.9.0"
-NAME="Redox OS"
-VERSION_ID="0.9.0"
-VERSION="0.9.0"
-ID="redox-os"
-
-HOME_URL="https://redox-os.org/"
-DOCUMENTATION_URL="https://redox-os.org/docs/"
-SUPPORT_URL="https://redox-os.org/community/"
-"#;
-        let os_release_path = format!("{}/usr/lib/os-release", self.root_mount_point);
-        fs::write(&os_release_path, os_release)
-            .map_err(|e| format!("Error creando /usr/lib/os-release: {}", e))?;
-
-        // /etc/os-release (enlace simbólico)
-        let etc_os_release_path = format!("{}/etc/os-release", self.root_mount_point);
-        std::os::unix::fs::symlink("../usr/lib/os-release", &etc_os_release_path)
-            .map_err(|e| format!("Error creando enlace /etc/os-release: {}", e))?;
-
-        // /etc/pkg.d/50_redox
-        let pkg_path = format!("{}/etc/pkg.d", self.root_mount_point);
-        fs::create_dir_all(&pkg_path)
-            .map_err(|e| format!("Error creando directorio /etc/pkg.d: {}", e))?;
         
-        let redox_pkg_path = format!("{}/etc/pkg.d/50_redox", self.root_mount_point);
-        fs::write(&redox_pkg_path, "https://static.redox-os.org/pkg")
-            .map_err(|e| format!("Error creando /etc/pkg.d/50_redox: {}", e))?;
-
-        // Scripts de inicialización
-        self.create_init_scripts()?;
+        if !config.files.is_empty() {
+            println!("   Instalando {} ficheros extra...", config.files.len());
+            self.install_files(config.files.iter())?;
+        }
         
         println!("   ✅ Archivos de configuración creados");
         Ok(())
     }
 
-    fn create_init_scripts(&self) -> Result<(), String> {
-        // /usr/lib/init.d/00_base
-        let init_base = r#"# clear and recreate tmpdir with 0o1777 permission
-/usr/bin/rm -r /tmp
-/usr/bin/mkdir -m a=rwxt /tmp
+    // Usuarios y grupos de [users] y [groups]: /etc/passwd, /etc/shadow,
+    // /etc/group y los directorios personales, como redox_installer
+    fn install_accounts(&self, build_config: &BuildConfig) -> Result<(), String> {
+        if build_config.users.is_empty() && build_config.groups.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "   Creando {} usuarios y {} grupos...",
+            build_config.users.len(),
+            build_config.groups.len()
+        );
+        let accounts = build_config.accounts()?;
 
-/usr/bin/ipcd
-/usr/bin/ptyd
-/usr/bin/sudo --daemon
-"#;
-        let init_base_dir = format!("{}/usr/lib/init.d", self.root_mount_point);
-        fs::create_dir_all(&init_base_dir)
-            .map_err(|e| format!("Error creando directorio /usr/lib/init.d: {}", e))?;
-        
-        let init_base_path = format!("{}/usr/lib/init.d/00_base", self.root_mount_point);
-        fs::write(&init_base_path, init_base)
-            .map_err(|e| format!("Error creando /usr/lib/init.d/00_base: {}", e))?;
+        for home in &accounts.homes {
+            let path = &home.path;
+            let mut dirs = vec![path.clone()];
+            if let Some((xdg_dirs, _)) = home.xdg_dirs() {
+                dirs.extend(xdg_dirs.iter().map(|dir| format!("{}/{}", path, dir)));
+            }
+            for dir in &dirs {
+                self.create_dir(Volume::Root, dir)
+                    .and_then(|_| self.set_mode(Volume::Root, dir, 0o700))
+                    .and_then(|_| self.set_owner(Volume::Root, dir, home.uid, home.gid))
+                    .map_err(|e| format!("Error creando directorio personal {}: {}", dir, e))?;
+            }
+            if let Some((_, user_dirs)) = home.xdg_dirs() {
+                let user_dirs_path = format!("{}/.config/user-dirs.dirs", path);
+                self.write_file(Volume::Root, &user_dirs_path, user_dirs)
+                    .and_then(|_| self.set_mode(Volume::Root, &user_dirs_path, 0o600))
+                    .and_then(|_| self.set_owner(Volume::Root, &user_dirs_path, home.uid, home.gid))
+                    .map_err(|e| format!("Error creando {}: {}", user_dirs_path, e))?;
+            }
+        }
 
-        // /usr/lib/init.d/00_drivers
-        let init_drivers = r#"/usr/bin/pcid-spawner /etc/pcid.d/
-"#;
-        let init_drivers_path = format!("{}/usr/lib/init.d/00_drivers", self.root_mount_point);
-        fs::write(&init_drivers_path, init_drivers)
-            .map_err(|e| format!("Error creando /usr/lib/init.d/00_drivers: {}", e))?;
+        let etc = "/etc";
+        self.create_dir(Volume::Root, etc)
+            .map_err(|e| format!("Error creando directorio /etc: {}", e))?;
+        for (name, contents, mode) in [
+            ("passwd", &accounts.passwd, None),
+            ("shadow", &accounts.shadow, Some(0o600)),
+            ("group", &accounts.group, Some(0o600)),
+        ] {
+            if contents.is_empty() {
+                continue;
+            }
+            let path = format!("{}/{}", etc, name);
+            self.write_file(Volume::Root, &path, contents)
+                .and_then(|_| mode.map_or(Ok(()), |mode| self.set_mode(Volume::Root, &path, mode)))
+                .map_err(|e| format!("Error creando /etc/{}: {}", name, e))?;
+        }
 
+        println!("   ✅ /etc/passwd, /etc/shadow y /etc/group creados");
         Ok(())
     }
-    
+
     fn create_boot_directory(&self) -> Result<(), String> {
         println!("   Creando directorio /boot/ en partición raíz...");
         
         // Crear directorio /boot/ en la partición raíz (donde Redox lo busca)
-        let boot_dir = format!("{}/boot", self.root_mount_point);
-        fs::create_dir_all(&boot_dir)
+        self.create_dir(Volume::Root, "/boot")
             .map_err(|e| format!("Error creando directorio /boot en raíz: {}", e))?;
         
         // Crear archivo placeholder para indicar que el directorio existe
-        let placeholder_path = format!("{}/boot/.redox_boot", self.root_mount_point);
-        fs::write(&placeholder_path, "Redox OS Boot Directory\nCreated by installer\n")
+        self.write_file(Volume::Root, REDOX_BOOT_MARKER, "Redox OS Boot Directory\nCreated by installer\n")
             .map_err(|e| format!("Error creando placeholder en /boot: {}", e))?;
         
         println!("   ✅ Directorio /boot/ creado en partición raíz");
         Ok(())
     }
     
-    fn install_redox_applications(&self) -> Result<(), String> {
+    // Los ficheros que ya existan bajo `preserve` no se sobrescriben
+    fn install_redox_applications(&self, packages: &[String], extra_packages: &[String], preserve: &[&str]) -> Result<(), String> {
         let mut total_apps = 0;
+        let mut missing = Vec::new();
         
-        // Lista de recetas core con aplicaciones compiladas
-        // Orden importante: drivers-initfs debe ir antes que drivers para evitar conflictos
-        let core_recipes = vec![
-            "uutils",           // rm, mkdir, ls, etc.
-            "base",             // ipcd, ptyd
-            "userutils",        // sudo
-            "coreutils",        // utilidades básicas
-            "drivers-initfs",   // drivers (pcid-spawner en /bin/)
-            "drivers",          // drivers adicionales
-            "ion",              // shell
-            "extrautils",       // utilidades adicionales
-            "netutils",         // utilidades de red
-        ];
+        for package in packages {
+            self.check_interrupted()?;
+            match self.artifacts.recipe_stage(package) {
+                Some(stage) => {
+                    println!("     Instalando {} desde {}...", package, stage);
+                    let count = self.install_stage_directory(&stage.path.to_string_lossy(), preserve)?;
+                    println!("     ✅ {} - {} archivos instalados", package, count);
+                    total_apps += count;
+                }
+                None => missing.push(package.as_str()),
+            }
+        }
         
-        for recipe in core_recipes {
-            let stage_path = format!(
-                "cookbook/recipes/core/{}/target/x86_64-unknown-redox/stage",
-                recipe
-            );
+        // Paquetes adicionales pedidos en el fichero de respuestas
+        for package in extra_packages {
+            if packages.contains(package) {
+                continue;
+            }
             
-            if Path::new(&stage_path).exists() {
-                println!("     Instalando {} ...", recipe);
-                let count = self.install_stage_directory(&stage_path)?;
-                if count > 0 {
-                    println!("     ✅ {} - {} archivos instalados", recipe, count);
+            match self.artifacts.recipe_stage(package) {
+                Some(stage) => {
+                    println!("     Instalando {} desde {}...", package, stage);
+                    let count = self.install_stage_directory(&stage.path.to_string_lossy(), preserve)?;
+                    println!("     ✅ {} - {} archivos instalados", package, count);
                     total_apps += count;
                 }
+                None => {
+                    return Err(format!(
+                        "Paquete {} no compilado (no se encontró cookbook/recipes/*/{}/target/{}/stage)",
+                        package, package, self.artifacts.location().target()
+                    ));
+                }
             }
         }
         
+        if !missing.is_empty() {
+            println!("   ⚠️  Paquetes de la configuración sin compilar: {}", missing.join(", "));
+        }
+        
         if total_apps > 0 {
             println!("   ✅ {} archivos de aplicaciones instalados en total", total_apps);
         } else {
@@ -850,76 +1698,159 @@ SUPPORT_URL="https://redox-os.org/community/"
         Ok(())
     }
 
-    fn install_stage_directory(&self, stage_path: &str) -> Result<usize, String> {
+    // Aplica entradas [[files]] igual que redox_installer: data, symlink, directory y mode
+    fn install_files<'a>(&self, files: impl Iterator<Item = &'a FileConfig>) -> Result<(), String> {
+        for file in files {
+            let dest = &file.path;
+            
+            if let Some(parent) = Path::new(dest).parent() {
+                self.create_dir(Volume::Root, &parent.to_string_lossy())
+                    .map_err(|e| format!("Error creando directorio para {}: {}", file.path, e))?;
+            }
+            
+            if file.directory {
+                self.create_dir(Volume::Root, dest)
+                    .map_err(|e| format!("Error creando directorio {}: {}", file.path, e))?;
+            } else if file.symlink {
+                self.symlink(Volume::Root, &file.data, dest)
+                    .map_err(|e| format!("Error creando enlace {} -> {}: {}", file.path, file.data, e))?;
+            } else {
+                self.write_file(Volume::Root, dest, &file.data)
+                    .map_err(|e| format!("Error creando {}: {}", file.path, e))?;
+            }
+            
+            if let Some(mode) = file.mode {
+                if !file.symlink {
+                    self.set_mode(Volume::Root, dest, mode)
+                        .map_err(|e| format!("Error cambiando permisos de {}: {}", file.path, e))?;
+                }
+            }
+            
+            println!("     - {}", file.path);
+        }
+        
+        Ok(())
+    }
+
+    fn install_stage_directory(&self, stage_path: &str, preserve: &[&str]) -> Result<usize, String> {
+        if self.dry_run() {
+            let (files, bytes) = Self::stage_tree_size(Path::new(stage_path))?;
+            self.record(|| PlannedAction::CopyTree {
+                source: stage_path.to_string(),
+                destination: self.volume_path(Volume::Root, ""),
+                files,
+                bytes,
+            });
+            return Ok(files);
+        }
+
+        // Copiar el stage completo de la receta, como hace pkgar al instalar el paquete
+        let preserve: Vec<PathBuf> = preserve.iter().map(|dir| PathBuf::from(dir.trim_start_matches('/'))).collect();
+        self.copy_stage_tree(Path::new(stage_path), Path::new(""), &preserve)
+    }
+
+    // Número de ficheros (y enlaces) y bytes que copiaría copy_stage_tree
+    fn stage_tree_size(src: &Path) -> Result<(usize, u64), String> {
+        let mut files = 0;
+        let mut bytes = 0;
+
+        let entries = fs::read_dir(src)
+            .map_err(|e| format!("Error leyendo directorio {}: {}", src.display(), e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Error leyendo entrada de {}: {}", src.display(), e))?;
+            let metadata = fs::symlink_metadata(entry.path())
+                .map_err(|e| format!("Error leyendo {}: {}", entry.path().display(), e))?;
+
+            if metadata.is_dir() {
+                let (sub_files, sub_bytes) = Self::stage_tree_size(&entry.path())?;
+                files += sub_files;
+                bytes += sub_bytes;
+            } else {
+                files += 1;
+                if metadata.is_file() {
+                    bytes += metadata.len();
+                }
+            }
+        }
+
+        Ok((files, bytes))
+    }
+
+    // `dest` y `preserve` son rutas relativas a la raíz de root
+    fn copy_stage_tree(&self, src: &Path, dest: &Path, preserve: &[PathBuf]) -> Result<usize, String> {
+        let root = self.writer(Volume::Root);
         let mut file_count = 0;
         
-        // Buscar subdirectorios en stage (bin, usr/bin, etc.)
-        let subdirs_to_check = vec![
-            ("bin", "/bin"),
-            ("sbin", "/sbin"),
-            ("usr/bin", "/usr/bin"),
-            ("usr/sbin", "/usr/sbin"),
-            ("usr/lib", "/usr/lib"),
-            ("etc", "/etc"),
-        ];
+        let entries = fs::read_dir(src)
+            .map_err(|e| format!("Error leyendo directorio {}: {}", src.display(), e))?;
         
-        for (subdir, dest_base) in subdirs_to_check {
-            let source_dir = format!("{}/{}", stage_path, subdir);
+        for entry in entries {
+            if rollback::interrupted() {
+                return Err("Instalación interrumpida con Ctrl-C".to_string());
+            }
+            let entry = entry.map_err(|e| format!("Error leyendo entrada de {}: {}", src.display(), e))?;
+            let source_path = entry.path();
+            let dest_path = dest.join(entry.file_name());
+            let file_type = entry.file_type()
+                .map_err(|e| format!("Error leyendo tipo de {}: {}", source_path.display(), e))?;
             
-            if Path::new(&source_dir).exists() {
-                let dest_dir = format!("{}{}", self.root_mount_point, dest_base);
-                
-                // Asegurar que el directorio destino existe
-                fs::create_dir_all(&dest_dir)
-                    .map_err(|e| format!("Error creando directorio {}: {}", dest_base, e))?;
-                
-                // Copiar archivos del directorio
-                let entries = fs::read_dir(&source_dir)
-                    .map_err(|e| format!("Error leyendo directorio {}: {}", source_dir, e))?;
-                
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let source_file = entry.path();
-                        if source_file.is_file() {
-                            let file_name = source_file.file_name().unwrap();
-                            let dest_file = format!("{}/{}", dest_dir, file_name.to_string_lossy());
-                            
-                            fs::copy(&source_file, &dest_file)
-                                .map_err(|e| format!("Error copiando {}: {}", file_name.to_string_lossy(), e))?;
-                            
-                            file_count += 1;
-                        }
-                    }
-                }
+            if file_type.is_dir() {
+                // Si el destino es un enlace a directorio (p. ej. /bin -> usr/bin) se sigue
+                root.create_dir_all(&dest_path)
+                    .map_err(|e| format!("Error creando directorio {}: {}", dest_path.display(), e))?;
+                file_count += self.copy_stage_tree(&source_path, &dest_path, preserve)?;
+                continue;
+            }
+
+            let existing = root.is_dir(&dest_path);
+            if existing.is_some() && preserve.iter().any(|dir| dest_path.starts_with(dir)) {
+                continue;
+            }
+            
+            // No escribir a través de enlaces existentes
+            if existing == Some(false) {
+                let _ = root.remove_file(&dest_path);
+            }
+            
+            if file_type.is_symlink() {
+                let target = fs::read_link(&source_path)
+                    .map_err(|e| format!("Error leyendo enlace {}: {}", source_path.display(), e))?;
+                root.symlink(&target, &dest_path)
+                    .map_err(|e| format!("Error creando enlace {}: {}", dest_path.display(), e))?;
+            } else {
+                root.copy_file(&source_path, &dest_path)
+                    .map_err(|e| format!("Error copiando {}: {}", source_path.display(), e))?;
             }
+            
+            file_count += 1;
         }
         
         Ok(file_count)
     }
 
-    fn create_config_files(&self, disk: &DiskInfo) -> Result<(), String> {
-        let (_efi_partition, root_partition) = self.get_partition_names(disk);
+    fn create_config_files(&self, config: &InstallationConfig, placed: &[PlacedPartition]) -> Result<(), String> {
+        let root_partition = &Self::root_partition(placed).device;
+        let has_efi = Self::efi_partition(placed).is_some();
         
         // Crear directorio boot/ (requerido por Redox)
-        let boot_dir = format!("{}/boot", self.efi_mount_point);
-        fs::create_dir_all(&boot_dir)
-            .map_err(|e| format!("Error creando directorio /boot: {}", e))?;
+        if has_efi {
+            self.create_dir(Volume::Esp, "boot")
+                .map_err(|e| format!("Error creando directorio /boot: {}", e))?;
+        }
         
         // El directorio boot/ ya se creó en install_kernel()
         // Los archivos kernel e initfs ya están en /boot/ donde el bootloader los busca
         
         // Leer UUID del RedoxFS creado
-        let redoxfs_uuid = match fs::read_to_string("/tmp/redox_install_uuid") {
-            Ok(uuid) => {
-                let uuid_clean = uuid.trim().to_string();
-                println!("   UUID del RedoxFS: {}", uuid_clean);
-                uuid_clean
+        match self.redoxfs_uuid.borrow().as_deref() {
+            Some(uuid) => {
+                println!("   UUID del RedoxFS: {}", uuid);
             },
-            Err(_) => {
+            None => {
                 println!("   ⚠️  No se pudo leer UUID, usando partición por defecto");
-                root_partition.clone()
             }
-        };
+        }
         
         // Crear archivo de configuración de arranque (con rutas correctas)
         // El bootloader busca el kernel en /boot/kernel y /boot/initfs
@@ -933,28 +1864,42 @@ initfs=/boot/initfs
         );
 
         // Crear configuración en partición EFI (para bootloader)
-        let boot_conf_path_efi = format!("{}/boot/redox.conf", self.efi_mount_point);
-        fs::write(&boot_conf_path_efi, &boot_conf)
-            .map_err(|e| format!("Error creando /boot/redox.conf en EFI: {}", e))?;
+        if has_efi {
+            self.write_file(Volume::Esp, "boot/redox.conf", &boot_conf)
+                .map_err(|e| format!("Error creando /boot/redox.conf en EFI: {}", e))?;
+        }
         
         // Crear configuración en partición raíz (donde Redox la busca)
-        let boot_conf_path_root = format!("{}/boot/redox.conf", self.root_mount_point);
-        fs::write(&boot_conf_path_root, &boot_conf)
+        self.write_file(Volume::Root, "/boot/redox.conf", &boot_conf)
             .map_err(|e| format!("Error creando /boot/redox.conf en raíz: {}", e))?;
         
         // También crear en la raíz del sistema de archivos
-        let boot_conf_path_root_alt = format!("{}/redox.conf", self.root_mount_point);
-        fs::write(&boot_conf_path_root_alt, &boot_conf)
+        self.write_file(Volume::Root, "/redox.conf", &boot_conf)
             .map_err(|e| format!("Error creando redox.conf en raíz: {}", e))?;
 
+        self.create_fstab(placed)?;
+
+        // Sin ESP el bootloader BIOS lee todo de RedoxFS
+        if !has_efi {
+            println!("   ✅ Configuración de arranque creada en /boot/redox.conf (raíz)");
+            return Ok(());
+        }
+
+        // La raíz de la ESP compartida pertenece al otro sistema: no se
+        // añaden startup.nsh ni README.txt
+        if config.dual_boot {
+            println!("   ✅ Configuración de arranque creada en /boot/redox.conf (EFI y raíz)");
+            return Ok(());
+        }
+
         // Crear startup.nsh para arranque automático en UEFI
-        let startup_script = "\\EFI\\BOOT\\BOOTX64.EFI\n";
-        let startup_path = format!("{}/startup.nsh", self.efi_mount_point);
-        fs::write(&startup_path, startup_script)
+        let loader = self.removable_loader()?;
+        let startup_script = format!("\\EFI\\BOOT\\{}\n", loader);
+        self.write_file(Volume::Esp, "startup.nsh", &startup_script)
             .map_err(|e| format!("Error creando startup.nsh: {}", e))?;
 
         // Crear README
-        let readme = r#"Redox OS - Sistema Operativo en Rust
+        let readme = format!(r#"Redox OS - Sistema Operativo en Rust
 ====================================
 
 Este disco contiene una instalación completa de Redox OS.
@@ -963,7 +1908,7 @@ Estructura de archivos:
 /boot/redox_kernel    - Kernel de Redox OS
 /boot/initfs.img      - Imagen initfs (si existe)
 /boot/redox.conf      - Configuración de arranque
-/EFI/BOOT/BOOTX64.EFI - Bootloader UEFI
+/EFI/BOOT/{:<12}- Bootloader UEFI
 
 Para arrancar:
 1. Reinicia tu computadora
@@ -974,10 +1919,9 @@ Documentación: https://doc.redox-os.org
 Sitio web: https://www.redox-os.org
 
 Desarrollado con ❤️ en Rust
-"#;
+"#, loader);
 
-        let readme_path = format!("{}/README.txt", self.efi_mount_point);
-        fs::write(&readme_path, readme)
+        self.write_file(Volume::Esp, "README.txt", &readme)
             .map_err(|e| format!("Error creando README.txt: {}", e))?;
 
         println!("   ✅ Estructura de arranque creada:");
@@ -991,81 +1935,1559 @@ Desarrollado con ❤️ en Rust
         Ok(())
     }
 
-    fn unmount_partitions(&self, _disk: &DiskInfo) -> Result<(), String> {
-        // Sincronizar datos
-        Command::new("sync").output().ok();
-        std::thread::sleep(std::time::Duration::from_secs(1));
-
-        // Desmontar partición root
-        println!("   Desmontando {}...", self.root_mount_point);
-        let _ = Command::new("umount")
-            .arg(&self.root_mount_point)
-            .output();
+    // Describe las particiones adicionales y el swap por etiqueta. Solo se
+    // escribe cuando la distribución tiene algo más que EFI + root.
+    fn create_fstab(&self, placed: &[PlacedPartition]) -> Result<(), String> {
+        let entries: Vec<String> = placed
+            .iter()
+            .map(|partition| &partition.spec)
+            .filter_map(|spec| match (&spec.filesystem, spec.mount_point.as_deref()) {
+                (PartitionFilesystem::Swap, _) => Some(format!("LABEL={} none swap sw", spec.label)),
+                (PartitionFilesystem::RedoxFS, Some(mount_point)) if mount_point != "/" => {
+                    Some(format!("LABEL={} {} redoxfs defaults", spec.label, mount_point))
+                }
+                (PartitionFilesystem::Ext4, Some(mount_point)) if mount_point != "/" => {
+                    Some(format!("LABEL={} {} ext4 defaults", spec.label, mount_point))
+                }
+                _ => None,
+            })
+            .collect();
 
-        // Desmontar partición EFI
-        println!("   Desmontando {}...", self.efi_mount_point);
-        let _ = Command::new("umount")
-            .arg(&self.efi_mount_point)
-            .output();
+        if entries.is_empty() {
+            return Ok(());
+        }
 
-        // Limpiar directorios de montaje
-        let _ = fs::remove_dir(&self.root_mount_point);
-        let _ = fs::remove_dir(&self.efi_mount_point);
+        self.create_dir(Volume::Root, "/etc")
+            .map_err(|e| format!("Error creando /etc: {}", e))?;
 
-        Ok(())
-    }
+        let fstab = format!(
+            "# Particiones adicionales creadas por redox-disk-installer\n# <dispositivo> <punto de montaje> <tipo> <opciones>\n{}\n",
+            entries.join("\n")
+        );
+        self.write_file(Volume::Root, "/etc/fstab", &fstab)
+            .map_err(|e| format!("Error creando /etc/fstab: {}", e))?;
 
-    fn print_installation_summary(&self, disk: &DiskInfo, config: &InstallationConfig) -> Result<(), String> {
-        let (efi_partition, root_partition) = self.get_partition_names(disk);
-        
-        println!("╔═══════════════════════════════════════════════════╗");
-        println!("║          📊 Resumen de Instalación 📊            ║");
-        println!("╠═══════════════════════════════════════════════════╣");
-        println!("║  Disco:              {}                  ", disk.name);
-        println!("║  Partición EFI:      {} (FAT32, {} MB)", efi_partition, config.efi_size_mb);
-        println!("║  Partición root:     {} ({:?})      ", root_partition, config.filesystem_type);
-        println!("║  Bootloader:         UEFI (BOOTX64.EFI)          ║");
-        println!("║  Kernel:             Redox OS                     ║");
-        println!("╚═══════════════════════════════════════════════════╝");
-        
+        println!("   ✅ /etc/fstab creado con {} entradas", entries.len());
         Ok(())
     }
 
-    fn get_partition_names(&self, disk: &DiskInfo) -> (String, String) {
-        if disk.name.contains("nvme") || disk.name.contains("mmcblk") {
-            (
-                format!("{}p1", disk.name),
-                format!("{}p2", disk.name),
-            )
-        } else {
-            (
-                format!("{}1", disk.name),
-                format!("{}2", disk.name),
-            )
+    fn unmount_partitions(&self, placed: &[PlacedPartition]) -> Result<(), String> {
+        // Sincronizar datos
+        self.execute("sync", &[]).ok();
+        self.pause(Duration::from_secs(1));
+
+        // Primero las particiones montadas dentro de root, después root y
+        // por último la partición EFI
+        let has_efi = Self::efi_partition(placed).is_some();
+        let mut targets: Vec<String> = Self::extra_mounts(placed)
+            .iter()
+            .rev()
+            .map(|(_, mount_point)| format!("{}{}", self.root_mount_point, mount_point))
+            .collect();
+        if self.direct_root.borrow().is_none() {
+            targets.push(self.root_mount_point.clone());
         }
+        if has_efi {
+            targets.push(self.efi_mount_point.clone());
+        }
+
+        // Un montaje que no se suelta puede no haber escrito todo al disco
+        let mut failures = Vec::new();
+        if let Some(root) = self.root_writer.borrow_mut().take() {
+            println!("   Cerrando RedoxFS de {}...", Self::root_partition(placed).device);
+            if let Err(e) = root.sync() {
+                let e = format!("Error cerrando RedoxFS: {}", e);
+                eprintln!("   ⚠️  {}", e);
+                failures.push(e);
+            }
+        }
+        for target in &targets {
+            println!("   Desmontando {}...", target);
+            if let Err(e) = self.unmount(target) {
+                eprintln!("   ⚠️  {}", e);
+                failures.push(e);
+            }
+        }
+
+        // Limpiar directorios de montaje
+        if !self.dry_run() {
+            let _ = fs::remove_dir(&self.root_mount_point);
+            if has_efi {
+                let _ = fs::remove_dir(&self.efi_mount_point);
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(format!("No se pudieron desmontar las particiones: {}", failures.join("; ")));
+        }
+        Ok(())
     }
 
-    fn copy_directory_recursive(&self, src: &str, dest: &str) -> Result<(), String> {
-        let entries = fs::read_dir(src)
-            .map_err(|e| format!("Error leyendo directorio {}: {}", src, e))?;
+    // Los montajes de redoxfs se sueltan con su guard, que espera a que el
+    // daemon termine de escribir
+    fn unmount(&self, target: &str) -> Result<(), String> {
+        if let Some(mount) = self.undo.take_fuse(target) {
+            return mount.unmount();
+        }
 
-        for entry in entries {
-            let entry = entry.map_err(|e| format!("Error leyendo entrada: {}", e))?;
-            let path = entry.path();
-            let file_name = path.file_name().unwrap();
-            let dest_path = format!("{}/{}", dest, file_name.to_string_lossy());
-
-            if path.is_dir() {
-                fs::create_dir_all(&dest_path)
-                    .map_err(|e| format!("Error creando directorio {}: {}", dest_path, e))?;
-                self.copy_directory_recursive(&path.to_string_lossy(), &dest_path)?;
-            } else {
-                fs::copy(&path, &dest_path)
-                    .map_err(|e| format!("Error copiando archivo {}: {}", file_name.to_string_lossy(), e))?;
+        let output = self.execute("umount", &[target])
+            .map_err(|e| format!("Error ejecutando umount: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("umount {}: {}", target, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+
+    fn print_installation_summary(&self, disk: &DiskInfo, config: &InstallationConfig, placed: &[PlacedPartition]) -> Result<(), String> {
+        println!("╔═══════════════════════════════════════════════════╗");
+        println!("║          📊 Resumen de Instalación 📊            ║");
+        println!("╠═══════════════════════════════════════════════════╣");
+        println!("║  Disco:              {}                  ", disk.name);
+        for partition in placed {
+            let reused = if partition.reused { " (existente)" } else { "" };
+            println!(
+                "║  {:<19} {}{}",
+                partition.device,
+                Self::describe_partition(&partition.spec),
+                reused
+            );
+        }
+        match config.boot_mode {
+            BootMode::Uefi => println!("║  Bootloader:         UEFI ({})", self.removable_loader()?),
+            BootMode::Hybrid => println!("║  Bootloader:         UEFI ({}) y BIOS (MBR)", self.removable_loader()?),
+            BootMode::Bios => println!("║  Bootloader:         BIOS (MBR)"),
+        }
+        if let Some(secure_boot) = &config.secure_boot {
+            let chain = if secure_boot.shim.is_some() { "firmado, arranca con shim" } else { "firmado" };
+            println!("║  Secure Boot:        {} ({})", chain, MOK_CERTIFICATE);
+        }
+        println!("║  Kernel:             Redox OS                     ║");
+        println!("╚═══════════════════════════════════════════════════╝");
+        
+        Ok(())
+    }
+
+    fn describe_partition(spec: &PartitionSpec) -> String {
+        let filesystem = match spec.filesystem {
+            PartitionFilesystem::Efi => "EFI (FAT32)",
+            PartitionFilesystem::RedoxFS => "RedoxFS",
+            PartitionFilesystem::Ext4 => "ext4",
+            PartitionFilesystem::Swap => "swap",
+        };
+        match &spec.mount_point {
+            Some(mount_point) => format!("{} - {}, {}, en {}", spec.label, filesystem, spec.size.describe(), mount_point),
+            None => format!("{} - {}, {}", spec.label, filesystem, spec.size.describe()),
+        }
+    }
+
+    fn check_interrupted(&self) -> Result<(), String> {
+        if rollback::interrupted() {
+            return Err("Instalación interrumpida con Ctrl-C".to_string());
+        }
+        Ok(())
+    }
+
+    // En --dry-run no se ha hecho nada que deshacer
+    fn push_undo(&self, action: UndoAction) {
+        if !self.dry_run() {
+            self.undo.push(action);
+        }
+    }
+
+    fn dry_run(&self) -> bool {
+        self.plan.borrow().is_some()
+    }
+
+    fn plan_step(&self, title: &str) {
+        if let Some(plan) = self.plan.borrow_mut().as_mut() {
+            plan.begin_step(title);
+        }
+    }
+
+    // Devuelve true si la acción se registró en el plan en lugar de ejecutarse
+    fn record(&self, action: impl FnOnce() -> PlannedAction) -> bool {
+        match self.plan.borrow_mut().as_mut() {
+            Some(plan) => {
+                plan.record(action());
+                true
             }
+            None => false,
+        }
+    }
+
+    // En --dry-run el disco se abre solo para lectura
+    fn open_disk(&self, disk: &DiskInfo) -> Result<(fs::File, u64, u64), String> {
+        if self.dry_run() {
+            gpt::open_disk_readonly(&disk.name)
+        } else {
+            gpt::open_disk(&disk.name)
+        }
+    }
+
+    // Las operaciones que modifican el disco o el sistema pasan por estos
+    // métodos: en --dry-run se registran en el plan y se dan por correctas
+
+    fn execute(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        let recorded = self.record(|| PlannedAction::Command {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        });
+        if recorded {
+            return Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
+        self.runner.run(program, args)
+    }
+
+    fn pause(&self, duration: Duration) {
+        if !self.dry_run() {
+            self.runner.wait(duration);
+        }
+    }
+
+    // La ESP siempre se monta; root solo si no se escribe directamente
+    fn writer(&self, volume: Volume) -> Rc<dyn FilesystemWriter> {
+        match volume {
+            Volume::Esp => Rc::new(MountedWriter::new(&self.efi_mount_point)),
+            Volume::Root => match self.root_writer.borrow().as_ref() {
+                Some(writer) => writer.clone(),
+                None => Rc::new(MountedWriter::new(&self.root_mount_point)),
+            },
+        }
+    }
+
+    // Ruta con la que se muestra `path` en el plan y en los mensajes
+    fn volume_path(&self, volume: Volume, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+        let mount_point = match (volume, self.direct_root.borrow().as_deref()) {
+            (Volume::Esp, _) => &self.efi_mount_point,
+            (Volume::Root, None) => &self.root_mount_point,
+            (Volume::Root, Some(device)) => return format!("{}:/{}", device, path),
+        };
+        if path.is_empty() {
+            return mount_point.clone();
+        }
+        format!("{}/{}", mount_point, path)
+    }
+
+    fn create_dir(&self, volume: Volume, path: &str) -> io::Result<()> {
+        if self.record(|| PlannedAction::CreateDirectory { path: self.volume_path(volume, path) }) {
+            return Ok(());
+        }
+        self.writer(volume).create_dir_all(Path::new(path))
+    }
+
+    fn copy_file(&self, source: impl AsRef<Path>, volume: Volume, destination: &str) -> io::Result<u64> {
+        let source = source.as_ref();
+        if self.dry_run() {
+            let bytes = fs::metadata(source)?.len();
+            self.record(|| PlannedAction::CopyFile {
+                source: source.display().to_string(),
+                destination: self.volume_path(volume, destination),
+                bytes,
+            });
+            return Ok(bytes);
         }
+        self.writer(volume).copy_file(source, Path::new(destination))
+    }
+
+    fn write_file(&self, volume: Volume, path: &str, contents: &str) -> io::Result<()> {
+        let recorded = self.record(|| PlannedAction::WriteFile {
+            path: self.volume_path(volume, path),
+            contents: contents.to_string(),
+        });
+        if recorded {
+            return Ok(());
+        }
+        self.writer(volume).write_file(Path::new(path), contents.as_bytes())
+    }
+
+    // Sustituye lo que hubiera en `path`
+    fn symlink(&self, volume: Volume, target: &str, path: &str) -> io::Result<()> {
+        let recorded = self.record(|| PlannedAction::Symlink {
+            path: self.volume_path(volume, path),
+            target: target.to_string(),
+        });
+        if recorded {
+            return Ok(());
+        }
+        self.writer(volume).symlink(Path::new(target), Path::new(path))
+    }
+
+    fn set_mode(&self, volume: Volume, path: &str, mode: u32) -> io::Result<()> {
+        if self.record(|| PlannedAction::SetMode { path: self.volume_path(volume, path), mode }) {
+            return Ok(());
+        }
+        self.writer(volume).set_mode(Path::new(path), mode)
+    }
+
+    fn set_owner(&self, volume: Volume, path: &str, uid: u32, gid: u32) -> io::Result<()> {
+        if self.record(|| PlannedAction::SetOwner { path: self.volume_path(volume, path), uid, gid }) {
+            return Ok(());
+        }
+        self.writer(volume).set_owner(Path::new(path), uid, gid)
+    }
+
+    fn efi_partition(placed: &[PlacedPartition]) -> Option<&PlacedPartition> {
+        placed
+            .iter()
+            .find(|partition| partition.spec.filesystem == PartitionFilesystem::Efi)
+    }
+
+    fn root_partition(placed: &[PlacedPartition]) -> &PlacedPartition {
+        placed
+            .iter()
+            .find(|partition| partition.spec.mount_point.as_deref() == Some("/"))
+            .expect("la distribución validada tiene una partición root")
+    }
+
+    fn extra_mounts(placed: &[PlacedPartition]) -> Vec<(usize, String)> {
+        let specs: Vec<PartitionSpec> = placed.iter().map(|partition| partition.spec.clone()).collect();
+        partition_layout::extra_mounts(&specs)
+    }
 
+    // Un bootloader de otra arquitectura, o que falte, dejaría el disco sin
+    // arrancar: se comprueba antes de tocarlo
+    fn check_bootloaders(&self, boot_mode: BootMode, secure_boot: Option<&SecureBootConfig>) -> Result<(), String> {
+        let arch = self.artifacts.location().target_arch()?;
+        if boot_mode.uefi() {
+            let bootloader = self.artifacts.bootloader()?;
+            target_arch::check_bootloader(&bootloader.path, arch)?;
+            // Una clave que no corresponde al certificado o un bootloader
+            // que no se puede firmar también se detectan aquí
+            if let Some(secure_boot) = secure_boot {
+                let key = self.signing_key(secure_boot)?;
+                let image = fs::read(&bootloader.path)
+                    .map_err(|e| format!("Error leyendo {}: {}", bootloader.path.display(), e))?;
+                authenticode::sign(&image, &key)
+                    .map_err(|e| format!("No se puede firmar {}: {}", bootloader.path.display(), e))?;
+                for loader in secure_boot.shim.iter().chain(&secure_boot.mok_manager) {
+                    target_arch::check_bootloader(Path::new(loader), arch)?;
+                }
+            }
+        }
+        if boot_mode.bios() {
+            if !arch.supports_bios() {
+                return Err(format!("{} no tiene bootloader BIOS: solo admite arranque UEFI", arch.name()));
+            }
+            self.read_bios_bootloader()?;
+        }
         Ok(())
     }
+
+    // Nombre del cargador en EFI/BOOT/ para la arquitectura de destino
+    fn removable_loader(&self) -> Result<&'static str, String> {
+        Ok(self.artifacts.location().target_arch()?.removable_loader())
+    }
+
+    fn efi_suffix(&self) -> Result<&'static str, String> {
+        Ok(self.artifacts.location().target_arch()?.efi_suffix())
+    }
+
+    // Si el nombre del disco acaba en dígito (nvme0n1, mmcblk0, loop0)
+    // el kernel separa el número de partición con una "p"
+    fn partition_device(&self, disk: &DiskInfo, number: usize) -> String {
+        sysfs::partition_node(&disk.name, number)
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeRunner;
+    use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+    use tempfile::TempDir;
+
+    const DISK_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+
+    const MKFS_OK: &str = "redoxfs-mkfs: created filesystem on disk2, reserved 1 blocks, size 1024 MB, uuid 5c3f0a64-1b2e-4a9d-8f41-6e0c2d7b9a13\n";
+
+    const TEST_CONFIG: &str = r#"
+[packages]
+ion = {}
+
+[[files]]
+path = "/usr/bin"
+data = ""
+directory = true
+mode = 0o755
+
+[[files]]
+path = "/bin"
+data = "usr/bin"
+symlink = true
+
+[[files]]
+path = "/etc/hostname"
+data = "redox"
+"#;
+
+    const BOOTLOADER: &str = "src/cookbook/recipes/core/bootloader/target/x86_64-unknown-redox/stage/boot/bootloader.efi";
+
+    // Bootloader PE de la arquitectura con ese campo Machine
+    fn bootloader_image(machine: u16) -> Vec<u8> {
+        target_arch::pe_stub(machine, b"bootloader")
+    }
+
+    struct Fixture {
+        dir: TempDir,
+        disk: DiskInfo,
+        config: InstallationConfig,
+    }
+
+    impl Fixture {
+        fn new(filesystem_type: FilesystemType) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let path = |relative: &str| dir.path().join(relative);
+            let write = |relative: &str, contents: &str| {
+                let file = path(relative);
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(file, contents).unwrap();
+            };
+
+            // Sin entrada en sysfs, el nodo de cada partición es el nombre del
+            // disco seguido de su número
+            for node in ["disk1", "disk2", "redoxfs-mkfs", "redoxfs", "mountinfo"] {
+                write(node, "");
+            }
+            // Disco disperso donde se escribe la tabla GPT
+            fs::File::create(path("disk")).unwrap().set_len(DISK_SIZE).unwrap();
+            // El sistema arrancó por UEFI y aún no hay entradas de arranque
+            fs::create_dir_all(path("firmware/efivars")).unwrap();
+            let bootloader = path(BOOTLOADER);
+            fs::create_dir_all(bootloader.parent().unwrap()).unwrap();
+            fs::write(bootloader, bootloader_image(0x8664)).unwrap();
+            write("src/cookbook/recipes/core/kernel/target/x86_64-unknown-redox/stage/boot/kernel", "kernel");
+            write("src/cookbook/recipes/core/base-initfs/target/x86_64-unknown-redox/build/initfs.img", "initfs");
+            write("src/cookbook/recipes/core/ion/target/x86_64-unknown-redox/stage/usr/bin/ion", "ion");
+            write("src/config/test.toml", TEST_CONFIG);
+
+            let disk = DiskInfo {
+                name: path("disk").to_string_lossy().into_owned(),
+                size_bytes: DISK_SIZE,
+                logical_sector_size: 512,
+                physical_sector_size: 512,
+                model: "Test Disk".to_string(),
+                disk_type: "Virtual Disk".to_string(),
+                ..DiskInfo::default()
+            };
+            let config = InstallationConfig {
+                filesystem_type,
+                filesystem_config: path("src/config/test.toml").to_string_lossy().into_owned(),
+                hostname: "lab-01".to_string(),
+                backup_dir: path("backups").to_string_lossy().into_owned(),
+                ..InstallationConfig::default()
+            };
+
+            Self { dir, disk, config }
+        }
+
+        fn path(&self, relative: &str) -> String {
+            self.dir.path().join(relative).to_string_lossy().into_owned()
+        }
+
+        fn installer(&self, runner: &Rc<FakeRunner>) -> DirectInstaller {
+            let mut installer = DirectInstaller::with_runner(runner.clone());
+            installer.efi_mount_point = self.path("efi");
+            installer.root_mount_point = self.path("root");
+            installer.artifacts = ArtifactResolver::with_tools(
+                BuildLocation {
+                    source_root: self.dir.path().join("src"),
+                    ..BuildLocation::default()
+                },
+                Some(self.dir.path().join("redoxfs-mkfs")),
+                Some(self.dir.path().join("redoxfs")),
+                Vec::new(),
+            );
+            installer.sys_block = self.dir.path().join("sys");
+            installer.mountinfo = self.dir.path().join("mountinfo");
+            installer.firmware = UefiFirmware::new(self.dir.path().join("firmware"));
+            installer
+        }
+
+        fn efivars(&self) -> PathBuf {
+            self.dir.path().join("firmware/efivars")
+        }
+
+        // efibootmgr simulado sobre las variables de firmware del fixture, y
+        // un redoxfs que anota sus montajes en el mountinfo del fixture
+        fn runner(&self) -> FakeRunner {
+            let mountinfo = self.dir.path().join("mountinfo");
+            FakeRunner::new()
+                .on("efibootmgr", boot_entries::fake_efibootmgr(&self.efivars()))
+                .on(&format!("{} ", self.path("redoxfs")), {
+                    let mountinfo = mountinfo.clone();
+                    move |args| {
+                        let mut mounts = fs::read_to_string(&mountinfo).unwrap();
+                        mounts.push_str(&format!("60 25 0:52 / {} rw - fuse redoxfs rw\n", args[1]));
+                        fs::write(&mountinfo, mounts).unwrap();
+                    }
+                })
+                .on("fusermount -u ", move |args| {
+                    let target = format!(" {} ", args[1]);
+                    let mounts: String = fs::read_to_string(&mountinfo)
+                        .unwrap()
+                        .lines()
+                        .filter(|line| !line.contains(&target))
+                        .map(|line| format!("{}\n", line))
+                        .collect();
+                    fs::write(&mountinfo, mounts).unwrap();
+                })
+        }
+
+        fn partitioning_commands(&self) -> Vec<String> {
+            let disk = &self.disk.name;
+            vec![format!("mkfs.vfat -F 32 -n REDOX_EFI {}1", disk)]
+        }
+
+        fn mount_and_finish_commands(&self) -> Vec<String> {
+            let disk = &self.disk.name;
+            let root = self.path("root");
+            let (mount_root, umount_root) = match self.config.filesystem_type {
+                FilesystemType::RedoxFS => (
+                    format!("{} {}2 {}", self.path("redoxfs"), disk, root),
+                    format!("fusermount -u {}", root),
+                ),
+                _ => (format!("mount -t auto {}2 {}", disk, root), format!("umount {}", root)),
+            };
+            vec![
+                format!("mount {}1 {}", disk, self.path("efi")),
+                mount_root,
+                format!(
+                    "efibootmgr --create --disk {} --part 1 --label Redox OS --loader \\EFI\\redox\\redox-bootloader.efi",
+                    disk
+                ),
+                "sync".to_string(),
+                umount_root,
+                format!("umount {}", self.path("efi")),
+            ]
+        }
+    }
+
+    fn redoxfs_runner(fixture: &Fixture) -> FakeRunner {
+        fixture.runner()
+            .respond("blockdev --getsize64", 0, "1073741824\n", "")
+            .respond(&fixture.path("redoxfs-mkfs"), 0, "", MKFS_OK)
+    }
+
+    #[test]
+    fn redoxfs_install_runs_expected_commands() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let root_partition = format!("{}2", fixture.disk.name);
+        let mut expected = fixture.partitioning_commands();
+        expected.extend([
+            format!("blockdev --getsize64 {}", root_partition),
+            format!("wipefs -a {}", root_partition),
+            format!("dd if=/dev/zero of={} bs=1M count=10 conv=notrunc", root_partition),
+            "sync".to_string(),
+            format!("{} {}", fixture.path("redoxfs-mkfs"), root_partition),
+            "sync".to_string(),
+        ]);
+        expected.extend(fixture.mount_and_finish_commands());
+        assert_eq!(runner.calls(), expected);
+    }
+
+    #[test]
+    fn ext4_install_runs_expected_commands() {
+        let fixture = Fixture::new(FilesystemType::Ext4);
+        let runner = Rc::new(fixture.runner());
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let mut expected = fixture.partitioning_commands();
+        expected.push(format!("mkfs.ext4 -F -L REDOX_ROOT {}2", fixture.disk.name));
+        expected.extend(fixture.mount_and_finish_commands());
+        assert_eq!(runner.calls(), expected);
+    }
+
+    #[test]
+    fn install_populates_root_from_build_config() {
+        let fixture = Fixture::new(FilesystemType::Ext4);
+        let users = "\n[users.user]\npassword = \"\"\n\n[groups.sudo]\ngid = 1\nmembers = [\"user\"]\n";
+        fs::write(fixture.path("src/config/test.toml"), format!("{}{}", TEST_CONFIG, users)).unwrap();
+        let runner = Rc::new(fixture.runner());
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let root = fixture.dir.path().join("root");
+        assert_eq!(fs::read_to_string(root.join("etc/hostname")).unwrap(), "lab-01");
+        assert_eq!(fs::read_link(root.join("bin")).unwrap(), Path::new("usr/bin"));
+        assert_eq!(fs::read_to_string(root.join("usr/bin/ion")).unwrap(), "ion");
+        assert_eq!(fs::read_to_string(root.join("boot/kernel")).unwrap(), "kernel");
+        assert_eq!(fs::read_to_string(root.join("boot/initfs")).unwrap(), "initfs");
+        assert_eq!(fs::read_to_string(root.join("etc/passwd")).unwrap(), "user;1000;1000;user;/home/user;/bin/ion\n");
+        assert_eq!(fs::read_to_string(root.join("etc/shadow")).unwrap(), "user;\n");
+        assert_eq!(fs::read_to_string(root.join("etc/group")).unwrap(), "user;x;1000;user\nsudo;x;1;user\n");
+        assert_eq!(fs::metadata(root.join("etc/shadow")).unwrap().permissions().mode() & 0o777, 0o600);
+        let home = fs::metadata(root.join("home/user/Documents")).unwrap();
+        assert_eq!((home.uid(), home.gid(), home.permissions().mode() & 0o777), (1000, 1000, 0o700));
+        assert!(fs::read_to_string(root.join("home/user/.config/user-dirs.dirs")).unwrap().contains("XDG_DESKTOP_DIR"));
+        let efi = fixture.dir.path().join("efi");
+        assert_eq!(fs::read(efi.join("EFI/BOOT/BOOTX64.EFI")).unwrap(), bootloader_image(0x8664));
+    }
+
+    #[test]
+    fn aarch64_install_uses_arch_artifacts_and_loader_name() {
+        let fixture = Fixture::new(FilesystemType::Ext4);
+        for (x86_64, contents) in [
+            (BOOTLOADER.to_string(), bootloader_image(0xAA64)),
+            (
+                "src/cookbook/recipes/core/kernel/target/x86_64-unknown-redox/stage/boot/kernel".to_string(),
+                b"kernel-aarch64".to_vec(),
+            ),
+        ] {
+            let file = PathBuf::from(fixture.path(&x86_64.replace("x86_64", "aarch64")));
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, contents).unwrap();
+        }
+        let runner = Rc::new(fixture.runner());
+        let mut installer = fixture.installer(&runner);
+        installer.artifacts = ArtifactResolver::with_tools(
+            BuildLocation {
+                source_root: fixture.dir.path().join("src"),
+                arch: "aarch64".to_string(),
+                ..BuildLocation::default()
+            },
+            Some(fixture.dir.path().join("redoxfs-mkfs")),
+            Some(fixture.dir.path().join("redoxfs")),
+            Vec::new(),
+        );
+
+        installer.install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let efi = fixture.dir.path().join("efi");
+        assert_eq!(fs::read(efi.join("EFI/BOOT/BOOTAA64.EFI")).unwrap(), bootloader_image(0xAA64));
+        assert!(!efi.join("EFI/BOOT/BOOTX64.EFI").exists());
+        assert!(fs::read_to_string(efi.join("startup.nsh")).unwrap().contains("BOOTAA64.EFI"));
+        let kernel = fixture.dir.path().join("root/boot/kernel");
+        assert_eq!(fs::read_to_string(kernel).unwrap(), "kernel-aarch64");
+    }
+
+    #[test]
+    fn bootloader_for_another_arch_is_rejected_before_touching_disk() {
+        let fixture = Fixture::new(FilesystemType::Ext4);
+        fs::write(fixture.path(BOOTLOADER), bootloader_image(0xAA64)).unwrap();
+        let runner = Rc::new(fixture.runner());
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+
+        assert!(err.contains("es para aarch64, no para x86_64"), "{}", err);
+        assert!(runner.calls().is_empty());
+    }
+
+    fn with_bios_bootloader(fixture: &Fixture, boot_mode: BootMode) -> InstallationConfig {
+        let bootloader = PathBuf::from(fixture.path(&BOOTLOADER.replace("bootloader.efi", "bootloader.bios")));
+        fs::write(bootloader, bios_boot::image_stub(b"stage2")).unwrap();
+        fs::write(fixture.path("disk3"), "").unwrap();
+        InstallationConfig { boot_mode, ..fixture.config.clone() }
+    }
+
+    #[test]
+    fn hybrid_install_writes_bios_stages_next_to_the_esp() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let config = with_bios_bootloader(&fixture, BootMode::Hybrid);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap();
+
+        let (file, size, sector_size) = gpt::open_disk_readonly(&fixture.disk.name).unwrap();
+        let table = GptTable::read_from(&file, size, sector_size).unwrap();
+        let bios = table.partition(1).unwrap();
+        assert_eq!((bios.type_guid, bios.first_lba, bios.last_lba), (gpt::BIOS_BOOT, 34, 2047));
+        assert_eq!(table.partition(2).unwrap().type_guid, gpt::EFI_SYSTEM_PARTITION);
+        let mut start = vec![0u8; 34 * 512 + 6];
+        file.read_exact_at(&mut start, 0).unwrap();
+        assert_eq!(start[..440], bios_boot::image_stub(b"stage2")[..440]);
+        assert_eq!(&start[34 * 512..], b"stage2");
+
+        let calls = runner.calls();
+        assert!(calls.contains(&format!("mkfs.vfat -F 32 -n REDOX_EFI {}2", fixture.disk.name)));
+        assert!(calls.iter().any(|call| call.starts_with("efibootmgr --create") && call.contains("--part 2")));
+        assert!(Path::new(&fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).exists());
+    }
+
+    // Bootloader que se puede firmar y configuración de Secure Boot con la
+    // clave de prueba
+    fn with_secure_boot(fixture: &Fixture) -> InstallationConfig {
+        fs::write(fixture.path(BOOTLOADER), authenticode::pe_image(0x8664, b"bootloader")).unwrap();
+        let (key, certificate) = authenticode::write_test_key(fixture.dir.path());
+        InstallationConfig {
+            secure_boot: Some(SecureBootConfig {
+                key: key.to_string_lossy().into_owned(),
+                certificate: certificate.to_string_lossy().into_owned(),
+                ..SecureBootConfig::default()
+            }),
+            ..fixture.config.clone()
+        }
+    }
+
+    #[test]
+    fn secure_boot_install_signs_both_loaders_and_leaves_the_certificate() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let config = with_secure_boot(&fixture);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap();
+
+        let certificate = Certificate::load(&fixture.dir.path().join("secure-boot.crt")).unwrap();
+        for loader in ["efi/EFI/BOOT/BOOTX64.EFI", "efi/EFI/redox/redox-bootloader.efi"] {
+            authenticode::verify(&fs::read(fixture.path(loader)).unwrap(), &certificate).unwrap();
+        }
+        assert_eq!(fs::read(fixture.path("efi/EFI/redox/redox-mok.cer")).unwrap(), certificate.der());
+        assert!(runner.calls().iter().any(|call| call.ends_with("--loader \\EFI\\redox\\redox-bootloader.efi")));
+    }
+
+    #[test]
+    fn shim_loads_the_signed_bootloader() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let mut config = with_secure_boot(&fixture);
+        for (name, contents) in [("shimx64.efi", b"shim".as_slice()), ("mmx64.efi", b"mok manager")] {
+            fs::write(fixture.path(name), target_arch::pe_stub(0x8664, contents)).unwrap();
+        }
+        let secure_boot = config.secure_boot.as_mut().unwrap();
+        secure_boot.shim = Some(fixture.path("shimx64.efi"));
+        secure_boot.mok_manager = Some(fixture.path("mmx64.efi"));
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap();
+
+        let certificate = Certificate::load(&fixture.dir.path().join("secure-boot.crt")).unwrap();
+        let read = |relative: &str| fs::read(fixture.path(relative)).unwrap();
+        for dir in ["efi/EFI/BOOT", "efi/EFI/redox"] {
+            authenticode::verify(&read(&format!("{}/grubx64.efi", dir)), &certificate).unwrap();
+            assert_eq!(read(&format!("{}/mmx64.efi", dir)), read("mmx64.efi"));
+        }
+        assert_eq!(read("efi/EFI/BOOT/BOOTX64.EFI"), read("shimx64.efi"));
+        assert_eq!(read("efi/EFI/redox/shimx64.efi"), read("shimx64.efi"));
+        assert!(runner.calls().iter().any(|call| call.ends_with("--loader \\EFI\\redox\\shimx64.efi")));
+    }
+
+    #[test]
+    fn secure_boot_key_must_match_the_certificate() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let mut config = with_secure_boot(&fixture);
+        let other = fixture.path("other.crt");
+        fs::write(&other, include_str!("../testdata/other.crt")).unwrap();
+        config.secure_boot.as_mut().unwrap().certificate = other;
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap_err();
+
+        assert!(err.contains("no corresponde al certificado"), "{}", err);
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn bios_install_has_no_efi_partition() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let config = with_bios_bootloader(&fixture, BootMode::Bios);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap();
+
+        let (file, size, sector_size) = gpt::open_disk_readonly(&fixture.disk.name).unwrap();
+        let table = GptTable::read_from(&file, size, sector_size).unwrap();
+        let types: Vec<_> = table.partitions.iter().map(|partition| partition.type_guid).collect();
+        assert_eq!(types, [gpt::BIOS_BOOT, gpt::REDOX_ROOT]);
+        let calls = runner.calls();
+        assert!(calls.iter().all(|call| !call.contains("mkfs.vfat") && !call.contains("efibootmgr")), "{:?}", calls);
+        assert!(!calls.contains(&format!("umount {}", fixture.path("efi"))));
+        assert!(fs::read_to_string(fixture.path("root/boot/redox.conf")).unwrap().contains("root="));
+        assert!(!Path::new(&fixture.path("efi")).exists());
+
+        // El bootloader BIOS no lee ext4
+        let fixture = Fixture::new(FilesystemType::Ext4);
+        let config = with_bios_bootloader(&fixture, BootMode::Bios);
+        let runner = Rc::new(fixture.runner());
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap_err();
+        assert!(err.contains("RedoxFS"), "{}", err);
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn partition_names_follow_kernel_naming() {
+        let installer = DirectInstaller::with_runner(Rc::new(FakeRunner::new()));
+        let name = |disk: &str, number: usize| {
+            installer.partition_device(&DiskInfo {
+                name: disk.to_string(),
+                ..DiskInfo::default()
+            }, number)
+        };
+
+        assert_eq!(name("/dev/sda", 1), "/dev/sda1");
+        assert_eq!(name("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+        assert_eq!(name("/dev/loop0", 3), "/dev/loop0p3");
+    }
+
+    #[test]
+    fn partition_nodes_are_taken_from_sysfs() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        // Nombres que no siguen la regla del dígito final, p. ej. un disco
+        // device-mapper con particiones creadas por kpartx
+        for (partition, number) in [("disk-part1", 1), ("disk-part2", 2)] {
+            let dir = fixture.dir.path().join("sys/disk").join(partition);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("partition"), format!("{}\n", number)).unwrap();
+            fs::write(fixture.path(partition), "").unwrap();
+        }
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(calls[0], format!("mkfs.vfat -F 32 -n REDOX_EFI {}", fixture.path("disk-part1")));
+        assert!(calls.contains(&format!("{} {} {}", fixture.path("redoxfs"), fixture.path("disk-part2"), fixture.path("root"))), "{:?}", calls);
+        let config = fs::read_to_string(fixture.path("root/boot/redox.conf")).unwrap();
+        assert!(config.contains(&format!("root={}", fixture.path("disk-part2"))), "{}", config);
+    }
+
+    #[test]
+    fn workstation_layout_creates_swap_home_and_free_tail() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        for node in ["disk3", "disk4"] {
+            fs::write(fixture.path(node), "").unwrap();
+        }
+        let spec = |label: &str, size, filesystem, mount_point: Option<&str>| PartitionSpec {
+            label: label.to_string(),
+            size,
+            filesystem,
+            mount_point: mount_point.map(str::to_string),
+        };
+        fixture.config.partitions = vec![
+            spec("ESP", PartitionSize::Mebibytes(256), PartitionFilesystem::Efi, None),
+            spec("REDOX_SWAP", PartitionSize::Mebibytes(1024), PartitionFilesystem::Swap, None),
+            spec("REDOX_ROOT", PartitionSize::Percent(40), PartitionFilesystem::RedoxFS, Some("/")),
+            spec("REDOX_HOME", PartitionSize::Percent(25), PartitionFilesystem::Ext4, Some("/home")),
+        ];
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let disk = &fixture.disk.name;
+        let calls = runner.calls();
+        let position = |expected: &str| {
+            calls.iter().position(|call| call == expected).unwrap_or_else(|| panic!("falta: {}", expected))
+        };
+        position(&format!("mkfs.vfat -F 32 -n ESP {}1", disk));
+        position(&format!("mkswap -L REDOX_SWAP {}2", disk));
+        position(&format!("{} {}3", fixture.path("redoxfs-mkfs"), disk));
+        position(&format!("mkfs.ext4 -F -L REDOX_HOME {}4", disk));
+        let home = format!("{}/home", fixture.path("root"));
+        assert!(position(&format!("{} {}3 {}", fixture.path("redoxfs"), disk, fixture.path("root")))
+            < position(&format!("mount -t auto {}4 {}", disk, home)));
+        assert!(position(&format!("umount {}", home)) < position(&format!("fusermount -u {}", fixture.path("root"))));
+        assert!(calls.iter().any(|call| call.contains("--part 1 ")));
+
+        let fstab = fs::read_to_string(fixture.dir.path().join("root/etc/fstab")).unwrap();
+        assert!(fstab.contains("LABEL=REDOX_SWAP none swap sw"));
+        assert!(fstab.contains("LABEL=REDOX_HOME /home ext4 defaults"));
+        let boot_conf = fs::read_to_string(fixture.dir.path().join("root/boot/redox.conf")).unwrap();
+        assert!(boot_conf.contains(&format!("root={}3", disk)));
+
+        // Lo que no ocupan las particiones (más de 1 GiB) queda sin asignar
+        let file = fs::File::open(disk).unwrap();
+        let mut entry = [0u8; 128];
+        file.read_exact_at(&mut entry, 1024 + 3 * 128).unwrap();
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        assert!((last_lba + 1) * 512 + 1024 * 1024 * 1024 < DISK_SIZE);
+        file.read_exact_at(&mut entry, 1024 + 4 * 128).unwrap();
+        assert!(entry.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn invalid_layout_is_rejected_before_touching_disk() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.partitions = partition_layout::default_layout(512, &FilesystemType::RedoxFS);
+        fixture.config.partitions[1].mount_point = Some("/home".to_string());
+        let runner = Rc::new(fixture.runner());
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.contains("montada en /"), "{}", err);
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn install_writes_gpt_with_typed_partitions() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let file = fs::File::open(&fixture.disk.name).unwrap();
+        let mut disk = vec![0u8; 2048];
+        file.read_exact_at(&mut disk, 0).unwrap();
+        let mut backup_header = [0u8; 8];
+        file.read_exact_at(&mut backup_header, DISK_SIZE - 512).unwrap();
+
+        assert_eq!(&disk[510..512], &[0x55, 0xAA]);
+        assert_eq!(&disk[512..520], b"EFI PART");
+        assert_eq!(&backup_header, b"EFI PART");
+
+        let efi_entry = &disk[1024..1152];
+        assert_eq!(&efi_entry[0..16], gpt::EFI_SYSTEM_PARTITION.as_bytes());
+        assert_eq!(u64::from_le_bytes(efi_entry[32..40].try_into().unwrap()), 2048);
+        assert_eq!(u64::from_le_bytes(efi_entry[40..48].try_into().unwrap()), 2048 + 512 * 2048 - 1);
+        let root_entry = &disk[1152..1280];
+        assert_eq!(&root_entry[0..16], gpt::REDOX_ROOT.as_bytes());
+        assert_ne!(&efi_entry[16..32], &root_entry[16..32]);
+    }
+
+    // Disco con la ESP y la partición de otro sistema, y 5 GB libres al final
+    fn write_existing_os(fixture: &Fixture) -> GptTable {
+        let mut table = GptTable::new(DISK_SIZE, 512).unwrap();
+        table.add_partition("EFI system partition", gpt::EFI_SYSTEM_PARTITION, Some(256 * 1024 * 1024)).unwrap();
+        table.add_partition("debian", gpt::LINUX_FILESYSTEM, Some(3 * 1024 * 1024 * 1024)).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&fixture.disk.name).unwrap();
+        table.write_to(&file).unwrap();
+        table
+    }
+
+    #[test]
+    fn dual_boot_reuses_esp_and_keeps_existing_partitions() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.dual_boot = true;
+        fs::write(fixture.path("disk3"), "").unwrap();
+        fs::create_dir_all(fixture.path("efi/EFI/BOOT")).unwrap();
+        fs::write(fixture.path("efi/EFI/BOOT/BOOTX64.EFI"), "grub").unwrap();
+        let existing = write_existing_os(&fixture);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let file = fs::File::open(&fixture.disk.name).unwrap();
+        let table = GptTable::read_from(&file, DISK_SIZE, 512).unwrap();
+        assert_eq!(table.partitions.len(), 3);
+        assert_eq!(&table.partitions[..2], &existing.partitions[..]);
+        let root = table.partition(3).unwrap();
+        assert_eq!(root.type_guid, gpt::REDOX_ROOT);
+        assert_eq!(root.first_lba, existing.partitions[1].last_lba + 1);
+
+        let calls = runner.calls();
+        assert!(!calls.iter().any(|call| call.starts_with("mkfs.vfat")), "{:?}", calls);
+        assert!(calls.contains(&format!("{} {}3", fixture.path("redoxfs-mkfs"), fixture.disk.name)));
+        assert!(calls.contains(&format!("mount {}1 {}", fixture.disk.name, fixture.path("efi"))));
+        assert_eq!(fs::read_to_string(fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).unwrap(), "grub");
+        assert_eq!(fs::read(fixture.path("efi/EFI/redox/redox-bootloader.efi")).unwrap(), bootloader_image(0x8664));
+        assert!(!Path::new(&fixture.path("efi/startup.nsh")).exists());
+        assert!(fs::read_to_string(fixture.path("root/boot/redox.conf")).unwrap().contains(&format!("root={}3", fixture.disk.name)));
+
+        // La tabla anterior queda guardada para poder restaurarla
+        let backups: Vec<_> = fs::read_dir(fixture.path("backups")).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(backups.len(), 1);
+        let backup = TableBackup::load(backups[0].to_str().unwrap()).unwrap();
+        assert_eq!(backup.layout.table, "gpt");
+        let names: Vec<&str> = backup.layout.partitions.iter().map(|partition| partition.name.as_str()).collect();
+        assert_eq!(names, ["EFI system partition", "debian"]);
+    }
+
+    // Disco con una instalación anterior de Redox y datos del usuario en root
+    fn write_existing_redox(fixture: &Fixture) {
+        let mut table = GptTable::new(DISK_SIZE, 512).unwrap();
+        table.add_partition("REDOX_EFI", gpt::EFI_SYSTEM_PARTITION, Some(512 * 1024 * 1024)).unwrap();
+        table.add_partition("REDOX_ROOT", gpt::REDOX_ROOT, None).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&fixture.disk.name).unwrap();
+        table.write_to(&file).unwrap();
+        file.write_all_at(b"RedoxFS\0", table.partition(2).unwrap().first_lba * 512).unwrap();
+
+        for (relative, contents) in [
+            ("root/boot/.redox_boot", "Redox OS Boot Directory\n"),
+            ("root/boot/kernel", "old kernel"),
+            ("root/etc/hostname", "mine"),
+            ("root/etc/ion/initrc", "custom"),
+            ("root/home/user/notes.txt", "notes"),
+            ("root/usr/bin/ion", "old ion"),
+        ] {
+            let path = fixture.dir.path().join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let stage = fixture.path("src/cookbook/recipes/core/ion/target/x86_64-unknown-redox/stage/etc/ion");
+        fs::create_dir_all(&stage).unwrap();
+        fs::write(format!("{}/initrc", stage), "default").unwrap();
+        fs::write(format!("{}/completions", stage), "new").unwrap();
+    }
+
+    #[test]
+    fn upgrade_replaces_system_and_keeps_user_files() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.upgrade = true;
+        write_existing_redox(&fixture);
+        let runner = Rc::new(fixture.runner());
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let disk = &fixture.disk.name;
+        assert_eq!(
+            runner.calls(),
+            [
+                format!("mount {}1 {}", disk, fixture.path("efi")),
+                format!("{} {}2 {}", fixture.path("redoxfs"), disk, fixture.path("root")),
+                "sync".to_string(),
+                format!("fusermount -u {}", fixture.path("root")),
+                format!("umount {}", fixture.path("efi")),
+            ]
+        );
+        let read = |relative: &str| fs::read_to_string(fixture.path(relative)).unwrap();
+        assert_eq!(fs::read(fixture.path("efi/EFI/redox/redox-bootloader.efi")).unwrap(), bootloader_image(0x8664));
+        assert_eq!(read("root/boot/kernel"), "kernel");
+        assert_eq!(read("root/boot/initfs"), "initfs");
+        assert_eq!(read("root/usr/bin/ion"), "ion");
+        assert_eq!(read("root/etc/ion/completions"), "new");
+        assert_eq!(read("root/etc/ion/initrc"), "custom");
+        assert_eq!(read("root/etc/hostname"), "mine");
+        assert_eq!(read("root/home/user/notes.txt"), "notes");
+        assert!(!Path::new(&fixture.path("backups")).exists());
+    }
+
+    #[test]
+    fn upgrade_requires_existing_installation() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.upgrade = true;
+        let runner = Rc::new(fixture.runner());
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+        assert!(err.contains("no tiene una instalación de Redox OS"), "{}", err);
+        assert!(runner.calls().is_empty());
+
+        // Redox sin la marca del instalador: se desmonta sin tocar nada
+        write_existing_redox(&fixture);
+        fs::remove_file(fixture.path("root/boot/.redox_boot")).unwrap();
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+        assert!(err.contains(".redox_boot"), "{}", err);
+        assert_eq!(fs::read_to_string(fixture.path("root/boot/kernel")).unwrap(), "old kernel");
+        assert!(runner.calls().ends_with(&[
+            format!("fusermount -u {}", fixture.path("root")),
+            format!("umount {}", fixture.path("efi")),
+        ]));
+    }
+
+    #[test]
+    fn upgrade_of_dual_boot_finds_the_shared_esp_by_its_bootloader() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.upgrade = true;
+        let mut table = write_existing_os(&fixture);
+        table.add_partition("REDOX_ROOT", gpt::REDOX_ROOT, None).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&fixture.disk.name).unwrap();
+        table.write_to(&file).unwrap();
+        file.write_all_at(b"RedoxFS\0", table.partition(3).unwrap().first_lba * 512).unwrap();
+        fs::write(fixture.path("disk3"), "").unwrap();
+        for (relative, contents) in [
+            ("efi/EFI/BOOT/BOOTX64.EFI", "grub"),
+            ("efi/EFI/redox/redox-bootloader.efi", "old redox"),
+            ("root/boot/.redox_boot", "Redox OS Boot Directory\n"),
+        ] {
+            let path = fixture.dir.path().join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let runner = Rc::new(fixture.runner());
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let disk = &fixture.disk.name;
+        assert_eq!(
+            runner.calls()[..3],
+            [
+                format!("mount -o ro {}1 {}", disk, fixture.path("efi")),
+                format!("umount {}", fixture.path("efi")),
+                format!("mount {}1 {}", disk, fixture.path("efi")),
+            ]
+        );
+        // EFI/BOOT es del otro sistema y se conserva
+        assert_eq!(fs::read_to_string(fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).unwrap(), "grub");
+        assert_eq!(fs::read(fixture.path("efi/EFI/redox/redox-bootloader.efi")).unwrap(), bootloader_image(0x8664));
+        assert_eq!(fs::read_to_string(fixture.path("root/boot/kernel")).unwrap(), "kernel");
+
+        // Si la ruta extraíble ya arrancaba Redox, se actualiza con él
+        fs::write(fixture.path("efi/EFI/BOOT/BOOTX64.EFI"), bootloader_image(0x8664)).unwrap();
+        fs::write(fixture.path(BOOTLOADER), bootloader_image(0x8664).repeat(2)).unwrap();
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        assert_eq!(fs::read(fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).unwrap(), bootloader_image(0x8664).repeat(2));
+    }
+
+    #[test]
+    fn repair_restores_boot_files_and_replaces_boot_entry() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        write_existing_redox(&fixture);
+        fs::create_dir_all(fixture.path("efi/EFI/BOOT")).unwrap();
+        fs::write(fixture.path("efi/EFI/BOOT/BOOTX64.EFI"), "other loader").unwrap();
+        boot_entries::write_entry(&fixture.efivars(), 1, "debian");
+        boot_entries::write_entry_on(&fixture.efivars(), 3, "Redox OS", Some(esp_guid(&fixture)));
+        // Redox instalado en otro disco
+        boot_entries::write_entry_on(&fixture.efivars(), 4, "Redox OS", Some(gpt::Guid::random().unwrap()));
+        boot_entries::write_boot_order(&fixture.efivars(), &[1, 3, 4]);
+        let runner = Rc::new(fixture.runner());
+
+        fixture.installer(&runner).repair_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let disk = &fixture.disk.name;
+        let calls = runner.calls();
+        assert!(!calls.iter().any(|call| call.starts_with("mkfs") || call.contains("redoxfs-mkfs")), "{:?}", calls);
+        let create = calls.iter().position(|call| call.starts_with("efibootmgr --create")).unwrap();
+        assert_eq!(calls[create + 1], "efibootmgr --bootnum 0003 --delete-bootnum");
+        assert!(!calls.iter().any(|call| call.contains("--bootnum 0004")), "{:?}", calls);
+        let firmware = UefiFirmware::new(fixture.path("firmware"));
+        let entries = firmware.entries().unwrap();
+        let labels: Vec<_> = entries.iter().map(|entry| entry.label.as_str()).collect();
+        assert_eq!(labels, ["Redox OS", "debian", "Redox OS"]);
+        assert_eq!(entries[0].partition_guid, Some(esp_guid(&fixture)));
+        assert_eq!(firmware.boot_order().unwrap(), [0, 1, 4]);
+        assert!(calls.contains(&format!("umount {}", fixture.path("efi"))));
+
+        let read = |relative: &str| fs::read_to_string(fixture.path(relative)).unwrap();
+        // El cargador de otro sistema en la ruta extraíble se conserva
+        assert_eq!(read("efi/EFI/BOOT/BOOTX64.EFI"), "other loader");
+        assert_eq!(fs::read(fixture.path("efi/EFI/redox/redox-bootloader.efi")).unwrap(), bootloader_image(0x8664));
+        assert_eq!(read("root/boot/kernel"), "kernel");
+        assert!(read("root/boot/redox.conf").contains(&format!("root={}2", disk)));
+        // Los paquetes y la configuración del sistema no se tocan
+        assert_eq!(read("root/usr/bin/ion"), "old ion");
+        assert_eq!(read("root/etc/hostname"), "mine");
+        let file = fs::File::open(disk).unwrap();
+        assert_eq!(GptTable::read_from(&file, DISK_SIZE, 512).unwrap().partitions.len(), 2);
+
+        // Si falta, se vuelve a escribir con el de Redox
+        fs::remove_file(fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).unwrap();
+        fixture.installer(&runner).repair_redox_os(&fixture.disk, &fixture.config).unwrap();
+        assert_eq!(fs::read(fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).unwrap(), bootloader_image(0x8664));
+    }
+
+    #[test]
+    fn repair_of_bios_install_rewrites_the_mbr_and_stage2() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let config = with_bios_bootloader(&fixture, BootMode::Bios);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).open(&fixture.disk.name).unwrap();
+        let table = GptTable::read_from(&file, DISK_SIZE, 512).unwrap();
+        file.write_all_at(b"RedoxFS\0", table.partition(2).unwrap().first_lba * 512).unwrap();
+        // MBR y stage 2 dañados
+        file.write_all_at(&[0u8; 440], 0).unwrap();
+        file.write_all_at(b"broken", 34 * 512).unwrap();
+        let runner = Rc::new(fixture.runner());
+
+        // La configuración por defecto es UEFI: el modo sale del disco
+        fixture.installer(&runner).repair_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let mut start = vec![0u8; 34 * 512 + 6];
+        file.read_exact_at(&mut start, 0).unwrap();
+        assert_eq!(start[..440], bios_boot::image_stub(b"stage2")[..440]);
+        assert_eq!(&start[34 * 512..], b"stage2");
+        let calls = runner.calls();
+        assert!(calls.iter().all(|call| !call.contains(&fixture.path("efi")) && !call.starts_with("efibootmgr")), "{:?}", calls);
+        assert_eq!(fs::read_to_string(fixture.path("root/boot/kernel")).unwrap(), "kernel");
+    }
+
+    #[test]
+    fn upgrade_of_bios_install_updates_the_mbr_and_stage2() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        let config = with_bios_bootloader(&fixture, BootMode::Bios);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &config).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).open(&fixture.disk.name).unwrap();
+        let table = GptTable::read_from(&file, DISK_SIZE, 512).unwrap();
+        file.write_all_at(b"RedoxFS\0", table.partition(2).unwrap().first_lba * 512).unwrap();
+        file.write_all_at(&[0u8; 440], 0).unwrap();
+        file.write_all_at(b"old", 34 * 512).unwrap();
+        fs::write(fixture.path("root/boot/kernel"), "old kernel").unwrap();
+        fs::write(fixture.path("root/etc/hostname"), "mine").unwrap();
+        let runner = Rc::new(fixture.runner());
+
+        // La configuración por defecto es UEFI: el modo sale del disco
+        fixture.config.upgrade = true;
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let mut start = vec![0u8; 34 * 512 + 6];
+        file.read_exact_at(&mut start, 0).unwrap();
+        assert_eq!(start[..440], bios_boot::image_stub(b"stage2")[..440]);
+        assert_eq!(&start[34 * 512..], b"stage2");
+        let calls = runner.calls();
+        assert!(calls.iter().all(|call| !call.contains(&fixture.path("efi")) && !call.starts_with("efibootmgr")), "{:?}", calls);
+        assert_eq!(fs::read_to_string(fixture.path("root/boot/kernel")).unwrap(), "kernel");
+        assert_eq!(fs::read_to_string(fixture.path("root/etc/hostname")).unwrap(), "mine");
+    }
+
+    fn esp_guid(fixture: &Fixture) -> gpt::Guid {
+        let file = fs::File::open(&fixture.disk.name).unwrap();
+        GptTable::read_from(&file, DISK_SIZE, 512).unwrap().partition(1).unwrap().unique_guid
+    }
+
+    #[test]
+    fn stale_boot_entry_that_cannot_be_deleted_is_only_a_warning() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        write_existing_redox(&fixture);
+        boot_entries::write_entry_on(&fixture.efivars(), 3, "Redox OS", Some(esp_guid(&fixture)));
+        boot_entries::write_boot_order(&fixture.efivars(), &[3]);
+        let runner = Rc::new(fixture.runner().respond("efibootmgr --bootnum 0003 --delete-bootnum", 1, "", "Could not delete variable: Permission denied"));
+
+        fixture.installer(&runner).repair_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let firmware = UefiFirmware::new(fixture.path("firmware"));
+        let numbers: Vec<_> = firmware.entries().unwrap().iter().map(boot_entries::BootEntry::bootnum).collect();
+        assert_eq!(numbers, ["0000", "0003"]);
+    }
+
+    #[test]
+    fn boot_entry_can_be_booted_once_keeping_the_boot_order() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.boot_priority = boot_entries::BootPriority::Next;
+        boot_entries::write_entry(&fixture.efivars(), 0, "Windows Boot Manager");
+        boot_entries::write_entry(&fixture.efivars(), 2, "Redox OS");
+        boot_entries::write_boot_order(&fixture.efivars(), &[0, 2]);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let calls = runner.calls();
+        assert!(calls.iter().any(|call| call.starts_with("efibootmgr --create-only")), "{:?}", calls);
+        assert!(calls.contains(&"efibootmgr --bootorder 0000,0002,0001".to_string()), "{:?}", calls);
+        assert!(calls.contains(&"efibootmgr --bootnext 0001".to_string()), "{:?}", calls);
+        // La entrada de Redox de otra partición no se toca
+        let firmware = UefiFirmware::new(fixture.path("firmware"));
+        let labels: Vec<_> = firmware.entries().unwrap().into_iter().map(|entry| entry.label).collect();
+        assert_eq!(labels, ["Windows Boot Manager", "Redox OS", "Redox OS"]);
+        assert_eq!(firmware.boot_order().unwrap(), [0, 2, 1]);
+        assert_eq!(firmware.boot_next().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn install_without_uefi_variables_keeps_the_fallback_loader() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        fs::remove_dir_all(fixture.path("firmware")).unwrap();
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        // Sin BOOTX64.EFI de otro sistema, la ruta de respaldo basta para arrancar
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        assert!(!runner.calls().iter().any(|call| call.starts_with("efibootmgr")));
+        assert!(std::path::Path::new(&fixture.path("efi/EFI/BOOT/BOOTX64.EFI")).exists());
+    }
+
+    #[test]
+    fn dual_boot_rejects_missing_gap_and_small_space() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.dual_boot = true;
+        fixture.config.free_gap = Some(2);
+        let existing = write_existing_os(&fixture);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+        assert!(err.contains("No existe el hueco libre 2"), "{}", err);
+
+        // Dejar menos de 2 GB libres
+        let mut table = existing;
+        table.add_partition("data", gpt::LINUX_FILESYSTEM, Some(4 * 1024 * 1024 * 1024)).unwrap();
+        table.write_to(&fs::OpenOptions::new().write(true).open(&fixture.disk.name).unwrap()).unwrap();
+        fixture.config.free_gap = None;
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+        assert!(err.contains("demasiado pequeño"), "{}", err);
+        let file = fs::File::open(&fixture.disk.name).unwrap();
+        assert_eq!(GptTable::read_from(&file, DISK_SIZE, 512).unwrap().partitions.len(), 3);
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn blank_disk_is_not_backed_up() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        assert!(!Path::new(&fixture.path("backups")).exists());
+    }
+
+    #[test]
+    fn dry_run_plans_every_step_without_touching_anything() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        let plan = fixture.installer(&runner).plan_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+        let file = fs::File::open(&fixture.disk.name).unwrap();
+        let mut header = [0u8; 1024];
+        file.read_exact_at(&mut header, 0).unwrap();
+        assert!(header.iter().all(|byte| *byte == 0));
+        assert!(!Path::new(&fixture.path("efi")).exists());
+        assert!(!Path::new(&fixture.path("root")).exists());
+
+        let titles: Vec<&str> = plan.steps.iter().map(|step| step.title.as_str()).collect();
+        assert_eq!(titles[..3], ["Preparar disco", "Crear particiones", "Formatear particiones"]);
+        let actions: Vec<&PlannedAction> = plan.steps.iter().flat_map(|step| &step.actions).collect();
+        let disk = &fixture.disk.name;
+        let command = |line: String| {
+            let mut parts = line.split(' ').map(str::to_string);
+            PlannedAction::Command { program: parts.next().unwrap(), args: parts.collect() }
+        };
+
+        assert_eq!(actions[0], &PlannedAction::BackupPartitionTable { directory: fixture.path("backups") });
+        assert!(matches!(&actions[1], PlannedAction::PartitionTable { partitions, existing: false, .. } if partitions.len() == 2));
+        assert!(actions.contains(&&command(format!("mkfs.vfat -F 32 -n REDOX_EFI {}1", disk))));
+        assert!(actions.contains(&&command(format!("{} {}2", fixture.path("redoxfs-mkfs"), disk))));
+        assert!(actions.contains(&&command(format!("mount {}1 {}", disk, fixture.path("efi")))));
+        assert!(actions.contains(&&PlannedAction::CopyFile {
+            source: fixture.path("src/cookbook/recipes/core/kernel/target/x86_64-unknown-redox/stage/boot/kernel"),
+            destination: fixture.path("root/boot/kernel"),
+            bytes: 6,
+        }));
+        assert!(actions.contains(&&PlannedAction::CopyTree {
+            source: fixture.path("src/cookbook/recipes/core/ion/target/x86_64-unknown-redox/stage"),
+            destination: fixture.path("root"),
+            files: 1,
+            bytes: 3,
+        }));
+        assert!(actions.iter().any(|action| matches!(action,
+            PlannedAction::WriteFile { path, contents }
+                if *path == fixture.path("root/boot/redox.conf") && contents.contains(&format!("root={}2", disk)))));
+        assert!(actions.iter().any(|action| matches!(action,
+            PlannedAction::Command { program, args } if program == "efibootmgr" && args.contains(&"Redox OS".to_string()))));
+    }
+
+    #[test]
+    fn failed_step_unmounts_and_removes_boot_entry() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        fs::remove_file(fixture.path("src/cookbook/recipes/core/kernel/target/x86_64-unknown-redox/stage/boot/kernel")).unwrap();
+        boot_entries::write_entry(&fixture.efivars(), 1, "debian");
+        boot_entries::write_boot_order(&fixture.efivars(), &[1]);
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+
+        assert!(err.starts_with("Kernel no encontrado"), "{}", err);
+        let calls = runner.calls();
+        let rollback_start = calls.len() - 4;
+        assert_eq!(
+            calls[rollback_start..],
+            [
+                "sync".to_string(),
+                "efibootmgr --bootnum 0000 --delete-bootnum".to_string(),
+                format!("fusermount -u {}", fixture.path("root")),
+                format!("umount {}", fixture.path("efi")),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_dual_boot_can_restore_original_table() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.dual_boot = true;
+        fixture.config.restore_table_on_failure = true;
+        fs::write(fixture.path("disk3"), "").unwrap();
+        let existing = write_existing_os(&fixture);
+        let runner = Rc::new(
+            fixture.runner()
+                .respond("blockdev --getsize64", 0, "1073741824\n", "")
+                .respond(&fixture.path("redoxfs-mkfs"), 1, "", "disk full"),
+        );
+
+        let err = fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+
+        assert!(err.contains("redoxfs-mkfs falló"), "{}", err);
+        let file = fs::File::open(&fixture.disk.name).unwrap();
+        let table = GptTable::read_from(&file, DISK_SIZE, 512).unwrap();
+        assert_eq!(table.partitions, existing.partitions);
+    }
+
+    #[test]
+    fn only_partitions_of_the_disk_are_unmounted() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        for (partition, number) in [("disk1", 1), ("disk2", 2)] {
+            let dir = fixture.dir.path().join("sys/disk").join(partition);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("partition"), format!("{}\n", number)).unwrap();
+            let dev = format!("8:{}", number);
+            fs::write(dir.join("dev"), format!("{}\n", dev)).unwrap();
+        }
+        fs::write(fixture.path("disk10"), "").unwrap();
+        fs::write(
+            fixture.path("mountinfo"),
+            format!(
+                "30 1 8:1 / /media/old rw - vfat {disk}1 rw\n\
+                 31 30 8:2 / /media/old/data rw - ext4 {disk}2 rw\n\
+                 32 1 8:10 / /srv rw - ext4 {disk}10 rw\n",
+                disk = fixture.disk.name
+            ),
+        ).unwrap();
+
+        let busy = Rc::new(FakeRunner::new().respond("umount /media/old/data", 32, "", "target is busy"));
+        let err = fixture.installer(&busy).install_redox_os(&fixture.disk, &fixture.config).unwrap_err();
+        assert!(err.contains("No se pudo desmontar /media/old/data"), "{}", err);
+        assert_eq!(busy.calls(), ["umount /media/old/data"]);
+
+        let runner = Rc::new(redoxfs_runner(&fixture));
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+        let calls = runner.calls();
+        assert_eq!(calls[..3], ["umount /media/old/data", "umount /media/old", &fixture.partitioning_commands()[0]]);
+        assert!(!calls.iter().any(|call| call.contains("/srv")), "{:?}", calls);
+    }
+
+    #[test]
+    fn disk_too_small_for_layout_stops_before_formatting() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.efi_size_mb = 16 * 1024;
+        let runner = Rc::new(fixture.runner());
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.contains("No hay espacio suficiente"), "{}", err);
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+    }
+
+    #[test]
+    fn missing_partition_nodes_are_reported() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        fs::remove_file(fixture.path("disk2")).unwrap();
+        let runner = Rc::new(fixture.runner());
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.starts_with("Las particiones no aparecieron"), "{}", err);
+        assert!(!runner.calls().iter().any(|call| call.starts_with("mkfs")));
+    }
+
+    #[test]
+    fn missing_mkfs_vfat_is_reported() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let runner = Rc::new(redoxfs_runner(&fixture).fail_to_start("mkfs.vfat"));
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.starts_with("Error formateando EFI"), "{}", err);
+        assert!(runner.calls().last().unwrap().starts_with("mkfs.vfat"));
+    }
+
+    #[test]
+    fn redoxfs_mkfs_without_success_message_fails() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let runner = Rc::new(
+            fixture.runner()
+                .respond("blockdev --getsize64", 0, "1073741824\n", "")
+                .respond(&fixture.path("redoxfs-mkfs"), 0, "", "redoxfs-mkfs: disk too small\n"),
+        );
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.starts_with("redoxfs-mkfs no reportó éxito"), "{}", err);
+        assert!(!runner.calls().iter().any(|call| call.starts_with("mount ")));
+    }
+
+    #[test]
+    fn redoxfs_root_is_mounted_with_redoxfs_without_trying_the_kernel() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let root = fixture.path("root");
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let calls = runner.calls();
+        let position = |call: &str| calls.iter().position(|c| c == call).unwrap_or_else(|| panic!("{}: {:?}", call, calls));
+        assert!(position(&format!("{} {}2 {}", fixture.path("redoxfs"), fixture.disk.name, root))
+            < position(&format!("fusermount -u {}", root)));
+        assert!(!calls.iter().any(|call| call.starts_with("mount -t auto")), "{:?}", calls);
+        assert!(!calls.contains(&format!("umount {}", root)), "{:?}", calls);
+    }
+
+    #[test]
+    fn direct_redoxfs_root_is_written_without_mounting_it() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.redoxfs_direct = true;
+        let partition = fixture.path("disk2");
+        fs::File::create(&partition).unwrap().set_len(64 * 1024 * 1024).unwrap();
+        let root = fixture.path("root");
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        fixture.installer(&runner).install_redox_os(&fixture.disk, &fixture.config).unwrap();
+
+        let calls = runner.calls();
+        assert!(!calls.iter().any(|call| call.starts_with(&fixture.path("redoxfs"))), "{:?}", calls);
+        assert!(!calls.iter().any(|call| call.ends_with(&root)), "{:?}", calls);
+        assert!(calls.contains(&format!("umount {}", fixture.path("efi"))), "{:?}", calls);
+        assert!(!Path::new(&root).exists());
+        let writer = crate::fs_writer::reopen(Path::new(&partition));
+        assert_eq!(writer.read_file(Path::new("boot/kernel")).unwrap(), b"kernel");
+        assert_eq!(writer.read_file(Path::new("etc/hostname")).unwrap(), b"lab-01");
+        assert_eq!(writer.read_file(Path::new("bin/ion")).unwrap(), b"ion");
+    }
+
+    #[test]
+    fn direct_redoxfs_is_planned_as_a_single_step() {
+        let mut fixture = Fixture::new(FilesystemType::RedoxFS);
+        fixture.config.redoxfs_direct = true;
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        let plan = fixture.installer(&runner).plan_redox_os(&fixture.disk, &fixture.config).unwrap().to_string();
+
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+        let device = format!("{}2", fixture.disk.name);
+        assert!(plan.contains(&format!("crear RedoxFS en {} y escribirlo sin montar", device)), "{}", plan);
+        assert!(plan.contains(&format!("escribir {}:/etc/hostname", device)), "{}", plan);
+        assert!(!plan.contains("redoxfs-mkfs"), "{}", plan);
+    }
+
+    #[test]
+    fn direct_redoxfs_requires_a_fresh_redoxfs_root() {
+        let mut fixture = Fixture::new(FilesystemType::Ext4);
+        fixture.config.redoxfs_direct = true;
+        let runner = Rc::new(fixture.runner());
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.starts_with("--redoxfs-direct solo se aplica"), "{}", err);
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+    }
+
+    #[test]
+    fn missing_redoxfs_fails_before_mounting_root() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        fs::remove_file(fixture.path("redoxfs")).unwrap();
+        let runner = Rc::new(redoxfs_runner(&fixture));
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.contains("redoxfs") && err.contains("no existe"), "{}", err);
+        assert!(!runner.calls().iter().any(|call| call.starts_with("efibootmgr")));
+    }
+
+    #[test]
+    fn failed_unmount_is_reported() {
+        let fixture = Fixture::new(FilesystemType::RedoxFS);
+        let efi = fixture.path("efi");
+        let runner = Rc::new(redoxfs_runner(&fixture).respond(&format!("umount {}", efi), 32, "", "target is busy"));
+
+        let err = fixture.installer(&runner)
+            .install_redox_os(&fixture.disk, &fixture.config)
+            .unwrap_err();
+
+        assert!(err.starts_with("No se pudieron desmontar las particiones"), "{}", err);
+        assert!(err.contains(&format!("umount {}: target is busy", efi)), "{}", err);
+        // El resto de montajes se sueltan igualmente
+        assert!(runner.calls().contains(&format!("fusermount -u {}", fixture.path("root"))));
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::command_runner::CommandRunner;
+use crate::DiskInfo;
+
+// Redox OS requiere al menos 2 GB, igual que check_disk_space
+const MIN_IMAGE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+// Nombre del dispositivo en el plan de --dry-run: el loop real solo se
+// asigna (y solo puede asignarlo root) al instalar
+const PLANNED_LOOP_DEVICE: &str = "/dev/loopN";
+
+// Convierte tamaños como "4G", "512M" o "2048MiB" a bytes (unidades binarias)
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let trimmed = size.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Tamaño inválido: {} (ejemplos: 4G, 512M)", size))?;
+
+    let multiplier: u64 = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+        _ => return Err(format!("Unidad de tamaño desconocida en {} (K, M, G o T)", size)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Tamaño demasiado grande: {}", size))
+}
+
+// Tamaño que tendrá la imagen, sin crearla ni modificarla
+pub fn image_size(path: &str, size: Option<u64>) -> Result<u64, String> {
+    let exists = Path::new(path).exists();
+
+    if exists && !fs::metadata(path).map(|m| m.is_file()).unwrap_or(false) {
+        return Err(format!("{} no es un fichero regular", path));
+    }
+
+    let size = match (size, exists) {
+        (Some(size), _) => size,
+        (None, true) => fs::metadata(path)
+            .map_err(|e| format!("Error leyendo {}: {}", path, e))?
+            .len(),
+        (None, false) => {
+            return Err(format!("{} no existe; indica el tamaño con --size (p. ej. 4G)", path));
+        }
+    };
+
+    if size < MIN_IMAGE_SIZE {
+        return Err(format!(
+            "La imagen es demasiado pequeña ({:.2} GB). Se requieren al menos 2 GB",
+            size as f64 / 1024.0 / 1024.0 / 1024.0
+        ));
+    }
+
+    Ok(size)
+}
+
+// Crea (o reutiliza) el fichero de imagen. Con `size` el fichero se vacía y
+// se redimensiona sin escribir datos, de modo que queda disperso.
+pub fn prepare_image(path: &str, size: Option<u64>) -> Result<u64, String> {
+    let size = image_size(path, size)?;
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| format!("Error creando imagen {}: {}", path, e))?;
+    file.set_len(size)
+        .map_err(|e| format!("Error redimensionando imagen {}: {}", path, e))?;
+
+    Ok(size)
+}
+
+// Dispositivo loop asociado a la imagen. Se libera al salir de ámbito,
+// también cuando la instalación falla a mitad.
+pub struct LoopDevice {
+    runner: Rc<dyn CommandRunner>,
+    device: String,
+}
+
+impl LoopDevice {
+    // --partscan hace que el kernel cree /dev/loopNpM para cada partición
+    pub fn attach(runner: Rc<dyn CommandRunner>, image: &str) -> Result<Self, String> {
+        let output = runner
+            .run("losetup", &["--find", "--show", "--partscan", image])
+            .map_err(|e| format!("Error ejecutando losetup: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "Error asociando {} a un dispositivo loop: {}",
+                image,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if device.is_empty() {
+            return Err("losetup no devolvió ningún dispositivo loop".to_string());
+        }
+
+        println!("🔗 Imagen {} asociada a {}", image, device);
+        Ok(Self { runner, device })
+    }
+
+    pub fn disk_info(&self, image: &str, size: u64) -> DiskInfo {
+        image_disk_info(&self.device, image, size)
+    }
+}
+
+// Disco con el que se calcula el plan de --dry-run para una imagen
+pub fn planned_disk_info(image: &str, size: u64) -> DiskInfo {
+    image_disk_info(PLANNED_LOOP_DEVICE, image, size)
+}
+
+fn image_disk_info(device: &str, image: &str, size: u64) -> DiskInfo {
+    DiskInfo {
+        name: device.to_string(),
+        size_bytes: size,
+        logical_sector_size: 512,
+        physical_sector_size: 512,
+        model: image.to_string(),
+        transport: "loop".to_string(),
+        disk_type: "Loop Image".to_string(),
+        ..DiskInfo::default()
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        self.runner.run("sync", &[]).ok();
+        match self.runner.run("losetup", &["--detach", &self.device]) {
+            Ok(output) if output.status.success() => {
+                println!("🔗 {} liberado", self.device);
+            }
+            Ok(output) => {
+                eprintln!(
+                    "⚠️  No se pudo liberar {}: {}",
+                    self.device,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Err(e) => {
+                eprintln!("⚠️  No se pudo liberar {}: {}", self.device, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeRunner;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn parses_binary_sizes() {
+        assert_eq!(parse_size("4G"), Ok(4 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("2048MiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn creates_sparse_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("redox.img");
+        let path = path.to_str().unwrap();
+
+        assert_eq!(prepare_image(path, Some(MIN_IMAGE_SIZE)), Ok(MIN_IMAGE_SIZE));
+
+        let metadata = fs::metadata(path).unwrap();
+        assert_eq!(metadata.len(), MIN_IMAGE_SIZE);
+        assert!(metadata.blocks() * 512 < MIN_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn image_requires_size_or_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("redox.img");
+        let path = path.to_str().unwrap();
+
+        assert!(prepare_image(path, None).unwrap_err().contains("--size"));
+        assert!(prepare_image(path, Some(1024 * 1024)).unwrap_err().contains("demasiado pequeña"));
+
+        prepare_image(path, Some(MIN_IMAGE_SIZE)).unwrap();
+        assert_eq!(prepare_image(path, None), Ok(MIN_IMAGE_SIZE));
+    }
+
+    #[test]
+    fn planned_image_is_not_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("redox.img");
+        let path = path.to_str().unwrap();
+
+        assert_eq!(image_size(path, Some(MIN_IMAGE_SIZE)), Ok(MIN_IMAGE_SIZE));
+        assert!(!Path::new(path).exists());
+
+        fs::write(path, b"datos").unwrap();
+        assert_eq!(image_size(path, Some(MIN_IMAGE_SIZE)), Ok(MIN_IMAGE_SIZE));
+        assert_eq!(fs::read(path).unwrap(), b"datos");
+
+        let disk = planned_disk_info(path, MIN_IMAGE_SIZE);
+        assert_eq!(disk.name, PLANNED_LOOP_DEVICE);
+        assert_eq!(disk.model, path);
+    }
+
+    #[test]
+    fn loop_device_is_detached_on_drop() {
+        let runner = Rc::new(FakeRunner::new().respond("losetup --find", 0, "/dev/loop7\n", ""));
+
+        {
+            let loop_device = LoopDevice::attach(runner.clone(), "redox.img").unwrap();
+            let disk = loop_device.disk_info("redox.img", MIN_IMAGE_SIZE);
+            assert_eq!(disk.name, "/dev/loop7");
+            assert_eq!(disk.size_bytes, MIN_IMAGE_SIZE);
+            assert_eq!(disk.size_human(), "2G");
+        }
+
+        assert_eq!(
+            runner.calls(),
+            ["losetup --find --show --partscan redox.img", "sync", "losetup --detach /dev/loop7"]
+        );
+    }
+
+    #[test]
+    fn losetup_failure_is_reported() {
+        let runner = Rc::new(FakeRunner::new().respond("losetup", 1, "", "losetup: cannot find an unused loop device"));
+
+        let err = LoopDevice::attach(runner.clone(), "redox.img").err().unwrap();
+
+        assert!(err.contains("unused loop device"), "{}", err);
+        assert_eq!(runner.calls().len(), 1);
+    }
+}
@@ -0,0 +1,364 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::gpt::{self, Guid};
+use crate::mounts::{self, MountInfo};
+use crate::sysfs;
+use crate::table_backup;
+use crate::DiskInfo;
+
+// Bytes leídos al principio de cada partición: cubren todas las firmas que se
+// buscan, incluida la cabecera de swap con páginas de 64 KiB
+const PROBE_LEN: usize = 64 * 1024 + 4096;
+
+// Sistema de archivos reconocido por su firma en el superbloque
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbedFilesystem {
+    // FAT32, ext4, RedoxFS, NTFS, swap, LUKS2...
+    pub kind: String,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InspectedPartition {
+    pub number: usize,
+    pub device: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub bytes: u64,
+    // Nombre GPT (vacío en MBR)
+    pub name: String,
+    // Tipo de partición legible, o el GUID / byte de tipo si no se conoce
+    pub partition_type: String,
+    pub filesystem: Option<ProbedFilesystem>,
+    pub mount_points: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskInspection {
+    // "gpt", "mbr" o "ninguna"
+    pub table: String,
+    pub partitions: Vec<InspectedPartition>,
+}
+
+impl DiskInspection {
+    // Partición RedoxFS y la ESP más probable de una instalación existente.
+    // Devuelve sus números de partición; la ESP solo se confirma mirando si
+    // contiene el bootloader de Redox (ver efi_partitions)
+    pub fn redox_installation(&self) -> Option<(usize, usize)> {
+        let root = self.redox_root()?;
+        let esp = self.efi_partitions().first()?.number;
+        Some((esp, root))
+    }
+
+    pub fn redox_root(&self) -> Option<usize> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.filesystem.as_ref().is_some_and(|fs| fs.kind == "RedoxFS"))
+            .map(|partition| partition.number)
+    }
+
+    // Particiones EFI del disco, primero la REDOX_EFI que crea el instalador.
+    // En dual-boot Redox comparte la ESP del otro sistema, que no se renombra
+    pub fn efi_partitions(&self) -> Vec<&InspectedPartition> {
+        let mut esps: Vec<&InspectedPartition> = self
+            .partitions
+            .iter()
+            .filter(|partition| partition.partition_type == "EFI System" || is_redox_esp(partition))
+            .collect();
+        esps.sort_by_key(|partition| !is_redox_esp(partition));
+        esps
+    }
+}
+
+// ESP con la etiqueta REDOX_EFI, creada por el instalador solo para Redox
+pub fn is_redox_esp(partition: &InspectedPartition) -> bool {
+    partition.name == "REDOX_EFI"
+        || partition.filesystem.as_ref().is_some_and(|fs| fs.label == "REDOX_EFI")
+}
+
+// Lee la tabla de particiones de `disk` y el superbloque de cada partición
+// sin modificar nada; `mounts` indica qué particiones están montadas
+pub fn inspect(disk: &DiskInfo, sys_block: &Path, mounts: &[MountInfo]) -> Result<DiskInspection, String> {
+    let (file, disk_bytes, sector_size) = gpt::open_disk_readonly(&disk.name)?;
+    let layout = table_backup::describe_layout(&file, disk_bytes, sector_size);
+    let kernel_partitions = sysfs::disk_partitions(sys_block, &disk.name);
+    let dev_dir = Path::new(&disk.name).parent().unwrap_or(Path::new("/dev"));
+
+    let partitions = layout
+        .partitions
+        .iter()
+        .map(|partition| {
+            let kernel = kernel_partitions.iter().find(|found| found.number == partition.number);
+            let device = match kernel {
+                Some(found) => dev_dir.join(&found.name).to_string_lossy().into_owned(),
+                None => sysfs::partition_node(&disk.name, partition.number),
+            };
+            let mount_points = mounts
+                .iter()
+                .filter(|mount| mounts::is_mount_of(mount, &device, kernel.and_then(|found| found.dev)))
+                .map(|mount| mount.mount_point.clone())
+                .collect();
+
+            InspectedPartition {
+                number: partition.number,
+                first_lba: partition.first_lba,
+                last_lba: partition.last_lba,
+                bytes: partition.bytes,
+                name: partition.name.clone(),
+                partition_type: describe_type(&partition.partition_type),
+                filesystem: probe_filesystem(&file, partition.first_lba * sector_size),
+                mount_points,
+                device,
+            }
+        })
+        .collect();
+
+    Ok(DiskInspection {
+        table: layout.table,
+        partitions,
+    })
+}
+
+fn describe_type(partition_type: &str) -> String {
+    let known: [(Guid, &str); 5] = [
+        (gpt::EFI_SYSTEM_PARTITION, "EFI System"),
+        (gpt::LINUX_FILESYSTEM, "Linux"),
+        (gpt::LINUX_SWAP, "Linux swap"),
+        (gpt::REDOX_ROOT, "Redox root"),
+        (gpt::REDOX_DATA, "Redox datos"),
+    ];
+    let mbr: [(&str, &str); 6] = [
+        ("0x07", "NTFS/exFAT"),
+        ("0x0B", "FAT32"),
+        ("0x0C", "FAT32 LBA"),
+        ("0x82", "Linux swap"),
+        ("0x83", "Linux"),
+        ("0xEF", "EFI System"),
+    ];
+
+    known
+        .iter()
+        .find(|(guid, _)| guid.to_string() == partition_type)
+        .map(|(_, name)| *name)
+        .or_else(|| mbr.iter().find(|(code, _)| *code == partition_type).map(|(_, name)| *name))
+        .map(str::to_string)
+        .unwrap_or_else(|| partition_type.to_string())
+}
+
+// Identifica el sistema de archivos que empieza en `offset` por su firma
+pub fn probe_filesystem(file: &File, offset: u64) -> Option<ProbedFilesystem> {
+    let mut data = vec![0u8; PROBE_LEN];
+    let mut read = 0;
+    while read < data.len() {
+        match file.read_at(&mut data[read..], offset + read as u64) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n,
+        }
+    }
+    data.truncate(read);
+    probe_bytes(&data)
+}
+
+fn probe_bytes(data: &[u8]) -> Option<ProbedFilesystem> {
+    let at = |offset: usize, len: usize| data.get(offset..offset + len);
+    let text = |offset: usize, len: usize| {
+        at(offset, len)
+            .map(|bytes| {
+                let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+                String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+            })
+            .unwrap_or_default()
+    };
+    let u16_le = |offset: usize| at(offset, 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    let u32_le = |offset: usize| at(offset, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    let found = |kind: &str, label: String| Some(ProbedFilesystem { kind: kind.to_string(), label });
+
+    // LUKS: "LUKS\xba\xbe", versión big-endian; solo LUKS2 tiene etiqueta
+    if at(0, 6) == Some(b"LUKS\xba\xbe") {
+        return match at(6, 2) {
+            Some([0, 2]) => found("LUKS2", text(24, 48)),
+            _ => found("LUKS1", String::new()),
+        };
+    }
+
+    if at(0, 8) == Some(b"RedoxFS\0") {
+        return found("RedoxFS", String::new());
+    }
+
+    // NTFS también lleva la firma 0x55AA del sector de arranque: va antes que FAT
+    if at(3, 8) == Some(b"NTFS    ") {
+        return found("NTFS", String::new());
+    }
+
+    if at(510, 2) == Some(&[0x55, 0xAA]) {
+        let fat_label = |offset: usize| {
+            let label = text(offset, 11);
+            if label == "NO NAME" { String::new() } else { label }
+        };
+        if at(82, 5) == Some(b"FAT32") {
+            return found("FAT32", fat_label(71));
+        }
+        if let Some(kind @ (b"FAT12" | b"FAT16")) = at(54, 5) {
+            return found(&String::from_utf8_lossy(kind), fat_label(43));
+        }
+    }
+
+    // ext2/3/4: superbloque a 1024 bytes con la firma 0xEF53
+    if u16_le(1024 + 56) == Some(0xEF53) {
+        let compat = u32_le(1024 + 92).unwrap_or(0);
+        let incompat = u32_le(1024 + 96).unwrap_or(0);
+        // extents o flex_bg solo existen en ext4; has_journal distingue ext3
+        let kind = if incompat & (0x40 | 0x200) != 0 {
+            "ext4"
+        } else if compat & 0x4 != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+        return found(kind, text(1024 + 120, 16));
+    }
+
+    // swap: la firma ocupa los últimos 10 bytes de la primera página
+    for page_size in [4096, 8192, 16384, 65536] {
+        if at(page_size - 10, 10) == Some(b"SWAPSPACE2") {
+            return found("swap", text(1024 + 28, 16));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::GptTable;
+    use std::fs;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn fat32(label: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 512];
+        data[71..82].copy_from_slice(format!("{:<11}", label).as_bytes());
+        data[82..90].copy_from_slice(b"FAT32   ");
+        data[510..512].copy_from_slice(&[0x55, 0xAA]);
+        data
+    }
+
+    fn ext4(label: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 2048];
+        data[1024 + 56..1024 + 58].copy_from_slice(&0xEF53u16.to_le_bytes());
+        data[1024 + 96..1024 + 100].copy_from_slice(&0x2C2u32.to_le_bytes());
+        data[1024 + 120..1024 + 120 + label.len()].copy_from_slice(label.as_bytes());
+        data
+    }
+
+    fn swap(label: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 4096];
+        data[1024 + 28..1024 + 28 + label.len()].copy_from_slice(label.as_bytes());
+        data[4086..4096].copy_from_slice(b"SWAPSPACE2");
+        data
+    }
+
+    fn luks2(label: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 512];
+        data[..6].copy_from_slice(b"LUKS\xba\xbe");
+        data[6..8].copy_from_slice(&[0, 2]);
+        data[24..24 + label.len()].copy_from_slice(label.as_bytes());
+        data
+    }
+
+    #[test]
+    fn recognises_filesystem_signatures() {
+        let kind = |data: &[u8]| probe_bytes(data).map(|fs| (fs.kind, fs.label));
+        let pair = |kind: &str, label: &str| Some((kind.to_string(), label.to_string()));
+
+        assert_eq!(kind(&fat32("REDOX_EFI")), pair("FAT32", "REDOX_EFI"));
+        assert_eq!(kind(&fat32("NO NAME")), pair("FAT32", ""));
+        assert_eq!(kind(&ext4("home")), pair("ext4", "home"));
+        assert_eq!(kind(&swap("swap0")), pair("swap", "swap0"));
+        assert_eq!(kind(&luks2("cryptroot")), pair("LUKS2", "cryptroot"));
+        assert_eq!(kind(b"RedoxFS\0\x06\0\0\0"), pair("RedoxFS", ""));
+
+        let mut ntfs = fat32("");
+        ntfs[3..11].copy_from_slice(b"NTFS    ");
+        assert_eq!(kind(&ntfs), pair("NTFS", ""));
+        assert_eq!(kind(&[0u8; 8192]), None);
+    }
+
+    #[test]
+    fn inspects_existing_redox_installation() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk_path = dir.path().join("disk");
+        let file = File::create(&disk_path).unwrap();
+        file.set_len(64 * MB).unwrap();
+
+        let mut table = GptTable::new(64 * MB, 512).unwrap();
+        table.add_partition("REDOX_EFI", gpt::EFI_SYSTEM_PARTITION, Some(8 * MB)).unwrap();
+        table.add_partition("REDOX_ROOT", gpt::REDOX_ROOT, Some(32 * MB)).unwrap();
+        table.add_partition("swap", gpt::LINUX_SWAP, None).unwrap();
+        table.write_to(&file).unwrap();
+        let start = |number: usize| table.partition(number).unwrap().first_lba * 512;
+        file.write_all_at(&fat32("REDOX_EFI"), start(1)).unwrap();
+        file.write_all_at(b"RedoxFS\0", start(2)).unwrap();
+        file.write_all_at(&swap(""), start(3)).unwrap();
+
+        let disk = DiskInfo {
+            name: disk_path.to_string_lossy().into_owned(),
+            ..DiskInfo::default()
+        };
+        let partition1 = format!("{}1", disk.name);
+        fs::write(&partition1, "").unwrap();
+        let mountinfo = mounts::parse_mountinfo(&format!("30 1 0:50 / /mnt/efi rw - vfat {} rw\n", partition1));
+
+        let inspection = inspect(&disk, &dir.path().join("sys"), &mountinfo).unwrap();
+
+        assert_eq!(inspection.table, "gpt");
+        assert_eq!(inspection.partitions.len(), 3);
+        let esp = &inspection.partitions[0];
+        assert_eq!(esp.device, partition1);
+        assert_eq!(esp.partition_type, "EFI System");
+        assert_eq!(esp.bytes, 8 * MB);
+        assert_eq!(esp.mount_points, ["/mnt/efi"]);
+        assert_eq!(inspection.partitions[1].filesystem.as_ref().unwrap().kind, "RedoxFS");
+        assert_eq!(inspection.partitions[2].filesystem.as_ref().unwrap().kind, "swap");
+        assert!(inspection.partitions[2].mount_points.is_empty());
+        assert_eq!(inspection.redox_installation(), Some((1, 2)));
+    }
+
+    #[test]
+    fn redox_esp_is_preferred_over_other_efi_partitions() {
+        let partition = |number: usize, name: &str, partition_type: &str| InspectedPartition {
+            number,
+            device: format!("/dev/sdb{}", number),
+            first_lba: 0,
+            last_lba: 0,
+            bytes: 0,
+            name: name.to_string(),
+            partition_type: partition_type.to_string(),
+            filesystem: None,
+            mount_points: Vec::new(),
+        };
+        let mut inspection = DiskInspection {
+            table: "gpt".to_string(),
+            partitions: vec![
+                partition(1, "EFI system partition", "EFI System"),
+                partition(2, "debian", "Linux"),
+                partition(3, "REDOX_EFI", "EFI System"),
+            ],
+        };
+
+        let esps: Vec<usize> = inspection.efi_partitions().iter().map(|esp| esp.number).collect();
+        assert_eq!(esps, [3, 1]);
+        assert_eq!(inspection.redox_installation(), None);
+
+        // Redox en dual-boot, con la ESP del otro sistema
+        inspection.partitions.remove(2);
+        inspection.partitions[1].filesystem = Some(ProbedFilesystem {
+            kind: "RedoxFS".to_string(),
+            label: String::new(),
+        });
+        assert_eq!(inspection.redox_installation(), Some((1, 2)));
+    }
+}
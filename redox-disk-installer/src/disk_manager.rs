@@ -1,66 +1,136 @@
-use std::process::Command;
+use std::collections::HashMap;
 use std::fs;
 use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use crate::disk_inspect::{self, DiskInspection};
+use crate::host_disks::HostDisks;
+use crate::mounts::{self, MountedPartition};
 use crate::DiskInfo;
 
+// /sys/block/<disco>/size siempre cuenta sectores de 512 bytes, sea cual sea
+// el tamaño de sector real del disco
+const SYSFS_SECTOR: u64 = 512;
+
 pub struct DiskManager {
+    // Raíces de sysfs, udev y /proc; se cambian en los tests
+    sys_block: PathBuf,
+    udev_data: PathBuf,
+    mountinfo: PathBuf,
+    host_disks: HostDisks,
     disks: Vec<DiskInfo>,
 }
 
 impl DiskManager {
     pub fn new() -> Self {
         Self {
+            sys_block: PathBuf::from("/sys/block"),
+            udev_data: PathBuf::from("/run/udev/data"),
+            mountinfo: PathBuf::from("/proc/self/mountinfo"),
+            host_disks: HostDisks::new(),
             disks: Vec::new(),
         }
     }
-    
+
     pub fn list_disks(&mut self) -> Vec<DiskInfo> {
         self.scan_disks();
         self.disks.clone()
     }
-    
+
     fn scan_disks(&mut self) {
-        self.disks.clear();
-        
-        // Escanear discos usando lsblk
-        let output = Command::new("lsblk")
-            .args(&["-d", "-o", "NAME,SIZE,MODEL,TYPE", "-n"])
-            .output();
-            
-        match output {
-            Ok(result) => {
-                let output_str = String::from_utf8_lossy(&result.stdout);
-                
-                for line in output_str.lines() {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    
-                    if parts.len() >= 4 && parts[parts.len() - 1] == "disk" {
-                        let name = format!("/dev/{}", parts[0]);
-                        let size = parts[1].to_string();
-                        let model = if parts.len() > 3 {
-                            parts[2..parts.len() - 1].join(" ")
-                        } else {
-                            "Unknown".to_string()
-                        };
-                        
-                        // Verificar que el disco existe y es accesible
-                        if self.is_disk_accessible(&name) {
-                            self.disks.push(DiskInfo {
-                                name: name.clone(),
-                                size,
-                                model,
-                                disk_type: self.get_disk_type(&name),
-                            });
-                        }
-                    }
-                }
-            }
+        let mut host_usage = self.host_disks.scan();
+
+        // Verificar que el disco existe y es accesible
+        self.disks = self.read_sysfs_disks()
+            .into_iter()
+            .filter(|disk| self.is_disk_accessible(&disk.name))
+            .map(|mut disk| {
+                disk.host_usage = host_usage.remove(disk.name.trim_start_matches("/dev/")).unwrap_or_default();
+                disk
+            })
+            .collect();
+    }
+
+    // Discos físicos o virtuales de /sys/block, ordenados por nombre. Se
+    // descartan los dispositivos sin hardware detrás (loop, ram, zram,
+    // device-mapper, md), las unidades ópticas y los lectores sin medio.
+    fn read_sysfs_disks(&self) -> Vec<DiskInfo> {
+        let entries = match fs::read_dir(&self.sys_block) {
+            Ok(entries) => entries,
             Err(e) => {
-                eprintln!("⚠️  Error escaneando discos: {}", e);
+                eprintln!("⚠️  Error escaneando discos en {}: {}", self.sys_block.display(), e);
+                return Vec::new();
             }
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+
+        names.iter().filter_map(|name| self.read_sysfs_disk(name)).collect()
+    }
+
+    fn read_sysfs_disk(&self, name: &str) -> Option<DiskInfo> {
+        let block = self.sys_block.join(name);
+        if name.starts_with("sr") || !block.join("device").exists() {
+            return None;
+        }
+
+        let sectors: u64 = read_attr(&block, "size")?.parse().ok()?;
+        if sectors == 0 {
+            return None;
         }
+
+        let udev = read_udev_properties(&self.udev_data, read_attr(&block, "dev").as_deref());
+        let udev_value = |key: &str| udev.get(key).filter(|value| !value.is_empty()).cloned();
+        let number = |attr: &str, default: u64| {
+            read_attr(&block, attr).and_then(|value| value.parse().ok()).unwrap_or(default)
+        };
+        let flag = |attr: &str| read_attr(&block, attr).as_deref() == Some("1");
+
+        let model = read_attr(&block, "device/model")
+            .or_else(|| read_attr(&block, "device/name"))
+            .or_else(|| udev_value("ID_MODEL").map(|model| model.replace('_', " ")))
+            .unwrap_or_else(|| "Unknown".to_string());
+        // Los buses virtio y PCI exponen aquí un identificador numérico, no
+        // el nombre del fabricante
+        let vendor = read_attr(&block, "device/vendor")
+            .filter(|vendor| !vendor.starts_with("0x"))
+            .or_else(|| udev_value("ID_VENDOR").map(|vendor| vendor.replace('_', " ")))
+            .unwrap_or_default();
+        let serial = udev_value("ID_SERIAL_SHORT")
+            .or_else(|| read_attr(&block, "serial"))
+            .or_else(|| read_attr(&block, "device/serial"))
+            .unwrap_or_default();
+        let wwn = udev_value("ID_WWN")
+            .or_else(|| read_attr(&block, "wwid"))
+            .or_else(|| read_attr(&block, "device/wwid"))
+            .unwrap_or_default();
+        let transport = transport_from_path(&block)
+            .map(str::to_string)
+            .or_else(|| udev_value("ID_BUS"))
+            .unwrap_or_default();
+
+        let logical_sector_size = number("queue/logical_block_size", SYSFS_SECTOR);
+        let rotational = flag("queue/rotational");
+        Some(DiskInfo {
+            name: format!("/dev/{}", name),
+            size_bytes: sectors * SYSFS_SECTOR,
+            logical_sector_size,
+            physical_sector_size: number("queue/physical_block_size", logical_sector_size),
+            removable: flag("removable"),
+            rotational,
+            model,
+            vendor,
+            serial,
+            wwn,
+            disk_type: describe_disk_type(name, &transport, rotational),
+            transport,
+            host_usage: Vec::new(),
+        })
     }
-    
+
     fn is_disk_accessible(&self, disk_path: &str) -> bool {
         // Verificar que el disco existe y es accesible
         if let Ok(metadata) = fs::metadata(disk_path) {
@@ -69,72 +139,223 @@ impl DiskManager {
             false
         }
     }
-    
-    fn get_disk_type(&self, disk_path: &str) -> String {
-        // Determinar el tipo de disco
-        if disk_path.contains("nvme") {
-            "NVMe SSD".to_string()
-        } else if disk_path.contains("sd") {
-            // Intentar determinar si es SSD o HDD
-            let device_name = disk_path.trim_start_matches("/dev/");
-            let rotational_path = format!("/sys/block/{}/queue/rotational", device_name);
-            
-            if let Ok(content) = fs::read_to_string(&rotational_path) {
-                if content.trim() == "0" {
-                    return "SATA SSD".to_string();
-                } else {
-                    return "SATA HDD".to_string();
-                }
+
+    // Tabla de particiones, sistemas de archivos y montajes del disco
+    pub fn inspect(&self, disk: &DiskInfo) -> Result<DiskInspection, String> {
+        let mounts = mounts::read_mountinfo(&self.mountinfo)?;
+        disk_inspect::inspect(disk, &self.sys_block, &mounts)
+    }
+
+    // Particiones del disco montadas ahora mismo y dónde
+    pub fn mounted_partitions(&self, disk_path: &str) -> Vec<MountedPartition> {
+        match mounts::read_mountinfo(&self.mountinfo) {
+            Ok(mounts) => mounts::mounted_partitions(&self.sys_block, &mounts, disk_path),
+            Err(e) => {
+                eprintln!("⚠️  {}", e);
+                Vec::new()
             }
-            
-            "SATA/SCSI".to_string()
-        } else if disk_path.contains("hd") {
-            "IDE HDD".to_string()
-        } else if disk_path.contains("vd") {
-            "Virtual Disk".to_string()
-        } else if disk_path.contains("mmcblk") {
-            "MMC/SD Card".to_string()
-        } else {
-            "Unknown".to_string()
         }
     }
-    
-    pub fn is_disk_mounted(&self, disk_path: &str) -> bool {
-        let output = Command::new("mount")
-            .output();
-            
-        match output {
-            Ok(result) => {
-                let output_str = String::from_utf8_lossy(&result.stdout);
-                output_str.contains(disk_path)
+}
+
+// Atributo de sysfs sin espacios alrededor; None si no existe o está vacío
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr))
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// Propiedades "E:CLAVE=valor" que udev guarda para el dispositivo de bloque
+// con número mayor:menor `dev`
+fn read_udev_properties(udev_data: &Path, dev: Option<&str>) -> HashMap<String, String> {
+    let Some(contents) = dev.and_then(|dev| fs::read_to_string(udev_data.join(format!("b{}", dev))).ok()) else {
+        return HashMap::new();
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("E:"))
+        .filter_map(|property| property.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// El bus se deduce de la ruta real del dispositivo en /sys/devices, p. ej.
+// .../0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda
+fn transport_from_path(block: &Path) -> Option<&'static str> {
+    let path = fs::canonicalize(block).ok()?;
+    let components: Vec<String> = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let has = |prefix: &str| components.iter().any(|component| component.starts_with(prefix));
+
+    // USB va primero: un adaptador USB-SATA también crea nodos host/target
+    if has("usb") {
+        Some("usb")
+    } else if has("nvme") {
+        Some("nvme")
+    } else if has("mmc") {
+        Some("mmc")
+    } else if has("virtio") {
+        Some("virtio")
+    } else if has("ata") {
+        Some("sata")
+    } else if has("host") {
+        Some("scsi")
+    } else {
+        None
+    }
+}
+
+fn describe_disk_type(name: &str, transport: &str, rotational: bool) -> String {
+    let description = match transport {
+        "nvme" => "NVMe SSD",
+        "sata" | "ata" if rotational => "SATA HDD",
+        "sata" | "ata" => "SATA SSD",
+        "usb" => "USB Disk",
+        "mmc" => "MMC/SD Card",
+        "virtio" => "Virtual Disk",
+        "scsi" => "SATA/SCSI",
+        _ if name.starts_with("hd") => "IDE HDD",
+        _ if name.starts_with("vd") || name.starts_with("xvd") => "Virtual Disk",
+        _ => "Unknown",
+    };
+    description.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    struct SysFs {
+        dir: tempfile::TempDir,
+    }
+
+    impl SysFs {
+        fn new() -> Self {
+            let sysfs = Self { dir: tempfile::tempdir().unwrap() };
+            fs::create_dir_all(sysfs.dir.path().join("block")).unwrap();
+            fs::create_dir_all(sysfs.dir.path().join("udev")).unwrap();
+            sysfs
+        }
+
+        // Crea el disco en `device_path` (relativo a devices/) y lo enlaza
+        // desde block/, como hace el kernel
+        fn disk(&self, name: &str, device_path: &str, attrs: &[(&str, &str)]) {
+            let real = self.dir.path().join("devices").join(device_path).join("block").join(name);
+            fs::create_dir_all(real.join("queue")).unwrap();
+            fs::create_dir_all(real.join("device")).unwrap();
+            for (attr, value) in attrs {
+                fs::write(real.join(attr), format!("{}\n", value)).unwrap();
             }
-            Err(_) => false
+            symlink(&real, self.dir.path().join("block").join(name)).unwrap();
         }
-    }
-    
-    pub fn unmount_disk(&self, disk_path: &str) -> Result<(), String> {
-        // Intentar desmontar todas las particiones del disco
-        let device_name = disk_path.trim_start_matches("/dev/");
-        
-        // Listar todas las particiones montadas
-        let output = Command::new("mount")
-            .output()
-            .map_err(|e| format!("Error ejecutando mount: {}", e))?;
-        
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        
-        for line in output_str.lines() {
-            if line.contains(device_name) {
-                if let Some(partition) = line.split_whitespace().next() {
-                    println!("   Desmontando {}...", partition);
-                    let _ = Command::new("umount")
-                        .arg(partition)
-                        .output();
-                }
+
+        fn manager(&self) -> DiskManager {
+            DiskManager {
+                sys_block: self.dir.path().join("block"),
+                udev_data: self.dir.path().join("udev"),
+                ..DiskManager::new()
             }
         }
-        
-        Ok(())
     }
-}
 
+    #[test]
+    fn reads_sata_disk_with_udev_serial_and_wwn() {
+        let sysfs = SysFs::new();
+        sysfs.disk("sda", "pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0", &[
+            ("size", "1953525168"),
+            ("dev", "8:0"),
+            ("removable", "0"),
+            ("queue/rotational", "1"),
+            ("queue/logical_block_size", "512"),
+            ("queue/physical_block_size", "4096"),
+            ("device/model", "WDC WD10EZEX-08W  "),
+            ("device/vendor", "ATA     "),
+        ]);
+        fs::write(
+            sysfs.dir.path().join("udev/b8:0"),
+            "S:disk/by-id/ata-WDC\nE:ID_BUS=ata\nE:ID_SERIAL_SHORT=WD-WCC6Y0123456\nE:ID_WWN=0x50014ee2b5a1c2d3\n",
+        ).unwrap();
+
+        let disks = sysfs.manager().read_sysfs_disks();
+
+        assert_eq!(disks.len(), 1);
+        let disk = &disks[0];
+        assert_eq!(disk.name, "/dev/sda");
+        assert_eq!(disk.size_bytes, 1_000_204_886_016);
+        assert_eq!((disk.logical_sector_size, disk.physical_sector_size), (512, 4096));
+        assert!(disk.rotational && !disk.removable);
+        assert_eq!(disk.model, "WDC WD10EZEX-08W");
+        assert_eq!(disk.vendor, "ATA");
+        assert_eq!(disk.serial, "WD-WCC6Y0123456");
+        assert_eq!(disk.wwn, "0x50014ee2b5a1c2d3");
+        assert_eq!(disk.transport, "sata");
+        assert_eq!(disk.disk_type, "SATA HDD");
+        assert_eq!(disk.size_human(), "931.5G");
+    }
+
+    #[test]
+    fn reads_nvme_and_usb_disks_from_sysfs_only() {
+        let sysfs = SysFs::new();
+        sysfs.disk("nvme0n1", "pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0", &[
+            ("size", "1000215216"),
+            ("wwid", "eui.0025385b71b0a1c2"),
+            ("queue/rotational", "0"),
+            ("queue/logical_block_size", "4096"),
+            ("device/model", "Samsung SSD 970 EVO Plus 500GB"),
+            ("device/serial", "S4EVNX0N123456"),
+        ]);
+        sysfs.disk("sdb", "pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0", &[
+            ("size", "60063744"),
+            ("removable", "1"),
+            ("queue/rotational", "1"),
+            ("device/model", "Ultra Fit"),
+            ("device/vendor", "SanDisk"),
+        ]);
+
+        let disks = sysfs.manager().read_sysfs_disks();
+
+        let names: Vec<&str> = disks.iter().map(|disk| disk.name.as_str()).collect();
+        assert_eq!(names, ["/dev/nvme0n1", "/dev/sdb"]);
+        let nvme = &disks[0];
+        assert_eq!(nvme.transport, "nvme");
+        assert_eq!(nvme.disk_type, "NVMe SSD");
+        assert_eq!((nvme.logical_sector_size, nvme.physical_sector_size), (4096, 4096));
+        assert_eq!(nvme.serial, "S4EVNX0N123456");
+        assert_eq!(nvme.wwn, "eui.0025385b71b0a1c2");
+        let usb = &disks[1];
+        assert_eq!(usb.transport, "usb");
+        assert!(usb.removable);
+        assert_eq!(usb.vendor, "SanDisk");
+        assert_eq!(usb.size_bytes, 60063744 * 512);
+    }
+
+    #[test]
+    fn skips_virtual_optical_and_empty_devices() {
+        let sysfs = SysFs::new();
+        sysfs.disk("vda", "pci0000:00/0000:00:02.0/virtio1", &[
+            ("size", "16777216"),
+            ("serial", "redox-test"),
+            ("device/vendor", "0x1af4"),
+        ]);
+        sysfs.disk("sr0", "pci0000:00/0000:00:1f.2/ata2/host1/target1:0:0/1:0:0:0", &[("size", "2097152")]);
+        sysfs.disk("sdc", "pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0/host7/target7:0:0/7:0:0:0", &[("size", "0")]);
+        // loop y zram no tienen enlace device/
+        let loop0 = sysfs.dir.path().join("block/loop0");
+        fs::create_dir_all(&loop0).unwrap();
+        fs::write(loop0.join("size"), "4194304\n").unwrap();
+
+        let disks = sysfs.manager().read_sysfs_disks();
+
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].name, "/dev/vda");
+        assert_eq!(disks[0].disk_type, "Virtual Disk");
+        assert_eq!(disks[0].vendor, "");
+        assert_eq!(disks[0].serial, "redox-test");
+        assert_eq!(disks[0].size_human(), "8G");
+    }
+}
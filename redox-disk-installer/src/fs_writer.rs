@@ -0,0 +1,468 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redoxfs::{DiskFile, FileSystem, Node, Transaction, TreeData, TreePtr};
+use syscall::error::{Error, EINVAL, EISDIR, ELOOP, ENOENT, ENOTDIR};
+
+// Escritura de los ficheros de la instalación (ESP y root). DirectInstaller
+// no toca el sistema de archivos de destino más que a través de este trait.
+// Las rutas son relativas a la raíz del sistema de archivos ("boot/kernel"),
+// de forma que da igual si está montado en el host (MountedWriter) o si se
+// escribe RedoxFS directamente en la partición (RedoxFsWriter).
+pub trait FilesystemWriter {
+    // Crea el directorio y los que falten por encima
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    // Copia un fichero del host al destino, con sus permisos, y devuelve los
+    // bytes copiados
+    fn copy_file(&self, source: &Path, destination: &Path) -> io::Result<u64>;
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    // Crea el enlace sustituyendo lo que hubiera en `path`
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()>;
+
+    // Sin seguir enlaces: None si no existe, Some(true) si es un directorio
+    fn is_dir(&self, path: &Path) -> Option<bool>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    // Deja en el disco todo lo escrito; después no se escribe nada más
+    fn sync(&self) -> io::Result<()>;
+}
+
+// Escribe a través de un sistema de archivos montado en el host
+pub struct MountedWriter {
+    root: PathBuf,
+}
+
+impl MountedWriter {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+impl FilesystemWriter for MountedWriter {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(self.host_path(path))
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(self.host_path(path), contents)
+    }
+
+    fn copy_file(&self, source: &Path, destination: &Path) -> io::Result<u64> {
+        fs::copy(source, self.host_path(destination))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(self.host_path(path))
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let path = self.host_path(path);
+        let _ = fs::remove_file(&path);
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(self.host_path(path), fs::Permissions::from_mode(mode))
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        std::os::unix::fs::lchown(self.host_path(path), Some(uid), Some(gid))
+    }
+
+    fn is_dir(&self, path: &Path) -> Option<bool> {
+        fs::symlink_metadata(self.host_path(path)).ok().map(|metadata| metadata.is_dir())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.host_path(path))
+    }
+
+    // Los datos llegan al disco al desmontar
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Como Linux, se abandona la ruta tras 40 enlaces
+const MAX_SYMLINKS: usize = 40;
+
+// Escribe RedoxFS directamente en la partición con la librería redoxfs, sin
+// montarla con FUSE: no necesita /dev/fuse ni el daemon redoxfs
+pub struct RedoxFsWriter {
+    fs: RefCell<FileSystem<DiskFile>>,
+}
+
+impl RedoxFsWriter {
+    // Crea un RedoxFS vacío en `device` (como redoxfs-mkfs)
+    pub fn create(device: &Path) -> io::Result<Self> {
+        let disk = DiskFile::open(device).map_err(syscall_error)?;
+        let (seconds, nanoseconds) = now();
+        let fs = FileSystem::create(disk, None, seconds, nanoseconds).map_err(syscall_error)?;
+        Ok(Self { fs: RefCell::new(fs) })
+    }
+
+    // En el mismo formato que imprime redoxfs-mkfs
+    pub fn uuid(&self) -> String {
+        let uuid = self.fs.borrow().header.uuid();
+        let hex: String = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+
+    fn tx<T>(&self, f: impl FnOnce(&mut Transaction<DiskFile>) -> syscall::Result<T>) -> io::Result<T> {
+        self.fs.borrow_mut().tx(f).map_err(syscall_error)
+    }
+}
+
+impl FilesystemWriter for RedoxFsWriter {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let names = components(path)?;
+        self.tx(|tx| {
+            let mut stack = vec![TreePtr::root()];
+            walk(tx, &mut stack, &names, true, true, 0)?;
+            if !tx.read_tree(current(&stack))?.data().is_dir() {
+                return Err(Error::new(ENOTDIR));
+            }
+            Ok(())
+        })
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let (parent, name) = split(path)?;
+        self.tx(|tx| write_node(tx, &parent, name, contents, Node::MODE_FILE | 0o644).map(|_| ()))
+    }
+
+    fn copy_file(&self, source: &Path, destination: &Path) -> io::Result<u64> {
+        let contents = fs::read(source)?;
+        let permissions = fs::metadata(source)?.permissions().mode() as u16 & Node::MODE_PERM;
+        let (parent, name) = split(destination)?;
+        self.tx(|tx| {
+            let mut node = write_node(tx, &parent, name, &contents, Node::MODE_FILE | permissions)?;
+            node.data_mut().set_mode(Node::MODE_FILE | permissions);
+            tx.sync_tree(node)
+        })?;
+        Ok(contents.len() as u64)
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let names = components(path)?;
+        self.tx(|tx| {
+            let node = lookup(tx, &names, true)?;
+            if node.data().is_dir() {
+                return Err(Error::new(EISDIR));
+            }
+            read_node(tx, &node)
+        })
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let (parent, name) = split(path)?;
+        let target = target.as_os_str().as_bytes();
+        self.tx(|tx| {
+            let mut stack = vec![TreePtr::root()];
+            walk(tx, &mut stack, &parent, true, false, 0)?;
+            let directory = current(&stack);
+            if let Ok(existing) = tx.find_node(directory, name) {
+                tx.remove_node(directory, name, existing.data().mode())?;
+            }
+            let (seconds, nanoseconds) = now();
+            let node = tx.create_node(directory, name, Node::MODE_SYMLINK | 0o777, seconds, nanoseconds)?;
+            tx.write_node(node.ptr(), 0, target, seconds, nanoseconds)?;
+            Ok(())
+        })
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let names = components(path)?;
+        self.tx(|tx| {
+            let mut node = lookup(tx, &names, true)?;
+            let kind = node.data().mode() & Node::MODE_TYPE;
+            node.data_mut().set_mode(kind | (mode as u16 & Node::MODE_PERM));
+            tx.sync_tree(node)
+        })
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        let names = components(path)?;
+        self.tx(|tx| {
+            let mut node = lookup(tx, &names, false)?;
+            node.data_mut().set_uid(uid);
+            node.data_mut().set_gid(gid);
+            tx.sync_tree(node)
+        })
+    }
+
+    fn is_dir(&self, path: &Path) -> Option<bool> {
+        let names = components(path).ok()?;
+        self.tx(|tx| lookup(tx, &names, false)).ok().map(|node| node.data().is_dir())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let (parent, name) = split(path)?;
+        self.tx(|tx| {
+            let mut stack = vec![TreePtr::root()];
+            walk(tx, &mut stack, &parent, true, false, 0)?;
+            tx.remove_node(current(&stack), name, Node::MODE_FILE).map(|_| ())
+        })
+    }
+
+    // Libera los nodos sin enlaces y compacta el registro de bloques, como
+    // hace redoxfs al desmontar
+    fn sync(&self) -> io::Result<()> {
+        let mut fs = self.fs.borrow_mut();
+        fs.cleanup().map_err(syscall_error)?;
+        fs.disk.file.sync_all()
+    }
+}
+
+fn syscall_error(error: Error) -> io::Error {
+    io::Error::from_raw_os_error(error.errno)
+}
+
+fn now() -> (u64, u32) {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (elapsed.as_secs(), elapsed.subsec_nanos())
+}
+
+// Componentes de una ruta relativa a la raíz; ".." se resuelve al recorrerla
+fn components(path: &Path) -> io::Result<Vec<&str>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => names.push(".."),
+            Component::Normal(name) => names.push(name.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{} no es UTF-8", path.display()))
+            })?),
+            Component::Prefix(_) => unreachable!("no hay prefijos en Unix"),
+        }
+    }
+    Ok(names)
+}
+
+// Directorio padre y nombre del último componente
+fn split(path: &Path) -> io::Result<(Vec<&str>, &str)> {
+    let mut names = components(path)?;
+    match names.pop() {
+        Some(name) if name != ".." => Ok((names, name)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} no nombra un fichero", path.display()))),
+    }
+}
+
+fn current(stack: &[TreePtr<Node>]) -> TreePtr<Node> {
+    *stack.last().expect("la pila empieza en la raíz")
+}
+
+// Recorre `names` desde el último directorio de `stack` dejando en la pila
+// los nodos atravesados. Los enlaces intermedios se siguen siempre; el
+// último solo con `follow`. Con `create` se crean los directorios que falten.
+fn walk(
+    tx: &mut Transaction<DiskFile>,
+    stack: &mut Vec<TreePtr<Node>>,
+    names: &[&str],
+    follow: bool,
+    create: bool,
+    depth: usize,
+) -> syscall::Result<()> {
+    for (index, name) in names.iter().enumerate() {
+        if *name == ".." {
+            if stack.len() > 1 {
+                stack.pop();
+            }
+            continue;
+        }
+
+        let parent = current(stack);
+        if !tx.read_tree(parent)?.data().is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        let node = match tx.find_node(parent, name) {
+            Ok(node) => node,
+            Err(error) if error.errno == ENOENT && create => {
+                let (seconds, nanoseconds) = now();
+                tx.create_node(parent, name, Node::MODE_DIR | 0o755, seconds, nanoseconds)?
+            }
+            Err(error) => return Err(error),
+        };
+
+        let last = index + 1 == names.len();
+        if !node.data().is_symlink() || (last && !follow) {
+            stack.push(node.ptr());
+            continue;
+        }
+
+        if depth == MAX_SYMLINKS {
+            return Err(Error::new(ELOOP));
+        }
+        let target = read_node(tx, &node)?;
+        let target = Path::new(std::ffi::OsStr::from_bytes(&target));
+        if target.has_root() {
+            stack.truncate(1);
+        }
+        let target_names = components(target).map_err(|_| Error::new(EINVAL))?;
+        walk(tx, stack, &target_names, true, create, depth + 1)?;
+    }
+    Ok(())
+}
+
+fn lookup(tx: &mut Transaction<DiskFile>, names: &[&str], follow: bool) -> syscall::Result<TreeData<Node>> {
+    let mut stack = vec![TreePtr::root()];
+    walk(tx, &mut stack, names, follow, false, 0)?;
+    tx.read_tree(current(&stack))
+}
+
+fn read_node(tx: &mut Transaction<DiskFile>, node: &TreeData<Node>) -> syscall::Result<Vec<u8>> {
+    let mut contents = vec![0; node.data().size() as usize];
+    let (seconds, nanoseconds) = now();
+    let read = tx.read_node(node.ptr(), 0, &mut contents, seconds, nanoseconds)?;
+    contents.truncate(read);
+    Ok(contents)
+}
+
+// Sustituye el contenido del fichero `name` (siguiendo un enlace, como
+// fs::write) o lo crea con `mode`
+fn write_node(
+    tx: &mut Transaction<DiskFile>,
+    parent: &[&str],
+    name: &str,
+    contents: &[u8],
+    mode: u16,
+) -> syscall::Result<TreeData<Node>> {
+    let mut stack = vec![TreePtr::root()];
+    walk(tx, &mut stack, parent, true, false, 0)?;
+    let (seconds, nanoseconds) = now();
+    let node = match tx.find_node(current(&stack), name) {
+        Ok(_) => {
+            walk(tx, &mut stack, &[name], true, false, 0)?;
+            let node = tx.read_tree(current(&stack))?;
+            if node.data().is_dir() {
+                return Err(Error::new(EISDIR));
+            }
+            tx.truncate_node(node.ptr(), 0, seconds, nanoseconds)?;
+            node
+        }
+        Err(error) if error.errno == ENOENT => tx.create_node(current(&stack), name, mode, seconds, nanoseconds)?,
+        Err(error) => return Err(error),
+    };
+    tx.write_node(node.ptr(), 0, contents, seconds, nanoseconds)?;
+    tx.read_tree(node.ptr())
+}
+
+// Abre el RedoxFS que ya hay en la imagen, como haría redoxfs al montarla
+#[cfg(test)]
+pub fn reopen(image: &Path) -> RedoxFsWriter {
+    let fs = FileSystem::open(DiskFile::open(image).unwrap(), None, None, true).unwrap();
+    RedoxFsWriter { fs: RefCell::new(fs) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlink_replaces_what_was_there_without_following_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = MountedWriter::new(dir.path());
+        writer.write_file(Path::new("ion"), b"ion").unwrap();
+        writer.write_file(Path::new("sh"), b"old").unwrap();
+
+        writer.symlink(Path::new("ion"), Path::new("/sh")).unwrap();
+        writer.set_mode(Path::new("ion"), 0o755).unwrap();
+
+        assert_eq!(fs::read_link(dir.path().join("sh")).unwrap(), Path::new("ion"));
+        assert_eq!(writer.read_file(Path::new("sh")).unwrap(), b"ion");
+        assert_eq!(writer.is_dir(Path::new("sh")), Some(false));
+        assert_eq!(writer.is_dir(Path::new("")), Some(true));
+        assert_eq!(writer.is_dir(Path::new("missing")), None);
+        let mode = fs::metadata(dir.path().join("ion")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    // Partición de 16 MiB en un fichero disperso
+    fn redoxfs_image(dir: &Path) -> PathBuf {
+        let image = dir.join("root.img");
+        fs::File::create(&image).unwrap().set_len(16 * 1024 * 1024).unwrap();
+        image
+    }
+
+    #[test]
+    fn redoxfs_writer_creates_a_filesystem_that_can_be_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = redoxfs_image(dir.path());
+        let source = dir.path().join("ion");
+        fs::write(&source, "ion").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let writer = RedoxFsWriter::create(&image).unwrap();
+        let uuid = writer.uuid();
+        writer.create_dir_all(Path::new("usr/bin")).unwrap();
+        writer.symlink(Path::new("usr/bin"), Path::new("bin")).unwrap();
+        // A través del enlace, como /bin -> usr/bin en el stage
+        assert_eq!(writer.copy_file(&source, Path::new("bin/ion")).unwrap(), 3);
+        writer.symlink(Path::new("ion"), Path::new("usr/bin/sh")).unwrap();
+        writer.write_file(Path::new("/etc/hostname"), b"old").unwrap_err();
+        writer.create_dir_all(Path::new("etc")).unwrap();
+        writer.write_file(Path::new("/etc/hostname"), b"a-much-longer-name").unwrap();
+        writer.write_file(Path::new("/etc/hostname"), b"lab-01").unwrap();
+        writer.set_mode(Path::new("etc/hostname"), 0o600).unwrap();
+        writer.set_owner(Path::new("etc/hostname"), 1000, 100).unwrap();
+        writer.sync().unwrap();
+        drop(writer);
+
+        let writer = reopen(&image);
+        assert_eq!(writer.uuid(), uuid);
+        assert_eq!(writer.read_file(Path::new("usr/bin/ion")).unwrap(), b"ion");
+        assert_eq!(writer.read_file(Path::new("bin/sh")).unwrap(), b"ion");
+        assert_eq!(writer.read_file(Path::new("etc/hostname")).unwrap(), b"lab-01");
+        assert_eq!(writer.is_dir(Path::new("bin")), Some(false));
+        // ".." tras un enlace sube desde su destino, como en Linux
+        assert_eq!(writer.is_dir(Path::new("bin/../etc")), None);
+        assert_eq!(writer.is_dir(Path::new("bin/../../etc")), Some(true));
+        assert_eq!(writer.is_dir(Path::new("missing")), None);
+        writer.tx(|tx| {
+            let ion = lookup(tx, &["usr", "bin", "ion"], true)?;
+            assert_eq!(ion.data().mode(), Node::MODE_FILE | 0o755);
+            let hostname = lookup(tx, &["etc", "hostname"], true)?;
+            assert_eq!(hostname.data().mode(), Node::MODE_FILE | 0o600);
+            assert_eq!((hostname.data().uid(), hostname.data().gid()), (1000, 100));
+            let sh = lookup(tx, &["bin", "sh"], false)?;
+            assert!(sh.data().is_symlink());
+            Ok(())
+        })
+        .unwrap();
+
+        writer.remove_file(Path::new("usr/bin/sh")).unwrap();
+        assert_eq!(writer.is_dir(Path::new("usr/bin/sh")), None);
+        assert_eq!(writer.remove_file(Path::new("usr")).unwrap_err().raw_os_error(), Some(EISDIR));
+    }
+
+    #[test]
+    fn redoxfs_symlink_loops_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let writer = RedoxFsWriter::create(&redoxfs_image(dir.path())).unwrap();
+        writer.symlink(Path::new("b"), Path::new("a")).unwrap();
+        writer.symlink(Path::new("/a"), Path::new("b")).unwrap();
+
+        let err = writer.read_file(Path::new("a")).unwrap_err();
+
+        assert_eq!(err.raw_os_error(), Some(ELOOP));
+    }
+}
@@ -0,0 +1,751 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::AsRawFd;
+
+// Escritor de tablas de particiones GPT (UEFI 2.x, capítulo 5) sin depender
+// de parted. Genera el MBR protector, las cabeceras primaria y de respaldo
+// con sus CRC32 y las entradas de partición alineadas a 1 MiB.
+
+// ioctls de bloque que libc no exporta para todas las arquitecturas
+const BLKRRPART: libc::c_ulong = 0x125F;
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+const BLKPG: libc::c_ulong = 0x1269;
+const BLKPG_ADD_PARTITION: libc::c_int = 1;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+const NAME_UTF16_LEN: usize = 36;
+const ALIGNMENT: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    // Convierte la forma textual a la codificación en disco: los tres primeros
+    // campos son little endian y los dos últimos se guardan tal cual
+    pub const fn parse(text: &str) -> Guid {
+        const fn hex(c: u8) -> u8 {
+            match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                _ => panic!("GUID con caracteres no hexadecimales"),
+            }
+        }
+
+        let text = text.as_bytes();
+        assert!(text.len() == 36, "GUID con longitud incorrecta");
+
+        // Posición de cada byte en el orden de disco dentro del texto
+        const ORDER: [usize; 16] = [6, 4, 2, 0, 11, 9, 16, 14, 19, 21, 24, 26, 28, 30, 32, 34];
+        let mut bytes = [0u8; 16];
+        let mut i = 0;
+        while i < 16 {
+            bytes[i] = hex(text[ORDER[i]]) << 4 | hex(text[ORDER[i] + 1]);
+            i += 1;
+        }
+        Guid(bytes)
+    }
+
+    // GUID aleatorio versión 4 (RFC 4122)
+    pub fn random() -> Result<Guid, String> {
+        let mut bytes = [0u8; 16];
+        File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+            .map_err(|e| format!("Error leyendo /dev/urandom: {}", e))?;
+        bytes[7] = (bytes[7] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        Ok(Guid(bytes))
+    }
+
+    // GUID en la codificación de disco, como en la tabla GPT
+    pub fn from_bytes(bytes: [u8; 16]) -> Guid {
+        Guid(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6],
+            b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub const EFI_SYSTEM_PARTITION: Guid = Guid::parse("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+pub const LINUX_FILESYSTEM: Guid = Guid::parse("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+pub const LINUX_SWAP: Guid = Guid::parse("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F");
+// Redox no tiene tipos registrados en la especificación UEFI; estos GUID fijos
+// permiten reconocer la partición root (y las de datos) de una instalación
+pub const REDOX_ROOT: Guid = Guid::parse("52454458-4F53-4653-8000-0000526F6F74");
+pub const REDOX_DATA: Guid = Guid::parse("52454458-4F53-4653-8000-000044617461");
+// Partición sin sistema de archivos donde va el stage 2 de un bootloader BIOS
+pub const BIOS_BOOT: Guid = Guid::parse("21686148-6449-6E6F-744E-656564454649");
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GptPartition {
+    // Número de partición (posición de la entrada en la tabla, desde 1)
+    pub number: usize,
+    pub name: String,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    // Inclusivo, como en la especificación
+    pub last_lba: u64,
+    // Se conservan tal cual en las particiones existentes
+    pub attributes: u64,
+}
+
+impl GptPartition {
+    pub fn size_bytes(&self, sector_size: u64) -> u64 {
+        (self.last_lba - self.first_lba + 1) * sector_size
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GptTable {
+    pub sector_size: u64,
+    pub total_sectors: u64,
+    pub disk_guid: Guid,
+    pub partitions: Vec<GptPartition>,
+}
+
+impl GptTable {
+    pub fn new(total_bytes: u64, sector_size: u64) -> Result<Self, String> {
+        let table = Self {
+            sector_size,
+            total_sectors: total_bytes / sector_size,
+            disk_guid: Guid::random()?,
+            partitions: Vec::new(),
+        };
+
+        if table.last_usable_lba() < table.aligned(table.first_usable_lba()) {
+            return Err(format!("El disco es demasiado pequeño para una tabla GPT ({} bytes)", total_bytes));
+        }
+
+        Ok(table)
+    }
+
+    fn entries_sectors(&self) -> u64 {
+        (ENTRY_COUNT as u64 * ENTRY_SIZE as u64).div_ceil(self.sector_size)
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        2 + self.entries_sectors()
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        self.total_sectors - 2 - self.entries_sectors()
+    }
+
+    fn backup_header_lba(&self) -> u64 {
+        self.total_sectors - 1
+    }
+
+    fn backup_entries_lba(&self) -> u64 {
+        self.backup_header_lba() - self.entries_sectors()
+    }
+
+    // Primer sector alineado a 1 MiB a partir de `lba`
+    fn aligned(&self, lba: u64) -> u64 {
+        let alignment = (ALIGNMENT / self.sector_size).max(1);
+        lba.div_ceil(alignment) * alignment
+    }
+
+    // Añade una partición tras la última existente. Con `size_bytes` a None
+    // ocupa el resto del disco.
+    pub fn add_partition(&mut self, name: &str, type_guid: Guid, size_bytes: Option<u64>) -> Result<&GptPartition, String> {
+        let start = self.partitions
+            .iter()
+            .map(|partition| partition.last_lba + 1)
+            .max()
+            .unwrap_or_else(|| self.first_usable_lba());
+        let end = self.last_usable_lba();
+        self.add_partition_in(name, type_guid, (start, end), size_bytes)
+    }
+
+    // Añade una partición dentro del rango de sectores `gap` (inclusivo), que
+    // debe estar libre. Con `size_bytes` a None ocupa hasta el final del rango.
+    pub fn add_partition_in(&mut self, name: &str, type_guid: Guid, gap: (u64, u64), size_bytes: Option<u64>) -> Result<&GptPartition, String> {
+        if name.encode_utf16().count() > NAME_UTF16_LEN {
+            return Err(format!("Nombre de partición demasiado largo: {}", name));
+        }
+
+        let number = self.free_number()?;
+        let (start, end) = gap;
+        let first_lba = self.aligned(start.max(self.first_usable_lba()));
+        let end = end.min(self.last_usable_lba());
+
+        let last_lba = match size_bytes {
+            Some(size) => first_lba + size.div_ceil(self.sector_size) - 1,
+            None => end,
+        };
+
+        let overlaps = self.partitions
+            .iter()
+            .any(|partition| first_lba <= partition.last_lba && partition.first_lba <= last_lba);
+        if first_lba > end || last_lba > end || last_lba < first_lba || overlaps {
+            return Err(format!("No hay espacio suficiente en el disco para la partición {}", name));
+        }
+
+        self.partitions.push(GptPartition {
+            number,
+            name: name.to_string(),
+            type_guid,
+            unique_guid: Guid::random()?,
+            first_lba,
+            last_lba,
+            attributes: 0,
+        });
+        Ok(self.partitions.last().unwrap())
+    }
+
+    // Partición de arranque BIOS en los sectores que la alineación a 1 MiB
+    // deja libres antes de la primera partición (34-2047 con sectores de 512
+    // bytes), igual que en harddrive.img
+    pub fn add_bios_boot_partition(&mut self) -> Result<&GptPartition, String> {
+        let first_lba = self.first_usable_lba();
+        let last_lba = self.aligned(first_lba) - 1;
+        let overlaps = self.partitions.iter().any(|partition| partition.first_lba <= last_lba);
+        if last_lba < first_lba || overlaps {
+            return Err("No hay espacio antes de la primera partición para la partición de arranque BIOS".to_string());
+        }
+
+        self.partitions.push(GptPartition {
+            number: self.free_number()?,
+            name: "BIOS".to_string(),
+            type_guid: BIOS_BOOT,
+            unique_guid: Guid::random()?,
+            first_lba,
+            last_lba,
+            attributes: 0,
+        });
+        Ok(self.partitions.last().unwrap())
+    }
+
+    fn free_number(&self) -> Result<usize, String> {
+        (1..=ENTRY_COUNT as usize)
+            .find(|number| self.partitions.iter().all(|partition| partition.number != *number))
+            .ok_or_else(|| "La tabla GPT no admite más particiones".to_string())
+    }
+
+    // Huecos libres de al menos 1 MiB entre particiones, como rangos de
+    // sectores inclusivos ya alineados
+    pub fn free_gaps(&self) -> Vec<(u64, u64)> {
+        let mut used: Vec<(u64, u64)> = self.partitions
+            .iter()
+            .map(|partition| (partition.first_lba, partition.last_lba))
+            .collect();
+        used.sort();
+
+        let min_sectors = ALIGNMENT / self.sector_size;
+        let mut gaps = Vec::new();
+        let mut next = self.first_usable_lba();
+        for (first, last) in used.into_iter().chain([(self.last_usable_lba() + 1, 0)]) {
+            let start = self.aligned(next);
+            if first > start && first - start >= min_sectors {
+                gaps.push((start, first - 1));
+            }
+            next = next.max(last + 1);
+        }
+        gaps
+    }
+
+    pub fn partition(&self, number: usize) -> Option<&GptPartition> {
+        self.partitions.iter().find(|partition| partition.number == number)
+    }
+
+    // Lee la tabla GPT primaria de un disco, comprobando firmas y CRC32
+    pub fn read_from(file: &File, total_bytes: u64, sector_size: u64) -> Result<Self, String> {
+        let read = |offset: u64, len: usize| {
+            let mut data = vec![0u8; len];
+            file.read_exact_at(&mut data, offset)
+                .map(|_| data)
+                .map_err(|e| format!("Error leyendo la tabla GPT en el byte {}: {}", offset, e))
+        };
+        let u32_at = |data: &[u8], offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at = |data: &[u8], offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        let header = read(sector_size, sector_size as usize)?;
+        if &header[0..8] != GPT_SIGNATURE {
+            return Err("El disco no tiene una tabla de particiones GPT".to_string());
+        }
+
+        let header_size = u32_at(&header, 12) as usize;
+        if !(GPT_HEADER_SIZE as usize..=sector_size as usize).contains(&header_size) {
+            return Err(format!("Cabecera GPT con tamaño inválido: {}", header_size));
+        }
+        let mut zeroed = header[..header_size].to_vec();
+        zeroed[16..20].fill(0);
+        if crc32fast::hash(&zeroed) != u32_at(&header, 16) {
+            return Err("La cabecera GPT está dañada (CRC32 incorrecto)".to_string());
+        }
+
+        if u32_at(&header, 80) != ENTRY_COUNT || u32_at(&header, 84) != ENTRY_SIZE {
+            return Err(format!(
+                "Formato de tabla GPT no soportado ({} entradas de {} bytes)",
+                u32_at(&header, 80),
+                u32_at(&header, 84)
+            ));
+        }
+
+        let entries = read(u64_at(&header, 72) * sector_size, (ENTRY_COUNT * ENTRY_SIZE) as usize)?;
+        if crc32fast::hash(&entries) != u32_at(&header, 88) {
+            return Err("Las entradas de la tabla GPT están dañadas (CRC32 incorrecto)".to_string());
+        }
+
+        let mut table = Self {
+            sector_size,
+            total_sectors: total_bytes / sector_size,
+            disk_guid: Guid(header[56..72].try_into().unwrap()),
+            partitions: Vec::new(),
+        };
+
+        for (i, entry) in entries.chunks(ENTRY_SIZE as usize).enumerate() {
+            let type_guid = Guid(entry[0..16].try_into().unwrap());
+            if type_guid.0 == [0u8; 16] {
+                continue;
+            }
+
+            let name: Vec<u16> = entry[56..]
+                .chunks(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+
+            table.partitions.push(GptPartition {
+                number: i + 1,
+                name: String::from_utf16_lossy(&name),
+                type_guid,
+                unique_guid: Guid(entry[16..32].try_into().unwrap()),
+                first_lba: u64_at(entry, 32),
+                last_lba: u64_at(entry, 40),
+                attributes: u64_at(entry, 48),
+            });
+        }
+
+        if table.partitions.iter().any(|partition| partition.last_lba > table.last_usable_lba()) {
+            return Err("La tabla GPT describe particiones fuera del área utilizable del disco".to_string());
+        }
+
+        Ok(table)
+    }
+
+    fn entries_bytes(&self) -> Vec<u8> {
+        let mut entries = vec![0u8; (self.entries_sectors() * self.sector_size) as usize];
+        for partition in &self.partitions {
+            let i = partition.number - 1;
+            let entry = &mut entries[i * ENTRY_SIZE as usize..(i + 1) * ENTRY_SIZE as usize];
+            entry[0..16].copy_from_slice(partition.type_guid.as_bytes());
+            entry[16..32].copy_from_slice(partition.unique_guid.as_bytes());
+            entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+            entry[48..56].copy_from_slice(&partition.attributes.to_le_bytes());
+            for (j, unit) in partition.name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        entries
+    }
+
+    fn header_bytes(&self, current_lba: u64, backup_lba: u64, entries_lba: u64, entries_crc: u32) -> Vec<u8> {
+        let mut header = vec![0u8; self.sector_size as usize];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        header[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        // CRC de la cabecera (16..20) se calcula con el campo a cero
+        header[24..32].copy_from_slice(&current_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba().to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable_lba().to_le_bytes());
+        header[56..72].copy_from_slice(self.disk_guid.as_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&ENTRY_COUNT.to_le_bytes());
+        header[84..88].copy_from_slice(&ENTRY_SIZE.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+
+        let header_crc = crc32fast::hash(&header[..GPT_HEADER_SIZE as usize]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        header
+    }
+
+    // MBR con una única partición 0xEE que cubre el disco, para que las
+    // herramientas que solo conocen MBR no lo consideren vacío
+    fn protective_mbr(&self) -> Vec<u8> {
+        let mut mbr = vec![0u8; self.sector_size as usize];
+        let sectors = (self.total_sectors - 1).min(u32::MAX as u64) as u32;
+        let entry = &mut mbr[446..462];
+        entry[0] = 0x00;
+        entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+        entry[4] = 0xEE;
+        entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        mbr
+    }
+
+    // Escribe la tabla completa. Antes se borra el primer y el último MiB del
+    // disco para eliminar restos de tablas y firmas anteriores.
+    pub fn write_to(&self, file: &File) -> Result<(), String> {
+        let total_bytes = self.total_sectors * self.sector_size;
+        let wipe_len = ALIGNMENT.min(total_bytes / 2);
+        let zeros = vec![0u8; wipe_len as usize];
+        write_at(file, 0, &zeros)?;
+        write_at(file, total_bytes - wipe_len, &zeros)?;
+        write_at(file, 0, &self.protective_mbr())?;
+
+        self.write_headers_and_entries(file)
+    }
+
+    // Reescribe cabeceras y entradas de una tabla existente sin tocar el MBR
+    // ni el resto del disco (instalación junto a otro sistema)
+    pub fn update_on(&self, file: &File) -> Result<(), String> {
+        self.write_headers_and_entries(file)
+    }
+
+    fn write_headers_and_entries(&self, file: &File) -> Result<(), String> {
+        let write = |offset: u64, data: &[u8]| write_at(file, offset, data);
+
+        let entries = self.entries_bytes();
+        let entries_crc = crc32fast::hash(&entries[..(ENTRY_COUNT * ENTRY_SIZE) as usize]);
+        let sector = |lba: u64| lba * self.sector_size;
+
+        write(sector(1), &self.header_bytes(1, self.backup_header_lba(), 2, entries_crc))?;
+        write(sector(2), &entries)?;
+        write(sector(self.backup_entries_lba()), &entries)?;
+        write(
+            sector(self.backup_header_lba()),
+            &self.header_bytes(self.backup_header_lba(), 1, self.backup_entries_lba(), entries_crc),
+        )?;
+
+        file.sync_all()
+            .map_err(|e| format!("Error sincronizando tabla GPT: {}", e))
+    }
+}
+
+pub fn write_at(file: &File, offset: u64, data: &[u8]) -> Result<(), String> {
+    file.write_all_at(data, offset)
+        .map_err(|e| format!("Error escribiendo tabla GPT en el byte {}: {}", offset, e))
+}
+
+// Abre el disco (o fichero de imagen) y devuelve su tamaño y tamaño de sector
+pub fn open_disk(path: &str) -> Result<(File, u64, u64), String> {
+    open_disk_with(path, true)
+}
+
+// Para consultar la tabla sin riesgo de modificar el disco
+pub fn open_disk_readonly(path: &str) -> Result<(File, u64, u64), String> {
+    open_disk_with(path, false)
+}
+
+fn open_disk_with(path: &str, writable: bool) -> Result<(File, u64, u64), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .map_err(|e| format!("Error abriendo {}: {}", path, e))?;
+
+    let metadata = fs::metadata(path)
+        .map_err(|e| format!("Error leyendo metadata de {}: {}", path, e))?;
+    if !metadata.file_type().is_block_device() {
+        return Ok((file, metadata.len(), 512));
+    }
+
+    let mut size: u64 = 0;
+    let mut sector_size: libc::c_int = 0;
+    // SAFETY: ambos ioctls escriben en las variables locales pasadas por puntero
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) != 0 {
+            return Err(format!("Error obteniendo el tamaño de {}: {}", path, std::io::Error::last_os_error()));
+        }
+        if libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET as _, &mut sector_size) != 0 {
+            return Err(format!("Error obteniendo el tamaño de sector de {}: {}", path, std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok((file, size, sector_size as u64))
+}
+
+#[repr(C)]
+struct BlkpgPartition {
+    start: libc::c_longlong,
+    length: libc::c_longlong,
+    pno: libc::c_int,
+    devname: [libc::c_char; 64],
+    volname: [libc::c_char; 64],
+}
+
+#[repr(C)]
+struct BlkpgIoctlArg {
+    op: libc::c_int,
+    flags: libc::c_int,
+    datalen: libc::c_int,
+    data: *mut libc::c_void,
+}
+
+// Registra particiones nuevas en el kernel una a una (BLKPG). A diferencia de
+// BLKRRPART funciona aunque otras particiones del disco estén montadas.
+pub fn add_partitions_to_kernel(file: &File, table: &GptTable, numbers: &[usize]) -> Result<(), String> {
+    let is_block_device = file.metadata()
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false);
+    if !is_block_device {
+        return Ok(());
+    }
+
+    for &number in numbers {
+        let partition = table.partition(number)
+            .ok_or_else(|| format!("La partición {} no está en la tabla GPT", number))?;
+        let mut data = BlkpgPartition {
+            start: (partition.first_lba * table.sector_size) as libc::c_longlong,
+            length: partition.size_bytes(table.sector_size) as libc::c_longlong,
+            pno: number as libc::c_int,
+            devname: [0; 64],
+            volname: [0; 64],
+        };
+        let mut arg = BlkpgIoctlArg {
+            op: BLKPG_ADD_PARTITION,
+            flags: 0,
+            datalen: std::mem::size_of::<BlkpgPartition>() as libc::c_int,
+            data: &mut data as *mut BlkpgPartition as *mut libc::c_void,
+        };
+
+        // SAFETY: arg y data viven hasta el final de la llamada
+        if unsafe { libc::ioctl(file.as_raw_fd(), BLKPG as _, &mut arg) } != 0 {
+            return Err(format!(
+                "El kernel no pudo registrar la partición {}: {}",
+                number,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Pide al kernel que vuelva a leer la tabla de particiones (BLKRRPART). En
+// ficheros normales no hay nada que releer.
+pub fn reread_partition_table(file: &File) -> Result<(), String> {
+    let is_block_device = file.metadata()
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false);
+    if !is_block_device {
+        return Ok(());
+    }
+
+    // SAFETY: BLKRRPART no recibe argumentos
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKRRPART as _) } != 0 {
+        return Err(format!(
+            "El kernel no pudo releer la tabla de particiones: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u64 = 512;
+    const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn read_back(data: &[u8]) -> GptTable {
+        let file = tempfile::tempfile().unwrap();
+        file.write_all_at(data, 0).unwrap();
+        GptTable::read_from(&file, DISK_SIZE, SECTOR).unwrap()
+    }
+
+    fn write_image(table: &GptTable) -> Vec<u8> {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(DISK_SIZE).unwrap();
+        table.write_to(&file).unwrap();
+        let mut data = Vec::new();
+        (&file).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn two_partition_table() -> GptTable {
+        let mut table = GptTable::new(DISK_SIZE, SECTOR).unwrap();
+        table.add_partition("REDOX_EFI", EFI_SYSTEM_PARTITION, Some(16 * 1024 * 1024)).unwrap();
+        table.add_partition("REDOX_ROOT", REDOX_ROOT, None).unwrap();
+        table
+    }
+
+    #[test]
+    fn reads_back_written_table() {
+        let mut table = two_partition_table();
+        table.partitions[0].attributes = 1;
+        let data = write_image(&table);
+
+        let read = read_back(&data);
+
+        assert_eq!(read.disk_guid, table.disk_guid);
+        assert_eq!(read.partitions, table.partitions);
+    }
+
+    #[test]
+    fn corrupted_table_is_rejected() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(DISK_SIZE).unwrap();
+        assert!(GptTable::read_from(&file, DISK_SIZE, SECTOR).unwrap_err().contains("no tiene"));
+
+        let mut data = write_image(&two_partition_table());
+        data[1024 + 60] ^= 0xFF;
+        file.write_all_at(&data, 0).unwrap();
+        assert!(GptTable::read_from(&file, DISK_SIZE, SECTOR).unwrap_err().contains("CRC32"));
+    }
+
+    #[test]
+    fn finds_gaps_and_fills_them_without_renumbering() {
+        let mut table = GptTable::new(DISK_SIZE, SECTOR).unwrap();
+        table.add_partition("A", LINUX_FILESYSTEM, Some(8 * 1024 * 1024)).unwrap();
+        table.add_partition("B", LINUX_FILESYSTEM, Some(8 * 1024 * 1024)).unwrap();
+        table.add_partition("C", LINUX_FILESYSTEM, Some(8 * 1024 * 1024)).unwrap();
+        // Quitar la partición del medio deja un hueco y la entrada 2 libre
+        table.partitions.remove(1);
+
+        let gaps = table.free_gaps();
+        assert_eq!(gaps, [(2048 + 16384, 2048 + 2 * 16384 - 1), (2048 + 3 * 16384, DISK_SIZE / SECTOR - 34)]);
+
+        let added = table.add_partition_in("REDOX", REDOX_ROOT, gaps[0], None).unwrap().clone();
+        assert_eq!(added.number, 2);
+        assert_eq!((added.first_lba, added.last_lba), gaps[0]);
+        assert_eq!(table.free_gaps(), [gaps[1]]);
+        assert!(table.add_partition_in("X", REDOX_ROOT, (2048, 4096), None).is_err());
+    }
+
+    #[test]
+    fn guid_uses_mixed_endian_encoding() {
+        assert_eq!(
+            EFI_SYSTEM_PARTITION.as_bytes(),
+            &[0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]
+        );
+        assert_eq!(EFI_SYSTEM_PARTITION.to_string(), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+    }
+
+    #[test]
+    fn random_guids_are_unique_version_4() {
+        let a = Guid::random().unwrap();
+        let b = Guid::random().unwrap();
+        assert_ne!(a, b);
+        assert_eq!(a.to_string().as_bytes()[14], b'4');
+    }
+
+    #[test]
+    fn partitions_are_aligned_and_fill_the_disk() {
+        let table = two_partition_table();
+        let efi = &table.partitions[0];
+        let root = &table.partitions[1];
+
+        assert_eq!(efi.first_lba, 2048);
+        assert_eq!(efi.size_bytes(SECTOR), 16 * 1024 * 1024);
+        assert_eq!(root.first_lba, efi.last_lba + 1);
+        assert_eq!(root.last_lba, DISK_SIZE / SECTOR - 34);
+        assert_ne!(efi.unique_guid, root.unique_guid);
+    }
+
+    #[test]
+    fn bios_boot_partition_uses_the_sectors_before_alignment() {
+        let mut table = GptTable::new(DISK_SIZE, SECTOR).unwrap();
+        let bios = table.add_bios_boot_partition().unwrap().clone();
+        let efi = table.add_partition("REDOX_EFI", EFI_SYSTEM_PARTITION, Some(16 * 1024 * 1024)).unwrap();
+
+        assert_eq!((bios.number, bios.first_lba, bios.last_lba), (1, 34, 2047));
+        assert_eq!(bios.type_guid.to_string(), "21686148-6449-6E6F-744E-656564454649");
+        assert_eq!((efi.number, efi.first_lba), (2, 2048));
+        assert!(table.add_bios_boot_partition().is_err());
+    }
+
+    #[test]
+    fn oversized_partition_is_rejected() {
+        let mut table = GptTable::new(DISK_SIZE, SECTOR).unwrap();
+        assert!(table.add_partition("EFI", EFI_SYSTEM_PARTITION, Some(DISK_SIZE)).is_err());
+        assert!(table.add_partition("x".repeat(37).as_str(), REDOX_ROOT, None).is_err());
+        assert!(GptTable::new(512 * 1024, SECTOR).is_err());
+    }
+
+    #[test]
+    fn writes_protective_mbr_and_both_headers() {
+        let table = two_partition_table();
+        let data = write_image(&table);
+        let last_lba = DISK_SIZE / SECTOR - 1;
+
+        // MBR protector
+        assert_eq!(data[446 + 4], 0xEE);
+        assert_eq!(read_u32(&data, 446 + 8), 1);
+        assert_eq!(read_u32(&data, 446 + 12) as u64, last_lba);
+        assert_eq!(&data[510..512], &[0x55, 0xAA]);
+
+        let primary = &data[SECTOR as usize..2 * SECTOR as usize];
+        let backup = &data[(last_lba * SECTOR) as usize..];
+        for (header, current, other, entries_lba) in [
+            (primary, 1, last_lba, 2),
+            (backup, last_lba, 1, last_lba - 32),
+        ] {
+            assert_eq!(&header[0..8], GPT_SIGNATURE);
+            assert_eq!(read_u64(header, 24), current);
+            assert_eq!(read_u64(header, 32), other);
+            assert_eq!(read_u64(header, 40), 34);
+            assert_eq!(read_u64(header, 48), last_lba - 33);
+            assert_eq!(read_u64(header, 72), entries_lba);
+
+            let mut zeroed = header[..92].to_vec();
+            zeroed[16..20].fill(0);
+            assert_eq!(read_u32(header, 16), crc32fast::hash(&zeroed));
+
+            let entries_start = (entries_lba * SECTOR) as usize;
+            let entries = &data[entries_start..entries_start + 128 * 128];
+            assert_eq!(read_u32(header, 88), crc32fast::hash(entries));
+        }
+    }
+
+    #[test]
+    fn writes_partition_entries() {
+        let table = two_partition_table();
+        let data = write_image(&table);
+
+        let entry = &data[1024..1024 + 128];
+        assert_eq!(&entry[0..16], EFI_SYSTEM_PARTITION.as_bytes());
+        assert_eq!(&entry[16..32], table.partitions[0].unique_guid.as_bytes());
+        assert_eq!(read_u64(entry, 32), 2048);
+        let name: Vec<u16> = entry[56..]
+            .chunks(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        assert_eq!(String::from_utf16(&name).unwrap(), "REDOX_EFI");
+
+        let entry = &data[1024 + 128..1024 + 256];
+        assert_eq!(&entry[0..16], REDOX_ROOT.as_bytes());
+        assert!(data[1024 + 256..1024 + 384].iter().all(|&b| b == 0));
+    }
+}
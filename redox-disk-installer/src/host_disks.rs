@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::mounts::unescape_mount_field;
+
+// Puntos de montaje de medios extraíbles o temporales: un disco montado solo
+// aquí no forma parte del sistema en ejecución
+const USER_MOUNT_PREFIXES: [&str; 3] = ["/media", "/run/media", "/mnt"];
+
+// Averigua qué discos sostienen el sistema en ejecución: sus sistemas de
+// archivos montados, la swap y los dispositivos LVM, dm-crypt y md activos.
+// Instalar en uno de ellos destruiría el propio sistema anfitrión.
+pub struct HostDisks {
+    dev: PathBuf,
+    sysfs: PathBuf,
+    proc_dir: PathBuf,
+}
+
+impl HostDisks {
+    pub fn new() -> Self {
+        Self::with_roots("/dev", "/sys", "/proc")
+    }
+
+    pub fn with_roots(dev: impl Into<PathBuf>, sysfs: impl Into<PathBuf>, proc_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dev: dev.into(),
+            sysfs: sysfs.into(),
+            proc_dir: proc_dir.into(),
+        }
+    }
+
+    // Nombre del disco (p. ej. "sda") -> motivos por los que está protegido
+    pub fn scan(&self) -> BTreeMap<String, Vec<String>> {
+        let mut usage: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut add = |disks: Vec<String>, reason: String| {
+            for disk in disks {
+                let reasons = usage.entry(disk).or_default();
+                if !reasons.contains(&reason) {
+                    reasons.push(reason.clone());
+                }
+            }
+        };
+
+        for (source, mount_point) in self.system_mounts() {
+            add(self.backing_disks(&source), format!("{} montado en {}", source, mount_point));
+        }
+        for source in self.swap_devices() {
+            add(self.backing_disks(&source), format!("swap en {}", source));
+        }
+        for (disk, device, holder) in self.held_devices() {
+            add(vec![disk], format!("{} en uso por {}", device, holder));
+        }
+
+        usage
+    }
+
+    // Dispositivos montados fuera de /media, /run/media y /mnt
+    fn system_mounts(&self) -> Vec<(String, String)> {
+        let contents = fs::read_to_string(self.proc_dir.join("mounts")).unwrap_or_default();
+        contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let source = fields.next()?;
+                let mount_point = unescape_mount_field(fields.next()?);
+                Some((source.to_string(), mount_point))
+            })
+            .filter(|(source, _)| source.starts_with("/dev/"))
+            .filter(|(_, mount_point)| {
+                !USER_MOUNT_PREFIXES.iter().any(|prefix| {
+                    mount_point == prefix || mount_point.starts_with(&format!("{}/", prefix))
+                })
+            })
+            .collect()
+    }
+
+    // Las swaps en fichero ya están cubiertas por el sistema de archivos que
+    // las contiene
+    fn swap_devices(&self) -> Vec<String> {
+        let contents = fs::read_to_string(self.proc_dir.join("swaps")).unwrap_or_default();
+        contents
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|source| source.starts_with("/dev/"))
+            .map(unescape_mount_field)
+            .collect()
+    }
+
+    // (disco, dispositivo, dispositivo que lo retiene) para cada disco o
+    // partición con entradas en holders/, p. ej. un PV de LVM o un miembro de
+    // un RAID md activos aunque nada esté montado
+    fn held_devices(&self) -> Vec<(String, String, String)> {
+        let mut held = Vec::new();
+        for disk in list_dir(&self.sysfs.join("block")) {
+            let disk_dir = self.sysfs.join("block").join(&disk);
+            let mut devices = vec![disk.clone()];
+            devices.extend(
+                list_dir(&disk_dir)
+                    .into_iter()
+                    .filter(|entry| disk_dir.join(entry).join("partition").exists()),
+            );
+
+            for device in devices {
+                let dir = if device == disk { disk_dir.clone() } else { disk_dir.join(&device) };
+                for holder in list_dir(&dir.join("holders")) {
+                    held.push((disk.clone(), format!("/dev/{}", device), self.describe_holder(&holder)));
+                }
+            }
+        }
+        held
+    }
+
+    fn describe_holder(&self, holder: &str) -> String {
+        let dir = self.sysfs.join("class/block").join(holder);
+        let read = |attr: &str| fs::read_to_string(dir.join(attr)).ok().map(|value| value.trim().to_string());
+        let kind = match read("dm/uuid") {
+            Some(uuid) if uuid.starts_with("LVM-") => "LVM",
+            Some(uuid) if uuid.starts_with("CRYPT-") => "dm-crypt",
+            Some(_) => "device-mapper",
+            None if dir.join("md").exists() => "RAID md",
+            None => "otro dispositivo",
+        };
+        match read("dm/name") {
+            Some(name) => format!("{} ({}, /dev/mapper/{})", holder, kind, name),
+            None => format!("{} ({})", holder, kind),
+        }
+    }
+
+    // Discos físicos bajo un dispositivo: la partición se sube a su disco y
+    // los dispositivos dm y md se siguen a través de slaves/
+    pub fn backing_disks(&self, source: &str) -> Vec<String> {
+        match self.block_name(source) {
+            Some(name) => self.disks_under(&name, 0),
+            None => Vec::new(),
+        }
+    }
+
+    // Nombre del dispositivo en sysfs, resolviendo enlaces como
+    // /dev/mapper/vg-root -> dm-0 o /dev/disk/by-uuid/... -> sda2
+    fn block_name(&self, source: &str) -> Option<String> {
+        let relative = source.strip_prefix("/dev/")?;
+        let path = self.dev.join(relative);
+        let resolved = fs::canonicalize(&path).unwrap_or(path);
+        let name = resolved.file_name()?.to_string_lossy().into_owned();
+        Some(name)
+    }
+
+    fn disks_under(&self, name: &str, depth: usize) -> Vec<String> {
+        let class = self.sysfs.join("class/block").join(name);
+        // Límite de seguridad ante ciclos en un sysfs inesperado
+        if depth > 8 || !class.exists() {
+            return Vec::new();
+        }
+
+        if class.join("partition").exists() {
+            return fs::canonicalize(&class)
+                .ok()
+                .and_then(|real| real.parent().and_then(Path::file_name).map(|disk| disk.to_string_lossy().into_owned()))
+                .into_iter()
+                .collect();
+        }
+
+        let slaves = list_dir(&class.join("slaves"));
+        if slaves.is_empty() {
+            return vec![name.to_string()];
+        }
+
+        let mut disks: Vec<String> = slaves.iter().flat_map(|slave| self.disks_under(slave, depth + 1)).collect();
+        disks.sort();
+        disks.dedup();
+        disks
+    }
+}
+
+fn list_dir(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    struct FakeHost {
+        dir: tempfile::TempDir,
+    }
+
+    impl FakeHost {
+        fn new() -> Self {
+            let host = Self { dir: tempfile::tempdir().unwrap() };
+            for dir in ["dev/mapper", "sys/block", "sys/class/block", "proc"] {
+                fs::create_dir_all(host.path(dir)).unwrap();
+            }
+            host
+        }
+
+        fn path(&self, relative: &str) -> PathBuf {
+            self.dir.path().join(relative)
+        }
+
+        // Disco con sus particiones, enlazado desde block/ y class/block/
+        fn disk(&self, name: &str, partitions: &[&str]) {
+            let real = self.path(&format!("sys/devices/pci0000:00/block/{}", name));
+            fs::create_dir_all(real.join("holders")).unwrap();
+            symlink(&real, self.path(&format!("sys/block/{}", name))).unwrap();
+            symlink(&real, self.path(&format!("sys/class/block/{}", name))).unwrap();
+            for partition in partitions {
+                fs::create_dir_all(real.join(partition).join("holders")).unwrap();
+                fs::write(real.join(partition).join("partition"), "1\n").unwrap();
+                symlink(real.join(partition), self.path(&format!("sys/class/block/{}", partition))).unwrap();
+            }
+        }
+
+        // Dispositivo device-mapper sobre `slaves`
+        fn dm(&self, name: &str, mapper_name: &str, uuid: &str, slaves: &[&str]) {
+            let real = self.path(&format!("sys/devices/virtual/block/{}", name));
+            fs::create_dir_all(real.join("dm")).unwrap();
+            fs::create_dir_all(real.join("slaves")).unwrap();
+            fs::create_dir_all(real.join("holders")).unwrap();
+            fs::write(real.join("dm/name"), format!("{}\n", mapper_name)).unwrap();
+            fs::write(real.join("dm/uuid"), format!("{}\n", uuid)).unwrap();
+            symlink(&real, self.path(&format!("sys/class/block/{}", name))).unwrap();
+            fs::write(self.path(&format!("dev/{}", name)), "").unwrap();
+            symlink(format!("../{}", name), self.path(&format!("dev/mapper/{}", mapper_name))).unwrap();
+            for slave in slaves {
+                let slave_dir = self.path(&format!("sys/class/block/{}", slave));
+                symlink(&slave_dir, real.join("slaves").join(slave)).unwrap();
+                symlink(&real, slave_dir.join("holders").join(name)).unwrap();
+            }
+        }
+
+        fn host_disks(&self) -> HostDisks {
+            HostDisks::with_roots(self.path("dev"), self.path("sys"), self.path("proc"))
+        }
+    }
+
+    #[test]
+    fn protects_disks_behind_mounts_swap_and_device_mapper() {
+        let host = FakeHost::new();
+        host.disk("nvme0n1", &["nvme0n1p1", "nvme0n1p2"]);
+        host.disk("sda", &["sda1"]);
+        host.disk("sdb", &["sdb1"]);
+        host.disk("sdc", &["sdc1"]);
+        host.dm("dm-0", "luks-root", "CRYPT-LUKS2-0123-luks-root", &["nvme0n1p2"]);
+        host.dm("dm-1", "vg-root", "LVM-abcdef", &["dm-0"]);
+        fs::write(
+            host.path("proc/mounts"),
+            "proc /proc proc rw 0 0\n\
+             /dev/mapper/vg-root / ext4 rw 0 0\n\
+             /dev/nvme0n1p1 /boot/efi vfat rw 0 0\n\
+             /dev/sdb1 /media/usuario/USB\\040Stick vfat rw 0 0\n",
+        ).unwrap();
+        fs::write(
+            host.path("proc/swaps"),
+            "Filename\tType\tSize\tUsed\tPriority\n/dev/sda1 partition 8388604 0 -2\n/swapfile file 1048572 0 -3\n",
+        ).unwrap();
+
+        let usage = host.host_disks().scan();
+
+        assert_eq!(usage.keys().collect::<Vec<_>>(), ["nvme0n1", "sda"]);
+        assert_eq!(
+            usage["nvme0n1"],
+            [
+                "/dev/mapper/vg-root montado en /",
+                "/dev/nvme0n1p1 montado en /boot/efi",
+                "/dev/nvme0n1p2 en uso por dm-0 (dm-crypt, /dev/mapper/luks-root)",
+            ]
+        );
+        assert_eq!(usage["sda"], ["swap en /dev/sda1"]);
+    }
+
+    #[test]
+    fn unknown_devices_are_ignored() {
+        let host = HostDisks::with_roots("/nonexistent/dev", "/nonexistent/sys", "/nonexistent/proc");
+
+        assert!(host.scan().is_empty());
+        assert!(host.backing_disks("/dev/sdz1").is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::gpt::GptTable;
+
+// Plan de una instalación en modo --dry-run: el instalador recorre los mismos
+// pasos que una instalación real pero registra aquí cada acción destructiva
+// en lugar de ejecutarla
+#[derive(Debug, Default, Serialize)]
+pub struct InstallPlan {
+    pub disk: String,
+    pub steps: Vec<PlanStep>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanStep {
+    pub title: String,
+    pub actions: Vec<PlannedAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlannedAction {
+    // Tabla GPT completa tal como quedará en el disco. `existing` indica que
+    // se actualiza una tabla previa (dual-boot) en lugar de crear una nueva
+    PartitionTable {
+        sector_size: u64,
+        existing: bool,
+        partitions: Vec<PlannedPartition>,
+    },
+    // Copia de la tabla actual que se guarda antes de modificarla
+    BackupPartitionTable {
+        directory: String,
+    },
+    Command {
+        program: String,
+        args: Vec<String>,
+    },
+    // RedoxFS creado con la librería redoxfs y escrito sin montarlo
+    CreateRedoxFs {
+        device: String,
+    },
+    CreateDirectory {
+        path: String,
+    },
+    CopyFile {
+        source: String,
+        destination: String,
+        bytes: u64,
+    },
+    // Cargador UEFI copiado con una firma Authenticode de `certificate`
+    SignFile {
+        source: String,
+        destination: String,
+        certificate: String,
+    },
+    // Certificado de firma en DER para inscribirlo como MOK
+    WriteCertificate {
+        path: String,
+        subject: String,
+        fingerprint: String,
+    },
+    // Stage 1 del bootloader BIOS en el MBR y stage 2 en la partición de
+    // arranque BIOS
+    WriteBiosBootloader {
+        source: String,
+        bytes: u64,
+    },
+    // Árbol stage de un paquete, resumido para no listar cada fichero
+    CopyTree {
+        source: String,
+        destination: String,
+        files: usize,
+        bytes: u64,
+    },
+    WriteFile {
+        path: String,
+        contents: String,
+    },
+    Symlink {
+        path: String,
+        target: String,
+    },
+    SetMode {
+        path: String,
+        mode: u32,
+    },
+    SetOwner {
+        path: String,
+        uid: u32,
+        gid: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedPartition {
+    pub number: usize,
+    pub name: String,
+    pub type_guid: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub bytes: u64,
+    // Partición que ya estaba en el disco y no se modifica
+    pub existing: bool,
+}
+
+impl InstallPlan {
+    pub fn new(disk: &str) -> Self {
+        Self {
+            disk: disk.to_string(),
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn begin_step(&mut self, title: &str) {
+        self.steps.push(PlanStep {
+            title: title.to_string(),
+            actions: Vec::new(),
+        });
+    }
+
+    pub fn record(&mut self, action: PlannedAction) {
+        if self.steps.is_empty() {
+            self.begin_step("Preparación");
+        }
+        self.steps.last_mut().expect("hay al menos un paso").actions.push(action);
+    }
+
+    // `existing` son los números de las particiones que ya había en el disco
+    pub fn record_table(&mut self, table: &GptTable, existing: &[usize]) {
+        let partitions = table
+            .partitions
+            .iter()
+            .map(|partition| PlannedPartition {
+                number: partition.number,
+                name: partition.name.clone(),
+                type_guid: partition.type_guid.to_string(),
+                first_lba: partition.first_lba,
+                last_lba: partition.last_lba,
+                bytes: partition.size_bytes(table.sector_size),
+                existing: existing.contains(&partition.number),
+            })
+            .collect();
+
+        self.record(PlannedAction::PartitionTable {
+            sector_size: table.sector_size,
+            existing: !existing.is_empty(),
+            partitions,
+        });
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("Error generando el plan en JSON: {}", e))
+    }
+}
+
+impl fmt::Display for InstallPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "📝 Plan de instalación en {} (no se ha modificado nada)", self.disk)?;
+        for step in &self.steps {
+            writeln!(f)?;
+            writeln!(f, "== {} ==", step.title)?;
+            for action in &step.actions {
+                write!(f, "{}", action)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedAction::PartitionTable { sector_size, existing, partitions } => {
+                let kind = if *existing { "Actualizar la tabla GPT existente" } else { "Escribir tabla GPT nueva" };
+                writeln!(f, "  {} (sectores de {} bytes):", kind, sector_size)?;
+                for partition in partitions {
+                    writeln!(
+                        f,
+                        "    {:>2}  {:<20} {:>10}-{:<10} {:>8} MB  {}{}",
+                        partition.number,
+                        partition.name,
+                        partition.first_lba,
+                        partition.last_lba,
+                        partition.bytes / 1024 / 1024,
+                        partition.type_guid,
+                        if partition.existing { "  (se conserva)" } else { "" }
+                    )?;
+                }
+                Ok(())
+            }
+            PlannedAction::BackupPartitionTable { directory } => {
+                writeln!(f, "  guardar copia de la tabla de particiones actual en {}/", directory)
+            }
+            PlannedAction::Command { program, args } => {
+                writeln!(f, "  $ {} {}", program, args.join(" "))
+            }
+            PlannedAction::CreateRedoxFs { device } => {
+                writeln!(f, "  crear RedoxFS en {} y escribirlo sin montar (sin FUSE)", device)
+            }
+            PlannedAction::CreateDirectory { path } => writeln!(f, "  crear directorio {}", path),
+            PlannedAction::CopyFile { source, destination, bytes } => {
+                writeln!(f, "  copiar {} -> {} ({} bytes)", source, destination, bytes)
+            }
+            PlannedAction::SignFile { source, destination, certificate } => {
+                writeln!(f, "  firmar {} -> {} (certificado {})", source, destination, certificate)
+            }
+            PlannedAction::WriteCertificate { path, subject, fingerprint } => {
+                writeln!(f, "  escribir certificado {} en {} (SHA-256 {})", subject, path, fingerprint)
+            }
+            PlannedAction::WriteBiosBootloader { source, bytes } => {
+                writeln!(f, "  escribir bootloader BIOS {} en el MBR y la partición de arranque BIOS ({} bytes)", source, bytes)
+            }
+            PlannedAction::CopyTree { source, destination, files, bytes } => {
+                writeln!(f, "  copiar {} -> {} ({} ficheros, {} bytes)", source, destination, files, bytes)
+            }
+            PlannedAction::WriteFile { path, contents } => {
+                writeln!(f, "  escribir {} ({} bytes):", path, contents.len())?;
+                for line in contents.lines() {
+                    writeln!(f, "    | {}", line)?;
+                }
+                Ok(())
+            }
+            PlannedAction::Symlink { path, target } => writeln!(f, "  enlace {} -> {}", path, target),
+            PlannedAction::SetMode { path, mode } => writeln!(f, "  permisos {:o} en {}", mode, path),
+            PlannedAction::SetOwner { path, uid, gid } => writeln!(f, "  propietario {}:{} en {}", uid, gid, path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt;
+
+    fn sample_plan() -> InstallPlan {
+        let mut table = GptTable::new(4 * 1024 * 1024 * 1024, 512).unwrap();
+        table.add_partition("REDOX_EFI", gpt::EFI_SYSTEM_PARTITION, Some(512 * 1024 * 1024)).unwrap();
+        table.add_partition("REDOX_ROOT", gpt::REDOX_ROOT, None).unwrap();
+
+        let mut plan = InstallPlan::new("/dev/sdb");
+        plan.begin_step("Crear particiones");
+        plan.record_table(&table, &[]);
+        plan.begin_step("Formatear particiones");
+        plan.record(PlannedAction::Command {
+            program: "mkfs.vfat".to_string(),
+            args: vec!["-F".to_string(), "32".to_string(), "/dev/sdb1".to_string()],
+        });
+        plan.record(PlannedAction::WriteFile {
+            path: "/boot/redox.conf".to_string(),
+            contents: "kernel=/boot/kernel\nroot=/dev/sdb2\n".to_string(),
+        });
+        plan
+    }
+
+    #[test]
+    fn human_readable_plan_lists_every_action() {
+        let text = sample_plan().to_string();
+
+        assert!(text.contains("Plan de instalación en /dev/sdb"), "{}", text);
+        assert!(text.contains("== Formatear particiones =="), "{}", text);
+        assert!(text.contains("Escribir tabla GPT nueva"), "{}", text);
+        assert!(text.contains("REDOX_EFI"), "{}", text);
+        assert!(text.contains("512 MB  C12A7328-F81F-11D2-BA4B-00A0C93EC93B"), "{}", text);
+        assert!(text.contains("  $ mkfs.vfat -F 32 /dev/sdb1"), "{}", text);
+        assert!(text.contains("    | root=/dev/sdb2"), "{}", text);
+    }
+
+    #[test]
+    fn json_plan_is_tagged_by_action() {
+        let json: serde_json::Value = serde_json::from_str(&sample_plan().to_json().unwrap()).unwrap();
+
+        assert_eq!(json["disk"], "/dev/sdb");
+        let table = &json["steps"][0]["actions"][0];
+        assert_eq!(table["action"], "partition_table");
+        assert_eq!(table["partitions"][1]["name"], "REDOX_ROOT");
+        assert_eq!(table["partitions"][0]["bytes"], 512 * 1024 * 1024);
+        let command = &json["steps"][1]["actions"][0];
+        assert_eq!(command["action"], "command");
+        assert_eq!(command["program"], "mkfs.vfat");
+    }
+}
@@ -1,14 +1,55 @@
 use std::io::{self, Write};
 
+use serde::Deserialize;
+
+mod answer_file;
+mod artifacts;
+mod authenticode;
+mod bios_boot;
+mod boot_entries;
+mod build_config;
+mod cli;
+mod command_runner;
+mod disk_image;
 mod disk_manager;
 mod direct_installer;
+mod disk_inspect;
+mod fs_writer;
+mod gpt;
+mod host_disks;
+mod install_plan;
+mod mounts;
+mod partition_layout;
+mod redoxfs_mount;
+mod rollback;
+mod sysfs;
+mod table_backup;
+mod target_arch;
 mod validation;
 
+use artifacts::{ArtifactResolver, BuildLocation};
+use boot_entries::BootPriority;
 use disk_manager::DiskManager;
 use direct_installer::DirectInstaller;
 use validation::SystemValidator;
+use cli::CliCommand;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    
+    // Con un subcomando se ejecuta el modo no interactivo; sin él, el menú
+    match cli::parse_args(&args) {
+        Ok(CliCommand::Menu) => run_menu(),
+        Ok(command) => std::process::exit(cli::run(command).code()),
+        Err(e) => {
+            eprintln!("❌ Error: {}", e);
+            eprintln!("   Usa 'redox-disk-installer help' para ver las opciones disponibles");
+            std::process::exit(cli::ExitCode::Usage.code());
+        }
+    }
+}
+
+fn run_menu() {
     println!("🦀 Redox OS - Instalador en Disco v1.0.0 🦀");
     println!("===========================================");
     println!();
@@ -17,7 +58,7 @@ fn main() {
     if !is_root() {
         eprintln!("❌ Error: Este instalador debe ejecutarse como root");
         eprintln!("   Usa: sudo ./redox-disk-installer");
-        std::process::exit(1);
+        std::process::exit(cli::ExitCode::NotRoot.code());
     }
     
     // Validar sistema
@@ -25,11 +66,12 @@ fn main() {
     if let Err(e) = validator.validate_system() {
         eprintln!("❌ Error de validación: {}", e);
         eprintln!("   Asegúrate de que todos los comandos requeridos estén instalados");
-        std::process::exit(1);
+        std::process::exit(cli::ExitCode::SystemValidation.code());
     }
     
     // Verificar que Redox OS esté compilado
-    if let Err(e) = validator.validate_redox_build() {
+    let artifacts = ArtifactResolver::new(BuildLocation::from_env());
+    if let Err(e) = validator.validate_redox_build(&artifacts) {
         eprintln!("⚠️  Advertencia: {}", e);
         eprintln!("   Ejecuta 'make all' para compilar Redox OS antes de continuar");
         let proceed = read_input("¿Deseas continuar de todos modos? (s/N): ");
@@ -97,12 +139,13 @@ fn install_redox_os_direct() {
     println!("💽 Discos disponibles:");
     println!("─────────────────────");
     for (i, disk) in disks.iter().enumerate() {
-        println!("  {}. {} - {} ({}) - {}", 
+        println!("  {}. {} - {} ({}) - {}{}", 
             i + 1, 
             disk.name, 
-            disk.size, 
+            disk.size_human(),
             disk.model,
-            disk.disk_type
+            disk.disk_type,
+            if disk.is_protected() { "  🔒 disco del sistema" } else { "" }
         );
     }
     println!();
@@ -121,7 +164,9 @@ fn install_redox_os_direct() {
     
     // Validar disco seleccionado
     let validator = SystemValidator::new();
-    if let Err(e) = validator.validate_disk(&selected_disk.name) {
+    // Desde el menú no se puede forzar: el disco del sistema solo se admite
+    // con --allow-system-disk en la línea de comandos
+    if let Err(e) = validator.validate_disk(selected_disk, false) {
         println!("❌ Error validando disco: {}", e);
         return;
     }
@@ -137,7 +182,26 @@ fn install_redox_os_direct() {
     println!("⚙️  Configuración de instalación:");
     println!("─────────────────────────────────");
     
-    let config = match get_installation_config() {
+    // Si el disco ya tiene Redox, ofrecer actualizarlo en lugar de reinstalar
+    let existing = disk_manager
+        .inspect(selected_disk)
+        .ok()
+        .and_then(|inspection| inspection.redox_installation());
+    let upgrade = match existing {
+        Some((esp, root)) => {
+            println!("🔎 {} ya tiene Redox OS instalado (EFI: partición {}, root: partición {})", selected_disk.name, esp, root);
+            let answer = read_input("¿Actualizarlo conservando /home, /root y /etc en lugar de reinstalar? (S/n): ");
+            answer.trim().to_lowercase() != "n"
+        }
+        None => false,
+    };
+
+    let config = if upgrade {
+        get_upgrade_config(selected_disk)
+    } else {
+        get_installation_config(selected_disk)
+    };
+    let config = match config {
         Some(cfg) => cfg,
         None => {
             println!("❌ Instalación cancelada");
@@ -170,7 +234,49 @@ fn install_redox_os_direct() {
     }
 }
 
-fn get_installation_config() -> Option<InstallationConfig> {
+fn get_upgrade_config(disk: &DiskInfo) -> Option<InstallationConfig> {
+    let default_config = BuildLocation::from_env().filesystem_config();
+    let filesystem_config = read_input(&format!("Configuración de Redox [{}]: ", default_config));
+    let filesystem_config = if filesystem_config.trim().is_empty() {
+        default_config
+    } else {
+        filesystem_config.trim().to_string()
+    };
+
+    let config = InstallationConfig {
+        filesystem_config,
+        upgrade: true,
+        ..InstallationConfig::default()
+    };
+
+    let preview = read_input("¿Ver el plan de actualización antes de confirmar? (s/N): ");
+    if preview.trim().to_lowercase() == "s" {
+        match DirectInstaller::new().plan_redox_os(disk, &config) {
+            Ok(plan) => {
+                println!();
+                print!("{}", plan);
+            }
+            Err(e) => {
+                println!("❌ Error preparando el plan de actualización: {}", e);
+                return None;
+            }
+        }
+    }
+
+    println!();
+    println!("Se sustituirán el bootloader, el kernel, el initfs y los paquetes.");
+    println!("Las particiones, /home, /root y /etc se conservan.");
+    println!();
+
+    let confirm = read_input("¿Continuar con la actualización? (s/N): ");
+    if confirm.trim().to_lowercase() != "s" {
+        return None;
+    }
+
+    Some(config)
+}
+
+fn get_installation_config(disk: &DiskInfo) -> Option<InstallationConfig> {
     let efi_size = read_input("Tamaño de partición EFI en MB (por defecto: 512): ");
     let efi_size_mb = if efi_size.trim().is_empty() {
         512
@@ -184,16 +290,57 @@ fn get_installation_config() -> Option<InstallationConfig> {
         }
     };
     
+    let default_config = BuildLocation::from_env().filesystem_config();
+    let filesystem_config = read_input(&format!("Configuración de Redox [{}]: ", default_config));
+    let filesystem_config = if filesystem_config.trim().is_empty() {
+        default_config
+    } else {
+        filesystem_config.trim().to_string()
+    };
+    
     let filesystem = read_input("Sistema de archivos para root (redoxfs/ext4) [redoxfs]: ");
-    let filesystem_type = if filesystem.trim().is_empty() || filesystem.trim().to_lowercase() == "redoxfs" {
+    let filesystem_type = if filesystem.trim().is_empty() {
         FilesystemType::RedoxFS
-    } else if filesystem.trim().to_lowercase() == "ext4" {
-        FilesystemType::Ext4
     } else {
-        println!("⚠️  Sistema de archivos inválido, usando RedoxFS");
-        FilesystemType::RedoxFS
+        cli::parse_filesystem(&filesystem).unwrap_or_else(|| {
+            println!("⚠️  Sistema de archivos inválido, usando RedoxFS");
+            FilesystemType::RedoxFS
+        })
     };
     
+    let boot_mode = read_input("Modo de arranque (uefi/hybrid/bios) [uefi]: ");
+    let boot_mode = if boot_mode.trim().is_empty() {
+        BootMode::Uefi
+    } else {
+        BootMode::parse(&boot_mode).unwrap_or_else(|_| {
+            println!("⚠️  Modo de arranque inválido, usando UEFI");
+            BootMode::Uefi
+        })
+    };
+    
+    let config = InstallationConfig {
+        efi_size_mb,
+        filesystem_type,
+        filesystem_config,
+        boot_mode,
+        ..InstallationConfig::default()
+    };
+
+    // Mostrar exactamente qué se va a hacer antes de pedir la confirmación
+    let preview = read_input("¿Ver el plan de instalación antes de confirmar? (s/N): ");
+    if preview.trim().to_lowercase() == "s" {
+        match DirectInstaller::new().plan_redox_os(disk, &config) {
+            Ok(plan) => {
+                println!();
+                print!("{}", plan);
+            }
+            Err(e) => {
+                println!("❌ Error preparando el plan de instalación: {}", e);
+                return None;
+            }
+        }
+    }
+
     println!();
     println!("⚠️  ¡ADVERTENCIA! ⚠️");
     println!("═══════════════════════════════════════════════════");
@@ -207,10 +354,7 @@ fn get_installation_config() -> Option<InstallationConfig> {
         return None;
     }
     
-    Some(InstallationConfig {
-        efi_size_mb,
-        filesystem_type,
-    })
+    Some(config)
 }
 
 fn show_disk_info() {
@@ -230,20 +374,78 @@ fn show_disk_info() {
     for (i, disk) in disks.iter().enumerate() {
         println!("┌─── Disco #{} ────────────────────────────────────", i + 1);
         println!("│ Dispositivo: {}", disk.name);
-        println!("│ Tamaño:      {}", disk.size);
+        println!("│ Tamaño:      {} ({} bytes, sectores de {}/{} bytes)",
+            disk.size_human(), disk.size_bytes, disk.logical_sector_size, disk.physical_sector_size);
         println!("│ Modelo:      {}", disk.model);
-        println!("│ Tipo:        {}", disk.disk_type);
+        if !disk.vendor.is_empty() {
+            println!("│ Fabricante:  {}", disk.vendor);
+        }
+        if !disk.serial.is_empty() {
+            println!("│ Serie:       {}", disk.serial);
+        }
+        if !disk.wwn.is_empty() {
+            println!("│ WWN:         {}", disk.wwn);
+        }
+        println!("│ Tipo:        {}{}", disk.disk_type, if disk.removable { " (extraíble)" } else { "" });
         
-        if disk_manager.is_disk_mounted(&disk.name) {
+        let mounted = disk_manager.mounted_partitions(&disk.name);
+        if disk.is_protected() {
+            println!("│ Estado:      🔒 Disco del sistema ({})", disk.host_usage.join(", "));
+        } else if !mounted.is_empty() {
             println!("│ Estado:      ⚠️  MONTADO");
         } else {
             println!("│ Estado:      ✅ Disponible");
         }
+        if mounted.iter().any(|partition| partition.device == disk.name) {
+            println!("│              {} montado entero", disk.name);
+        }
+
+        match disk_manager.inspect(disk) {
+            Ok(inspection) => print_disk_inspection(&inspection),
+            Err(e) => println!("│ Particiones: ⚠️  {}", e),
+        }
         println!("└──────────────────────────────────────────────────");
         println!();
     }
 }
 
+fn print_disk_inspection(inspection: &disk_inspect::DiskInspection) {
+    let table = match inspection.table.as_str() {
+        "gpt" => "GPT",
+        "mbr" => "MBR",
+        _ => "ninguna",
+    };
+    println!("│ Tabla:       {}", table);
+
+    for partition in &inspection.partitions {
+        let filesystem = match &partition.filesystem {
+            Some(fs) if fs.label.is_empty() => fs.kind.clone(),
+            Some(fs) => format!("{} \"{}\"", fs.kind, fs.label),
+            None => "desconocido".to_string(),
+        };
+        println!(
+            "│   {:>2} {:<16} {:>10}-{:<10} {:>8} MB  {:<18} {}",
+            partition.number,
+            partition.device,
+            partition.first_lba,
+            partition.last_lba,
+            partition.bytes / 1024 / 1024,
+            filesystem,
+            partition.partition_type
+        );
+        if !partition.name.is_empty() {
+            println!("│      Nombre: {}", partition.name);
+        }
+        for mount_point in &partition.mount_points {
+            println!("│      Montada en {}", mount_point);
+        }
+    }
+
+    if let Some((esp, root)) = inspection.redox_installation() {
+        println!("│ Redox OS:    ✅ Instalación existente (EFI: partición {}, root: partición {})", esp, root);
+    }
+}
+
 fn show_help() {
     println!("╔═══════════════════════════════════════════════════╗");
     println!("║      🦀 Ayuda del Instalador de Redox OS 🦀      ║");
@@ -256,7 +458,7 @@ fn show_help() {
     println!("⚙️  REQUISITOS");
     println!("──────────────");
     println!("  • Disco duro con al menos 2GB de espacio libre");
-    println!("  • Sistema UEFI compatible");
+    println!("  • Sistema UEFI compatible (o BIOS en x86_64 e i686)");
     println!("  • Redox OS compilado (ejecuta 'make all' primero)");
     println!("  • Privilegios de root/sudo");
     println!();
@@ -273,7 +475,7 @@ fn show_help() {
     println!("  2. Configuración (tamaño EFI, sistema de archivos)");
     println!("  3. Creación de particiones GPT (EFI + Root)");
     println!("  4. Formateo de particiones");
-    println!("  5. Instalación del bootloader UEFI");
+    println!("  5. Instalación del bootloader UEFI y/o BIOS");
     println!("  6. Instalación del kernel de Redox");
     println!("  7. Copia de archivos del sistema");
     println!("  8. Creación de configuración de arranque");
@@ -291,6 +493,13 @@ fn show_help() {
     println!("  • Asegúrate de que UEFI esté habilitado en tu BIOS");
     println!("  • Si el sistema no arranca, verifica la configuración UEFI");
     println!();
+    println!("🤖 MODO NO INTERACTIVO");
+    println!("───────────────────────");
+    println!("  • redox-disk-installer install --disk /dev/sdX --efi-size 512 --fs redoxfs --yes");
+    println!("  • redox-disk-installer install --image redox.img --size 4G --yes");
+    println!("  • redox-disk-installer list-disks");
+    println!("  • redox-disk-installer help");
+    println!();
 }
 
 fn is_root() -> bool {
@@ -308,12 +517,51 @@ fn read_input(prompt: &str) -> String {
     input
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DiskInfo {
     pub name: String,
-    pub size: String,
+    // Capacidad exacta en bytes
+    pub size_bytes: u64,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+    pub removable: bool,
+    pub rotational: bool,
     pub model: String,
+    pub vendor: String,
+    pub serial: String,
+    // Identificador mundial (WWN/EUI) si el disco lo expone
+    pub wwn: String,
+    // Bus de conexión: sata, nvme, usb, virtio, mmc, scsi...
+    pub transport: String,
+    // Descripción para mostrar, p. ej. "SATA SSD"
     pub disk_type: String,
+    // Usos del sistema anfitrión (/, swap, LVM...). Si no está vacío el disco
+    // está protegido y no se instala en él salvo que se fuerce
+    pub host_usage: Vec<String>,
+}
+
+impl DiskInfo {
+    pub fn is_protected(&self) -> bool {
+        !self.host_usage.is_empty()
+    }
+
+    // Tamaño en potencias de 1024, como lo muestra lsblk: 931.5G, 8G
+    pub fn size_human(&self) -> String {
+        let units = ["B", "K", "M", "G", "T", "P"];
+        let mut size = self.size_bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+
+        let rounded = (size * 10.0).round() / 10.0;
+        if rounded.fract() == 0.0 {
+            format!("{}{}", rounded, units[unit])
+        } else {
+            format!("{:.1}{}", rounded, units[unit])
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -322,9 +570,189 @@ pub enum FilesystemType {
     Ext4,
 }
 
+// Cómo arranca el firmware la instalación
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    Uefi,
+    // UEFI y BIOS en el mismo disco, como harddrive.img
+    Hybrid,
+    // Solo BIOS: partición de arranque BIOS y root, sin partición EFI
+    Bios,
+}
+
+impl BootMode {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "uefi" => Ok(BootMode::Uefi),
+            "hybrid" => Ok(BootMode::Hybrid),
+            "bios" => Ok(BootMode::Bios),
+            _ => Err(format!("Modo de arranque inválido: {} (uefi/hybrid/bios)", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BootMode::Uefi => "uefi",
+            BootMode::Hybrid => "hybrid",
+            BootMode::Bios => "bios",
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            BootMode::Uefi => "UEFI",
+            BootMode::Hybrid => "UEFI y BIOS",
+            BootMode::Bios => "BIOS",
+        }
+    }
+
+    pub fn uefi(self) -> bool {
+        self != BootMode::Bios
+    }
+
+    pub fn bios(self) -> bool {
+        self != BootMode::Uefi
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InstallationConfig {
     pub efi_size_mb: u64,
     pub filesystem_type: FilesystemType,
+    // Configuración del sistema de compilación que define el contenido de root
+    pub filesystem_config: String,
+    pub hostname: String,
+    // Recetas adicionales a instalar además de las de filesystem_config
+    pub packages: Vec<String>,
+    pub files: Vec<FileConfig>,
+    // Particiones en orden de creación. Vacío: EFI + root con el resto del
+    // disco según efi_size_mb y filesystem_type
+    pub partitions: Vec<PartitionSpec>,
+    // Instalar junto a otro sistema en el espacio libre, reutilizando su ESP
+    pub dual_boot: bool,
+    // Hueco libre a usar en dual-boot (1 = el primero); None: el mayor
+    pub free_gap: Option<usize>,
+    // Si la instalación falla, volver a escribir la tabla de particiones
+    // que tenía el disco antes de empezar
+    pub restore_table_on_failure: bool,
+    // Directorio donde se guarda la copia de la tabla de particiones original
+    pub backup_dir: String,
+    // Actualizar la instalación de Redox existente en el disco (bootloader,
+    // kernel, initfs y paquetes) sin particionar ni tocar /home, /root y /etc
+    pub upgrade: bool,
+    // UEFI, BIOS o ambos; con BIOS se crea además una partición de arranque
+    // BIOS y se escribe el bootloader en el MBR
+    pub boot_mode: BootMode,
+    // Dónde queda la entrada "Redox OS" en el orden de arranque UEFI
+    pub boot_priority: BootPriority,
+    // Firmar los cargadores UEFI para arrancar con Secure Boot
+    pub secure_boot: Option<SecureBootConfig>,
+    // Crear y escribir root RedoxFS con la librería redoxfs en lugar de
+    // montarla con FUSE (sin /dev/fuse, p. ej. dentro de un contenedor)
+    pub redoxfs_direct: bool,
+}
+
+// Clave y certificado con los que se firma el bootloader, y opcionalmente un
+// shim ya firmado (p. ej. el shimx64.efi de una distribución) que lo carga
+// después de comprobarlo contra los certificados inscritos en MOK
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecureBootConfig {
+    pub key: String,
+    pub certificate: String,
+    pub shim: Option<String>,
+    // MokManager (mmx64.efi) que shim abre para inscribir el certificado
+    pub mok_manager: Option<String>,
+}
+
+impl SecureBootConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mok_manager.is_some() && self.shim.is_none() {
+            return Err("MokManager solo se instala junto con shim".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl InstallationConfig {
+    pub fn partition_layout(&self) -> Vec<PartitionSpec> {
+        if self.partitions.is_empty() {
+            let mut layout = partition_layout::default_layout(self.efi_size_mb, &self.filesystem_type);
+            if !self.boot_mode.uefi() {
+                layout.retain(|spec| spec.filesystem != PartitionFilesystem::Efi);
+            }
+            layout
+        } else {
+            self.partitions.clone()
+        }
+    }
+}
+
+impl Default for InstallationConfig {
+    fn default() -> Self {
+        Self {
+            efi_size_mb: 512,
+            filesystem_type: FilesystemType::RedoxFS,
+            filesystem_config: "config/x86_64/desktop.toml".to_string(),
+            hostname: "redox".to_string(),
+            packages: Vec::new(),
+            files: Vec::new(),
+            partitions: Vec::new(),
+            dual_boot: false,
+            free_gap: None,
+            restore_table_on_failure: false,
+            backup_dir: "/var/backups/redox-disk-installer".to_string(),
+            upgrade: false,
+            boot_mode: BootMode::Uefi,
+            boot_priority: BootPriority::First,
+            secure_boot: None,
+            redoxfs_direct: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartitionSize {
+    Mebibytes(u64),
+    // Porcentaje del tamaño total del disco
+    Percent(u64),
+    // Lo que quede hasta el final del disco (solo la última partición)
+    Remaining,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartitionFilesystem {
+    // Partición de sistema EFI (FAT32)
+    Efi,
+    RedoxFS,
+    Ext4,
+    Swap,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionSpec {
+    // Nombre GPT y etiqueta del sistema de archivos
+    pub label: String,
+    pub size: PartitionSize,
+    pub filesystem: PartitionFilesystem,
+    // Punto de montaje dentro de Redox ("/" para root); None para EFI y swap
+    pub mount_point: Option<String>,
+}
+
+// Entrada [[files]] con el mismo formato que los config/*.toml de Redox
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub path: String,
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub symlink: bool,
+    #[serde(default)]
+    pub directory: bool,
+    pub mode: Option<u32>,
+    // Se aplica después de instalar los paquetes
+    #[serde(default)]
+    pub postinstall: bool,
 }
 
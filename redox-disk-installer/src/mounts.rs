@@ -0,0 +1,216 @@
+use std::fs;
+use std::path::Path;
+
+use crate::sysfs;
+
+// Entrada de /proc/self/mountinfo
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountInfo {
+    // Número mayor:menor del dispositivo montado
+    pub device: (u32, u32),
+    pub mount_point: String,
+    pub fs_type: String,
+    pub source: String,
+}
+
+// Partición (o el disco completo) montada en algún punto
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountedPartition {
+    pub device: String,
+    pub mount_point: String,
+    pub fs_type: String,
+}
+
+pub fn read_mountinfo(path: &Path) -> Result<Vec<MountInfo>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Error leyendo {}: {}", path.display(), e))?;
+    Ok(parse_mountinfo(&contents))
+}
+
+// Formato (man 5 proc):
+//   36 35 98:0 /mnt1 /mnt/parent rw,noatime master:1 - ext3 /dev/root rw
+// Los campos opcionales terminan en " - ", tras el que van el tipo y el origen
+pub fn parse_mountinfo(contents: &str) -> Vec<MountInfo> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let mount: Vec<&str> = mount.split(' ').collect();
+            let mut filesystem = filesystem.split(' ');
+            let (major, minor) = mount.get(2)?.split_once(':')?;
+
+            Some(MountInfo {
+                device: (major.parse().ok()?, minor.parse().ok()?),
+                mount_point: unescape_mount_field(mount.get(4)?),
+                fs_type: filesystem.next()?.to_string(),
+                source: unescape_mount_field(filesystem.next()?),
+            })
+        })
+        .collect()
+}
+
+// El disco y sus particiones con su número mayor:menor, si sysfs lo indica
+fn disk_devices(sys_block: &Path, disk: &str) -> Vec<(String, Option<(u32, u32)>)> {
+    let Some(name) = Path::new(disk).file_name().map(|name| name.to_string_lossy().into_owned()) else {
+        return Vec::new();
+    };
+
+    let mut devices = vec![(name.clone(), sysfs::read_dev(&sys_block.join(&name)))];
+    devices.extend(
+        sysfs::disk_partitions(sys_block, disk)
+            .into_iter()
+            .map(|partition| (partition.name, partition.dev)),
+    );
+    devices
+}
+
+// Montajes del disco o de sus particiones. Se compara el número
+// mayor:menor y el nombre exacto del dispositivo de origen (los sistemas de
+// archivos como btrfs o FUSE usan un número anónimo), nunca subcadenas:
+// /dev/sda1 no coincide con /dev/sda10 ni /dev/sda con /dev/sdab1.
+pub fn mounted_partitions(sys_block: &Path, mounts: &[MountInfo], disk: &str) -> Vec<MountedPartition> {
+    let devices = disk_devices(sys_block, disk);
+    let dev_dir = Path::new(disk).parent().unwrap_or(Path::new("/dev"));
+
+    let mut mounted: Vec<MountedPartition> = mounts
+        .iter()
+        .filter_map(|mount| {
+            let device = devices
+                .iter()
+                .map(|(name, dev)| (dev_dir.join(name).to_string_lossy().into_owned(), *dev))
+                .find(|(device, dev)| is_mount_of(mount, device, *dev))?
+                .0;
+
+            Some(MountedPartition {
+                device,
+                mount_point: mount.mount_point.clone(),
+                fs_type: mount.fs_type.clone(),
+            })
+        })
+        .collect();
+
+    // Los montajes anidados se desmontan antes que su padre
+    mounted.sort_by_key(|partition| std::cmp::Reverse(partition.mount_point.matches('/').count()));
+    mounted
+}
+
+// Si `mount` es un montaje del dispositivo `device` (con número `dev`)
+pub fn is_mount_of(mount: &MountInfo, device: &str, dev: Option<(u32, u32)>) -> bool {
+    if dev == Some(mount.device) {
+        return true;
+    }
+    let source = fs::canonicalize(&mount.source)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| mount.source.clone());
+    source == device
+}
+
+// /proc/mounts y mountinfo escapan espacios, tabuladores y saltos de línea
+// en octal (\040)
+pub fn unescape_mount_field(field: &str) -> String {
+    let mut result = String::new();
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 4).and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+23 22 8:1 / /boot/efi rw,relatime shared:2 - vfat /dev/sda1 rw
+24 22 8:10 / /srv rw,relatime shared:3 - xfs /dev/sda10 rw
+25 22 65:176 / /media/usb\\040stick rw,relatime shared:4 - vfat /dev/sdab1 rw
+26 25 8:17 / /media/usb\\040stick/data rw - ext4 /dev/sdb1 rw
+27 22 0:45 / /home rw,relatime shared:5 - btrfs /dev/sdb2 rw,subvol=/home
+28 22 0:22 / /proc rw,nosuid - proc proc rw
+";
+
+    fn sysfs() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let partition = |disk: &str, name: &str, dev: &str| {
+            let path = dir.path().join(disk).join(name);
+            fs::create_dir_all(&path).unwrap();
+            let number = name.trim_start_matches(disk);
+            fs::write(path.join("partition"), format!("{}\n", number)).unwrap();
+            fs::write(path.join("dev"), format!("{}\n", dev)).unwrap();
+        };
+        partition("sda", "sda1", "8:1");
+        partition("sda", "sda2", "8:2");
+        partition("sda", "sda10", "8:10");
+        partition("sdab", "sdab1", "65:177");
+        partition("sdb", "sdb1", "8:17");
+        partition("sdb", "sdb2", "8:18");
+        fs::write(dir.path().join("sdb/dev"), "8:16\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_mountinfo_with_optional_fields_and_escapes() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+
+        assert_eq!(mounts.len(), 7);
+        assert_eq!(mounts[1], MountInfo {
+            device: (8, 1),
+            mount_point: "/boot/efi".to_string(),
+            fs_type: "vfat".to_string(),
+            source: "/dev/sda1".to_string(),
+        });
+        assert_eq!(mounts[3].mount_point, "/media/usb stick");
+        assert_eq!(unescape_mount_field("/tmp/a\\b"), "/tmp/a\\b");
+    }
+
+    #[test]
+    fn matches_partitions_exactly_by_device_number_and_name() {
+        let sys_block = sysfs();
+        let mounts = parse_mountinfo(MOUNTINFO);
+
+        let mounted = mounted_partitions(sys_block.path(), &mounts, "/dev/sdb");
+
+        assert_eq!(
+            mounted,
+            [
+                MountedPartition {
+                    device: "/dev/sdb1".to_string(),
+                    mount_point: "/media/usb stick/data".to_string(),
+                    fs_type: "ext4".to_string(),
+                },
+                MountedPartition {
+                    device: "/dev/sdb2".to_string(),
+                    mount_point: "/home".to_string(),
+                    fs_type: "btrfs".to_string(),
+                },
+            ]
+        );
+        // sda10 es una partición de sda; sdab1 es de otro disco
+        let devices: Vec<(String, String)> = mounted_partitions(sys_block.path(), &mounts, "/dev/sda")
+            .into_iter()
+            .map(|partition| (partition.device, partition.mount_point))
+            .collect();
+        assert_eq!(
+            devices,
+            [
+                ("/dev/sda1".to_string(), "/boot/efi".to_string()),
+                ("/dev/sda2".to_string(), "/".to_string()),
+                ("/dev/sda10".to_string(), "/srv".to_string()),
+            ]
+        );
+        assert!(mounted_partitions(sys_block.path(), &mounts, "/dev/sdc").is_empty());
+    }
+}
@@ -0,0 +1,344 @@
+use serde::Deserialize;
+
+use crate::disk_image::parse_size;
+use crate::gpt::{self, Guid};
+use crate::{BootMode, FilesystemType, PartitionFilesystem, PartitionSize, PartitionSpec};
+
+// Entrada [[partitions]] del fichero de respuestas:
+//
+//   [[partitions]]
+//   size = "512M"          # "8G", "25%" o "rest"
+//   filesystem = "efi"     # efi, redoxfs, ext4 o swap
+//   label = "REDOX_EFI"
+//
+//   [[partitions]]
+//   size = "rest"
+//   filesystem = "redoxfs"
+//   mount = "/"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawPartition {
+    pub size: String,
+    pub filesystem: String,
+    pub mount: Option<String>,
+    pub label: Option<String>,
+}
+
+// Límites de las etiquetas de cada sistema de archivos
+const FAT_LABEL_MAX: usize = 11;
+const EXT4_LABEL_MAX: usize = 16;
+
+pub fn default_layout(efi_size_mb: u64, filesystem_type: &FilesystemType) -> Vec<PartitionSpec> {
+    vec![
+        PartitionSpec {
+            label: "REDOX_EFI".to_string(),
+            size: PartitionSize::Mebibytes(efi_size_mb),
+            filesystem: PartitionFilesystem::Efi,
+            mount_point: None,
+        },
+        PartitionSpec {
+            label: "REDOX_ROOT".to_string(),
+            size: PartitionSize::Remaining,
+            filesystem: match filesystem_type {
+                FilesystemType::RedoxFS => PartitionFilesystem::RedoxFS,
+                FilesystemType::Ext4 => PartitionFilesystem::Ext4,
+            },
+            mount_point: Some("/".to_string()),
+        },
+    ]
+}
+
+pub fn parse_partition_size(size: &str) -> Result<PartitionSize, String> {
+    let size = size.trim();
+    if size.eq_ignore_ascii_case("rest") {
+        return Ok(PartitionSize::Remaining);
+    }
+
+    if let Some(percent) = size.strip_suffix('%') {
+        return match percent.trim().parse::<u64>() {
+            Ok(n) if (1..=100).contains(&n) => Ok(PartitionSize::Percent(n)),
+            _ => Err(format!("Porcentaje inválido: {} (1% a 100%)", size)),
+        };
+    }
+
+    let bytes = parse_size(size)?;
+    let mib = 1024 * 1024;
+    if bytes == 0 || bytes % mib != 0 {
+        return Err(format!("El tamaño {} debe ser un múltiplo de 1 MiB", size));
+    }
+    Ok(PartitionSize::Mebibytes(bytes / mib))
+}
+
+pub fn parse_filesystem(name: &str) -> Result<PartitionFilesystem, String> {
+    match name.trim().to_lowercase().as_str() {
+        "efi" => Ok(PartitionFilesystem::Efi),
+        "redoxfs" => Ok(PartitionFilesystem::RedoxFS),
+        "ext4" => Ok(PartitionFilesystem::Ext4),
+        "swap" => Ok(PartitionFilesystem::Swap),
+        _ => Err(format!("Sistema de archivos de partición inválido: {} (efi/redoxfs/ext4/swap)", name)),
+    }
+}
+
+impl RawPartition {
+    pub fn to_spec(&self, index: usize) -> Result<PartitionSpec, String> {
+        let filesystem = parse_filesystem(&self.filesystem)?;
+        let label = match &self.label {
+            Some(label) => label.clone(),
+            None => default_label(&filesystem, self.mount.as_deref(), index),
+        };
+
+        Ok(PartitionSpec {
+            label,
+            size: parse_partition_size(&self.size)?,
+            filesystem,
+            mount_point: self.mount.clone(),
+        })
+    }
+}
+
+fn default_label(filesystem: &PartitionFilesystem, mount_point: Option<&str>, index: usize) -> String {
+    match (filesystem, mount_point) {
+        (PartitionFilesystem::Efi, _) => "REDOX_EFI".to_string(),
+        (PartitionFilesystem::Swap, _) => "REDOX_SWAP".to_string(),
+        (_, Some("/")) => "REDOX_ROOT".to_string(),
+        (_, Some(mount_point)) => format!("REDOX_{}", mount_point.trim_matches('/').replace('/', "_").to_uppercase()),
+        (_, None) => format!("REDOX_DATA{}", index + 1),
+    }
+}
+
+// Comprueba que la distribución puede instalarse: una única partición EFI
+// (ninguna si solo se arranca por BIOS), una única root y puntos de montaje
+// coherentes
+pub fn validate_layout(layout: &[PartitionSpec], boot_mode: BootMode) -> Result<(), String> {
+    let efi_count = layout.iter().filter(|spec| spec.filesystem == PartitionFilesystem::Efi).count();
+    if boot_mode.uefi() && efi_count != 1 {
+        return Err(format!("La distribución debe tener exactamente una partición EFI (tiene {})", efi_count));
+    }
+    if !boot_mode.uefi() && efi_count != 0 {
+        return Err("El arranque solo BIOS no usa partición EFI".to_string());
+    }
+
+    let root_count = layout.iter().filter(|spec| spec.mount_point.as_deref() == Some("/")).count();
+    if root_count != 1 {
+        return Err(format!("La distribución debe tener exactamente una partición montada en / (tiene {})", root_count));
+    }
+
+    // El bootloader BIOS solo sabe leer el kernel de RedoxFS
+    let root = &layout[root_index(layout)];
+    if boot_mode.bios() && root.filesystem != PartitionFilesystem::RedoxFS {
+        return Err(format!("El arranque BIOS necesita la partición root en RedoxFS ({} no lo es)", root.label));
+    }
+
+    let mut mount_points = Vec::new();
+    for (i, spec) in layout.iter().enumerate() {
+        if spec.label.is_empty() || !spec.label.is_ascii() || spec.label.contains(char::is_whitespace) {
+            return Err(format!("Etiqueta inválida en la partición {}: '{}'", i + 1, spec.label));
+        }
+
+        if spec.size == PartitionSize::Remaining && i != layout.len() - 1 {
+            return Err(format!("Solo la última partición puede usar \"rest\" (partición {})", i + 1));
+        }
+
+        match (&spec.filesystem, &spec.mount_point) {
+            (PartitionFilesystem::Efi, Some(_)) | (PartitionFilesystem::Swap, Some(_)) => {
+                return Err(format!("La partición {} ({}) no admite punto de montaje", i + 1, spec.label));
+            }
+            (PartitionFilesystem::Efi, None) if spec.label.len() > FAT_LABEL_MAX => {
+                return Err(format!("La etiqueta FAT32 {} supera {} caracteres", spec.label, FAT_LABEL_MAX));
+            }
+            (PartitionFilesystem::Ext4, _) if spec.label.len() > EXT4_LABEL_MAX => {
+                return Err(format!("La etiqueta ext4 {} supera {} caracteres", spec.label, EXT4_LABEL_MAX));
+            }
+            (_, Some(mount_point)) => {
+                if !mount_point.starts_with('/') {
+                    return Err(format!("El punto de montaje debe ser absoluto: {}", mount_point));
+                }
+                if mount_points.contains(mount_point) {
+                    return Err(format!("Punto de montaje repetido: {}", mount_point));
+                }
+                mount_points.push(mount_point.clone());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Posición de la partición EFI; validate_layout garantiza que existe
+pub fn efi_index(layout: &[PartitionSpec]) -> usize {
+    layout
+        .iter()
+        .position(|spec| spec.filesystem == PartitionFilesystem::Efi)
+        .expect("distribución sin partición EFI")
+}
+
+// Posición de la partición root; validate_layout garantiza que existe
+pub fn root_index(layout: &[PartitionSpec]) -> usize {
+    layout
+        .iter()
+        .position(|spec| spec.mount_point.as_deref() == Some("/"))
+        .expect("distribución sin partición root")
+}
+
+// Particiones que se montan dentro de root, como (posición en la distribución,
+// punto de montaje), ordenadas para montar antes los directorios padre
+pub fn extra_mounts(layout: &[PartitionSpec]) -> Vec<(usize, String)> {
+    let mut mounts: Vec<(usize, String)> = layout
+        .iter()
+        .enumerate()
+        .filter_map(|(i, spec)| match spec.mount_point.as_deref() {
+            Some("/") | None => None,
+            Some(mount_point) => Some((i, mount_point.trim_end_matches('/').to_string())),
+        })
+        .collect();
+    mounts.sort_by_key(|(_, mount_point)| mount_point.matches('/').count());
+    mounts
+}
+
+// Tamaño en bytes de cada partición para un disco concreto; None significa
+// "hasta el final del disco"
+pub fn resolve_size(size: &PartitionSize, disk_bytes: u64) -> Option<u64> {
+    let mib = 1024 * 1024;
+    match size {
+        PartitionSize::Mebibytes(n) => Some(n * mib),
+        // Redondeado a MiB completos para mantener la alineación
+        PartitionSize::Percent(percent) => Some(disk_bytes / 100 * percent / mib * mib),
+        PartitionSize::Remaining => None,
+    }
+}
+
+pub fn type_guid(spec: &PartitionSpec) -> Guid {
+    match (&spec.filesystem, spec.mount_point.as_deref()) {
+        (PartitionFilesystem::Efi, _) => gpt::EFI_SYSTEM_PARTITION,
+        (PartitionFilesystem::Swap, _) => gpt::LINUX_SWAP,
+        (PartitionFilesystem::RedoxFS, Some("/")) => gpt::REDOX_ROOT,
+        (PartitionFilesystem::RedoxFS, _) => gpt::REDOX_DATA,
+        (PartitionFilesystem::Ext4, _) => gpt::LINUX_FILESYSTEM,
+    }
+}
+
+impl PartitionSize {
+    pub fn describe(&self) -> String {
+        match self {
+            PartitionSize::Mebibytes(n) => format!("{} MB", n),
+            PartitionSize::Percent(n) => format!("{}%", n),
+            PartitionSize::Remaining => "resto del disco".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(label: &str, size: PartitionSize, filesystem: PartitionFilesystem, mount_point: Option<&str>) -> PartitionSpec {
+        PartitionSpec {
+            label: label.to_string(),
+            size,
+            filesystem,
+            mount_point: mount_point.map(str::to_string),
+        }
+    }
+
+    fn workstation_layout() -> Vec<PartitionSpec> {
+        vec![
+            spec("REDOX_EFI", PartitionSize::Mebibytes(512), PartitionFilesystem::Efi, None),
+            spec("REDOX_SWAP", PartitionSize::Mebibytes(4096), PartitionFilesystem::Swap, None),
+            spec("REDOX_ROOT", PartitionSize::Percent(40), PartitionFilesystem::RedoxFS, Some("/")),
+            spec("REDOX_HOME", PartitionSize::Percent(40), PartitionFilesystem::RedoxFS, Some("/home")),
+        ]
+    }
+
+    #[test]
+    fn parses_partition_sizes() {
+        assert_eq!(parse_partition_size("512M"), Ok(PartitionSize::Mebibytes(512)));
+        assert_eq!(parse_partition_size("8G"), Ok(PartitionSize::Mebibytes(8192)));
+        assert_eq!(parse_partition_size("25%"), Ok(PartitionSize::Percent(25)));
+        assert_eq!(parse_partition_size("REST"), Ok(PartitionSize::Remaining));
+        assert!(parse_partition_size("0%").is_err());
+        assert!(parse_partition_size("101%").is_err());
+        assert!(parse_partition_size("1500K").is_err());
+    }
+
+    #[test]
+    fn raw_partitions_get_default_labels() {
+        let raw = |filesystem: &str, mount: Option<&str>| RawPartition {
+            size: "1G".to_string(),
+            filesystem: filesystem.to_string(),
+            mount: mount.map(str::to_string),
+            label: None,
+        };
+
+        assert_eq!(raw("swap", None).to_spec(1).unwrap().label, "REDOX_SWAP");
+        assert_eq!(raw("ext4", Some("/home")).to_spec(2).unwrap().label, "REDOX_HOME");
+        assert_eq!(raw("redoxfs", Some("/")).to_spec(3).unwrap().label, "REDOX_ROOT");
+        assert!(raw("ntfs", None).to_spec(0).is_err());
+    }
+
+    #[test]
+    fn workstation_layout_is_valid() {
+        assert_eq!(validate_layout(&workstation_layout(), BootMode::Uefi), Ok(()));
+        assert_eq!(validate_layout(&default_layout(512, &FilesystemType::Ext4), BootMode::Uefi), Ok(()));
+        assert_eq!(validate_layout(&workstation_layout(), BootMode::Hybrid), Ok(()));
+        assert_eq!(validate_layout(&workstation_layout()[1..], BootMode::Bios), Ok(()));
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        let mut no_efi = workstation_layout();
+        no_efi.remove(0);
+        assert!(validate_layout(&no_efi, BootMode::Uefi).unwrap_err().contains("EFI"));
+        assert!(validate_layout(&workstation_layout(), BootMode::Bios).unwrap_err().contains("EFI"));
+        let ext4_root = default_layout(512, &FilesystemType::Ext4);
+        assert!(validate_layout(&ext4_root, BootMode::Hybrid).unwrap_err().contains("RedoxFS"));
+
+        let mut two_roots = workstation_layout();
+        two_roots[3].mount_point = Some("/".to_string());
+        assert!(validate_layout(&two_roots, BootMode::Uefi).unwrap_err().contains("/"));
+
+        let mut rest_in_middle = workstation_layout();
+        rest_in_middle[1].size = PartitionSize::Remaining;
+        assert!(validate_layout(&rest_in_middle, BootMode::Uefi).unwrap_err().contains("rest"));
+
+        let mut mounted_swap = workstation_layout();
+        mounted_swap[1].mount_point = Some("/swap".to_string());
+        assert!(validate_layout(&mounted_swap, BootMode::Uefi).is_err());
+
+        let mut long_fat_label = workstation_layout();
+        long_fat_label[0].label = "REDOX_EFI_PARTITION".to_string();
+        assert!(validate_layout(&long_fat_label, BootMode::Uefi).is_err());
+    }
+
+    #[test]
+    fn percentages_round_down_to_whole_mebibytes() {
+        let disk = 10 * 1024 * 1024 * 1024 + 12345;
+        let size = resolve_size(&PartitionSize::Percent(40), disk).unwrap();
+        assert_eq!(size % (1024 * 1024), 0);
+        assert!(size <= disk * 40 / 100);
+        assert_eq!(resolve_size(&PartitionSize::Remaining, disk), None);
+    }
+
+    #[test]
+    fn extra_mounts_are_ordered_parents_first() {
+        let mut layout = workstation_layout();
+        layout[3].mount_point = Some("/home/shared/".to_string());
+        layout.push(spec("REDOX_H", PartitionSize::Remaining, PartitionFilesystem::Ext4, Some("/home")));
+
+        assert_eq!(efi_index(&layout), 0);
+        assert_eq!(root_index(&layout), 2);
+        assert_eq!(
+            extra_mounts(&layout),
+            [(4, "/home".to_string()), (3, "/home/shared".to_string())]
+        );
+    }
+
+    #[test]
+    fn partition_types_follow_role() {
+        let layout = workstation_layout();
+        assert_eq!(type_guid(&layout[0]), gpt::EFI_SYSTEM_PARTITION);
+        assert_eq!(type_guid(&layout[1]), gpt::LINUX_SWAP);
+        assert_eq!(type_guid(&layout[2]), gpt::REDOX_ROOT);
+        assert_eq!(type_guid(&layout[3]), gpt::REDOX_DATA);
+    }
+}
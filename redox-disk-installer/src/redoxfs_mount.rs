@@ -0,0 +1,273 @@
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::command_runner::CommandRunner;
+use crate::mounts;
+
+// Montaje de RedoxFS a través del daemon FUSE `redoxfs`. Mientras el guard
+// existe el sistema de archivos está montado; al soltarlo (o con unmount) se
+// desmonta con fusermount -u y se recoge el proceso.
+
+// Cada cuánto se mira si el montaje ya aparece o si el daemon terminó
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Tiempo que se espera a que redoxfs salga después de desmontar
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+// Últimas líneas de stderr de redoxfs que se añaden a los errores
+const STDERR_LINES: usize = 20;
+
+pub struct RedoxFsMount {
+    runner: Rc<dyn CommandRunner>,
+    target: String,
+    // /proc/self/mountinfo, donde aparece el montaje
+    mountinfo: PathBuf,
+    child: Option<Child>,
+    stderr: Arc<Mutex<Vec<String>>>,
+    stderr_reader: Option<JoinHandle<()>>,
+}
+
+impl RedoxFsMount {
+    // Lanza `redoxfs partition target` y espera a que el montaje aparezca en
+    // mountinfo. Si el daemon termina antes o no monta en `timeout`, el error
+    // incluye lo que escribió en stderr
+    pub fn mount(
+        runner: Rc<dyn CommandRunner>,
+        redoxfs: &Path,
+        partition: &str,
+        target: &str,
+        mountinfo: &Path,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        let mut child = runner.spawn(&redoxfs.to_string_lossy(), &[partition, target])
+            .map_err(|e| format!("Error iniciando {}: {}", redoxfs.display(), e))?;
+
+        // stderr se lee en otro hilo para que redoxfs nunca se bloquee
+        // escribiendo en una tubería llena
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let stderr_reader = child.stderr.take().map(|pipe| {
+            let lines = stderr.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                    let mut lines = lines.lock().unwrap();
+                    if lines.len() == STDERR_LINES {
+                        lines.remove(0);
+                    }
+                    lines.push(line);
+                }
+            })
+        });
+
+        let mut mount = Self {
+            runner,
+            target: target.trim_end_matches('/').to_string(),
+            mountinfo: mountinfo.to_path_buf(),
+            child: Some(child),
+            stderr,
+            stderr_reader,
+        };
+
+        let attempts = (timeout.as_millis() / POLL_INTERVAL.as_millis()).max(1);
+        for _ in 0..attempts {
+            if mount.is_mounted() {
+                return Ok(mount);
+            }
+            let exited = mount.child.as_mut().and_then(|child| child.try_wait().ok().flatten());
+            if let Some(status) = exited {
+                mount.stop().ok();
+                return Err(format!("redoxfs terminó ({}) sin montar {}{}", status, target, mount.stderr_tail()));
+            }
+            mount.runner.wait(POLL_INTERVAL);
+        }
+        if mount.is_mounted() {
+            return Ok(mount);
+        }
+
+        mount.stop().ok();
+        Err(format!("redoxfs no montó {} en {} s{}", target, timeout.as_secs(), mount.stderr_tail()))
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.child.as_ref().map(Child::id)
+    }
+
+    // Desmonta y espera a que el daemon termine, devolviendo los errores que
+    // al soltar el guard solo se muestran
+    pub fn unmount(mut self) -> Result<(), String> {
+        self.stop()
+    }
+
+    fn is_mounted(&self) -> bool {
+        mounts::read_mountinfo(&self.mountinfo)
+            .is_ok_and(|mounts| mounts.iter().any(|mount| mount.mount_point == self.target))
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        let Some(mut child) = self.child.take() else {
+            return Ok(());
+        };
+
+        // Puede que ya se desmontara con umount: entonces solo queda recoger
+        // el proceso. Si está ocupado se desmonta en diferido para que el
+        // daemon pueda salir, pero el error se devuelve igualmente
+        let unmounted = if self.is_mounted() {
+            self.fusermount("-u").map_err(|e| match self.lazy_unmount() {
+                Ok(()) => format!("{} (desmontado en diferido)", e),
+                Err(lazy) => format!("{}; {}", e, lazy),
+            })
+        } else {
+            Ok(())
+        };
+
+        // El daemon sale al desmontarse; si no lo hace a tiempo se le mata
+        let attempts = EXIT_TIMEOUT.as_millis() / POLL_INTERVAL.as_millis();
+        for _ in 0..attempts {
+            if !matches!(child.try_wait(), Ok(None)) {
+                break;
+            }
+            self.runner.wait(POLL_INTERVAL);
+        }
+        if matches!(child.try_wait(), Ok(None)) {
+            child.kill().ok();
+        }
+        let reaped = child.wait().map_err(|e| format!("Error esperando a redoxfs: {}", e));
+        if let Some(reader) = self.stderr_reader.take() {
+            reader.join().ok();
+        }
+
+        unmounted.and(reaped.map(|_| ()))
+    }
+
+    fn fusermount(&self, flags: &str) -> Result<(), String> {
+        let args = [flags, self.target.as_str()];
+        // fuse3 solo instala fusermount3
+        let output = self.runner.run("fusermount", &args)
+            .or_else(|_| self.runner.run("fusermount3", &args))
+            .map_err(|e| format!("No se pudo ejecutar fusermount: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "fusermount {} {}: {}",
+                flags,
+                self.target,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    fn lazy_unmount(&self) -> Result<(), String> {
+        self.fusermount("-uz").or_else(|fusermount| {
+            let output = self.runner.run("umount", &["-l", &self.target])
+                .map_err(|e| format!("{}; no se pudo ejecutar umount: {}", fusermount, e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "{}; umount -l {}: {}",
+                    fusermount,
+                    self.target,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    fn stderr_tail(&self) -> String {
+        let lines = self.stderr.lock().unwrap();
+        if lines.is_empty() {
+            return String::new();
+        }
+        format!(": {}", lines.join(" / "))
+    }
+}
+
+impl Drop for RedoxFsMount {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            eprintln!("   ⚠️  {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeRunner;
+    use std::fs;
+
+    fn mountinfo_with(dir: &Path, target: Option<&str>) -> PathBuf {
+        let path = dir.join("mountinfo");
+        let line = target
+            .map(|target| format!("60 25 0:52 / {} rw,nosuid,nodev - fuse redoxfs rw\n", target))
+            .unwrap_or_default();
+        fs::write(&path, line).unwrap();
+        path
+    }
+
+    #[test]
+    fn waits_for_the_mount_and_unmounts_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let mountinfo = mountinfo_with(dir.path(), Some("/mnt/redox"));
+        let runner = Rc::new(FakeRunner::new());
+
+        let mount = RedoxFsMount::mount(runner.clone(), Path::new("redoxfs"), "/dev/sdb2", "/mnt/redox/", &mountinfo, Duration::from_secs(1)).unwrap();
+        assert_eq!(mount.target(), "/mnt/redox");
+        assert!(mount.pid().is_some());
+        drop(mount);
+
+        assert_eq!(runner.calls(), ["redoxfs /dev/sdb2 /mnt/redox/", "fusermount -u /mnt/redox"]);
+    }
+
+    #[test]
+    fn daemon_exit_reports_its_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let mountinfo = mountinfo_with(dir.path(), None);
+        let runner = Rc::new(FakeRunner::new().respond("redoxfs", 1, "", "failed to open image: permission denied"));
+
+        let err = RedoxFsMount::mount(runner.clone(), Path::new("redoxfs"), "/dev/sdb2", "/mnt/redox", &mountinfo, Duration::from_secs(60))
+            .err()
+            .unwrap();
+
+        assert!(err.starts_with("redoxfs terminó"), "{}", err);
+        assert!(err.ends_with(": failed to open image: permission denied"), "{}", err);
+        // Sin montaje no hay nada que desmontar
+        assert!(!runner.calls().iter().any(|call| call.starts_with("fusermount")));
+    }
+
+    #[test]
+    fn busy_mount_falls_back_to_a_lazy_unmount() {
+        let dir = tempfile::tempdir().unwrap();
+        let mountinfo = mountinfo_with(dir.path(), Some("/mnt/redox"));
+        let runner = Rc::new(
+            FakeRunner::new()
+                .respond("fusermount -u /mnt/redox", 1, "", "Device or resource busy")
+                .respond("fusermount -uz", 1, "", ""),
+        );
+        let mount = RedoxFsMount::mount(runner.clone(), Path::new("redoxfs"), "/dev/sdb2", "/mnt/redox", &mountinfo, Duration::from_secs(1)).unwrap();
+
+        let err = mount.unmount().unwrap_err();
+
+        assert!(err.starts_with("fusermount -u /mnt/redox: Device or resource busy"), "{}", err);
+        assert!(err.ends_with("(desmontado en diferido)"), "{}", err);
+        assert_eq!(runner.calls()[1..], ["fusermount -u /mnt/redox", "fusermount -uz /mnt/redox", "umount -l /mnt/redox"]);
+    }
+
+    #[test]
+    fn already_unmounted_filesystem_is_only_reaped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mountinfo = mountinfo_with(dir.path(), Some("/mnt/redox"));
+        let runner = Rc::new(FakeRunner::new());
+        let mount = RedoxFsMount::mount(runner.clone(), Path::new("redoxfs"), "/dev/sdb2", "/mnt/redox", &mountinfo, Duration::from_secs(1)).unwrap();
+
+        mountinfo_with(dir.path(), None);
+        mount.unmount().unwrap();
+
+        assert_eq!(runner.calls(), ["redoxfs /dev/sdb2 /mnt/redox"]);
+    }
+}
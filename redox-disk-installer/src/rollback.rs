@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::fs;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::command_runner::CommandRunner;
+use crate::gpt;
+use crate::redoxfs_mount::RedoxFsMount;
+use crate::table_backup::TableSnapshot;
+
+// Acción que deshace un paso ya completado de la instalación
+pub enum UndoAction {
+    Unmount(String),
+    // Daemon FUSE de redoxfs y su montaje
+    StopFuse(RedoxFsMount),
+    RemoveDir(String),
+    // Número de entrada de arranque UEFI creada con efibootmgr (p. ej. "0004")
+    RemoveBootEntry(String),
+    RestorePartitionTable { disk: String, snapshot: TableSnapshot },
+}
+
+impl UndoAction {
+    fn describe(&self) -> String {
+        match self {
+            UndoAction::Unmount(target) => format!("Desmontando {}", target),
+            UndoAction::StopFuse(mount) => match mount.pid() {
+                Some(pid) => format!("Deteniendo redoxfs (PID {}) en {}", pid, mount.target()),
+                None => format!("Desmontando {}", mount.target()),
+            },
+            UndoAction::RemoveDir(path) => format!("Eliminando {}", path),
+            UndoAction::RemoveBootEntry(bootnum) => format!("Eliminando la entrada de arranque Boot{}", bootnum),
+            UndoAction::RestorePartitionTable { disk, .. } => {
+                format!("Restaurando la tabla de particiones original de {}", disk)
+            }
+        }
+    }
+
+    fn undo(self, runner: &dyn CommandRunner) -> Result<(), String> {
+        match self {
+            UndoAction::Unmount(target) => {
+                let output = runner.run("umount", &[&target])
+                    .map_err(|e| format!("Error ejecutando umount: {}", e))?;
+                if output.status.success() {
+                    Ok(())
+                } else {
+                    // Ocupado (p. ej. un proceso con ficheros abiertos): se
+                    // desmonta de forma diferida
+                    runner.run("umount", &["-l", &target]).ok();
+                    Err(format!("umount {}: {}", target, String::from_utf8_lossy(&output.stderr).trim()))
+                }
+            }
+            UndoAction::StopFuse(mount) => mount.unmount(),
+            UndoAction::RemoveDir(path) => {
+                fs::remove_dir(&path).map_err(|e| format!("{}: {}", path, e))
+            }
+            UndoAction::RemoveBootEntry(bootnum) => {
+                let output = runner.run("efibootmgr", &["--bootnum", &bootnum, "--delete-bootnum"])
+                    .map_err(|e| format!("Error ejecutando efibootmgr: {}", e))?;
+                if output.status.success() {
+                    Ok(())
+                } else {
+                    Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+                }
+            }
+            UndoAction::RestorePartitionTable { disk, snapshot } => {
+                let (file, _, _) = gpt::open_disk(&disk)?;
+                snapshot.restore(&file)?;
+                // Sin particiones montadas el kernel puede volver a leer la tabla
+                gpt::reread_partition_table(&file).ok();
+                Ok(())
+            }
+        }
+    }
+}
+
+// Pila de acciones a deshacer. Cada paso registra la suya al completarse; si
+// la instalación falla o se interrumpe se ejecutan en orden inverso.
+pub struct UndoStack {
+    runner: Rc<dyn CommandRunner>,
+    actions: RefCell<Vec<UndoAction>>,
+}
+
+impl UndoStack {
+    pub fn new(runner: Rc<dyn CommandRunner>) -> Self {
+        Self {
+            runner,
+            actions: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn push(&self, action: UndoAction) {
+        self.actions.borrow_mut().push(action);
+    }
+
+    // Saca de la pila el montaje de redoxfs en `target` para desmontarlo
+    // antes de terminar
+    pub fn take_fuse(&self, target: &str) -> Option<RedoxFsMount> {
+        let mut actions = self.actions.borrow_mut();
+        let index = actions.iter().position(|action| {
+            matches!(action, UndoAction::StopFuse(mount) if mount.target() == target.trim_end_matches('/'))
+        })?;
+        match actions.remove(index) {
+            UndoAction::StopFuse(mount) => Some(mount),
+            _ => unreachable!("la posición es la de un StopFuse"),
+        }
+    }
+
+    // La instalación terminó: ya no hay nada que deshacer
+    pub fn commit(&self) {
+        // Al soltar los montajes de redoxfs, ya desmontados, se recoge el
+        // daemon FUSE
+        self.actions.borrow_mut().clear();
+    }
+
+    // Ejecuta todas las acciones aunque alguna falle, para dejar el sistema lo
+    // más limpio posible
+    pub fn rollback(&self) {
+        let actions = mem::take(&mut *self.actions.borrow_mut());
+        if actions.is_empty() {
+            return;
+        }
+
+        println!("↩️  Deshaciendo la instalación...");
+        self.runner.run("sync", &[]).ok();
+        for action in actions.into_iter().rev() {
+            println!("   {}...", action.describe());
+            if let Err(e) = action.undo(self.runner.as_ref()) {
+                eprintln!("   ⚠️  {}", e);
+            }
+        }
+        println!("   ✅ Cambios deshechos");
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Mientras existe, Ctrl-C no termina el proceso sino que marca la instalación
+// como interrumpida para que se deshaga de forma ordenada. Al soltarse se
+// restaura el manejador anterior.
+pub struct InterruptGuard {
+    previous: libc::sigaction,
+}
+
+impl InterruptGuard {
+    pub fn install() -> Result<Self, String> {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+
+        // SAFETY: sigaction con estructuras inicializadas; el manejador solo
+        // escribe en un atómico, lo que es seguro dentro de una señal
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(libc::SIGINT, &action, &mut previous) != 0 {
+                return Err(format!("Error instalando el manejador de Ctrl-C: {}", std::io::Error::last_os_error()));
+            }
+            Ok(Self { previous })
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        // SAFETY: restaura la acción guardada en install()
+        unsafe {
+            libc::sigaction(libc::SIGINT, &self.previous, ptr::null_mut());
+        }
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeRunner;
+
+    #[test]
+    fn actions_are_undone_in_reverse_order_even_if_one_fails() {
+        let runner = Rc::new(FakeRunner::new().respond("umount /mnt/efi", 32, "", "target is busy"));
+        let stack = UndoStack::new(runner.clone());
+        stack.push(UndoAction::Unmount("/mnt/efi".to_string()));
+        stack.push(UndoAction::Unmount("/mnt/root".to_string()));
+        stack.push(UndoAction::RemoveBootEntry("0004".to_string()));
+
+        stack.rollback();
+        stack.rollback();
+
+        assert_eq!(
+            runner.calls(),
+            [
+                "sync",
+                "efibootmgr --bootnum 0004 --delete-bootnum",
+                "umount /mnt/root",
+                "umount /mnt/efi",
+                "umount -l /mnt/efi",
+            ]
+        );
+    }
+
+    #[test]
+    fn committed_actions_are_not_undone() {
+        let runner = Rc::new(FakeRunner::new());
+        let stack = UndoStack::new(runner.clone());
+        stack.push(UndoAction::Unmount("/mnt/root".to_string()));
+
+        stack.commit();
+        stack.rollback();
+
+        assert!(runner.calls().is_empty());
+    }
+
+}
@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+
+// Partición del kernel tal como aparece en /sys/block/<disco>/<partición>
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockPartition {
+    // Nombre del nodo en /dev, p. ej. "nvme0n1p2" o "loop0p1"
+    pub name: String,
+    // Número de la partición en la tabla (fichero `partition`)
+    pub number: usize,
+    // Número mayor:menor del dispositivo
+    pub dev: Option<(u32, u32)>,
+}
+
+// Particiones que el kernel conoce para `disk`, ordenadas por número. El
+// nombre de cada nodo lo decide el kernel según el tipo de disco (sda1,
+// nvme0n1p1, loop0p1, md0p1...), así que se lee en lugar de deducirlo.
+pub fn disk_partitions(sys_block: &Path, disk: &str) -> Vec<BlockPartition> {
+    let Some(name) = Path::new(disk).file_name() else {
+        return Vec::new();
+    };
+    let disk_dir = sys_block.join(name);
+
+    let mut partitions: Vec<BlockPartition> = fs::read_dir(&disk_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let number = fs::read_to_string(entry.path().join("partition")).ok()?.trim().parse().ok()?;
+                    Some(BlockPartition {
+                        name: entry.file_name().to_string_lossy().into_owned(),
+                        number,
+                        dev: read_dev(&entry.path()),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    partitions.sort_by_key(|partition| partition.number);
+    partitions
+}
+
+// Si el disco tiene entrada en sysfs (los ficheros de imagen no la tienen)
+pub fn has_disk(sys_block: &Path, disk: &str) -> bool {
+    Path::new(disk).file_name().is_some_and(|name| sys_block.join(name).is_dir())
+}
+
+// Nombre del nodo de una partición cuando el disco no está en sysfs (p. ej.
+// un fichero de imagen): los discos cuyo nombre acaba en dígito llevan una
+// "p" antes del número (nvme0n1p1, loop0p1, mmcblk0p1, md0p1)
+pub fn partition_node(disk: &str, number: usize) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+// Fichero `dev` de sysfs: "mayor:menor"
+pub fn read_dev(dir: &Path) -> Option<(u32, u32)> {
+    let dev = fs::read_to_string(dir.join("dev")).ok()?;
+    let (major, minor) = dev.trim().split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_are_read_with_kernel_names_and_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, number, dev) in [("loop0p10", 10, "259:3"), ("loop0p2", 2, "259:1"), ("loop0p1", 1, "259:0")] {
+            let path = dir.path().join("loop0").join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("partition"), format!("{}\n", number)).unwrap();
+            fs::write(path.join("dev"), format!("{}\n", dev)).unwrap();
+        }
+        fs::create_dir_all(dir.path().join("loop0/queue")).unwrap();
+
+        let partitions = disk_partitions(dir.path(), "/dev/loop0");
+
+        let names: Vec<(&str, usize)> = partitions.iter().map(|partition| (partition.name.as_str(), partition.number)).collect();
+        assert_eq!(names, [("loop0p1", 1), ("loop0p2", 2), ("loop0p10", 10)]);
+        assert_eq!(partitions[2].dev, Some((259, 3)));
+        assert!(has_disk(dir.path(), "/dev/loop0"));
+        assert!(!has_disk(dir.path(), "/dev/loop1"));
+        assert!(disk_partitions(dir.path(), "/dev/loop1").is_empty());
+    }
+}
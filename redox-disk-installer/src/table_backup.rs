@@ -0,0 +1,332 @@
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::gpt::{self, GptTable};
+
+// Tamaño de las zonas que se guardan al principio y al final del disco: cubren
+// el MBR, ambas cabeceras GPT y sus tablas de entradas
+const SNAPSHOT_LEN: u64 = 1024 * 1024;
+
+// Copia binaria del principio y el final del disco
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableSnapshot {
+    #[serde(with = "base64_bytes")]
+    head: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    tail: Vec<u8>,
+    tail_offset: u64,
+}
+
+impl TableSnapshot {
+    pub fn read(file: &File, total_bytes: u64) -> Result<Self, String> {
+        let len = SNAPSHOT_LEN.min(total_bytes / 2);
+        let tail_offset = total_bytes - len;
+        let read = |offset: u64| {
+            let mut data = vec![0u8; len as usize];
+            file.read_exact_at(&mut data, offset)
+                .map(|_| data)
+                .map_err(|e| format!("Error guardando la tabla de particiones (byte {}): {}", offset, e))
+        };
+
+        Ok(Self {
+            head: read(0)?,
+            tail: read(tail_offset)?,
+            tail_offset,
+        })
+    }
+
+    pub fn restore(&self, file: &File) -> Result<(), String> {
+        gpt::write_at(file, 0, &self.head)?;
+        gpt::write_at(file, self.tail_offset, &self.tail)?;
+        file.sync_all()
+            .map_err(|e| format!("Error sincronizando el disco: {}", e))
+    }
+
+    // Disco sin ningún dato en las zonas de la tabla (nuevo o imagen recién creada)
+    pub fn is_blank(&self) -> bool {
+        self.head.iter().chain(&self.tail).all(|byte| *byte == 0)
+    }
+}
+
+// Fichero de copia de seguridad: la copia binaria más una descripción legible
+// de la distribución que había, para saber qué se va a restaurar
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableBackup {
+    pub disk: String,
+    // Fecha UTC, p. ej. 2026-10-17T15:30:00Z
+    pub created: String,
+    pub disk_bytes: u64,
+    pub sector_size: u64,
+    pub layout: BackupLayout,
+    pub snapshot: TableSnapshot,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupLayout {
+    // "gpt", "mbr" o "ninguna"
+    pub table: String,
+    pub partitions: Vec<BackupPartition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupPartition {
+    pub number: usize,
+    pub name: String,
+    // GUID de tipo en GPT, byte de tipo en hexadecimal en MBR
+    pub partition_type: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub bytes: u64,
+}
+
+impl TableBackup {
+    pub fn capture(disk: &str, file: &File, disk_bytes: u64, sector_size: u64) -> Result<Self, String> {
+        Ok(Self {
+            disk: disk.to_string(),
+            created: utc_timestamp(SystemTime::now(), "-", "T", ":", "Z"),
+            disk_bytes,
+            sector_size,
+            layout: describe_layout(file, disk_bytes, sector_size),
+            snapshot: TableSnapshot::read(file, disk_bytes)?,
+        })
+    }
+
+    // Escribe la copia en `dir` con un nombre que incluye el disco y la hora
+    pub fn save(&self, dir: &str) -> Result<PathBuf, String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Error creando el directorio de copias {}: {}", dir, e))?;
+
+        let disk_name = Path::new(&self.disk)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "disco".to_string());
+        let stamp = utc_timestamp(SystemTime::now(), "", "-", "", "");
+        let mut path = Path::new(dir).join(format!("{}-{}.json", disk_name, stamp));
+        // Dos copias del mismo disco en el mismo segundo no se pisan
+        let mut counter = 1;
+        while path.exists() {
+            path = Path::new(dir).join(format!("{}-{}-{}.json", disk_name, stamp, counter));
+            counter += 1;
+        }
+
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Error generando la copia de la tabla: {}", e))?;
+        fs::write(&path, json)
+            .map_err(|e| format!("Error escribiendo {}: {}", path.display(), e))?;
+
+        Ok(path)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Error leyendo {}: {}", path, e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("{} no es una copia de tabla de particiones válida: {}", path, e))
+    }
+
+    // Vuelve a escribir la copia en `disk`, que debe tener el mismo tamaño
+    pub fn restore_to(&self, disk: &str) -> Result<(), String> {
+        let (file, disk_bytes, sector_size) = gpt::open_disk(disk)?;
+        if disk_bytes != self.disk_bytes || sector_size != self.sector_size {
+            return Err(format!(
+                "{} tiene {} bytes (sectores de {}), pero la copia es de un disco de {} bytes (sectores de {})",
+                disk, disk_bytes, sector_size, self.disk_bytes, self.sector_size
+            ));
+        }
+
+        self.snapshot.restore(&file)?;
+        gpt::reread_partition_table(&file)
+    }
+}
+
+impl BackupLayout {
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!("Tabla: {}", self.table)];
+        for partition in &self.partitions {
+            lines.push(format!(
+                "  {}  {}-{}  {} MB  {}  {}",
+                partition.number,
+                partition.first_lba,
+                partition.last_lba,
+                partition.bytes / 1024 / 1024,
+                partition.partition_type,
+                partition.name
+            ));
+        }
+        lines
+    }
+}
+
+pub fn describe_layout(file: &File, disk_bytes: u64, sector_size: u64) -> BackupLayout {
+    if let Ok(table) = GptTable::read_from(file, disk_bytes, sector_size) {
+        return BackupLayout {
+            table: "gpt".to_string(),
+            partitions: table
+                .partitions
+                .iter()
+                .map(|partition| BackupPartition {
+                    number: partition.number,
+                    name: partition.name.clone(),
+                    partition_type: partition.type_guid.to_string(),
+                    first_lba: partition.first_lba,
+                    last_lba: partition.last_lba,
+                    bytes: partition.size_bytes(sector_size),
+                })
+                .collect(),
+        };
+    }
+
+    let mut mbr = [0u8; 512];
+    if file.read_exact_at(&mut mbr, 0).is_ok() && mbr[510..512] == [0x55, 0xAA] {
+        let partitions = (0..4)
+            .filter_map(|i| {
+                let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+                let first_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+                let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+                (entry[4] != 0 && sectors != 0).then(|| BackupPartition {
+                    number: i + 1,
+                    name: String::new(),
+                    partition_type: format!("0x{:02X}", entry[4]),
+                    first_lba,
+                    last_lba: first_lba + sectors - 1,
+                    bytes: sectors * sector_size,
+                })
+            })
+            .collect();
+        return BackupLayout { table: "mbr".to_string(), partitions };
+    }
+
+    BackupLayout {
+        table: "ninguna".to_string(),
+        partitions: Vec::new(),
+    }
+}
+
+// Fecha UTC a partir de SystemTime sin depender de una biblioteca de fechas
+// (algoritmo days-to-civil de Howard Hinnant)
+fn utc_timestamp(time: SystemTime, date_sep: &str, mid: &str, time_sep: &str, suffix: &str) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{ds}{:02}{ds}{:02}{}{:02}{ts}{:02}{ts}{:02}{}",
+        year, month, day, mid, hour, minute, second, suffix,
+        ds = date_sep,
+        ts = time_sep
+    )
+}
+
+mod base64_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+    fn disk_with_table(dir: &Path) -> (String, GptTable) {
+        let path = dir.join("disk");
+        File::create(&path).unwrap().set_len(DISK_SIZE).unwrap();
+        let mut table = GptTable::new(DISK_SIZE, 512).unwrap();
+        table.add_partition("EFI system partition", gpt::EFI_SYSTEM_PARTITION, Some(16 * 1024 * 1024)).unwrap();
+        table.add_partition("data", gpt::LINUX_FILESYSTEM, None).unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        table.write_to(&file).unwrap();
+        (path.to_string_lossy().into_owned(), table)
+    }
+
+    #[test]
+    fn backup_describes_and_restores_gpt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (disk, original) = disk_with_table(dir.path());
+
+        let (file, size, sector) = gpt::open_disk(&disk).unwrap();
+        let backup = TableBackup::capture(&disk, &file, size, sector).unwrap();
+        assert_eq!(backup.layout.table, "gpt");
+        assert_eq!(backup.layout.partitions[1].name, "data");
+        assert_eq!(backup.layout.partitions[0].partition_type, "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+        let saved = backup.save(dir.path().join("backups").to_str().unwrap()).unwrap();
+        assert!(saved.file_name().unwrap().to_string_lossy().starts_with("disk-"));
+
+        // Sobrescribir la tabla con otra y restaurar la copia desde el fichero
+        let mut other = GptTable::new(DISK_SIZE, 512).unwrap();
+        other.add_partition("REDOX_ROOT", gpt::REDOX_ROOT, None).unwrap();
+        other.write_to(&file).unwrap();
+        TableBackup::load(saved.to_str().unwrap()).unwrap().restore_to(&disk).unwrap();
+
+        let restored = GptTable::read_from(&file, size, sector).unwrap();
+        assert_eq!(restored.partitions, original.partitions);
+        assert_eq!(restored.disk_guid, original.disk_guid);
+    }
+
+    #[test]
+    fn restore_refuses_a_disk_of_another_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let (disk, _) = disk_with_table(dir.path());
+        let (file, size, sector) = gpt::open_disk(&disk).unwrap();
+        let backup = TableBackup::capture(&disk, &file, size, sector).unwrap();
+
+        let other = dir.path().join("other");
+        File::create(&other).unwrap().set_len(DISK_SIZE * 2).unwrap();
+
+        let err = backup.restore_to(other.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("la copia es de un disco de"), "{}", err);
+    }
+
+    #[test]
+    fn snapshot_restores_head_and_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk");
+        let size = 8 * 1024 * 1024;
+        fs::write(&path, vec![0xAB; size]).unwrap();
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+
+        let snapshot = TableSnapshot::read(&file, size as u64).unwrap();
+        file.write_all_at(&vec![0; size], 0).unwrap();
+        snapshot.restore(&file).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        assert!(data[..SNAPSHOT_LEN as usize].iter().all(|byte| *byte == 0xAB));
+        assert!(data[size - SNAPSHOT_LEN as usize..].iter().all(|byte| *byte == 0xAB));
+        assert!(data[SNAPSHOT_LEN as usize..size - SNAPSHOT_LEN as usize].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn timestamps_are_utc_calendar_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_251_000);
+        assert_eq!(utc_timestamp(time, "-", "T", ":", "Z"), "2026-10-17T15:30:00Z");
+        assert_eq!(utc_timestamp(time, "", "-", "", ""), "20261017-153000");
+        assert_eq!(utc_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400), "-", "T", ":", "Z"), "2000-02-29T00:00:00Z");
+    }
+}
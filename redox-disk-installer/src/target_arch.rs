@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Arquitecturas con configuración en config/<arch>/ que arrancan por UEFI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetArch {
+    X86_64,
+    Aarch64,
+    Riscv64gc,
+    I686,
+}
+
+const ALL: [TargetArch; 4] = [TargetArch::X86_64, TargetArch::Aarch64, TargetArch::Riscv64gc, TargetArch::I686];
+
+impl TargetArch {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        ALL.into_iter()
+            .find(|arch| arch.name() == name)
+            .ok_or_else(|| {
+                format!(
+                    "Arquitectura no soportada: {} ({})",
+                    name,
+                    ALL.map(TargetArch::name).join(", ")
+                )
+            })
+    }
+
+    // Nombre usado en config/<arch>/, build/<arch>/ y en el target de Rust
+    pub fn name(self) -> &'static str {
+        match self {
+            TargetArch::X86_64 => "x86_64",
+            TargetArch::Aarch64 => "aarch64",
+            TargetArch::Riscv64gc => "riscv64gc",
+            TargetArch::I686 => "i686",
+        }
+    }
+
+    // Cargador que el firmware busca en EFI/BOOT/ sin entradas en la NVRAM
+    pub fn removable_loader(self) -> &'static str {
+        match self {
+            TargetArch::X86_64 => "BOOTX64.EFI",
+            TargetArch::Aarch64 => "BOOTAA64.EFI",
+            TargetArch::Riscv64gc => "BOOTRISCV64.EFI",
+            TargetArch::I686 => "BOOTIA32.EFI",
+        }
+    }
+
+    // Sufijo de los cargadores de shim para esta arquitectura (shimx64.efi,
+    // grubx64.efi, mmx64.efi)
+    pub fn efi_suffix(self) -> &'static str {
+        match self {
+            TargetArch::X86_64 => "x64",
+            TargetArch::Aarch64 => "aa64",
+            TargetArch::Riscv64gc => "riscv64",
+            TargetArch::I686 => "ia32",
+        }
+    }
+
+    // Solo el bootloader de x86 tiene una variante BIOS (bootloader.bios)
+    pub fn supports_bios(self) -> bool {
+        matches!(self, TargetArch::X86_64 | TargetArch::I686)
+    }
+
+    // Campo Machine de la cabecera COFF de un ejecutable PE para esta
+    // arquitectura
+    pub fn pe_machine(self) -> u16 {
+        match self {
+            TargetArch::X86_64 => 0x8664,
+            TargetArch::Aarch64 => 0xAA64,
+            TargetArch::Riscv64gc => 0x5064,
+            TargetArch::I686 => 0x014C,
+        }
+    }
+
+    fn from_pe_machine(machine: u16) -> Option<Self> {
+        ALL.into_iter().find(|arch| arch.pe_machine() == machine)
+    }
+}
+
+// Lee el campo Machine de un ejecutable PE/COFF (las aplicaciones UEFI lo son)
+pub fn pe_machine(path: &Path) -> Result<u16, String> {
+    let mut header = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(4096).read_to_end(&mut header))
+        .map_err(|e| format!("Error leyendo {}: {}", path.display(), e))?;
+
+    let not_pe = || format!("{} no es un ejecutable PE/EFI", path.display());
+    if header.get(..2) != Some(b"MZ") {
+        return Err(not_pe());
+    }
+    let offset = header
+        .get(0x3C..0x40)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        .ok_or_else(not_pe)?;
+    if header.get(offset..offset + 4) != Some(b"PE\0\0") {
+        return Err(not_pe());
+    }
+    header
+        .get(offset + 4..offset + 6)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(not_pe)
+}
+
+// Un bootloader de otra arquitectura no arrancaría: mejor fallar antes de
+// copiarlo
+pub fn check_bootloader(path: &Path, arch: TargetArch) -> Result<(), String> {
+    let machine = pe_machine(path)?;
+    if machine == arch.pe_machine() {
+        return Ok(());
+    }
+    let found = TargetArch::from_pe_machine(machine)
+        .map(|found| found.name().to_string())
+        .unwrap_or_else(|| format!("máquina 0x{:04X}", machine));
+    Err(format!(
+        "El bootloader {} es para {}, no para {}",
+        path.display(),
+        found,
+        arch.name()
+    ))
+}
+
+// Cabeceras mínimas de un ejecutable PE para `machine` seguidas de `payload`
+#[cfg(test)]
+pub fn pe_stub(machine: u16, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; 0x40];
+    data[..2].copy_from_slice(b"MZ");
+    data[0x3C..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    data.extend_from_slice(b"PE\0\0");
+    data.extend_from_slice(&machine.to_le_bytes());
+    data.extend_from_slice(&[0u8; 18]);
+    data.extend_from_slice(payload);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn each_arch_has_its_loader_name_and_pe_machine() {
+        let arch = TargetArch::parse("aarch64").unwrap();
+        assert_eq!(arch.removable_loader(), "BOOTAA64.EFI");
+        assert_eq!(TargetArch::parse("riscv64gc").unwrap().removable_loader(), "BOOTRISCV64.EFI");
+        assert_eq!(TargetArch::parse("i686").unwrap().removable_loader(), "BOOTIA32.EFI");
+        assert!(TargetArch::parse("i686").unwrap().supports_bios());
+        assert!(!arch.supports_bios());
+        let err = TargetArch::parse("mips").unwrap_err();
+        assert!(err.contains("x86_64, aarch64, riscv64gc, i686"), "{}", err);
+
+        let dir = tempfile::tempdir().unwrap();
+        let loader = dir.path().join("bootloader.efi");
+        fs::write(&loader, pe_stub(0xAA64, b"loader")).unwrap();
+        assert_eq!(pe_machine(&loader).unwrap(), 0xAA64);
+        assert!(check_bootloader(&loader, arch).is_ok());
+        let err = check_bootloader(&loader, TargetArch::X86_64).unwrap_err();
+        assert!(err.ends_with("es para aarch64, no para x86_64"), "{}", err);
+
+        fs::write(&loader, "not a PE").unwrap();
+        assert!(check_bootloader(&loader, arch).unwrap_err().contains("no es un ejecutable PE"));
+    }
+}
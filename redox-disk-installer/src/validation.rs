@@ -1,18 +1,27 @@
 use std::path::Path;
-use std::process::Command;
 use std::os::unix::fs::FileTypeExt;
+use std::rc::Rc;
+use crate::artifacts::ArtifactResolver;
+use crate::command_runner::{CommandRunner, SystemRunner};
+use crate::target_arch;
+use crate::DiskInfo;
 
-pub struct SystemValidator;
+pub struct SystemValidator {
+    runner: Rc<dyn CommandRunner>,
+}
 
 impl SystemValidator {
     pub fn new() -> Self {
-        Self
+        Self::with_runner(Rc::new(SystemRunner))
+    }
+    
+    pub fn with_runner(runner: Rc<dyn CommandRunner>) -> Self {
+        Self { runner }
     }
     
     pub fn validate_system(&self) -> Result<(), String> {
         // Verificar comandos necesarios
         let required_commands = vec![
-            "parted",
             "mkfs.vfat",
             "lsblk",
             "mount",
@@ -29,38 +38,54 @@ impl SystemValidator {
         Ok(())
     }
     
-    pub fn validate_redox_build(&self) -> Result<(), String> {
-        // Verificar que existan archivos compilados de Redox
-        let paths_to_check = vec![
-            "build/x86_64",
-            "cookbook/recipes/core/kernel",
-            "cookbook/recipes/core/bootloader",
-        ];
-        
-        for path in paths_to_check {
-            if !Path::new(path).exists() {
-                return Err(format!("Directorio de compilación no encontrado: {}", path));
-            }
+    // Herramientas adicionales para instalar en un fichero de imagen
+    pub fn validate_image_tools(&self) -> Result<(), String> {
+        if !self.command_exists("losetup") {
+            return Err("Comando requerido no encontrado: losetup".to_string());
         }
-        
-        // Verificar que RedoxFS esté compilado
-        let redoxfs_mkfs = "/home/moebius/redox/redoxfs/target/release/redoxfs-mkfs";
-        let redoxfs_mount = "/home/moebius/redox/redoxfs/target/release/redoxfs";
-        
-        if !Path::new(redoxfs_mkfs).exists() || !Path::new(redoxfs_mount).exists() {
+        Ok(())
+    }
+    
+    // Comprueba que el árbol de Redox tiene lo necesario para instalar y
+    // muestra qué artefacto se usará de cada tipo y por qué
+    pub fn validate_redox_build(&self, artifacts: &ArtifactResolver) -> Result<(), String> {
+        let location = artifacts.location();
+        if !location.source_root.is_dir() {
             return Err(format!(
-                "RedoxFS no está compilado.\n   Compílalo con: cd /home/moebius/redox/redoxfs && cargo build --release"
+                "Árbol de Redox no encontrado en {} (usa --source-root o REDOX_SOURCE_ROOT)",
+                location.source_root.display()
             ));
         }
-        
-        println!("✅ RedoxFS encontrado:");
-        println!("   - {}", redoxfs_mkfs);
-        println!("   - {}", redoxfs_mount);
+
+        let arch = location.target_arch()?;
+        let bootloader = artifacts.bootloader()?;
+        target_arch::check_bootloader(&bootloader.path, arch)?;
+        let kernel = artifacts.kernel()?;
+        let redoxfs_mkfs = artifacts.redoxfs_mkfs()?;
+        let redoxfs_mount = artifacts.redoxfs_mount()?;
+
+        println!(
+            "✅ Compilación de Redox en {} ({}, {}):",
+            location.source_root.display(),
+            location.arch,
+            location.config_name
+        );
+        println!("   - Bootloader:   {} -> EFI/BOOT/{}", bootloader, arch.removable_loader());
+        println!("   - Kernel:       {}", kernel);
+        match artifacts.initfs() {
+            Some(initfs) => println!("   - Initfs:       {}", initfs),
+            None => println!("   - Initfs:       no encontrado, se omite"),
+        }
+        println!("   - redoxfs-mkfs: {}", redoxfs_mkfs);
+        println!("   - redoxfs:      {}", redoxfs_mount);
         
         Ok(())
     }
     
-    pub fn validate_disk(&self, disk_path: &str) -> Result<(), String> {
+    pub fn validate_disk(&self, disk: &DiskInfo, allow_system_disk: bool) -> Result<(), String> {
+        self.check_host_disk(disk, allow_system_disk)?;
+
+        let disk_path = disk.name.as_str();
         if !Path::new(disk_path).exists() {
             return Err(format!("{} no existe", disk_path));
         }
@@ -76,11 +101,28 @@ impl SystemValidator {
         Ok(())
     }
     
+    // Rechaza el disco del sistema en ejecución salvo que se fuerce
+    pub fn check_host_disk(&self, disk: &DiskInfo, allow_system_disk: bool) -> Result<(), String> {
+        if !disk.is_protected() {
+            return Ok(());
+        }
+
+        let usage = disk.host_usage.join(", ");
+        if !allow_system_disk {
+            return Err(format!(
+                "{} contiene el sistema en ejecución ({}). Instalar en él lo destruiría; \
+                 si es intencionado (p. ej. dual-boot en este mismo disco) usa --allow-system-disk",
+                disk.name, usage
+            ));
+        }
+
+        println!("⚠️  {} contiene el sistema en ejecución ({}); se continúa por --allow-system-disk", disk.name, usage);
+        Ok(())
+    }
+
     pub fn check_disk_space(&self, disk_path: &str) -> Result<(), String> {
         // Obtener tamaño del disco usando blockdev
-        let output = Command::new("blockdev")
-            .args(&["--getsize64", disk_path])
-            .output()
+        let output = self.runner.run("blockdev", &["--getsize64", disk_path])
             .map_err(|e| format!("Error ejecutando blockdev: {}", e))?;
         
         if !output.status.success() {
@@ -92,6 +134,12 @@ impl SystemValidator {
             .parse()
             .map_err(|_| "Error parseando tamaño del disco".to_string())?;
         
+        self.check_size(size_bytes)
+    }
+
+    // Como check_disk_space, con el tamaño ya conocido (p. ej. el de sysfs,
+    // que se puede leer sin ser root)
+    pub fn check_size(&self, size_bytes: u64) -> Result<(), String> {
         let size_gb = size_bytes as f64 / 1024.0 / 1024.0 / 1024.0;
         
         // Redox OS requiere al menos 2 GB
@@ -107,11 +155,64 @@ impl SystemValidator {
     }
     
     fn command_exists(&self, cmd: &str) -> bool {
-        Command::new("which")
-            .arg(cmd)
-            .output()
+        self.runner.run("which", &[cmd])
             .map(|output| output.status.success())
             .unwrap_or(false)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::FakeRunner;
+
+    #[test]
+    fn missing_required_command_is_reported() {
+        let runner = Rc::new(FakeRunner::new().respond("which mkfs.vfat", 1, "", ""));
+        let validator = SystemValidator::with_runner(runner);
+
+        assert_eq!(
+            validator.validate_system(),
+            Err("Comando requerido no encontrado: mkfs.vfat".to_string())
+        );
+    }
+
+    #[test]
+    fn system_disk_is_refused_unless_allowed() {
+        let validator = SystemValidator::with_runner(Rc::new(FakeRunner::new()));
+        let disk = DiskInfo {
+            name: "/dev/nvme0n1".to_string(),
+            host_usage: vec!["/dev/nvme0n1p2 montado en /".to_string()],
+            ..DiskInfo::default()
+        };
+
+        let err = validator.check_host_disk(&disk, false).unwrap_err();
+        assert!(err.contains("/dev/nvme0n1p2 montado en /"), "{}", err);
+        assert!(err.contains("--allow-system-disk"), "{}", err);
+        assert!(validator.check_host_disk(&disk, true).is_ok());
+        assert!(validator.check_host_disk(&DiskInfo::default(), false).is_ok());
+    }
+
+    #[test]
+    fn disk_space_requires_two_gigabytes() {
+        let small = SystemValidator::with_runner(Rc::new(
+            FakeRunner::new().respond("blockdev --getsize64", 0, "1073741824\n", ""),
+        ));
+        let large = SystemValidator::with_runner(Rc::new(
+            FakeRunner::new().respond("blockdev --getsize64", 0, "8589934592\n", ""),
+        ));
+
+        assert!(small.check_disk_space("/dev/sdz").unwrap_err().contains("1.00 GB"));
+        assert!(large.check_disk_space("/dev/sdz").is_ok());
+    }
+
+    #[test]
+    fn unreadable_disk_size_fails() {
+        let validator = SystemValidator::with_runner(Rc::new(
+            FakeRunner::new().respond("blockdev", 1, "", "blockdev: cannot open /dev/sdz"),
+        ));
+
+        assert!(validator.check_disk_space("/dev/sdz").is_err());
+    }
+}